@@ -0,0 +1,6 @@
+//! Builds the C bindings in `redis_metrics::ffi` into a shared library, so
+//! that `redis-metrics` itself is only ever built as an rlib, and builds of it
+//! without the standard library don't need an allocator or panic handler of
+//! their own.
+
+pub use redis_metrics::ffi::*;
@@ -0,0 +1,87 @@
+#ifndef REDIS_METRICS_H
+#define REDIS_METRICS_H
+
+/* C bindings for the redis-metrics StatsD parser, encoder, and Redis client.
+ * Build the shared library that exports these symbols with:
+ *
+ *     cargo build --release -p redis-metrics-ffi
+ *
+ * Strings returned by the accessors point into the metric, are NOT
+ * NUL-terminated, and are only valid until the metric is freed. */
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct redis_metrics_metric redis_metrics_metric;
+typedef struct redis_metrics_client redis_metrics_client;
+
+typedef enum {
+    REDIS_METRICS_COUNTER = 0,
+    REDIS_METRICS_GAUGE = 1,
+    REDIS_METRICS_SAMPLE = 2,
+    REDIS_METRICS_SET = 3,
+    REDIS_METRICS_KEY_VALUE = 4,
+    REDIS_METRICS_HISTOGRAM = 5,
+    REDIS_METRICS_DISTRIBUTION = 6,
+    REDIS_METRICS_METER = 7,
+} redis_metrics_type;
+
+/* Parses a single StatsD line. Returns NULL if the line isn't valid. */
+redis_metrics_metric *redis_metrics_parse_line(const char *line, size_t len);
+
+/* Frees a metric. Passing NULL is a no-op. */
+void redis_metrics_metric_free(redis_metrics_metric *metric);
+
+const char *redis_metrics_metric_name(const redis_metrics_metric *metric, size_t *len);
+const char *redis_metrics_metric_value(const redis_metrics_metric *metric, size_t *len);
+
+/* Returns NULL if the metric has no unit. */
+const char *redis_metrics_metric_unit(const redis_metrics_metric *metric, size_t *len);
+
+/* Returns NULL if the metric has no container ID. */
+const char *redis_metrics_metric_container_id(const redis_metrics_metric *metric, size_t *len);
+
+redis_metrics_type redis_metrics_metric_type(const redis_metrics_metric *metric);
+
+/* Returns 1.0 if the metric isn't sampled. */
+double redis_metrics_metric_sample_rate(const redis_metrics_metric *metric);
+
+/* Returns -1 for a minus sign, 1 for a plus sign, and 0 if unsigned. */
+int redis_metrics_metric_sign(const redis_metrics_metric *metric);
+
+size_t redis_metrics_metric_tag_count(const redis_metrics_metric *metric);
+
+/* Reads the tag at index. value is set to NULL for a bare tag. Returns 0 on
+ * success and -1 if index is out of range. */
+int redis_metrics_metric_tag(const redis_metrics_metric *metric, size_t index,
+                             const char **key, size_t *key_len,
+                             const char **value, size_t *value_len);
+
+/* Encodes the metric as a StatsD line with snprintf semantics. Returns the
+ * full length of the line excluding the NUL terminator. */
+size_t redis_metrics_metric_encode(const redis_metrics_metric *metric, char *buf, size_t buf_len);
+
+/* Connects to the Redis server at addr (e.g. "127.0.0.1:6379"). Returns NULL
+ * if it can't connect. */
+redis_metrics_client *redis_metrics_client_connect(const char *addr);
+
+/* Records a metric to be written on the next flush (key/values are written
+ * right away). The metric can be freed afterward. Returns 0 on success and -1
+ * on failure. */
+int redis_metrics_client_record(redis_metrics_client *client, const redis_metrics_metric *metric);
+
+/* Writes the metrics recorded since the last flush. Returns 0 on success and
+ * -1 on failure. */
+int redis_metrics_client_flush(redis_metrics_client *client);
+
+/* Frees a client without flushing it. Passing NULL is a no-op. */
+void redis_metrics_client_free(redis_metrics_client *client);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
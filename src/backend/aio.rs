@@ -0,0 +1,427 @@
+//! Async variants of the Redis backend, so that a server that ingests metrics
+//! asynchronously doesn't have to block a thread on Redis.
+//! `AsyncRedisBackend` stores metrics exactly like `RedisBackend` does.
+//!
+//! They don't depend on any runtime. Their connections read and write through
+//! an `AsyncStream`, which any runtime's sockets can implement (like
+//! async-std's or smol's `TcpStream`), and are opened with `with_stream`.
+//! With the `tokio` feature, tokio's `TcpStream` is an `AsyncStream`, and
+//! `connect` opens one.
+
+use super::connection::{check_replies, CredentialsProvider};
+use super::counters::AtomicCounters;
+use super::redis::{read_count, read_timer_stats, Aggregator};
+use super::resp::{
+    self, protocol_error, read_len, read_value, Command, Value, ENCODE_CAPACITY,
+};
+use super::{BackendError, RedisConfig, TimerStats};
+use crate::parser::{Metric, MetricId};
+use std::future::Future;
+use std::io;
+use std::str;
+use std::time::SystemTime;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "tokio")]
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+// How much is read from a stream at a time.
+const READ_SIZE: usize = 8192;
+
+/// AsyncBackend is the async equivalent of `Backend`.
+pub trait AsyncBackend {
+    /// Records a batch of metrics. Backends may write them right away, or
+    /// buffer them until the next `flush`.
+    fn record(
+        &mut self,
+        metrics: &[Metric],
+    ) -> impl Future<Output = Result<(), BackendError>> + Send;
+
+    /// Writes any metrics that the backend has buffered.
+    fn flush(&mut self) -> impl Future<Output = Result<(), BackendError>> + Send;
+}
+
+/// AsyncStream is the byte stream of a connection to Redis, like a socket of
+/// whichever async runtime a server uses.
+pub trait AsyncStream: Send {
+    /// Writes all of `buf`.
+    fn write_all(&mut self, buf: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Reads into `buf`, and returns how much was read, which is zero once the
+    /// stream's closed.
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncStream for TcpStream {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(self, buf).await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        AsyncReadExt::read(self, buf).await
+    }
+}
+
+/// AsyncConnection is a connection to a single Redis server.
+pub struct AsyncConnection<S> {
+    stream: S,
+
+    // Bytes that have been read from the stream, of which those from `start`
+    // haven't been parsed yet, and how far the reply that's being read has
+    // been scanned.
+    buffer: Vec<u8>,
+    start: usize,
+    scan: Scan,
+
+    // What commands are encoded into before they're written, which is kept
+    // so that each send doesn't allocate a buffer of its own.
+    out: Vec<u8>,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncConnection<TcpStream> {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+    ) -> Result<AsyncConnection<TcpStream>, BackendError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(AsyncConnection::with_stream(stream))
+    }
+}
+
+impl<S: AsyncStream> AsyncConnection<S> {
+    /// Talks to Redis over a stream that's already connected to it.
+    pub fn with_stream(stream: S) -> AsyncConnection<S> {
+        AsyncConnection {
+            stream,
+            buffer: Vec::new(),
+            start: 0,
+            scan: Scan::default(),
+            out: Vec::with_capacity(ENCODE_CAPACITY),
+        }
+    }
+
+    /// Sends commands in a single write and returns their replies in the same
+    /// order, including error replies.
+    pub async fn send(
+        &mut self,
+        commands: &[Command],
+    ) -> Result<Vec<Value>, BackendError> {
+        resp::encode(commands, &mut self.out);
+        self.stream.write_all(&self.out).await?;
+
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read_reply().await?);
+        }
+        Ok(replies)
+    }
+
+    /// Like `send`, but the first error reply is returned as
+    /// `BackendError::Server`.
+    pub async fn pipeline(
+        &mut self,
+        commands: &[Command],
+    ) -> Result<Vec<Value>, BackendError> {
+        check_replies(commands, self.send(commands).await?)
+    }
+
+    /// Sends a single command and returns its reply.
+    pub async fn query(&mut self, command: &Command) -> Result<Value, BackendError> {
+        let mut replies = self.pipeline(std::slice::from_ref(command)).await?;
+        Ok(replies.remove(0))
+    }
+
+    // Reads a reply, reading more from the stream for as long as the buffer
+    // only holds part of one. Each read only scans what's new, and the reply
+    // is parsed once it's all there, so that a reply that takes many reads
+    // doesn't take as many parses.
+    async fn read_reply(&mut self) -> Result<Value, BackendError> {
+        if self.scan.pending == 0 {
+            self.scan = Scan {
+                end: self.start,
+                pending: 1,
+            };
+        }
+        while !self.scan.advance(&self.buffer)? {
+            // The replies before this one are dropped before reading more, so
+            // the buffer only ever holds one that's partly read.
+            self.buffer.drain(..self.start);
+            self.scan.end -= self.start;
+            self.start = 0;
+            let mut chunk = [0; READ_SIZE];
+            let len = self.stream.read(&mut chunk).await?;
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.buffer.extend_from_slice(&chunk[..len]);
+        }
+        let value = read_value(&mut &self.buffer[self.start..self.scan.end])?;
+        self.start = self.scan.end;
+        if self.start == self.buffer.len() {
+            self.buffer.clear();
+            self.start = 0;
+        }
+        Ok(value)
+    }
+}
+
+// Scan is how far a reply has been checked for being complete: where the
+// values checked so far end, and how many more values (like the elements of
+// an array) make up the rest of it.
+#[derive(Debug, Default)]
+struct Scan {
+    end: usize,
+    pending: usize,
+}
+
+impl Scan {
+    // Checks the values in `buffer` from `end` on, and returns whether it
+    // holds the rest of the reply. What's malformed is left to `read_value`.
+    fn advance(&mut self, buffer: &[u8]) -> Result<bool, BackendError> {
+        while self.pending > 0 {
+            let rest = &buffer[self.end..];
+            let Some(line) = rest.windows(2).position(|pair| pair == b"\r\n") else {
+                return Ok(false);
+            };
+            let (kind, header) = rest[..line].split_first().unwrap_or((&b'_', &[]));
+            // Null values have a length of -1, and nothing after it.
+            let len = || match str::from_utf8(header) {
+                Ok(header) => read_len(header),
+                Err(_) => Err(protocol_error("reply isn't UTF-8")),
+            };
+            let mut end = self.end + line + 2;
+            let values = match kind {
+                b'$' | b'!' | b'=' => {
+                    if let Some(len) = len()? {
+                        end = end.saturating_add(len).saturating_add(2);
+                    }
+                    if end > buffer.len() {
+                        return Ok(false);
+                    }
+                    0
+                }
+                b'*' | b'~' | b'>' => len()?.unwrap_or(0),
+                b'%' => len()?.unwrap_or(0).saturating_mul(2),
+                // Attributes come before the value that they're of.
+                b'|' => len()?.unwrap_or(0).saturating_mul(2).saturating_add(1),
+                _ => 0,
+            };
+            self.end = end;
+            self.pending = (self.pending - 1).saturating_add(values);
+        }
+        Ok(true)
+    }
+}
+
+/// AsyncRedisBackend is the async equivalent of `RedisBackend`, for a single
+/// Redis server. Unlike `RedisBackend`, it doesn't buffer flushes to retry
+/// them or split them into pipelines (`RedisConfig::retry` and
+/// `RedisConfig::pipeline` are ignored): a flush that can't be sent is
+/// returned as an error, and what it would have written is lost, so servers
+/// that need flushes to survive an outage should use `RedisBackend`.
+pub struct AsyncRedisBackend<S> {
+    connection: AsyncConnection<S>,
+    aggregator: Aggregator,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncRedisBackend<TcpStream> {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379").
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+    ) -> Result<AsyncRedisBackend<TcpStream>, BackendError> {
+        AsyncRedisBackend::with_config(addr, RedisConfig::default()).await
+    }
+
+    /// Like `connect`, but with a configuration other than the default.
+    pub async fn with_config(
+        addr: impl ToSocketAddrs,
+        config: RedisConfig,
+    ) -> Result<AsyncRedisBackend<TcpStream>, BackendError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        AsyncRedisBackend::with_stream(stream, config).await
+    }
+}
+
+impl<S: AsyncStream> AsyncRedisBackend<S> {
+    /// Talks to Redis over a stream that's already connected to it, with any
+    /// runtime. The connection authenticates with `config.credentials` first.
+    pub async fn with_stream(
+        stream: S,
+        config: RedisConfig,
+    ) -> Result<AsyncRedisBackend<S>, BackendError> {
+        let mut connection = AsyncConnection::with_stream(stream);
+        if let Some(credentials) = &config.credentials {
+            connection.query(&credentials.credentials()?.auth()).await?;
+        }
+        Ok(AsyncRedisBackend {
+            connection,
+            aggregator: Aggregator::new(config)?,
+        })
+    }
+
+    /// See `RedisBackend::atomic_counters`.
+    pub fn atomic_counters(&self) -> AtomicCounters {
+        self.aggregator.atomic_counters()
+    }
+
+    /// See `RedisBackend::count_set`.
+    pub async fn count_set(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<u64, BackendError> {
+        let command = self.aggregator.count_set(id, at);
+        read_count(self.connection.query(&command).await?)
+    }
+
+    /// See `RedisBackend::timer_stats`.
+    pub async fn timer_stats(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<Option<TimerStats>, BackendError> {
+        let command = self.aggregator.timer_stats(id, at);
+        read_timer_stats(self.connection.query(&command).await?)
+    }
+
+    async fn pipeline(&mut self, commands: Vec<Command>) -> Result<(), BackendError> {
+        if !commands.is_empty() {
+            self.connection.pipeline(&commands).await?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncStream> AsyncBackend for AsyncRedisBackend<S> {
+    /// See `RedisBackend::record`.
+    async fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        let commands = self.aggregator.key_values(metrics);
+        self.pipeline(commands).await?;
+        self.aggregator.record(metrics);
+        Ok(())
+    }
+
+    /// See `RedisBackend::flush`.
+    async fn flush(&mut self) -> Result<(), BackendError> {
+        let commands = self.aggregator.flush(SystemTime::now());
+        self.pipeline(commands).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::parser::{parse, ParserConfig};
+    use std::task::{Context, Poll, Waker};
+
+    // A stream over std's blocking sockets, which needs no runtime.
+    struct BlockingStream(std::net::TcpStream);
+
+    impl AsyncStream for BlockingStream {
+        async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            std::io::Write::write_all(&mut self.0, buf)
+        }
+
+        async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::io::Read::read(&mut self.0, buf)
+        }
+    }
+
+    // Polls a future to completion. Futures over a `BlockingStream` are ready
+    // the first time that they're polled.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    // A stream that replies with `replies`, a few bytes per read.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncStream for Trickle {
+        async fn write_all(&mut self, _buf: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0.drain(..len);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn it_reads_replies_across_reads() {
+        let replies = b"+OK\r\n*2\r\n$5\r\nhello\r\n*1\r\n:7\r\n$-1\r\n\
+            |1\r\n+ttl\r\n:3\r\n%1\r\n+a\r\n$0\r\n\r\n-ERR bad\r\n";
+        let mut connection = AsyncConnection::with_stream(Trickle(replies.to_vec()));
+        let commands = vec![Command::new("PING"); 5];
+        let bulk = |value: &str| Value::Bulk(value.as_bytes().to_vec());
+        assert_eq!(
+            block_on(connection.send(&commands)),
+            Ok(vec![
+                Value::Status(String::from("OK")),
+                Value::Array(vec![bulk("hello"), Value::Array(vec![Value::Integer(7)])]),
+                Value::Nil,
+                Value::Map(vec![(Value::Status(String::from("a")), bulk(""))]),
+                Value::Error(String::from("ERR bad")),
+            ])
+        );
+        assert!(connection.buffer.is_empty());
+        assert!(block_on(connection.query(&Command::new("PING"))).is_err());
+    }
+
+    #[test]
+    fn it_writes_metrics_without_a_runtime() {
+        let redis = FakeRedis::start();
+        let stream = BlockingStream(std::net::TcpStream::connect(redis.addr()).unwrap());
+        let mut backend = block_on(AsyncRedisBackend::with_stream(
+            stream,
+            RedisConfig::default(),
+        ))
+        .unwrap();
+        let batch = parse(b"gorets:1|c\ngorets:2|c", &ParserConfig::default()).unwrap();
+        block_on(backend.record(&batch.metrics)).unwrap();
+        block_on(backend.flush()).unwrap();
+        assert_eq!(
+            redis.commands(),
+            vec![vec!["INCRBY", "stats.counters.gorets", "3"]]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn it_writes_metrics() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "PFCOUNT" => Value::Integer(2),
+            // A reply that's long enough to take more than one read.
+            _ => Value::Bulk(vec![b'x'; 100_000]),
+        });
+        let mut backend = AsyncRedisBackend::connect(redis.addr()).await.unwrap();
+        let input = "config.version:1.2.3|kv\ngorets:1|c\ngorets:2|c\n\
+            uniques:765|s\nuniques:abc|s";
+        let batch = parse(input.as_bytes(), &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).await.unwrap();
+        backend.flush().await.unwrap();
+
+        let id = MetricId::new("uniques", crate::parser::MetricType::Set, Vec::new());
+        assert_eq!(backend.count_set(&id, SystemTime::now()).await, Ok(2));
+        let commands = redis.commands();
+        assert_eq!(commands[0], ["SET", "stats.values.config.version", "1.2.3"]);
+        assert_eq!(commands[1], ["INCRBY", "stats.counters.gorets", "3"]);
+        assert_eq!(commands[2][0], "PFADD");
+        assert!(commands[2][1].starts_with("stats.sets.uniques."));
+        assert_eq!(commands[2][2..], ["765", "abc"]);
+        assert_eq!(commands[3][0], "PFCOUNT");
+    }
+}
@@ -0,0 +1,319 @@
+//! Caches replies to reads in memory, so that reading the same keys again
+//! doesn't cost a round trip. The connection is switched to RESP3 and turns
+//! on client tracking, so that Redis sends an invalidation whenever a key
+//! that was read changes, which drops its cached replies. See
+//! [client-side caching][caching].
+//!
+//! Invalidations are lost along with the connection, so when it's lost, the
+//! cache is cleared, and the new connection is switched to RESP3 and turns on
+//! client tracking again before any commands are sent on it.
+//!
+//! [caching]: https://redis.io/docs/manual/client-side-caching/
+
+use super::connection::{Client, ReconnectingConnection, SharedCredentials};
+use super::resp::{Command, Value};
+use super::BackendError;
+use std::collections::BTreeMap;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// The commands whose replies are cached, which read a single key and whose
+/// replies only depend on it.
+const CACHED_COMMANDS: [&[u8]; 4] = [b"GET", b"HGETALL", b"PFCOUNT", b"SCARD"];
+
+/// CachingConnection is a connection to a single Redis server that caches
+/// replies to reads.
+pub struct CachingConnection {
+    connection: ReconnectingConnection,
+
+    // The number of connections opened as of the last command, which the
+    // cache's replies were read on.
+    connects: u64,
+
+    // Replies, by key.
+    cache: BTreeMap<Vec<u8>, Entry>,
+
+    // The cached keys by when they were last read, so that once the cache is
+    // full, the least recently read ones can be evicted.
+    reads: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+
+    // The number of replies in the cache, and the most that it holds.
+    len: usize,
+    max_len: usize,
+}
+
+// The cached replies for a key.
+struct Entry {
+    // Replies by command name.
+    replies: BTreeMap<Vec<u8>, Value>,
+
+    // When the key was last read, as a tick of `clock`.
+    read: u64,
+}
+
+impl CachingConnection {
+    /// Connects to the Redis server at `addr`, which has to be Redis 6 or
+    /// later, and caches up to `max_len` replies. Connections authenticate
+    /// with `credentials`, if there are any, before tracking is turned on.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        max_len: usize,
+        credentials: Option<SharedCredentials>,
+    ) -> Result<CachingConnection, BackendError> {
+        let connection = ReconnectingConnection::to(addr, credentials)?
+            .set_up(|connection| {
+                connection.hello3()?;
+                connection.query(
+                    &Command::new("CLIENT")
+                        .arg_static("TRACKING")
+                        .arg_static("ON"),
+                )?;
+                Ok(())
+            })
+            .opened()?;
+        Ok(CachingConnection {
+            connects: connection.connects(),
+            connection,
+            cache: BTreeMap::new(),
+            reads: BTreeMap::new(),
+            clock: 0,
+            len: 0,
+            max_len,
+        })
+    }
+
+    // Drops the replies that invalidations have been received for, or all of
+    // them if the connection has been lost.
+    fn invalidate(&mut self) {
+        let pushes = match self.connection.pushes() {
+            Ok(pushes) => pushes,
+            Err(_) => return self.clear(),
+        };
+        for push in pushes {
+            match push.as_slice() {
+                [Value::Bulk(kind), Value::Array(keys)] if kind == b"invalidate" => {
+                    for key in keys {
+                        if let Value::Bulk(key) = key {
+                            self.remove(key);
+                        }
+                    }
+                }
+                // A null list of keys means that the whole database was
+                // flushed.
+                [Value::Bulk(kind), Value::Nil] if kind == b"invalidate" => self.clear(),
+                _ => (),
+            }
+        }
+    }
+
+    // Returns the cached reply to a command, if there is one.
+    fn cached(&mut self, command: &Command) -> Option<Value> {
+        let (name, key) = cache_key(command)?;
+        let entry = self.cache.get_mut(key)?;
+        let reply = entry.replies.get(&name)?.clone();
+        self.clock += 1;
+        self.reads.remove(&entry.read);
+        self.reads.insert(self.clock, key.to_vec());
+        entry.read = self.clock;
+        Some(reply)
+    }
+
+    fn insert(&mut self, command: &Command, reply: &Value) {
+        let Some((name, key)) = cache_key(command) else {
+            return;
+        };
+        if matches!(reply, Value::Error(_)) || self.max_len == 0 {
+            return;
+        }
+        while self.len >= self.max_len {
+            let (_, evicted) = self.reads.pop_first().unwrap();
+            self.remove(&evicted);
+        }
+
+        self.clock += 1;
+        let entry = self.cache.entry(key.to_vec()).or_insert_with(|| Entry {
+            replies: BTreeMap::new(),
+            read: 0,
+        });
+        self.reads.remove(&entry.read);
+        self.reads.insert(self.clock, key.to_vec());
+        entry.read = self.clock;
+        if entry.replies.insert(name, reply.clone()).is_none() {
+            self.len += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.cache.remove(key) {
+            self.reads.remove(&entry.read);
+            self.len -= entry.replies.len();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.cache.clear();
+        self.reads.clear();
+        self.len = 0;
+    }
+}
+
+impl Client for CachingConnection {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), BackendError> {
+        self.connection.set_timeout(timeout)
+    }
+
+    /// Answers the commands from the cache if they're all cached reads, and
+    /// sends them to the server otherwise, caching replies to the reads among
+    /// them. If talking to the server fails, the cache is cleared, since
+    /// invalidations may have been lost, and the connection is reopened for
+    /// the next commands. A connection that can't be reopened is
+    /// `BackendError::Unavailable`.
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        self.invalidate();
+        let cached: Option<Vec<_>> = commands.iter().map(|c| self.cached(c)).collect();
+        if let Some(replies) = cached {
+            return Ok(replies);
+        }
+
+        let result = self.connection.send(commands);
+        if self.connection.connects() != self.connects {
+            self.connects = self.connection.connects();
+            self.clear();
+        }
+        let Ok(replies) = result else {
+            self.clear();
+            return result;
+        };
+        for (command, reply) in commands.iter().zip(&replies) {
+            self.insert(command, reply);
+        }
+        // Reads that were answered before an invalidation that came with the
+        // replies may already be stale.
+        self.invalidate();
+        Ok(replies)
+    }
+}
+
+// Returns the uppercase name and the key of a command whose reply can be
+// cached.
+fn cache_key(command: &Command) -> Option<(Vec<u8>, &[u8])> {
+    match command.args() {
+        [name, key] => {
+            let name = name.to_ascii_uppercase();
+            CACHED_COMMANDS
+                .contains(&name.as_slice())
+                .then_some((name, &key[..]))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use std::thread;
+    use std::time::Duration;
+
+    fn bulk(s: &str) -> Value {
+        Value::Bulk(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn it_caches_reads_until_invalidated() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "HELLO" => Value::Map(vec![(bulk("proto"), Value::Integer(3))]),
+            "SCARD" => Value::Integer(2),
+            _ => Value::Status(String::from("OK")),
+        });
+        let mut connection = CachingConnection::connect(redis.addr(), 2, None).unwrap();
+        let (a, b, c) = (
+            Command::new("SCARD").arg("a"),
+            Command::new("SCARD").arg("b"),
+            Command::new("SCARD").arg("c"),
+        );
+        let set = Command::new("SADD").arg("a").arg("x");
+
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        assert_eq!(
+            connection.pipeline(&[a.clone(), b.clone()]).unwrap().len(),
+            2
+        );
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        assert!(connection.query(&set).is_ok());
+        redis.push(vec![bulk("invalidate"), Value::Array(vec![bulk("a")])]);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        // The cache is full, so "b", which was read least recently, is
+        // evicted.
+        assert_eq!(connection.query(&c), Ok(Value::Integer(2)));
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        assert_eq!(connection.query(&b), Ok(Value::Integer(2)));
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "HELLO 3",
+                "CLIENT TRACKING ON",
+                "SCARD a",
+                "SCARD a",
+                "SCARD b",
+                "SADD a x",
+                "SCARD a",
+                "SCARD c",
+                "SCARD b",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_tracks_keys_again_after_reconnecting() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "HELLO" => Value::Map(vec![(bulk("proto"), Value::Integer(3))]),
+            "SCARD" => Value::Integer(2),
+            _ => Value::Status(String::from("OK")),
+        });
+        let mut connection = CachingConnection::connect(redis.addr(), 2, None).unwrap();
+        let a = Command::new("SCARD").arg("a");
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+
+        // The connection is lost, along with any invalidations on their way,
+        // so "a" is read again on a new connection that tracks it.
+        redis.disconnect();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+
+        // The connection is lost while the server is down, and the server is
+        // back by the next read.
+        redis.stop();
+        let set = Command::new("SADD").arg("a").arg("x");
+        assert!(matches!(
+            connection.query(&set),
+            Err(BackendError::Unavailable { .. })
+        ));
+        redis.restart();
+        assert_eq!(connection.query(&a), Ok(Value::Integer(2)));
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "HELLO 3",
+                "CLIENT TRACKING ON",
+                "SCARD a",
+                "HELLO 3",
+                "CLIENT TRACKING ON",
+                "SCARD a",
+                "HELLO 3",
+                "CLIENT TRACKING ON",
+                "SCARD a",
+            ]
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Keeps a catalog in Redis of every metric that's been flushed, so that
+//! teams have an inventory of what's actually being emitted that updates
+//! itself. Each flush adds the names flushed to a set at `Catalog::key` (e.g.
+//! "stats.catalog"), and writes a hash for each of them at the key followed by
+//! the name ("stats.catalog:page.views") with the fields:
+//!
+//! * "type": the type that it was last flushed as, named like `StatsdScheme`
+//!   names types in keys (e.g. "counters").
+//! * "unit": the unit that it was last flushed with, if it had one.
+//! * "description": its description in `Catalog::descriptions`, if it has one.
+//! * "first_seen" and "last_seen": the Unix times of its first and latest
+//!   flushes, in milliseconds.
+//!
+//! The keys of the tags that it's been recorded with are added to a set at the
+//! hash's key followed by ":tags". Backends sharing Redis update the same
+//! catalog. Read it with `RedisQuery::catalog` and `RedisQuery::catalog_entry`.
+
+use super::redis::type_prefix;
+use super::resp::Command;
+use super::RedisConfig;
+use crate::parser::{MetricId, MetricType, Unit};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Configuration for keeping a catalog of the metrics flushed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Catalog {
+    /// The key of the set of metrics' names, which their hashes' keys start
+    /// with. Defaults to "stats.catalog".
+    pub key: String,
+
+    /// Descriptions of metrics, by name, which are written to their hashes.
+    pub descriptions: BTreeMap<String, String>,
+}
+
+impl Default for Catalog {
+    fn default() -> Catalog {
+        Catalog {
+            key: String::from("stats.catalog"),
+            descriptions: BTreeMap::new(),
+        }
+    }
+}
+
+/// What the catalog knows about a metric.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub metric_type: MetricType,
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+
+    /// The keys of the tags that the metric's been recorded with.
+    pub tags: BTreeSet<String>,
+}
+
+// Cataloged tracks the metrics recorded since the last flush, by name.
+#[derive(Default)]
+pub(super) struct Cataloged {
+    names: BTreeMap<String, Seen>,
+}
+
+struct Seen {
+    metric_type: MetricType,
+    unit: Option<String>,
+    tags: BTreeSet<String>,
+}
+
+impl Cataloged {
+    // Records that a series was recorded, if there's a catalog.
+    pub(super) fn record(
+        &mut self,
+        config: &RedisConfig,
+        id: &MetricId,
+        unit: Option<&Unit>,
+    ) {
+        if config.catalog.is_none() {
+            return;
+        }
+        let seen = self
+            .names
+            .entry(String::from(id.name()))
+            .or_insert_with(|| Seen {
+                metric_type: id.metric_type(),
+                unit: None,
+                tags: BTreeSet::new(),
+            });
+        seen.metric_type = id.metric_type();
+        if let Some(unit) = unit {
+            seen.unit = Some(String::from(unit.code()));
+        }
+        seen.tags
+            .extend(id.tags().iter().map(|tag| tag.key.clone()));
+    }
+
+    // Adds the commands that update the catalog with the metrics recorded
+    // since the last flush to `commands`.
+    pub(super) fn flush(
+        &mut self,
+        config: &RedisConfig,
+        now: SystemTime,
+        commands: &mut Vec<Command>,
+    ) {
+        let Some(catalog) = &config.catalog else {
+            return;
+        };
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        for (name, seen) in mem::take(&mut self.names) {
+            let key = entry_key(catalog, &name);
+            commands.push(Command::new("SADD").arg(&catalog.key).arg(&name));
+            commands.push(
+                Command::new("HSETNX")
+                    .arg(&key)
+                    .arg_static("first_seen")
+                    .arg(&millis),
+            );
+            let mut set = Command::new("HSET")
+                .arg(&key)
+                .arg_static("type")
+                .arg(type_prefix(seen.metric_type))
+                .arg_static("last_seen")
+                .arg(&millis);
+            if let Some(unit) = seen.unit {
+                set = set.arg_static("unit").arg(unit);
+            }
+            if let Some(description) = catalog.descriptions.get(&name) {
+                set = set.arg_static("description").arg(description);
+            }
+            commands.push(set);
+            if !seen.tags.is_empty() {
+                let tags = Command::new("SADD").arg(tags_key(catalog, &name));
+                commands.push(seen.tags.into_iter().fold(tags, Command::arg));
+            }
+        }
+    }
+}
+
+// Returns the key of a metric's hash.
+pub(super) fn entry_key(catalog: &Catalog, name: &str) -> String {
+    format!("{}:{}", catalog.key, name)
+}
+
+// Returns the key of the set of a metric's tags' keys.
+pub(super) fn tags_key(catalog: &Catalog, name: &str) -> String {
+    format!("{}:{}:tags", catalog.key, name)
+}
+
+// Returns the type that `type_prefix` names `prefix`.
+pub(super) fn metric_type(prefix: &str) -> Option<MetricType> {
+    [
+        MetricType::Counter,
+        MetricType::Gauge,
+        MetricType::Sample,
+        MetricType::Set,
+        MetricType::KeyValue,
+        MetricType::Histogram,
+        MetricType::Distribution,
+        MetricType::Meter,
+    ]
+    .into_iter()
+    .find(|metric_type| type_prefix(*metric_type) == prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, MemoryClient, RedisBackend, RedisQuery};
+    use crate::parser::{parse, ParserConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn it_catalogs_metrics() {
+        let memory = MemoryClient::new();
+        let catalog = Catalog {
+            descriptions: BTreeMap::from([(
+                String::from("glork"),
+                String::from("How long glorking takes."),
+            )]),
+            ..Catalog::default()
+        };
+        let config = RedisConfig {
+            catalog: Some(catalog),
+            ..RedisConfig::default()
+        };
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), config.clone()).unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (secs, input) in [
+            (10, &b"gorets:1|c|#env:production\nglork:320|ms"[..]),
+            (20, b"gorets:1|c|#region:us"),
+        ] {
+            backend
+                .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+                .unwrap();
+            backend.flush_at(at(secs)).unwrap();
+        }
+
+        let mut query = RedisQuery::with_client(Box::new(memory), config);
+        let names = BTreeSet::from([String::from("glork"), String::from("gorets")]);
+        assert_eq!(query.catalog(), Ok(names));
+        assert_eq!(
+            query.catalog_entry("gorets"),
+            Ok(Some(CatalogEntry {
+                metric_type: MetricType::Counter,
+                unit: None,
+                description: None,
+                first_seen: at(10),
+                last_seen: at(20),
+                tags: BTreeSet::from([String::from("env"), String::from("region")]),
+            }))
+        );
+        assert_eq!(
+            query.catalog_entry("glork"),
+            Ok(Some(CatalogEntry {
+                metric_type: MetricType::Sample,
+                unit: Some(String::from("ms")),
+                description: Some(String::from("How long glorking takes.")),
+                first_seen: at(10),
+                last_seen: at(10),
+                tags: BTreeSet::new(),
+            }))
+        );
+        assert_eq!(query.catalog_entry("missing"), Ok(None));
+    }
+}
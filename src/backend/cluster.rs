@@ -0,0 +1,544 @@
+//! Talks to a Redis Cluster, where each key lives in one of 16384 hash slots
+//! and each slot is served by one of the cluster's nodes. See [the cluster
+//! spec][spec].
+//!
+//! Commands are sent to the node that serves their key's slot according to the
+//! cluster's topology (`CLUSTER SLOTS`). When a slot has moved, the node
+//! replies with a `MOVED` redirection, which is followed and causes the
+//! topology to be reloaded. While a slot is being migrated, the node may reply
+//! with `ASK` instead, which is followed just for that command.
+//!
+//! A node that can't be reached before any commands were sent makes `send`
+//! fail with `BackendError::Unavailable`, so the commands can be sent again
+//! later. Once other nodes have been sent theirs, it's a `BackendError::Io`.
+//!
+//! [spec]: https://redis.io/docs/reference/cluster-spec/
+
+use super::connection::{
+    partly_sent, Client, Connect, Connection, ReconnectingConnection, SharedCredentials,
+};
+use super::resp::{protocol_error, Command, Value};
+use super::BackendError;
+use std::collections::BTreeMap;
+use std::str;
+use std::time::Duration;
+
+/// The number of hash slots in a cluster.
+const SLOTS: u16 = 16384;
+
+/// The most redirections that are followed for a single command.
+const MAX_REDIRECTS: usize = 5;
+
+/// Returns the hash slot of a key, which is the hash of its `hash_tag`.
+pub fn slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) % SLOTS
+}
+
+/// Returns the part of a key that's hashed to decide where it's stored. If
+/// it contains a hash tag (a non-empty substring between the first "{" and
+/// the next "}"), only the hash tag is hashed, which is how keys that are
+/// used together (e.g. by a Lua script) are kept together.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    key.iter()
+        .position(|&b| b == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.iter()
+                .position(|&b| b == b'}')
+                .filter(|&len| len > 0)
+                .map(|len| &tag[..len])
+        })
+        .unwrap_or(key)
+}
+
+// CRC16-CCITT (XMODEM), which is the checksum that Redis Cluster hashes keys
+// with.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// ClusterConnection is a client for a whole Redis Cluster, which connects to
+/// each of its nodes as commands need to be sent to them.
+pub struct ClusterConnection {
+    // The addresses that the topology was first loaded from, which it's
+    // reloaded from if none of the known nodes answer.
+    seeds: Vec<String>,
+
+    // Returns a client for the node at an address, which connects to it when
+    // commands are first sent.
+    connect: Box<Connect>,
+
+    // Clients for nodes by address ("host:port").
+    nodes: BTreeMap<String, Box<dyn Client>>,
+
+    // The node serving each range of slots, by the first slot of the range.
+    // Each value is the last slot of the range and the node's address.
+    slots: BTreeMap<u16, (u16, String)>,
+
+    // How long the nodes' reads and writes wait (see `Client::set_timeout`).
+    timeout: Option<Duration>,
+}
+
+impl ClusterConnection {
+    /// Loads the cluster's topology from the first of `seeds` (addresses like
+    /// "10.0.0.1:6379") that answers. Connections to the seeds and nodes
+    /// authenticate with `credentials`, if there are any.
+    pub fn connect(
+        seeds: &[&str],
+        credentials: Option<SharedCredentials>,
+    ) -> Result<ClusterConnection, BackendError> {
+        ClusterConnection::open(seeds, move |addr| {
+            let addr = String::from(addr);
+            let connect = move || Connection::connect(addr.as_str());
+            Box::new(
+                ReconnectingConnection::lazy(connect).authenticated(credentials.clone()),
+            )
+        })
+    }
+
+    // Like `connect`, but with `connect` returning the clients for nodes.
+    fn open(
+        seeds: &[&str],
+        connect: impl FnMut(&str) -> Box<dyn Client> + Send + 'static,
+    ) -> Result<ClusterConnection, BackendError> {
+        let mut cluster = ClusterConnection {
+            seeds: seeds.iter().map(|seed| String::from(*seed)).collect(),
+            connect: Box::new(connect),
+            nodes: BTreeMap::new(),
+            slots: BTreeMap::new(),
+            timeout: None,
+        };
+        cluster.refresh()?;
+        Ok(cluster)
+    }
+
+    /// Reloads the cluster's topology from the first node that answers,
+    /// trying the nodes of the current topology before the seeds.
+    pub fn refresh(&mut self) -> Result<(), BackendError> {
+        self.refresh_from(None)
+    }
+
+    // Like `refresh`, but tries `first` before any other node.
+    fn refresh_from(&mut self, first: Option<String>) -> Result<(), BackendError> {
+        let mut candidates: Vec<String> = Vec::new();
+        let known = self.slots.values().map(|(_, addr)| addr.clone());
+        for addr in first
+            .into_iter()
+            .chain(known)
+            .chain(self.seeds.iter().cloned())
+        {
+            if !candidates.contains(&addr) {
+                candidates.push(addr);
+            }
+        }
+
+        let mut last_error =
+            protocol_error("no seed nodes to load the cluster's topology from");
+        for addr in candidates {
+            let reply = self
+                .node(&addr)
+                .query(&Command::new("CLUSTER").arg_static("SLOTS"));
+            match reply.and_then(|reply| read_slots(reply, &addr)) {
+                Ok(slots) => {
+                    self.slots = slots;
+                    return Ok(());
+                }
+                Err(error) => {
+                    self.nodes.remove(&addr);
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn node(&mut self, addr: &str) -> &mut dyn Client {
+        if !self.nodes.contains_key(addr) {
+            let mut node = (self.connect)(addr);
+            // A new node's client hasn't connected yet, so there's no
+            // connection for setting its timeout to fail on.
+            let _ = node.set_timeout(self.timeout);
+            self.nodes.insert(String::from(addr), node);
+        }
+        self.nodes.get_mut(addr).unwrap().as_mut()
+    }
+
+    // Returns the address of the node to send a command to. Commands without
+    // keys can go to any node.
+    fn addr(&self, command: &Command) -> Result<String, BackendError> {
+        let node = match command.key() {
+            Some(key) => {
+                let slot = slot(key);
+                self.slots
+                    .range(..=slot)
+                    .next_back()
+                    .filter(|(_, (last, _))| slot <= *last)
+                    .map(|(_, (_, addr))| addr)
+            }
+            None => self.slots.values().next().map(|(_, addr)| addr),
+        };
+        node.cloned()
+            .ok_or_else(|| protocol_error("no node serves the command's slot"))
+    }
+
+    // Sends commands to a single node.
+    fn send_to(
+        &mut self,
+        addr: &str,
+        commands: &[Command],
+    ) -> Result<Vec<Value>, BackendError> {
+        self.node(addr).send(commands)
+    }
+}
+
+impl Client for ClusterConnection {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), BackendError> {
+        self.timeout = timeout;
+        self.nodes
+            .values_mut()
+            .try_for_each(|node| node.set_timeout(timeout))
+    }
+
+    /// Sends each node the commands for its slots in a single pipeline, then
+    /// follows any redirections one command at a time.
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        let mut by_node: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (i, command) in commands.iter().enumerate() {
+            by_node.entry(self.addr(command)?).or_default().push(i);
+        }
+
+        let mut replies = vec![Value::Nil; commands.len()];
+        let mut redirected = Vec::new();
+        for (n, (addr, indexes)) in by_node.into_iter().enumerate() {
+            let pipeline: Vec<_> = indexes.iter().map(|&i| commands[i].clone()).collect();
+            let node_replies = match self.send_to(&addr, &pipeline) {
+                Ok(replies) => replies,
+                Err(error) if n == 0 => return Err(error),
+                Err(error) => return Err(partly_sent(error)),
+            };
+            for (i, reply) in indexes.into_iter().zip(node_replies) {
+                match Redirect::from_reply(&reply) {
+                    Some(redirect) => redirected.push((i, redirect)),
+                    None => replies[i] = reply,
+                }
+            }
+        }
+
+        let mut moved = None;
+        for (i, mut redirect) in redirected {
+            for attempt in 1.. {
+                let reply = match redirect {
+                    Redirect::Moved(ref addr) => {
+                        moved = Some(addr.clone());
+                        self.send_to(addr, &commands[i..=i])
+                            .map_err(partly_sent)?
+                            .remove(0)
+                    }
+                    Redirect::Ask(ref addr) => {
+                        let asking = [Command::new("ASKING"), commands[i].clone()];
+                        self.send_to(addr, &asking).map_err(partly_sent)?.remove(1)
+                    }
+                };
+                match Redirect::from_reply(&reply) {
+                    Some(next) if attempt < MAX_REDIRECTS => redirect = next,
+                    _ => {
+                        replies[i] = reply;
+                        break;
+                    }
+                }
+            }
+        }
+        // The topology is reloaded from the node that a slot moved to, which
+        // is sure to know about the move.
+        if moved.is_some() {
+            self.refresh_from(moved).map_err(partly_sent)?;
+        }
+        Ok(replies)
+    }
+}
+
+// A redirection to another node.
+#[derive(Clone, Debug, PartialEq)]
+enum Redirect {
+    /// The slot has moved to the node for good (e.g. "MOVED 3999 10.0.0.2:6379").
+    Moved(String),
+
+    /// The slot is being migrated to the node, which the command should be sent
+    /// to just this once (e.g. "ASK 3999 10.0.0.2:6379").
+    Ask(String),
+}
+
+impl Redirect {
+    fn from_reply(reply: &Value) -> Option<Redirect> {
+        let message = match reply {
+            Value::Error(message) => message,
+            _ => return None,
+        };
+        let mut parts = message.split(' ');
+        let (kind, _slot, addr) = (parts.next()?, parts.next()?, parts.next()?);
+        match kind {
+            "MOVED" => Some(Redirect::Moved(String::from(addr))),
+            "ASK" => Some(Redirect::Ask(String::from(addr))),
+            _ => None,
+        }
+    }
+}
+
+// Reads the reply to `CLUSTER SLOTS` from the node at `addr`. Each range of
+// slots is its first and last slot followed by its master and then its
+// replicas, each of which is a host, a port, and more that isn't needed here.
+fn read_slots(
+    reply: Value,
+    addr: &str,
+) -> Result<BTreeMap<u16, (u16, String)>, BackendError> {
+    let invalid = || protocol_error("invalid cluster slots");
+    let ranges = match reply {
+        Value::Array(ranges) => ranges,
+        _ => return Err(invalid()),
+    };
+
+    let mut slots = BTreeMap::new();
+    for range in ranges {
+        let (first, last, host, port) = match range {
+            Value::Array(range) => match range.as_slice() {
+                [Value::Integer(first), Value::Integer(last), Value::Array(node), ..] => {
+                    match node.as_slice() {
+                        [Value::Bulk(host), Value::Integer(port), ..] => {
+                            (*first, *last, host.clone(), *port)
+                        }
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        let first = u16::try_from(first).map_err(|_| invalid())?;
+        let last = u16::try_from(last).map_err(|_| invalid())?;
+
+        // An empty host means the node that was asked.
+        let host = str::from_utf8(&host).map_err(|_| invalid())?;
+        let host = match host {
+            "" => addr.rsplit_once(':').map_or(addr, |(host, _)| host),
+            host => host,
+        };
+        slots.insert(first, (last, format!("{}:{}", host, port)));
+    }
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::faults::{Fault, Faults, FaultyClient};
+    use crate::backend::testing::FakeRedis;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    type Topology = Arc<Mutex<Vec<(i64, i64, SocketAddr)>>>;
+
+    // Starts a fake node that replies to `CLUSTER SLOTS` with `topology`, and to
+    // everything else with `reply`, or "OK" if it returns `None`.
+    fn node(
+        topology: &Topology,
+        reply: impl Fn(&[String]) -> Option<Value> + Send + 'static,
+    ) -> FakeRedis {
+        let topology = Arc::clone(topology);
+        FakeRedis::with_replies(move |command| match command[0].as_str() {
+            "CLUSTER" => Value::Array(
+                topology
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(first, last, addr)| {
+                        Value::Array(vec![
+                            Value::Integer(*first),
+                            Value::Integer(*last),
+                            Value::Array(vec![
+                                Value::Bulk(addr.ip().to_string().into_bytes()),
+                                Value::Integer(addr.port() as i64),
+                            ]),
+                        ])
+                    })
+                    .collect(),
+            ),
+            _ => reply(command).unwrap_or_else(|| Value::Status(String::from("OK"))),
+        })
+    }
+
+    #[test]
+    fn it_hashes_keys_to_slots() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(slot(b"foo"), 12182);
+        assert_eq!(slot(b"{user1000}.following"), slot(b"{user1000}.followers"));
+        assert_eq!(slot(b"{user1000}.following"), slot(b"user1000"));
+        assert_eq!(slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(slot(b"foo{{bar}}zap"), slot(b"{bar"));
+    }
+
+    #[test]
+    fn it_routes_commands_by_slot() {
+        let topology = Topology::default();
+        let (a, b) = (node(&topology, |_| None), node(&topology, |_| None));
+        *topology.lock().unwrap() = vec![(0, 8191, a.addr()), (8192, 16383, b.addr())];
+
+        let mut cluster =
+            ClusterConnection::connect(&[&a.addr().to_string()], None).unwrap();
+        // "foo" is in slot 12182, and "bar" is in slot 5061.
+        let commands = [
+            Command::new("SET").arg("foo").arg("1"),
+            Command::new("SET").arg("bar").arg("2"),
+            Command::new("SET").arg("{bar}:stats").arg("3"),
+        ];
+        assert_eq!(cluster.pipeline(&commands).unwrap().len(), 3);
+        assert_eq!(
+            a.commands(),
+            vec![
+                vec!["CLUSTER", "SLOTS"],
+                vec!["SET", "bar", "2"],
+                vec!["SET", "{bar}:stats", "3"],
+            ]
+        );
+        assert_eq!(b.commands(), vec![vec!["SET", "foo", "1"]]);
+    }
+
+    #[test]
+    fn it_follows_redirections() {
+        let topology = Topology::default();
+        let b_addr = Arc::new(Mutex::new(String::new()));
+        let moved_to = Arc::clone(&b_addr);
+        let a = node(&topology, move |command| match command[1].as_str() {
+            "moved" => Some(Value::Error(format!(
+                "MOVED 1 {}",
+                moved_to.lock().unwrap()
+            ))),
+            "asked" => Some(Value::Error(format!("ASK 2 {}", moved_to.lock().unwrap()))),
+            _ => None,
+        });
+        let b = node(&topology, |_| None);
+        *b_addr.lock().unwrap() = b.addr().to_string();
+        *topology.lock().unwrap() = vec![(0, 16383, a.addr())];
+
+        let mut cluster =
+            ClusterConnection::connect(&[&a.addr().to_string()], None).unwrap();
+        let commands = [Command::new("SET").arg("asked").arg("1")];
+        assert_eq!(
+            cluster.pipeline(&commands),
+            Ok(vec![Value::Status(String::from("OK"))])
+        );
+
+        // The slot moves for good, so the topology is reloaded.
+        *topology.lock().unwrap() = vec![(0, 16383, b.addr())];
+        let commands = [Command::new("SET").arg("moved").arg("2")];
+        assert_eq!(
+            cluster.pipeline(&commands),
+            Ok(vec![Value::Status(String::from("OK"))])
+        );
+        let commands = [Command::new("SET").arg("anything").arg("3")];
+        assert_eq!(
+            cluster.pipeline(&commands),
+            Ok(vec![Value::Status(String::from("OK"))])
+        );
+
+        assert_eq!(
+            a.commands(),
+            vec![
+                vec!["CLUSTER", "SLOTS"],
+                vec!["SET", "asked", "1"],
+                vec!["SET", "moved", "2"],
+            ]
+        );
+        assert_eq!(
+            b.commands(),
+            vec![
+                vec!["ASKING"],
+                vec!["SET", "asked", "1"],
+                vec!["SET", "moved", "2"],
+                vec!["CLUSTER", "SLOTS"],
+                vec!["SET", "anything", "3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_is_unavailable_until_commands_are_sent() {
+        let topology = Topology::default();
+        let (a, b) = (node(&topology, |_| None), node(&topology, |_| None));
+        *topology.lock().unwrap() = vec![(0, 8191, a.addr()), (8192, 16383, b.addr())];
+        // Nodes are sent their commands in the order of their addresses, so
+        // faults are injected into the second one's.
+        let (first, second) = match a.addr().to_string() < b.addr().to_string() {
+            true => (&a, &b),
+            false => (&b, &a),
+        };
+        let faulty = second.addr().to_string();
+        let faults = Faults::new();
+        let injected = faults.clone();
+        let mut cluster =
+            ClusterConnection::open(&[&a.addr().to_string()], move |addr| {
+                let addr = String::from(addr);
+                let connection = ReconnectingConnection::lazy({
+                    let addr = addr.clone();
+                    move || Connection::connect(addr.as_str())
+                });
+                match addr == faulty {
+                    true => Box::new(FaultyClient::new(connection, injected.clone())),
+                    false => Box::new(connection),
+                }
+            })
+            .unwrap();
+        let unavailable = BackendError::Unavailable {
+            message: String::from("down"),
+        };
+        let commands = [
+            Command::new("SET").arg("foo").arg("1"),
+            Command::new("SET").arg("bar").arg("2"),
+        ];
+
+        // The first node was sent its command before the second couldn't be
+        // reached.
+        faults.inject(Fault::Error(unavailable));
+        assert!(matches!(
+            cluster.pipeline(&commands),
+            Err(BackendError::Io {
+                kind: std::io::ErrorKind::NotConnected,
+                ..
+            })
+        ));
+        faults.inject(Fault::PartialReply(0));
+        assert!(matches!(
+            cluster.pipeline(&commands),
+            Err(BackendError::Io { .. })
+        ));
+        assert_eq!(
+            cluster.pipeline(&commands).map(|replies| replies.len()),
+            Ok(2)
+        );
+
+        // Neither node was sent anything while the first was down.
+        first.stop();
+        assert!(matches!(
+            cluster.pipeline(&commands),
+            Err(BackendError::Unavailable { .. })
+        ));
+        first.restart();
+        assert_eq!(
+            cluster.pipeline(&commands).map(|replies| replies.len()),
+            Ok(2)
+        );
+        let sets =
+            |redis: &FakeRedis| redis.commands().iter().filter(|c| c[0] == "SET").count();
+        assert_eq!((sets(first), sets(second)), (4, 2));
+    }
+}
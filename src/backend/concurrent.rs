@@ -0,0 +1,361 @@
+//! A `RedisBackend` that threads can record to at the same time. Its state in
+//! memory is split into shards, each behind its own lock, so that threads
+//! recording metrics of different shards don't wait on each other, and a
+//! flush only holds each shard's lock long enough to take what's in it.
+//!
+//! Metrics are sharded by their series, so that a namespace with many more
+//! series than the others doesn't pile into one shard. When namespaces have
+//! quotas (see `Quotas`), they're sharded by namespace instead, so every
+//! series of a namespace is in the same shard, and quotas limit each
+//! namespace like they would in a single backend.
+//!
+//! Nothing is sent to Redis while a shard's locked. Key/values are queued
+//! once they're recorded, and sent by whichever thread holds the connection:
+//! a thread that finds it busy leaves its key/values for the thread using it,
+//! rather than waiting for a flush to be sent.
+
+use super::connection::Client;
+use super::counters::AtomicCounters;
+use super::hash::SeriesState;
+use super::quota::series_namespace;
+use super::redis::{finish_flush, Aggregator, Recorded};
+use super::resp::Command;
+use super::retry::FlushBuffer;
+use super::series::{AsSeries, SeriesRef};
+use super::{Backend, BackendError, RedisBackend, RedisConfig};
+use crate::parser::{Metric, MetricRef};
+use std::hash::BuildHasher;
+use std::mem;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::time::SystemTime;
+
+/// ConcurrentRedisBackend writes metrics to Redis like `RedisBackend`, but can
+/// be shared between threads, which record with `&self`.
+pub struct ConcurrentRedisBackend {
+    shards: Vec<Mutex<Aggregator>>,
+    sender: Mutex<Sender>,
+
+    // Key/values recorded that haven't been sent yet.
+    pending: Mutex<Vec<Command>>,
+    hasher: SeriesState,
+    config: RedisConfig,
+}
+
+// Sender sends flushes, one at a time.
+struct Sender {
+    connection: Box<dyn Client>,
+
+    // Flushes that couldn't be sent yet.
+    buffer: FlushBuffer,
+}
+
+impl ConcurrentRedisBackend {
+    /// Takes over `backend`'s connection and the flushes that it's buffered,
+    /// splitting its state into `shards` shards (at least one), which is
+    /// usually about the number of threads that record. Anything that
+    /// `backend` has recorded is flushed first.
+    pub fn new(
+        mut backend: RedisBackend,
+        shards: usize,
+    ) -> Result<ConcurrentRedisBackend, BackendError> {
+        match backend.flush() {
+            Ok(()) | Err(BackendError::Unavailable { .. }) => {}
+            Err(error) => return Err(error),
+        }
+        let (connection, aggregator, buffer) = backend.into_parts();
+        let config = aggregator.config().clone();
+        // The flush's time and transaction are added once all of the shards'
+        // commands are together.
+        let shard_config = RedisConfig {
+            last_flush_key: None,
+            atomic_flushes: false,
+            ..config.clone()
+        };
+        let shards = (0..shards.max(1))
+            .map(|_| Aggregator::new(shard_config.clone()).map(Mutex::new))
+            .collect::<Result<_, _>>()?;
+        Ok(ConcurrentRedisBackend {
+            shards,
+            sender: Mutex::new(Sender { connection, buffer }),
+            pending: Mutex::new(Vec::new()),
+            hasher: SeriesState::new(config.hashing),
+            config,
+        })
+    }
+
+    /// Like `Backend::record`. If another thread is sending to Redis, it
+    /// sends this batch's key/values too, so an error sending them is
+    /// returned to that thread rather than this one.
+    pub fn record(&self, metrics: &[Metric]) -> Result<(), BackendError> {
+        self.record_all(metrics)
+    }
+
+    /// Like `Backend::record_refs`.
+    pub fn record_refs(&self, metrics: &[MetricRef]) -> Result<(), BackendError> {
+        self.record_all(metrics)
+    }
+
+    /// Like `Backend::flush`. Each shard is flushed in turn into a single
+    /// flush, so the pipelines are the same as `RedisBackend`'s.
+    pub fn flush(&self) -> Result<(), BackendError> {
+        self.flush_at(SystemTime::now())
+    }
+
+    /// See `RedisBackend::atomic_counters`.
+    pub fn atomic_counters(&self) -> AtomicCounters {
+        self.shards[0].lock().unwrap().atomic_counters()
+    }
+
+    /// Like `RedisBackend::send_buffered`.
+    pub fn send_buffered(&self) -> Result<bool, BackendError> {
+        let mut sender = self.sender.lock().unwrap();
+        let Sender { connection, buffer } = &mut *sender;
+        let sent = match buffer.sendable(SystemTime::now()) {
+            true => buffer.send(connection.as_mut(), Vec::new(), SystemTime::now()),
+            false => Ok(()),
+        };
+        let more = buffer.sendable(SystemTime::now());
+        sent.and(self.send_pending(sender)).map(|()| more)
+    }
+
+    /// See `RedisBackend::buffered_flushes`.
+    pub fn buffered_flushes(&self) -> usize {
+        self.sender.lock().unwrap().buffer.len()
+    }
+
+    fn record_all<M: Recorded>(&self, metrics: &[M]) -> Result<(), BackendError> {
+        let shards: Vec<_> = metrics
+            .iter()
+            .map(|metric| self.shard(&metric.series()))
+            .collect();
+        let mut commands = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            if !shards.contains(&i) {
+                continue;
+            }
+            let metrics = metrics.iter().zip(&shards);
+            let in_shard = metrics
+                .filter(|(_, shard)| **shard == i)
+                .map(|(metric, _)| metric);
+            let mut shard = shard.lock().unwrap();
+            commands.extend(shard.key_values(in_shard.clone()));
+            shard.record(in_shard);
+        }
+        if commands.is_empty() {
+            return Ok(());
+        }
+        self.pending.lock().unwrap().extend(commands);
+        match try_lock(&self.sender) {
+            Some(sender) => self.send_pending(sender),
+            None => Ok(()),
+        }
+    }
+
+    // Like `flush`, but with the time to flush sets and timers to the window
+    // of. Key/values recorded before the flush are sent before it.
+    fn flush_at(&self, now: SystemTime) -> Result<(), BackendError> {
+        let mut sender = self.sender.lock().unwrap();
+        let sent = self.send_key_values(&mut sender);
+        let Sender { connection, buffer } = &mut *sender;
+        let mut commands = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut shard = shard.lock().unwrap();
+            if i == 0 {
+                shard.replayed(buffer.take_replayed(), buffer.len());
+            }
+            commands.extend(shard.flush(now));
+        }
+        finish_flush(&self.config, now, &mut commands);
+        let flushed = buffer.send(connection.as_mut(), commands, now);
+        let sent_after = self.send_pending(sender);
+        flushed.and(sent).and(sent_after)
+    }
+
+    // Sends the pending key/values until there are none, and unlocks the
+    // sender. Key/values queued after it's unlocked by threads that found it
+    // locked are sent too, unless another thread has locked it since.
+    fn send_pending<'a>(
+        &'a self,
+        mut sender: MutexGuard<'a, Sender>,
+    ) -> Result<(), BackendError> {
+        let mut result = Ok(());
+        loop {
+            result = result.and(self.send_key_values(&mut sender));
+            drop(sender);
+            if self.pending.lock().unwrap().is_empty() {
+                return result;
+            }
+            sender = match try_lock(&self.sender) {
+                Some(sender) => sender,
+                None => return result,
+            };
+        }
+    }
+
+    // Sends the pending key/values, with the sender locked, until none are
+    // left, returning the first error.
+    fn send_key_values(&self, sender: &mut Sender) -> Result<(), BackendError> {
+        let mut result = Ok(());
+        loop {
+            let commands = mem::take(&mut *self.pending.lock().unwrap());
+            if commands.is_empty() {
+                return result;
+            }
+            let Sender { connection, buffer } = sender;
+            let sent =
+                buffer.send_key_values(connection.as_mut(), commands, SystemTime::now());
+            result = result.and(sent);
+        }
+    }
+
+    // Returns the index of the shard that a series is recorded to.
+    fn shard(&self, series: &SeriesRef) -> usize {
+        let quotas = &self.config.quotas;
+        let hash = if quotas.limited() {
+            self.hasher.hash_one(series_namespace(series, quotas))
+        } else {
+            self.hasher.hash_one(series as &dyn AsSeries)
+        };
+        (hash % self.shards.len() as u64) as usize
+    }
+}
+
+// Locks a mutex unless another thread has it locked.
+fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::WouldBlock) => None,
+        Err(TryLockError::Poisoned(error)) => panic!("{error}"),
+    }
+}
+
+impl Backend for ConcurrentRedisBackend {
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        ConcurrentRedisBackend::record(self, metrics)
+    }
+
+    fn record_refs(&mut self, metrics: &[MetricRef]) -> Result<(), BackendError> {
+        ConcurrentRedisBackend::record_refs(self, metrics)
+    }
+
+    fn flush(&mut self) -> Result<(), BackendError> {
+        ConcurrentRedisBackend::flush(self)
+    }
+
+    fn send_buffered(&mut self) -> Result<bool, BackendError> {
+        ConcurrentRedisBackend::send_buffered(self)
+    }
+
+    fn direct_counters(&self) -> Option<AtomicCounters> {
+        Some(self.atomic_counters())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MemoryClient, Quotas, Value};
+    use crate::parser::MetricType;
+    use crate::parser::{parse, parse_ref, ParserConfig};
+    use std::collections::BTreeSet;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_records_from_threads() {
+        let memory = MemoryClient::new();
+        let config = RedisConfig {
+            last_flush_key: Some(String::from("stats.last_flush")),
+            quotas: Quotas {
+                max_series: Some(2),
+                ..Quotas::default()
+            },
+            ..RedisConfig::default()
+        };
+        let backend =
+            RedisBackend::with_client(Box::new(memory.clone()), config).unwrap();
+        let backend = ConcurrentRedisBackend::new(backend, 4).unwrap();
+        let input = b"checkout.requests:1|c\nsearch.requests:2|c\nsearch.latency:5|ms\n\
+            login.version:1.2.3|kv\ncheckout.ids:a|s\ncheckout.dropped:1|c";
+        let metrics = parse(input, &ParserConfig::default()).unwrap().metrics;
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        backend.record(&metrics).unwrap();
+                    }
+                });
+            }
+        });
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+
+        let get = |key: &str| memory.get(key);
+        let bulk = |value: &str| Some(Value::Bulk(value.as_bytes().to_vec()));
+        assert_eq!(get("stats.counters.checkout.requests"), bulk("400"));
+        assert_eq!(get("stats.counters.search.requests"), bulk("800"));
+        assert_eq!(get("stats.values.login.version"), bulk("1.2.3"));
+        // Checkout's third series is past its quota, wherever it's recorded.
+        assert_eq!(get("stats.counters.checkout.dropped"), None);
+        assert_eq!(
+            get("stats.counters.quotas.dropped;namespace=checkout"),
+            bulk("400")
+        );
+        assert_eq!(get("stats.last_flush"), bulk("1000"));
+        let flushes = memory.sent().into_iter().filter(|commands| {
+            commands
+                .iter()
+                .any(|command| command.args()[0][..] == b"INCRBY"[..])
+        });
+        assert_eq!(flushes.count(), 1);
+    }
+
+    #[test]
+    fn it_shards_by_series_without_quotas() {
+        let memory = MemoryClient::new();
+        let backend =
+            RedisBackend::with_client(Box::new(memory.clone()), RedisConfig::default());
+        let mut backend = ConcurrentRedisBackend::new(backend.unwrap(), 16).unwrap();
+        let input = b"checkout.a:1|c|#env:production,canary\n\
+            checkout.a:2|c|#canary,env:production";
+        let metrics = parse_ref(input, &ParserConfig::default()).unwrap().metrics;
+        let shards: Vec<_> = metrics
+            .iter()
+            .map(|metric| backend.shard(&metric.series()))
+            .collect();
+        assert_eq!(shards[0], shards[1]);
+        let names = (0..32).map(|i| format!("checkout.{i}"));
+        let shards: BTreeSet<_> = names
+            .map(|name| {
+                backend.shard(&SeriesRef::of_refs(&name, MetricType::Counter, &[]))
+            })
+            .collect();
+        assert!(shards.len() > 1);
+
+        Backend::record_refs(&mut backend, &metrics).unwrap();
+        backend.flush().unwrap();
+        let key = "stats.counters.checkout.a;canary;env=production";
+        assert_eq!(memory.get(key), Some(Value::Bulk(b"3".to_vec())));
+    }
+
+    #[test]
+    fn it_leaves_key_values_for_the_thread_sending() {
+        let memory = MemoryClient::new();
+        let backend =
+            RedisBackend::with_client(Box::new(memory.clone()), RedisConfig::default());
+        let backend = ConcurrentRedisBackend::new(backend.unwrap(), 4).unwrap();
+        let metrics = parse(b"login.version:1.2.3|kv", &ParserConfig::default())
+            .unwrap()
+            .metrics;
+
+        let sending = backend.sender.lock().unwrap();
+        backend.record(&metrics).unwrap();
+        assert_eq!(memory.get("stats.values.login.version"), None);
+        drop(sending);
+        backend.flush().unwrap();
+        let version = Some(Value::Bulk(b"1.2.3".to_vec()));
+        assert_eq!(memory.get("stats.values.login.version"), version);
+        assert!(backend.pending.lock().unwrap().is_empty());
+    }
+}
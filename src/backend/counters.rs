@@ -0,0 +1,162 @@
+//! Untagged counters that ingestion threads add to directly with atomics,
+//! rather than parsing metrics and passing them to a backend's `record`, for
+//! the counters that make up most of a server's traffic. Their totals are
+//! written by the backend that they belong to on its next flush, like any
+//! other counter's. Servers add the untagged counters that they receive on
+//! their listeners' threads (see `Backend::direct_counters`).
+
+use super::hash::SeriesState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+
+// The number of maps that counters are spread across by the hashes of their
+// names, so that threads adding to different counters rarely share a lock.
+const SHARDS: usize = 16;
+
+/// AtomicCounters are a backend's untagged counters, by name (see
+/// `RedisBackend::atomic_counters`). Clones share the same counters, so each
+/// ingestion thread can have its own.
+///
+/// Counters are spread across several maps by the hashes of their names.
+/// `add` only takes a read lock of its counter's map, which threads share, to
+/// find a counter that exists already, and a write lock the first time a
+/// counter is added to since it was last flushed. A `CounterHandle` skips the
+/// lookup altogether, so adding to one is a single atomic add.
+///
+/// Names are hashed with `S`, which is the hasher chosen by
+/// `RedisConfig::hashing` for a backend's counters.
+#[derive(Clone, Debug)]
+pub struct AtomicCounters<S = SeriesState> {
+    shards: Arc<[RwLock<HashMap<String, CounterHandle, S>>]>,
+    hasher: S,
+}
+
+/// CounterHandle adds to one of `AtomicCounters` without looking it up.
+#[derive(Clone, Debug, Default)]
+pub struct CounterHandle {
+    total: Arc<AtomicI64>,
+}
+
+impl AtomicCounters {
+    pub fn new() -> AtomicCounters {
+        AtomicCounters::default()
+    }
+}
+
+impl<S: BuildHasher + Clone> AtomicCounters<S> {
+    /// Returns counters whose names are hashed with `hasher`.
+    pub fn with_hasher(hasher: S) -> AtomicCounters<S> {
+        let shards =
+            (0..SHARDS).map(|_| RwLock::new(HashMap::with_hasher(hasher.clone())));
+        AtomicCounters {
+            shards: shards.collect(),
+            hasher,
+        }
+    }
+
+    /// Adds `value` to the counter named `name`.
+    pub fn add(&self, name: &str, value: i64) {
+        if let Some(counter) = self.shard(name).read().unwrap().get(name) {
+            counter.add(value);
+            return;
+        }
+        self.counter(name).add(value);
+    }
+
+    /// Returns a handle to the counter named `name`, which is kept until the
+    /// handle is dropped.
+    pub fn counter(&self, name: &str) -> CounterHandle {
+        let mut counters = self.shard(name).write().unwrap();
+        counters.entry(String::from(name)).or_default().clone()
+    }
+
+    // Returns the counters' totals since the last call, and resets them.
+    // Counters that nothing holds a handle to once they've been taken are
+    // removed, so that names that are no longer added to don't stay forever.
+    pub(super) fn take(&self) -> Vec<(String, i64)> {
+        let mut totals = Vec::new();
+        for shard in self.shards.iter() {
+            let mut counters = shard.write().unwrap();
+            let taken = counters
+                .iter()
+                .map(|(name, counter)| {
+                    (name.clone(), counter.total.swap(0, Ordering::Relaxed))
+                })
+                .filter(|(_, total)| *total != 0);
+            totals.extend(taken);
+            counters.retain(|_, counter| Arc::strong_count(&counter.total) > 1);
+        }
+        totals
+    }
+
+    // Returns the map that the counter named `name` is in.
+    fn shard(&self, name: &str) -> &RwLock<HashMap<String, CounterHandle, S>> {
+        &self.shards[self.hasher.hash_one(name) as usize % SHARDS]
+    }
+}
+
+impl<S: BuildHasher + Clone + Default> Default for AtomicCounters<S> {
+    fn default() -> AtomicCounters<S> {
+        AtomicCounters::with_hasher(S::default())
+    }
+}
+
+impl CounterHandle {
+    /// Adds `value` to the counter.
+    pub fn add(&self, value: i64) {
+        self.total.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{Backend, MemoryClient, RedisBackend, RedisConfig, Value};
+    use std::thread;
+
+    #[test]
+    fn it_adds_to_counters_from_threads() {
+        let memory = MemoryClient::new();
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), RedisConfig::default())
+                .unwrap();
+        let counters = backend.atomic_counters();
+        let handle = counters.counter("gorets");
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let counters = counters.clone();
+                let handle = handle.clone();
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        counters.add("glork", 1);
+                        handle.add(2);
+                    }
+                });
+            }
+        });
+        backend.flush().unwrap();
+        assert_eq!(
+            memory.get("stats.counters.glork"),
+            Some(Value::Bulk(b"4000".to_vec()))
+        );
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"8000".to_vec()))
+        );
+
+        // Counters without handles are forgotten once they've been flushed.
+        handle.add(1);
+        backend.flush().unwrap();
+        let len: usize = counters
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum();
+        assert_eq!(len, 1);
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"8001".to_vec()))
+        );
+    }
+}
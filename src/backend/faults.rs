@@ -0,0 +1,171 @@
+//! Injects faults into a `Client`, for testing how code that uses a backend
+//! copes with a Redis server that's unreachable, slow, or goes away partway
+//! through a pipeline.
+
+use super::connection::Client;
+use super::resp::{Command, Value};
+use super::BackendError;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A fault that `FaultyClient` injects into a batch of commands.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// The commands are lost on their way to the server, so none of them are
+    /// sent, and `send` times out with a `BackendError::Io`.
+    Drop,
+
+    /// The commands are sent once this long has passed.
+    Delay(Duration),
+
+    /// `send` returns the error without sending anything, like
+    /// `BackendError::Unavailable` for a server that can't be reached.
+    Error(BackendError),
+
+    /// Only this many of the commands are sent before the connection is lost,
+    /// so `send` returns a `BackendError::Io` after reading their replies.
+    PartialReply(usize),
+}
+
+/// Faults are the faults that a `FaultyClient` injects. Clones share the same
+/// faults, so a test can keep one to inject faults into a client that a
+/// backend owns.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Faults for the next batches, one each.
+    next: VecDeque<Fault>,
+
+    // The fault for every batch after those, until it's cleared.
+    every: Option<Fault>,
+
+    sends: usize,
+}
+
+impl Faults {
+    pub fn new() -> Faults {
+        Faults::default()
+    }
+
+    /// Injects `fault` into the next batch of commands, or the first one
+    /// after those that faults have already been injected into.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().next.push_back(fault);
+    }
+
+    /// Injects `fault` into every batch of commands until `clear` is called.
+    pub fn inject_until_cleared(&self, fault: Fault) {
+        self.state.lock().unwrap().every = Some(fault);
+    }
+
+    /// Stops injecting faults.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.next.clear();
+        state.every = None;
+    }
+
+    /// Returns the number of batches of commands that clients have been
+    /// asked to send, including the ones that faults were injected into.
+    pub fn sends(&self) -> usize {
+        self.state.lock().unwrap().sends
+    }
+
+    fn next(&self) -> Option<Fault> {
+        let mut state = self.state.lock().unwrap();
+        state.sends += 1;
+        state.next.pop_front().or_else(|| state.every.clone())
+    }
+}
+
+/// FaultyClient sends commands with another `Client`, except for the batches
+/// that its `Faults` say to inject a fault into.
+pub struct FaultyClient<C> {
+    client: C,
+    faults: Faults,
+}
+
+impl<C: Client> FaultyClient<C> {
+    pub fn new(client: C, faults: Faults) -> FaultyClient<C> {
+        FaultyClient { client, faults }
+    }
+}
+
+impl<C: Client> Client for FaultyClient<C> {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), BackendError> {
+        self.client.set_timeout(timeout)
+    }
+
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        match self.faults.next() {
+            None => self.client.send(commands),
+            Some(Fault::Drop) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            Some(Fault::Delay(delay)) => {
+                thread::sleep(delay);
+                self.client.send(commands)
+            }
+            Some(Fault::Error(error)) => Err(error),
+            Some(Fault::PartialReply(len)) => {
+                self.client.send(&commands[..len.min(commands.len())])?;
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryClient;
+    use std::time::Instant;
+
+    #[test]
+    fn it_injects_faults() {
+        let memory = MemoryClient::new();
+        let faults = Faults::new();
+        let mut client = FaultyClient::new(memory.clone(), faults.clone());
+        let set = |key: &str| Command::new("SET").arg(key).arg("1");
+        let commands = [set("a"), set("b")];
+        let unavailable = BackendError::Unavailable {
+            message: String::from("down"),
+        };
+
+        faults.inject(Fault::Drop);
+        faults.inject(Fault::PartialReply(1));
+        faults.inject(Fault::Delay(Duration::from_millis(20)));
+        assert!(matches!(
+            client.send(&commands),
+            Err(BackendError::Io {
+                kind: io::ErrorKind::TimedOut,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.send(&commands),
+            Err(BackendError::Io {
+                kind: io::ErrorKind::UnexpectedEof,
+                ..
+            })
+        ));
+        let start = Instant::now();
+        assert!(client.send(&commands).is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        faults.inject_until_cleared(Fault::Error(unavailable.clone()));
+        assert_eq!(client.send(&commands), Err(unavailable.clone()));
+        assert_eq!(client.send(&commands), Err(unavailable));
+        faults.clear();
+        assert!(client.send(&commands).is_ok());
+
+        assert_eq!(faults.sends(), 6);
+        let sent: Vec<_> = memory.sent().iter().map(Vec::len).collect();
+        assert_eq!(sent, vec![1, 2, 2]);
+    }
+}
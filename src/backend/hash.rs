@@ -0,0 +1,120 @@
+//! Hashers for the maps that backends look series up in on every metric
+//! recorded, where hashing names is a measurable part of ingestion. They're
+//! FxHash by default, which is much faster than the standard library's
+//! SipHash, but which clients can craft names to collide in. Servers that
+//! take metrics from untrusted clients can switch back to SipHash with
+//! `RedisConfig::hashing`.
+
+use rustc_hash::FxHasher;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+/// Which hasher a backend's maps use.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Hashing {
+    /// FxHash, which isn't keyed, so names can be chosen to collide.
+    #[default]
+    Fast,
+
+    /// SipHash with random keys, like the standard library's `HashMap`,
+    /// which names can't be chosen to collide in without knowing the keys
+    /// (HashDoS). For listeners exposed to the internet.
+    DosResistant,
+}
+
+/// SeriesState builds the hashers chosen by a `Hashing`. It's the default
+/// hasher of `AtomicCounters` and of the maps inside `RedisBackend`.
+#[derive(Clone, Debug)]
+pub struct SeriesState(State);
+
+#[derive(Clone, Debug)]
+enum State {
+    Fast,
+    DosResistant(RandomState),
+}
+
+/// The hasher built by `SeriesState`.
+pub struct SeriesHasher(Inner);
+
+enum Inner {
+    Fast(FxHasher),
+    DosResistant(DefaultHasher),
+}
+
+impl SeriesState {
+    pub fn new(hashing: Hashing) -> SeriesState {
+        SeriesState(match hashing {
+            Hashing::Fast => State::Fast,
+            Hashing::DosResistant => State::DosResistant(RandomState::new()),
+        })
+    }
+}
+
+impl Default for SeriesState {
+    fn default() -> SeriesState {
+        SeriesState::new(Hashing::default())
+    }
+}
+
+impl BuildHasher for SeriesState {
+    type Hasher = SeriesHasher;
+
+    fn build_hasher(&self) -> SeriesHasher {
+        SeriesHasher(match &self.0 {
+            State::Fast => Inner::Fast(FxHasher::default()),
+            State::DosResistant(state) => Inner::DosResistant(state.build_hasher()),
+        })
+    }
+}
+
+impl Hasher for SeriesHasher {
+    fn finish(&self) -> u64 {
+        match &self.0 {
+            Inner::Fast(hasher) => hasher.finish(),
+            Inner::DosResistant(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match &mut self.0 {
+            Inner::Fast(hasher) => hasher.write(bytes),
+            Inner::DosResistant(hasher) => hasher.write(bytes),
+        }
+    }
+
+    // Strings are hashed with a trailing 0xff, and slices (like tags) with
+    // their lengths, which FxHash hashes faster as integers than as bytes.
+    fn write_u8(&mut self, i: u8) {
+        match &mut self.0 {
+            Inner::Fast(hasher) => hasher.write_u8(i),
+            Inner::DosResistant(hasher) => hasher.write_u8(i),
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        match &mut self.0 {
+            Inner::Fast(hasher) => hasher.write_usize(i),
+            Inner::DosResistant(hasher) => hasher.write_usize(i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_hash::FxBuildHasher;
+
+    #[test]
+    fn it_builds_the_chosen_hasher() {
+        let hash = |state: &SeriesState| state.hash_one("stats.counters.gorets");
+        let fast = SeriesState::new(Hashing::Fast);
+        assert_eq!(hash(&fast), hash(&SeriesState::new(Hashing::Fast)));
+        assert_eq!(hash(&fast), FxBuildHasher.hash_one("stats.counters.gorets"));
+
+        // Each SipHash state has keys of its own.
+        let resistant = SeriesState::new(Hashing::DosResistant);
+        let other_resistant = SeriesState::new(Hashing::DosResistant);
+        assert_ne!(hash(&resistant), hash(&other_resistant));
+        assert_eq!(hash(&resistant), hash(&resistant.clone()));
+    }
+}
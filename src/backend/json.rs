@@ -0,0 +1,442 @@
+//! Stores metrics as [RedisJSON][json] documents, which can be read whole
+//! with a single `JSON.GET` and handed to anything that takes JSON, rather
+//! than gathered from a key per series. It needs the RedisJSON module, which
+//! Redis Stack includes.
+//!
+//! The backend keeps a document of totals at `JsonConfig::key`, which each
+//! flush updates in place. It has objects of "counters", "gauges", and
+//! "values" (key/values) by series, and the time of the last flush in
+//! milliseconds as "last_flush". Counters and meters are added to with
+//! `JSON.NUMINCRBY`, so several servers can flush to the same document.
+//! Gauges are set to their latest value, except for ones that only got
+//! deltas since the last flush, which are added to the document's value with
+//! `JSON.NUMINCRBY` too, like `RedisBackend` does with `INCRBYFLOAT`.
+//!
+//! Each flush can also be stored as a document of its own, at the key
+//! followed by the flush's time (e.g. "metrics:1656581405000"), with what was
+//! recorded over the flush interval: its "time", then "counters", "gauges",
+//! "gauge_deltas" with the change of each gauge that only got deltas, "sets"
+//! with the number of unique members of each, "timers" with the statistics
+//! of each sample, histogram, and distribution (e.g.
+//! `{"count":2,"min":240,"max":320,"mean":280,"p50":240,...}`), and
+//! "values".
+//!
+//! Series are named like keys, with their tags after their names (e.g.
+//! "gorets;env=production").
+//!
+//! [json]: https://redis.io/docs/stack/json/
+
+use super::connection::{ReconnectingConnection, SharedCredentials};
+use super::keys::with_tags;
+use super::redis::{float_value, format_float, Count, Gauge};
+use super::resp::Command;
+use super::retry::{FlushBuffer, PipelineConfig, RetryConfig};
+use super::timeseries;
+use super::{Backend, BackendError};
+use crate::parser::{Metric, MetricId, MetricType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Configuration for `JsonBackend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonConfig {
+    /// The key of the document of totals, which flushes' documents are
+    /// stored after. Defaults to "metrics".
+    pub key: String,
+
+    /// Whether each flush is stored as a document of its own.
+    pub flush_documents: bool,
+
+    /// How long flushes' documents are kept. `None` keeps them forever.
+    pub flush_ttl: Option<Duration>,
+
+    /// How flushes are retried while Redis is unreachable.
+    pub retry: RetryConfig,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them (see `RedisConfig::credentials`).
+    pub credentials: Option<SharedCredentials>,
+}
+
+impl Default for JsonConfig {
+    fn default() -> JsonConfig {
+        JsonConfig {
+            key: String::from("metrics"),
+            flush_documents: true,
+            flush_ttl: None,
+            retry: RetryConfig::default(),
+            credentials: None,
+        }
+    }
+}
+
+/// JsonBackend writes metrics to RedisJSON documents.
+pub struct JsonBackend {
+    connection: ReconnectingConnection,
+    config: JsonConfig,
+
+    // Flushes that couldn't be sent yet.
+    buffer: FlushBuffer,
+
+    // The totals of the counters and meters recorded since the last flush.
+    counters: BTreeMap<MetricId, Count>,
+
+    // The latest state of the gauges recorded since the last flush.
+    gauges: BTreeMap<MetricId, Gauge>,
+
+    // The members of the sets recorded since the last flush.
+    sets: BTreeMap<MetricId, BTreeSet<String>>,
+
+    // The observations of the samples, histograms, and distributions recorded
+    // since the last flush.
+    timers: BTreeMap<MetricId, Vec<f64>>,
+
+    // The latest value of each key/value recorded since the last flush.
+    values: BTreeMap<MetricId, String>,
+}
+
+impl JsonBackend {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379").
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: JsonConfig,
+    ) -> Result<JsonBackend, BackendError> {
+        Ok(JsonBackend {
+            connection: ReconnectingConnection::to(addr, config.credentials.clone())?
+                .opened()?,
+            buffer: FlushBuffer::new(config.retry.clone(), PipelineConfig::default()),
+            config,
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            sets: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            values: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the number of flushes that are buffered because Redis was
+    /// unreachable, which are sent before the next one.
+    pub fn buffered_flushes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Like `flush`, but with the time of the flush.
+    fn flush_at(&mut self, now: SystemTime) -> Result<(), BackendError> {
+        if self.counters.is_empty()
+            && self.gauges.is_empty()
+            && self.sets.is_empty()
+            && self.timers.is_empty()
+            && self.values.is_empty()
+        {
+            return self.buffer.send(&mut self.connection, Vec::new(), now);
+        }
+
+        let key = &self.config.key;
+        let time = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis())
+            .to_string();
+        let set = |path: String, value: &str| {
+            Command::new("JSON.SET").arg(key).arg(path).arg(value)
+        };
+        let mut commands = vec![set(
+            String::from("$"),
+            r#"{"counters":{},"gauges":{},"values":{}}"#,
+        )
+        .arg_static("NX")];
+        let mut flush = Object::new();
+        flush.field("time", &time);
+
+        let mut counters = Object::new();
+        for (id, count) in mem::take(&mut self.counters) {
+            let path = format!("$.counters[{}]", json_string(&series(&id)));
+            let count = match count {
+                Count::Integer(i) => i.to_string(),
+                Count::Float(f) => json_number(f),
+            };
+            commands.push(set(path.clone(), "0").arg_static("NX"));
+            commands.push(
+                Command::new("JSON.NUMINCRBY")
+                    .arg(key)
+                    .arg(path)
+                    .arg(&count),
+            );
+            counters.field(&series(&id), &count);
+        }
+        flush.field("counters", &counters.finish());
+
+        let (mut gauges, mut deltas) = (Object::new(), Object::new());
+        for (id, gauge) in mem::take(&mut self.gauges) {
+            let path = format!("$.gauges[{}]", json_string(&series(&id)));
+            match gauge {
+                Gauge::Set(value) => {
+                    let value = json_number(f64::from_str(&value).unwrap_or(0.0));
+                    commands.push(set(path, &value));
+                    gauges.field(&series(&id), &value);
+                }
+                Gauge::Add(delta) => {
+                    let delta = json_number(delta);
+                    commands.push(set(path.clone(), "0").arg_static("NX"));
+                    commands.push(
+                        Command::new("JSON.NUMINCRBY")
+                            .arg(key)
+                            .arg(path)
+                            .arg(&delta),
+                    );
+                    deltas.field(&series(&id), &delta);
+                }
+            }
+        }
+        flush.field("gauges", &gauges.finish());
+        flush.field("gauge_deltas", &deltas.finish());
+
+        let mut sets = Object::new();
+        for (id, members) in mem::take(&mut self.sets) {
+            sets.field(&series(&id), &members.len().to_string());
+        }
+        flush.field("sets", &sets.finish());
+
+        let mut timers = Object::new();
+        for (id, mut observations) in mem::take(&mut self.timers) {
+            let mut stats = Object::new();
+            for (stat, value) in timeseries::stats(&mut observations) {
+                stats.field(stat, &json_number(value));
+            }
+            timers.field(&series(&id), &stats.finish());
+        }
+        flush.field("timers", &timers.finish());
+
+        let mut values = Object::new();
+        for (id, value) in mem::take(&mut self.values) {
+            let value = json_string(&value);
+            commands.push(set(
+                format!("$.values[{}]", json_string(&series(&id))),
+                &value,
+            ));
+            values.field(&series(&id), &value);
+        }
+        flush.field("values", &values.finish());
+
+        commands.push(set(String::from("$.last_flush"), &time));
+        if self.config.flush_documents {
+            let flush_key = format!("{}:{}", key, time);
+            let document = flush.finish();
+            let set = Command::new("JSON.SET").arg(&flush_key).arg_static("$");
+            commands.push(set.arg_owned(document));
+            if let Some(ttl) = self.config.flush_ttl {
+                commands.push(
+                    Command::new("PEXPIRE")
+                        .arg(&flush_key)
+                        .arg_owned(ttl.as_millis().to_string()),
+                );
+            }
+        }
+        self.buffer.send(&mut self.connection, commands, now)
+    }
+}
+
+impl Backend for JsonBackend {
+    /// Adds metrics to their state in memory to be written on `flush`,
+    /// including key/values.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        for metric in metrics {
+            let id = metric.id();
+            match metric.metric_type() {
+                MetricType::Counter | MetricType::Meter => {
+                    let count = self.counters.entry(id).or_insert(Count::Integer(0));
+                    *count = count.add(metric);
+                }
+                MetricType::Gauge => {
+                    let gauge = self.gauges.remove(&id);
+                    self.gauges.insert(id, Gauge::apply(gauge, metric));
+                }
+                MetricType::Set => {
+                    self.sets
+                        .entry(id)
+                        .or_default()
+                        .insert(String::from(metric.value()));
+                }
+                MetricType::Sample | MetricType::Histogram | MetricType::Distribution => {
+                    self.timers.entry(id).or_default().push(float_value(metric));
+                }
+                MetricType::KeyValue => {
+                    self.values.insert(id, String::from(metric.value()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the document of totals and stores the flush's document in a
+    /// single pipeline. As with `RedisBackend`, what was recorded is reset
+    /// even if writing fails, and if Redis couldn't be reached, the pipeline
+    /// is buffered and sent before the next flush (see `RetryConfig`).
+    fn flush(&mut self) -> Result<(), BackendError> {
+        self.flush_at(SystemTime::now())
+    }
+}
+
+// Object builds the text of a JSON object out of fields whose values are
+// already JSON.
+struct Object {
+    text: String,
+}
+
+impl Object {
+    fn new() -> Object {
+        Object {
+            text: String::from("{"),
+        }
+    }
+
+    fn field(&mut self, name: &str, value: &str) {
+        if self.text.len() > 1 {
+            self.text.push(',');
+        }
+        self.text.push_str(&json_string(name));
+        self.text.push(':');
+        self.text.push_str(value);
+    }
+
+    fn finish(mut self) -> String {
+        self.text.push('}');
+        self.text
+    }
+}
+
+// Returns the name of a series in documents, which is its name followed by
+// its tags.
+fn series(id: &MetricId) -> String {
+    with_tags(String::from(id.name()), id, true)
+}
+
+// Returns `s` as a JSON string, in quotes and with the characters that JSON
+// doesn't allow in strings escaped.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Returns a number as JSON, which doesn't have infinities or NaN, so they're
+// null.
+pub(crate) fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format_float(value)
+    } else {
+        String::from("null")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::parser::{parse, ParserConfig};
+
+    #[test]
+    fn it_escapes_json() {
+        assert_eq!(json_string("a \"b\"\\c\n\u{1}"), r#""a \"b\"\\c\n\u0001""#);
+        assert_eq!(json_number(2.5), "2.5");
+        assert_eq!(json_number(f64::NAN), "null");
+    }
+
+    #[test]
+    fn it_writes_documents() {
+        let redis = FakeRedis::start();
+        let config = JsonConfig {
+            flush_ttl: Some(Duration::from_secs(3600)),
+            ..JsonConfig::default()
+        };
+        let mut backend = JsonBackend::connect(redis.addr(), config).unwrap();
+        let input = b"gorets:1|c|#env:production\ngorets:2|c|#env:production\n\
+            gaugor:333|g\ngaugor:-10|g\ngaugee:+5|g\nuniques:765|s\nuniques:abc|s\n\
+            glork:320|ms\nglork:240|ms\nconfig.version:1.2.3|kv";
+        let batch = parse(input, &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_millis(1656581405000))
+            .unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_millis(1656581415000))
+            .unwrap();
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                r#"JSON.SET metrics $ {"counters":{},"gauges":{},"values":{}} NX"#,
+                r#"JSON.SET metrics $.counters["gorets;env=production"] 0 NX"#,
+                r#"JSON.NUMINCRBY metrics $.counters["gorets;env=production"] 3"#,
+                r#"JSON.SET metrics $.gauges["gaugee"] 0 NX"#,
+                r#"JSON.NUMINCRBY metrics $.gauges["gaugee"] 5"#,
+                r#"JSON.SET metrics $.gauges["gaugor"] 323"#,
+                r#"JSON.SET metrics $.values["config.version"] "1.2.3""#,
+                "JSON.SET metrics $.last_flush 1656581405000",
+                concat!(
+                    r#"JSON.SET metrics:1656581405000 $ {"time":1656581405000,"#,
+                    r#""counters":{"gorets;env=production":3},"gauges":{"gaugor":323},"#,
+                    r#""gauge_deltas":{"gaugee":5},"#,
+                    r#""sets":{"uniques":2},"#,
+                    r#""timers":{"glork":{"count":2,"min":240,"max":320,"#,
+                    r#""mean":280,"p50":240,"p90":320,"p99":320}},"#,
+                    r#""values":{"config.version":"1.2.3"}}"#,
+                ),
+                "PEXPIRE metrics:1656581405000 3600000",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_buffers_flushes_while_redis_is_down() {
+        let redis = FakeRedis::start();
+        let retry = RetryConfig {
+            min_backoff: Duration::ZERO,
+            ..RetryConfig::default()
+        };
+        let config = JsonConfig {
+            flush_documents: false,
+            retry,
+            ..JsonConfig::default()
+        };
+        let mut backend = JsonBackend::connect(redis.addr(), config).unwrap();
+        let batch = parse(b"gorets:1|c", &ParserConfig::default()).unwrap();
+
+        redis.stop();
+        backend.record(&batch.metrics).unwrap();
+        assert!(matches!(
+            backend.flush(),
+            Err(BackendError::Unavailable { .. })
+        ));
+        assert_eq!(backend.buffered_flushes(), 1);
+        redis.restart();
+        assert_eq!(backend.flush(), Ok(()));
+        assert_eq!(backend.buffered_flushes(), 0);
+
+        let incrs = redis
+            .commands()
+            .into_iter()
+            .filter(|c| c[0] == "JSON.NUMINCRBY")
+            .count();
+        assert_eq!(incrs, 1);
+    }
+}
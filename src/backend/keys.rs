@@ -0,0 +1,194 @@
+//! How `RedisBackend` names the keys that it stores metrics under. A
+//! `KeyScheme` decides the whole key other than the braces around timers'
+//! windows, which Redis Cluster needs (see `RedisBackend`).
+
+use super::redis::type_prefix;
+use crate::parser::MetricId;
+use std::fmt::Debug;
+
+/// KeyScheme names the keys that metrics are stored under. Two series with
+/// the same key are stored together.
+pub trait KeyScheme: Debug + Send + Sync {
+    /// Returns the key of a series that isn't split into windows, which is
+    /// every type but sets and timers.
+    fn key(&self, id: &MetricId) -> String;
+
+    /// Returns the key of the window of a set, sample, histogram, or
+    /// distribution that starts at `start`, in seconds since the Unix epoch.
+    fn window_key(&self, id: &MetricId, start: u64) -> String;
+
+    /// Returns the key of a rollup of a set's or timer's windows into a window
+    /// that's `length` seconds long and starts at `start` (see `Rollups`). By
+    /// default, it's the key of the window that starts at `start`, followed
+    /// by the length (e.g. "stats:sets:uniques:1656579600:3600s").
+    fn rollup_key(&self, id: &MetricId, length: u64, start: u64) -> String {
+        format!("{}:{}s", self.window_key(id, start), length)
+    }
+}
+
+/// StatsdScheme names keys like etsy's statsd names metrics in Graphite, with
+/// a namespace for each type (e.g. "stats.counters.page.views") and windows'
+/// start times after their names ("stats.sets.uniques.1656581400"). Rollups
+/// have "rollup" and their lengths before their start times
+/// ("stats.sets.uniques.rollup.3600s.1656579600"), so that they can't be
+/// mistaken for windows of series whose names end in numbers. Tags come last,
+/// in canonical order in the style of Graphite's tagged series (e.g.
+/// "stats.counters.page.views;env=production").
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsdScheme {
+    /// Prepended to every key. Defaults to "stats.".
+    pub prefix: String,
+
+    /// Whether metrics' tags are part of their keys. Without them, series that
+    /// differ only by their tags share a key.
+    pub tags_in_keys: bool,
+}
+
+impl Default for StatsdScheme {
+    fn default() -> StatsdScheme {
+        StatsdScheme {
+            prefix: String::from("stats."),
+            tags_in_keys: true,
+        }
+    }
+}
+
+impl KeyScheme for StatsdScheme {
+    fn key(&self, id: &MetricId) -> String {
+        let key = format!(
+            "{}{}.{}",
+            self.prefix,
+            type_prefix(id.metric_type()),
+            id.name()
+        );
+        with_tags(key, id, self.tags_in_keys)
+    }
+
+    fn window_key(&self, id: &MetricId, start: u64) -> String {
+        let key = format!(
+            "{}{}.{}.{}",
+            self.prefix,
+            type_prefix(id.metric_type()),
+            id.name(),
+            start
+        );
+        with_tags(key, id, self.tags_in_keys)
+    }
+
+    fn rollup_key(&self, id: &MetricId, length: u64, start: u64) -> String {
+        let key = format!(
+            "{}{}.{}.rollup.{}s.{}",
+            self.prefix,
+            type_prefix(id.metric_type()),
+            id.name(),
+            length,
+            start
+        );
+        with_tags(key, id, self.tags_in_keys)
+    }
+}
+
+/// ColonScheme separates keys' parts with colons, with the type first (e.g.
+/// "counters:page.views;env=production", and "sets:uniques:1656581400" for a
+/// window), which is how keys were named before schemes could be chosen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColonScheme {
+    /// Prepended to every key (e.g. "stats:").
+    pub prefix: String,
+
+    /// Whether metrics' tags are part of their keys, before windows' start
+    /// times.
+    pub tags_in_keys: bool,
+}
+
+impl Default for ColonScheme {
+    fn default() -> ColonScheme {
+        ColonScheme {
+            prefix: String::new(),
+            tags_in_keys: true,
+        }
+    }
+}
+
+impl KeyScheme for ColonScheme {
+    fn key(&self, id: &MetricId) -> String {
+        let key = format!(
+            "{}{}:{}",
+            self.prefix,
+            type_prefix(id.metric_type()),
+            id.name()
+        );
+        with_tags(key, id, self.tags_in_keys)
+    }
+
+    fn window_key(&self, id: &MetricId, start: u64) -> String {
+        format!("{}:{}", self.key(id), start)
+    }
+}
+
+// Appends a series' tags to `key`, if `tags_in_keys`.
+pub(super) fn with_tags(mut key: String, id: &MetricId, tags_in_keys: bool) -> String {
+    if !tags_in_keys {
+        return key;
+    }
+    for tag in id.tags() {
+        key.push(';');
+        key.push_str(&tag.key);
+        if let Some(ref value) = tag.value {
+            key.push('=');
+            key.push_str(value);
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{MetricType, Tag};
+
+    #[test]
+    fn it_names_keys() {
+        let tags = vec![
+            Tag::new("env", Some("production")),
+            Tag::new("canary", None),
+        ];
+        let id = MetricId::new("uniques", MetricType::Set, tags);
+        let statsd = StatsdScheme::default();
+        assert_eq!(statsd.key(&id), "stats.sets.uniques;canary;env=production");
+        assert_eq!(
+            statsd.window_key(&id, 1656581400),
+            "stats.sets.uniques.1656581400;canary;env=production"
+        );
+
+        let colon = ColonScheme {
+            prefix: String::from("app:"),
+            ..ColonScheme::default()
+        };
+        assert_eq!(colon.key(&id), "app:sets:uniques;canary;env=production");
+        assert_eq!(
+            colon.window_key(&id, 1656581400),
+            "app:sets:uniques;canary;env=production:1656581400"
+        );
+        let untagged = ColonScheme {
+            tags_in_keys: false,
+            ..ColonScheme::default()
+        };
+        assert_eq!(
+            untagged.window_key(&id, 1656581400),
+            "sets:uniques:1656581400"
+        );
+        assert_eq!(
+            untagged.rollup_key(&id, 60, 1656581400),
+            "sets:uniques:1656581400:60s"
+        );
+        assert_eq!(
+            StatsdScheme {
+                tags_in_keys: false,
+                ..statsd
+            }
+            .rollup_key(&id, 60, 1656581400),
+            "stats.sets.uniques.rollup.60s.1656581400"
+        );
+    }
+}
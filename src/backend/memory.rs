@@ -0,0 +1,559 @@
+//! An in-memory stand-in for a Redis server, for testing code that writes
+//! metrics without running one.
+
+use super::connection::Client;
+use super::redis::{format_float, TIMER_STATS_SCRIPT};
+use super::resp::{Command, Value};
+use super::BackendError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// MemoryClient is a `Client` that keeps what's sent to it in memory instead
+/// of sending it to Redis, for testing code that uses a backend without a
+/// Redis server (see `RedisBackend::with_client`). It records each batch of
+/// commands that it's sent, and runs the commands that backends use against an
+/// in-memory database, replying like Redis would, so that what was written can
+/// be read back. HyperLogLogs are stored as exact sets, and TTLs are kept but
+/// never expire keys. Other commands get an error reply.
+///
+/// Clones share the same database, so a test can keep one to look at what a
+/// backend that owns another has written.
+#[derive(Clone, Default)]
+pub struct MemoryClient {
+    memory: Arc<Mutex<Memory>>,
+
+    // The replies to the commands of a `MULTI` that hasn't been `EXEC`ed yet.
+    // Commands are run as they're queued, which is the same as running them
+    // on `EXEC`, since nothing else can run in between.
+    transaction: Option<Vec<Value>>,
+}
+
+#[derive(Default)]
+struct Memory {
+    sent: Vec<Vec<Command>>,
+    keys: BTreeMap<Vec<u8>, Entry>,
+    ttls: BTreeMap<Vec<u8>, Duration>,
+}
+
+// The value of a key.
+enum Entry {
+    String(Vec<u8>),
+
+    // A set, or a HyperLogLog.
+    Set(BTreeSet<Vec<u8>>),
+
+    // A sorted set, as its members' scores.
+    SortedSet(BTreeMap<Vec<u8>, f64>),
+
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+}
+
+impl MemoryClient {
+    pub fn new() -> MemoryClient {
+        MemoryClient::default()
+    }
+
+    /// Returns each batch of commands sent so far, in the order they were
+    /// sent.
+    pub fn sent(&self) -> Vec<Vec<Command>> {
+        self.memory.lock().unwrap().sent.clone()
+    }
+
+    /// Returns the value of `key` the way Redis would return it: a string as
+    /// `Value::Bulk`, a set as an array of its members, a sorted set as an
+    /// array of its members and scores ordered by score, and a hash as an
+    /// array of its fields and values. `None` if there's no such key.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        let memory = self.memory.lock().unwrap();
+        let bulk = |bytes: &[u8]| Value::Bulk(bytes.to_vec());
+        Some(match memory.keys.get(key.as_bytes())? {
+            Entry::String(value) => bulk(value),
+            Entry::Set(members) => {
+                Value::Array(members.iter().map(|m| bulk(m)).collect())
+            }
+            Entry::SortedSet(scores) => Value::Array(
+                ranked(scores)
+                    .into_iter()
+                    .flat_map(|(member, score)| {
+                        [bulk(member), bulk(format_float(score).as_bytes())]
+                    })
+                    .collect(),
+            ),
+            Entry::Hash(fields) => hash_value(fields),
+        })
+    }
+
+    /// Returns the keys that exist, in order.
+    pub fn keys(&self) -> Vec<String> {
+        let memory = self.memory.lock().unwrap();
+        memory
+            .keys
+            .keys()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect()
+    }
+
+    /// Returns the TTL that `key` was last given with `PEXPIRE`, if any.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        self.memory
+            .lock()
+            .unwrap()
+            .ttls
+            .get(key.as_bytes())
+            .copied()
+    }
+}
+
+impl Client for MemoryClient {
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        let mut memory = self.memory.lock().unwrap();
+        memory.sent.push(commands.to_vec());
+        let mut replies = Vec::with_capacity(commands.len());
+        for command in commands {
+            let args: Vec<Vec<u8>> =
+                command.args().iter().map(|arg| arg.to_vec()).collect();
+            let args = &args[..];
+            let name = args[0].to_ascii_uppercase();
+            let reply = match (name.as_slice(), &mut self.transaction) {
+                (b"MULTI", Some(_)) => error("ERR MULTI calls can not be nested"),
+                (b"MULTI", None) => {
+                    self.transaction = Some(Vec::new());
+                    ok()
+                }
+                (b"EXEC", transaction) => match transaction.take() {
+                    Some(replies) => Value::Array(replies),
+                    None => error("ERR EXEC without MULTI"),
+                },
+                (_, Some(queued)) => {
+                    queued.push(memory.run(args));
+                    Value::Status(String::from("QUEUED"))
+                }
+                (_, None) => memory.run(args),
+            };
+            replies.push(reply);
+        }
+        Ok(replies)
+    }
+}
+
+impl Memory {
+    // Runs a command other than `MULTI` or `EXEC`, and returns its reply.
+    fn run(&mut self, args: &[Vec<u8>]) -> Value {
+        if args.len() < 2 && !args[0].eq_ignore_ascii_case(b"PING") {
+            return error("ERR wrong number of arguments");
+        }
+        let key = || args[1].clone();
+        match args[0].to_ascii_uppercase().as_slice() {
+            b"PING" => Value::Status(String::from("PONG")),
+            b"SET" if args.len() >= 3 => {
+                self.keys.insert(key(), Entry::String(args[2].clone()));
+                self.ttls.remove(&args[1]);
+                ok()
+            }
+            b"GET" => match self.keys.get(&args[1]) {
+                Some(Entry::String(value)) => Value::Bulk(value.clone()),
+                Some(_) => wrong_type(),
+                None => Value::Nil,
+            },
+            b"GETDEL" => match self.keys.get(&args[1]) {
+                Some(Entry::String(_)) => match self.delete(&args[1]) {
+                    Some(Entry::String(value)) => Value::Bulk(value),
+                    _ => unreachable!(),
+                },
+                Some(_) => wrong_type(),
+                None => Value::Nil,
+            },
+            b"DEL" => {
+                let deleted = args[1..]
+                    .iter()
+                    .filter(|key| self.delete(key).is_some())
+                    .count();
+                Value::Integer(deleted as i64)
+            }
+            b"INCRBY" if args.len() == 3 => {
+                let by = parse::<i64>(&args[2]);
+                let value = match self.keys.get(&args[1]) {
+                    Some(Entry::String(value)) => parse::<i64>(value),
+                    Some(_) => return wrong_type(),
+                    None => Some(0),
+                };
+                match (value, by) {
+                    (Some(value), Some(by)) if value.checked_add(by).is_some() => {
+                        let total = value + by;
+                        self.keys
+                            .insert(key(), Entry::String(total.to_string().into_bytes()));
+                        Value::Integer(total)
+                    }
+                    _ => error("ERR value is not an integer or out of range"),
+                }
+            }
+            b"INCRBYFLOAT" if args.len() == 3 => {
+                let by = parse::<f64>(&args[2]);
+                let value = match self.keys.get(&args[1]) {
+                    Some(Entry::String(value)) => parse::<f64>(value),
+                    Some(_) => return wrong_type(),
+                    None => Some(0.0),
+                };
+                match (value, by) {
+                    (Some(value), Some(by)) => {
+                        let total = format_float(value + by).into_bytes();
+                        self.keys.insert(key(), Entry::String(total.clone()));
+                        Value::Bulk(total)
+                    }
+                    _ => error("ERR value is not a valid float"),
+                }
+            }
+            name @ (b"SADD" | b"PFADD") => {
+                let Some(members) = self.set(&args[1]) else {
+                    return wrong_type();
+                };
+                let added = args[2..]
+                    .iter()
+                    .filter(|m| members.insert(m.to_vec()))
+                    .count();
+                match name {
+                    b"SADD" => Value::Integer(added as i64),
+                    _ => Value::Integer(i64::from(added > 0)),
+                }
+            }
+            b"SCARD" | b"PFCOUNT" => match self.union(&args[1..]) {
+                Some(members) => Value::Integer(members.len() as i64),
+                None => wrong_type(),
+            },
+            b"SMEMBERS" => match self.union(&args[1..2]) {
+                Some(members) => {
+                    Value::Array(members.into_iter().map(Value::Bulk).collect())
+                }
+                None => wrong_type(),
+            },
+            name @ (b"PFMERGE" | b"SUNIONSTORE") => {
+                // PFMERGE merges the sources into the destination, while
+                // SUNIONSTORE replaces it.
+                let sources = match name {
+                    b"PFMERGE" => &args[1..],
+                    _ => &args[2..],
+                };
+                let Some(members) = self.union(sources) else {
+                    return wrong_type();
+                };
+                let len = members.len();
+                self.keys.insert(key(), Entry::Set(members));
+                match name {
+                    b"PFMERGE" => ok(),
+                    _ => Value::Integer(len as i64),
+                }
+            }
+            b"ZADD" if args.len().is_multiple_of(2) => {
+                let Some(scores) = self.sorted_set(&args[1]) else {
+                    return wrong_type();
+                };
+                let mut added = 0;
+                for pair in args[2..].chunks(2) {
+                    let Some(score) = parse::<f64>(&pair[0]) else {
+                        return error("ERR value is not a valid float");
+                    };
+                    if scores.insert(pair[1].clone(), score).is_none() {
+                        added += 1;
+                    }
+                }
+                Value::Integer(added)
+            }
+            b"ZUNIONSTORE" if args.len() >= 3 => self.zunionstore(args),
+            b"HSET" if args.len().is_multiple_of(2) => {
+                let Some(fields) = self.hash(&args[1]) else {
+                    return wrong_type();
+                };
+                let pairs = args[2..].chunks(2);
+                let added =
+                    pairs.filter(|p| fields.insert(p[0].clone(), p[1].clone()).is_none());
+                Value::Integer(added.count() as i64)
+            }
+            b"HINCRBY" if args.len() == 4 => {
+                let by = parse::<i64>(&args[3]);
+                let Some(fields) = self.hash(&args[1]) else {
+                    return wrong_type();
+                };
+                let value = fields
+                    .get(&args[2])
+                    .map_or(Some(0), |value| parse::<i64>(value));
+                match (value, by) {
+                    (Some(value), Some(by)) if value.checked_add(by).is_some() => {
+                        let total = value + by;
+                        fields.insert(args[2].clone(), total.to_string().into_bytes());
+                        Value::Integer(total)
+                    }
+                    _ => error("ERR hash value is not an integer"),
+                }
+            }
+            b"HSETNX" if args.len() == 4 => {
+                let Some(fields) = self.hash(&args[1]) else {
+                    return wrong_type();
+                };
+                if fields.contains_key(&args[2]) {
+                    return Value::Integer(0);
+                }
+                fields.insert(args[2].clone(), args[3].clone());
+                Value::Integer(1)
+            }
+            b"HGETALL" => match self.keys.get(&args[1]) {
+                Some(Entry::Hash(fields)) => hash_value(fields),
+                Some(_) => wrong_type(),
+                None => Value::Array(Vec::new()),
+            },
+            b"PEXPIRE" if args.len() == 3 => match parse::<u64>(&args[2]) {
+                Some(millis) if self.keys.contains_key(&args[1]) => {
+                    self.ttls.insert(key(), Duration::from_millis(millis));
+                    Value::Integer(1)
+                }
+                Some(_) => Value::Integer(0),
+                None => error("ERR value is not an integer or out of range"),
+            },
+            b"EVAL" if args[1] == TIMER_STATS_SCRIPT.as_bytes() && args.len() == 5 => {
+                self.timer_stats(&args[3], &args[4])
+            }
+            b"EVAL" => {
+                error("NOSCRIPT MemoryClient only runs the timer statistics script")
+            }
+            name => error(&format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(name)
+            )),
+        }
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Entry> {
+        self.ttls.remove(key);
+        self.keys.remove(key)
+    }
+
+    // Returns the members of the set at `key`, creating it if there's no such
+    // key, or `None` if the key isn't a set.
+    fn set(&mut self, key: &[u8]) -> Option<&mut BTreeSet<Vec<u8>>> {
+        match self
+            .keys
+            .entry(key.to_vec())
+            .or_insert(Entry::Set(BTreeSet::new()))
+        {
+            Entry::Set(members) => Some(members),
+            _ => None,
+        }
+    }
+
+    fn sorted_set(&mut self, key: &[u8]) -> Option<&mut BTreeMap<Vec<u8>, f64>> {
+        match self
+            .keys
+            .entry(key.to_vec())
+            .or_insert(Entry::SortedSet(BTreeMap::new()))
+        {
+            Entry::SortedSet(scores) => Some(scores),
+            _ => None,
+        }
+    }
+
+    fn hash(&mut self, key: &[u8]) -> Option<&mut BTreeMap<Vec<u8>, Vec<u8>>> {
+        match self
+            .keys
+            .entry(key.to_vec())
+            .or_insert(Entry::Hash(BTreeMap::new()))
+        {
+            Entry::Hash(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    // Returns the members of the sets at `keys`, or `None` if any of them
+    // isn't a set. Missing keys are empty sets.
+    fn union(&self, keys: &[Vec<u8>]) -> Option<BTreeSet<Vec<u8>>> {
+        let mut union = BTreeSet::new();
+        for key in keys {
+            match self.keys.get(key) {
+                Some(Entry::Set(members)) => union.extend(members.iter().cloned()),
+                Some(_) => return None,
+                None => (),
+            }
+        }
+        Some(union)
+    }
+
+    // Runs "ZUNIONSTORE destination numkeys key... [AGGREGATE SUM|MIN|MAX]".
+    fn zunionstore(&mut self, args: &[Vec<u8>]) -> Value {
+        let Some(len) = parse::<usize>(&args[2]).filter(|len| args.len() >= 3 + len)
+        else {
+            return error("ERR syntax error");
+        };
+        let (keys, options) = args[3..].split_at(len);
+        let aggregate = match options {
+            [] => b"SUM".to_vec(),
+            [option, aggregate] if option.eq_ignore_ascii_case(b"AGGREGATE") => {
+                aggregate.to_ascii_uppercase()
+            }
+            _ => return error("ERR syntax error"),
+        };
+        let mut union: BTreeMap<Vec<u8>, f64> = BTreeMap::new();
+        for key in keys {
+            let scores = match self.keys.get(key) {
+                Some(Entry::SortedSet(scores)) => scores,
+                Some(_) => return wrong_type(),
+                None => continue,
+            };
+            for (member, &score) in scores {
+                let Some(total) = union.get_mut(member) else {
+                    union.insert(member.clone(), score);
+                    continue;
+                };
+                *total = match aggregate.as_slice() {
+                    b"MAX" => total.max(score),
+                    b"MIN" => total.min(score),
+                    _ => *total + score,
+                };
+            }
+        }
+        let len = union.len();
+        self.delete(&args[1]);
+        if len > 0 {
+            self.keys.insert(args[1].clone(), Entry::SortedSet(union));
+        }
+        Value::Integer(len as i64)
+    }
+
+    // Computes a timer window's statistics like timer_stats.lua does.
+    fn timer_stats(&mut self, key: &[u8], stats_key: &[u8]) -> Value {
+        let scores = match self.keys.get(key) {
+            Some(Entry::SortedSet(scores)) => ranked(scores),
+            Some(_) => return wrong_type(),
+            None => Vec::new(),
+        };
+        if scores.is_empty() {
+            return Value::Array(Vec::new());
+        }
+        let count = scores.len();
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0 * count as f64).ceil() as usize).max(1);
+            scores[rank - 1].1
+        };
+        let sum: f64 = scores.iter().map(|(_, score)| score).sum();
+        let stats = [
+            ("count", count.to_string()),
+            ("min", format_float(scores[0].1)),
+            ("max", format_float(scores[count - 1].1)),
+            ("mean", format_float(sum / count as f64)),
+            ("p50", format_float(percentile(50.0))),
+            ("p90", format_float(percentile(90.0))),
+            ("p99", format_float(percentile(99.0))),
+        ];
+        let fields = stats
+            .into_iter()
+            .map(|(field, value)| (field.as_bytes().to_vec(), value.into_bytes()))
+            .collect();
+        let reply = hash_value(&fields);
+        self.delete(stats_key);
+        self.keys.insert(stats_key.to_vec(), Entry::Hash(fields));
+        reply
+    }
+}
+
+// Returns a sorted set's members and scores in the order Redis ranks them:
+// by score, and then by member.
+fn ranked(scores: &BTreeMap<Vec<u8>, f64>) -> Vec<(&[u8], f64)> {
+    let mut ranked: Vec<_> = scores
+        .iter()
+        .map(|(member, &score)| (member.as_slice(), score))
+        .collect();
+    ranked.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(b.0)));
+    ranked
+}
+
+// Returns a hash's fields and values the way `HGETALL` does.
+fn hash_value(fields: &BTreeMap<Vec<u8>, Vec<u8>>) -> Value {
+    let pairs = fields.iter().flat_map(|(field, value)| [field, value]);
+    Value::Array(pairs.map(|bytes| Value::Bulk(bytes.clone())).collect())
+}
+
+fn parse<T: FromStr>(bytes: &[u8]) -> Option<T> {
+    str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+fn ok() -> Value {
+    Value::Status(String::from("OK"))
+}
+
+fn error(message: &str) -> Value {
+    Value::Error(String::from(message))
+}
+
+fn wrong_type() -> Value {
+    error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, RedisBackend, RedisConfig, TimerStats, Ttls};
+    use crate::parser::{parse, MetricId, MetricType, ParserConfig};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn it_stores_what_backends_write() {
+        let client = MemoryClient::new();
+        let ttls = Ttls {
+            counters: Some(Duration::from_secs(60)),
+            ..Ttls::default()
+        };
+        let config = RedisConfig {
+            ttls,
+            atomic_flushes: true,
+            ..RedisConfig::default()
+        };
+        let mut backend =
+            RedisBackend::with_client(Box::new(client.clone()), config).unwrap();
+        let input = b"gorets:1|c\ngorets:2|c\ngaugor:333|g\ngaugor:-3|g\nuniques:765|s\n\
+            uniques:abc|s\nglork:320|ms\nglork:240|ms\nconfig.version:1.2.3|kv";
+        backend
+            .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+            .unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+        backend.flush_at(at).unwrap();
+        backend
+            .record(
+                &parse(b"gorets:4|c", &ParserConfig::default())
+                    .unwrap()
+                    .metrics,
+            )
+            .unwrap();
+        backend.flush_at(at).unwrap();
+
+        let bulk = |s: &str| Some(Value::Bulk(s.as_bytes().to_vec()));
+        assert_eq!(client.get("stats.counters.gorets"), bulk("7"));
+        assert_eq!(
+            client.ttl("stats.counters.gorets"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(client.get("stats.gauges.gaugor"), bulk("330"));
+        assert_eq!(client.get("stats.values.config.version"), bulk("1.2.3"));
+        let uniques = MetricId::new("uniques", MetricType::Set, Vec::new());
+        assert_eq!(backend.count_set(&uniques, at), Ok(2));
+        let glork = MetricId::new("glork", MetricType::Sample, Vec::new());
+        assert_eq!(
+            backend.timer_stats(&glork, at),
+            Ok(Some(TimerStats {
+                count: 2,
+                min: 240.0,
+                max: 320.0,
+                mean: 280.0,
+                p50: 240.0,
+                p90: 320.0,
+                p99: 320.0,
+            }))
+        );
+        // The key/value was written by itself, followed by each flush's
+        // transaction and the reads.
+        let sent = client.sent();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(&sent[0][0].args()[0][..], b"SET");
+        assert_eq!(&sent[1][0].args()[0][..], b"MULTI");
+        assert_eq!(client.get("missing"), None);
+    }
+}
@@ -0,0 +1,173 @@
+//! Backends persist parsed metrics. A server hands each batch that it parses
+//! to a `Backend` with `record`, and calls `flush` once per flush interval.
+//!
+//! `RedisBackend` stores metrics in Redis. It speaks RESP (the Redis protocol)
+//! directly over TCP (or TLS, with the `tls` feature), so it doesn't need a
+//! Redis client library. It can talk to a single server, to a Redis Cluster,
+//! to standalone servers that it shards keys across, or to a master found
+//! through Redis Sentinel. Threads can share connections to a single server
+//! through a `ConnectionPool`, and reads can be cached with RESP3 client-side
+//! caching. `ConcurrentRedisBackend` shards a backend's state so that
+//! threads can record to it at the same time. With the `async` feature,
+//! `AsyncRedisBackend` is an async equivalent for a single server on any
+//! runtime (or on tokio's sockets, with the `tokio` feature).
+//! `RedisQuery` reads back the metrics that any of them stores. They send
+//! commands through a `Client`, which tests can replace with a `MemoryClient`
+//! that doesn't need a Redis server, and wrap in a `FaultyClient` (with the
+//! `testing` feature) to inject faults.
+//!
+//! `TimeSeriesBackend` stores a sample per flush in RedisTimeSeries series
+//! instead, for history that can be queried by time, and `JsonBackend`
+//! stores RedisJSON documents of totals and of each flush.
+//!
+//! `StreamBackend` and `StreamConsumer` pass metrics between servers through
+//! a Redis Stream instead, and `PubSubBackend` publishes them to pub/sub
+//! channels for live subscribers.
+
+#[cfg(feature = "async")]
+mod aio;
+mod cache;
+mod catalog;
+mod cluster;
+mod concurrent;
+mod connection;
+mod counters;
+#[cfg(any(test, feature = "testing"))]
+mod faults;
+mod hash;
+mod json;
+mod keys;
+mod memory;
+mod pool;
+mod pubsub;
+mod query;
+mod quota;
+mod redis;
+mod resp;
+mod retention;
+mod retry;
+mod rollup;
+mod sentinel;
+mod series;
+mod sharding;
+mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
+mod timeseries;
+mod tls;
+
+#[cfg(feature = "async")]
+pub use self::aio::{AsyncBackend, AsyncConnection, AsyncRedisBackend, AsyncStream};
+pub use self::catalog::{Catalog, CatalogEntry};
+pub use self::concurrent::ConcurrentRedisBackend;
+pub use self::connection::{
+    check_replies, Client, Credentials, CredentialsProvider, SharedCredentials,
+};
+pub use self::counters::{AtomicCounters, CounterHandle};
+#[cfg(any(test, feature = "testing"))]
+pub use self::faults::{Fault, Faults, FaultyClient};
+pub use self::hash::{Hashing, SeriesHasher, SeriesState};
+pub use self::json::{JsonBackend, JsonConfig};
+pub use self::keys::{ColonScheme, KeyScheme, StatsdScheme};
+pub use self::memory::MemoryClient;
+pub use self::pool::{ConnectionPool, PoolConfig, PooledConnection};
+pub use self::pubsub::{PubSubBackend, PubSubConfig, PublishMode};
+pub use self::query::RedisQuery;
+pub use self::quota::{QuotaAction, Quotas};
+pub use self::redis::{Count, RedisBackend, RedisConfig, SetMode, TimerStats, Ttls};
+pub use self::resp::{Command, Value};
+pub use self::retention::Retention;
+pub use self::retry::{PipelineConfig, RetryConfig};
+pub use self::rollup::{RollupLevel, Rollups};
+pub use self::sharding::Shard;
+pub use self::stream::{
+    StreamBackend, StreamConfig, StreamConsumer, StreamConsumerConfig, StreamEntry,
+};
+pub use self::timeseries::{DuplicatePolicy, TimeSeriesBackend, TimeSeriesConfig};
+pub use self::tls::TlsConfig;
+
+#[cfg(feature = "server")]
+pub(crate) use self::json::{json_number, json_string};
+#[cfg(feature = "tls")]
+pub(crate) use self::tls::connect as connect_tls;
+
+use crate::parser::{Metric, MetricRef};
+use std::io;
+use thiserror::Error;
+
+/// Backend is a destination for parsed metrics.
+pub trait Backend {
+    /// Records a batch of metrics. Backends may write them right away, or
+    /// buffer them until the next `flush`.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError>;
+
+    /// Like `record`, but of metrics that borrow from the payload that they
+    /// were parsed from (see `parser::parse_ref`). By default they're copied
+    /// into `Metric`s for `record`, but backends that can record them as they
+    /// are do, so that a server's receive loop can go from a packet to the
+    /// backend's state without copying each metric first.
+    fn record_refs(&mut self, metrics: &[MetricRef]) -> Result<(), BackendError> {
+        let metrics: Vec<Metric> = metrics.iter().cloned().map(Metric::from).collect();
+        self.record(&metrics)
+    }
+
+    /// Writes any metrics that the backend has buffered.
+    fn flush(&mut self) -> Result<(), BackendError>;
+
+    /// Sends some of what earlier flushes left to be sent later (e.g. what
+    /// didn't fit in `PipelineConfig::time_budget`), and returns whether
+    /// there's more that could be sent right away. A server calls it between
+    /// the packets that it receives while it returns `true`, so that what's
+    /// left goes out before the next flush. `Ok(false)` by default.
+    fn send_buffered(&mut self) -> Result<bool, BackendError> {
+        Ok(false)
+    }
+
+    /// Returns the counters that threads receiving untagged counters can add
+    /// to directly, without passing them to `record`, if the backend has them
+    /// (see `AtomicCounters`). A server's listeners add to them on their own
+    /// threads. `None` by default.
+    fn direct_counters(&self) -> Option<AtomicCounters> {
+        None
+    }
+}
+
+/// BackendError represents an error storing metrics in a backend.
+#[derive(Clone, Debug, Error, PartialEq)]
+#[non_exhaustive]
+pub enum BackendError {
+    /// Talking to the backend's server failed. The `io::Error` is reduced to
+    /// its kind and message so that errors can be compared.
+    #[error("I/O error: {message}")]
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+
+    /// The server sent a reply that isn't valid RESP, or that doesn't fit the
+    /// command it's a reply to.
+    #[error("invalid reply from server: {message}")]
+    Protocol { message: String },
+
+    /// The server replied to a command with an error.
+    #[error("error reply from server: {message}")]
+    Server { message: String },
+
+    /// The backend's server couldn't be reached, so nothing was sent to it.
+    #[error("server unavailable: {message}")]
+    Unavailable { message: String },
+
+    /// The backend was configured with something that it can't use, like an
+    /// invalid URL or a certificate that can't be loaded.
+    #[error("invalid configuration: {message}")]
+    Config { message: String },
+}
+
+impl From<io::Error> for BackendError {
+    fn from(error: io::Error) -> BackendError {
+        BackendError::Io {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
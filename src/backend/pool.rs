@@ -0,0 +1,306 @@
+//! A pool of connections to a single Redis server, so that threads sharing a
+//! server (e.g. a backend per flush shard, and readers calling `count_set` or
+//! `timer_stats`) don't have to wait on each other's round trips.
+
+use super::connection::{unavailable, Client, ReconnectingConnection, SharedCredentials};
+use super::resp::{Command, Value};
+use super::BackendError;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Configuration for `ConnectionPool`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    /// The number of connections opened when the pool is created.
+    pub min_size: usize,
+
+    /// The most connections that the pool has open at once. Once they're all
+    /// in use, `get` waits for one to be returned.
+    pub max_size: usize,
+
+    /// How long `get` waits for a connection before giving up.
+    pub checkout_timeout: Duration,
+
+    /// Connections that have been idle for longer than this are checked with
+    /// `PING` before they're handed out, and replaced if they don't answer
+    /// (e.g. because the server closed them, or restarted).
+    pub health_check_after: Duration,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them. They're asked for again whenever a connection is reopened
+    /// or the server says that it has to authenticate again.
+    pub credentials: Option<SharedCredentials>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            min_size: 1,
+            max_size: 8,
+            checkout_timeout: Duration::from_secs(5),
+            health_check_after: Duration::from_secs(30),
+            credentials: None,
+        }
+    }
+}
+
+/// ConnectionPool hands out connections to a Redis server, opening new ones
+/// up to `PoolConfig::max_size` as they're needed. Clones share the same pool.
+///
+/// The pool is a `Client` itself, which sends each batch of commands on
+/// whichever connection is free, so it can be shared by several backends
+/// (see `RedisBackend::with_pool`).
+#[derive(Clone)]
+pub struct ConnectionPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    addrs: Vec<SocketAddr>,
+    config: PoolConfig,
+    state: Mutex<State>,
+
+    // Signaled whenever a connection is returned, or one that was open is
+    // closed, so that a waiting `get` can take its place.
+    returned: Condvar,
+}
+
+struct State {
+    // Connections that aren't in use, with the most recently returned last.
+    idle: Vec<(ReconnectingConnection, Instant)>,
+
+    // The number of connections that are open, whether they're idle or in
+    // use, plus the ones being opened.
+    open: usize,
+}
+
+impl ConnectionPool {
+    /// Opens `config.min_size` connections to the Redis server at `addr`
+    /// (e.g. "127.0.0.1:6379").
+    pub fn new(
+        addr: impl ToSocketAddrs,
+        config: PoolConfig,
+    ) -> Result<ConnectionPool, BackendError> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let mut idle = Vec::with_capacity(config.max_size);
+        for _ in 0..config.min_size.min(config.max_size) {
+            idle.push((open(&addrs, &config)?, Instant::now()));
+        }
+        let state = State {
+            open: idle.len(),
+            idle,
+        };
+        Ok(ConnectionPool {
+            shared: Arc::new(Shared {
+                addrs,
+                config,
+                state: Mutex::new(state),
+                returned: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Takes a connection from the pool, which is returned to it when it's
+    /// dropped. If every connection is in use and the pool is full, waits
+    /// for one to be returned, for up to `PoolConfig::checkout_timeout`. A
+    /// new connection that can't be opened is `BackendError::Unavailable`.
+    pub fn get(&self) -> Result<PooledConnection, BackendError> {
+        let shared = &self.shared;
+        let deadline = Instant::now() + shared.config.checkout_timeout;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if let Some((mut connection, since)) = state.idle.pop() {
+                if since.elapsed() <= shared.config.health_check_after {
+                    return Ok(self.pooled(connection));
+                }
+                drop(state);
+                if let Ok(Value::Status(_)) = connection.query(&Command::new("PING")) {
+                    return Ok(self.pooled(connection));
+                }
+                state = shared.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < shared.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match open(&shared.addrs, &shared.config) {
+                    Ok(connection) => Ok(self.pooled(connection)),
+                    Err(error) => {
+                        shared.close();
+                        Err(unavailable(error))
+                    }
+                };
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(BackendError::Io {
+                    kind: io::ErrorKind::TimedOut,
+                    message: String::from(
+                        "timed out waiting for a connection from the pool",
+                    ),
+                });
+            }
+            state = shared.returned.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Returns the number of connections that are open, and how many of them
+    /// are idle.
+    pub fn size(&self) -> (usize, usize) {
+        let state = self.shared.state.lock().unwrap();
+        (state.open, state.idle.len())
+    }
+
+    fn pooled(&self, connection: ReconnectingConnection) -> PooledConnection {
+        PooledConnection {
+            connection: Some(connection),
+            shared: Arc::clone(&self.shared),
+            broken: false,
+        }
+    }
+}
+
+impl Client for ConnectionPool {
+    /// Sends commands on a connection from the pool. If there isn't one to
+    /// send them on, whether because the server can't be reached or because
+    /// none were returned in time, the error is `BackendError::Unavailable`,
+    /// since none of the commands were sent.
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        self.get().map_err(unavailable)?.send(commands)
+    }
+}
+
+// Opens a connection that's reopened if the server closes it while it's idle
+// in the pool.
+fn open(
+    addrs: &[SocketAddr],
+    config: &PoolConfig,
+) -> Result<ReconnectingConnection, BackendError> {
+    ReconnectingConnection::to(addrs, config.credentials.clone())?.opened()
+}
+
+impl Shared {
+    // Forgets a connection that was open, making room for a new one.
+    fn close(&self) {
+        self.state.lock().unwrap().open -= 1;
+        self.returned.notify_one();
+    }
+}
+
+/// PooledConnection is a connection taken from a `ConnectionPool`. It goes
+/// back to the pool when it's dropped, unless talking to the server failed,
+/// in which case it's closed and the pool opens a new connection in its place
+/// when one is next needed.
+pub struct PooledConnection {
+    // Only `None` once it's been dropped.
+    connection: Option<ReconnectingConnection>,
+    shared: Arc<Shared>,
+
+    // Whether an I/O or protocol error left the connection in an unknown
+    // state, such as partway through a reply.
+    broken: bool,
+}
+
+impl Client for PooledConnection {
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        let result = self.connection.as_mut().unwrap().send(commands);
+        if result.is_err() {
+            self.broken = true;
+        }
+        result
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let connection = self.connection.take().unwrap();
+        if self.broken {
+            self.shared.close();
+            return;
+        }
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .idle
+            .push((connection, Instant::now()));
+        self.shared.returned.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use std::thread;
+
+    #[test]
+    fn it_limits_connections() {
+        let redis = FakeRedis::start();
+        let config = PoolConfig {
+            min_size: 1,
+            max_size: 2,
+            checkout_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(redis.addr(), config).unwrap();
+        assert_eq!(pool.size(), (1, 1));
+
+        let (a, b) = (pool.get().unwrap(), pool.get().unwrap());
+        assert_eq!(pool.size(), (2, 0));
+        assert_eq!(
+            pool.get().err().map(|error| match error {
+                BackendError::Io { kind, .. } => kind,
+                _ => io::ErrorKind::Other,
+            }),
+            Some(io::ErrorKind::TimedOut)
+        );
+
+        let mut shared = pool.clone();
+        let waiting =
+            thread::spawn(move || shared.query(&Command::new("SET").arg("a").arg("1")));
+        thread::sleep(Duration::from_millis(10));
+        drop(a);
+        assert_eq!(
+            waiting.join().unwrap(),
+            Ok(Value::Status(String::from("OK")))
+        );
+        drop(b);
+        assert_eq!(pool.size(), (2, 2));
+    }
+
+    #[test]
+    fn it_replaces_unhealthy_connections() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "PING" => Value::Error(String::from("LOADING Redis is loading the dataset")),
+            _ => Value::Status(String::from("OK")),
+        });
+        let config = PoolConfig {
+            health_check_after: Duration::ZERO,
+            ..PoolConfig::default()
+        };
+        let mut pool = ConnectionPool::new(redis.addr(), config).unwrap();
+        thread::sleep(Duration::from_millis(1));
+        assert!(pool.query(&Command::new("SET").arg("a").arg("1")).is_ok());
+        assert_eq!(pool.size(), (1, 1));
+        assert_eq!(redis.commands(), vec![vec!["PING"], vec!["SET", "a", "1"]]);
+
+        let down = ConnectionPool::new("127.0.0.1:1", PoolConfig::default());
+        assert!(matches!(down.err(), Some(BackendError::Io { .. })));
+        let config = PoolConfig {
+            min_size: 0,
+            ..PoolConfig::default()
+        };
+        let mut down = ConnectionPool::new("127.0.0.1:1", config).unwrap();
+        assert!(matches!(
+            down.query(&Command::new("PING")),
+            Err(BackendError::Unavailable { .. })
+        ));
+        assert_eq!(down.size(), (0, 0));
+    }
+}
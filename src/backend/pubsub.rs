@@ -0,0 +1,171 @@
+//! Publishes metrics over Redis pub/sub as StatsD lines, for live dashboards
+//! and ad-hoc subscribers (e.g. `redis-cli PSUBSCRIBE 'metrics:*'`). Nothing
+//! is stored, so a message that's published while no one is subscribed is
+//! gone.
+
+use super::connection::{Client, ReconnectingConnection, SharedCredentials};
+use super::resp::Command;
+use super::{Backend, BackendError};
+use crate::parser::Metric;
+use std::mem;
+use std::net::ToSocketAddrs;
+
+/// Configuration for `PubSubBackend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PubSubConfig {
+    /// The channel that flushes are published to. With
+    /// `PublishMode::PerMetric`, the prefix of the channels that metrics are
+    /// published to.
+    pub channel: String,
+
+    /// What gets published.
+    pub mode: PublishMode,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them (see `RedisConfig::credentials`).
+    pub credentials: Option<SharedCredentials>,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> PubSubConfig {
+        PubSubConfig {
+            channel: String::from("metrics"),
+            mode: PublishMode::default(),
+            credentials: None,
+        }
+    }
+}
+
+/// What `PubSubBackend` publishes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PublishMode {
+    /// The metrics recorded since the last flush are published on `flush`
+    /// as a single message of StatsD lines.
+    #[default]
+    PerFlush,
+
+    /// Each metric is published as soon as it's recorded, to a channel named
+    /// after it (e.g. "metrics:api.requests"), so subscribers can pick the
+    /// metrics they're interested in with a pattern.
+    PerMetric,
+}
+
+/// PubSubBackend publishes metrics to Redis pub/sub channels.
+pub struct PubSubBackend {
+    connection: ReconnectingConnection,
+    config: PubSubConfig,
+
+    // The metrics recorded since the last flush, encoded as StatsD lines.
+    message: String,
+}
+
+impl PubSubBackend {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379").
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: PubSubConfig,
+    ) -> Result<PubSubBackend, BackendError> {
+        let connection =
+            ReconnectingConnection::to(addr, config.credentials.clone())?.opened()?;
+        Ok(PubSubBackend {
+            connection,
+            config,
+            message: String::new(),
+        })
+    }
+}
+
+impl Backend for PubSubBackend {
+    /// Publishes each metric right away in a single pipeline with
+    /// `PublishMode::PerMetric`, or buffers them until `flush` otherwise.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        match self.config.mode {
+            PublishMode::PerFlush => {
+                for metric in metrics {
+                    if !self.message.is_empty() {
+                        self.message.push('\n');
+                    }
+                    self.message.push_str(&metric.to_string());
+                }
+            }
+            PublishMode::PerMetric if !metrics.is_empty() => {
+                let commands: Vec<_> = metrics
+                    .iter()
+                    .map(|metric| {
+                        let channel =
+                            format!("{}:{}", self.config.channel, metric.name());
+                        Command::new("PUBLISH")
+                            .arg(channel)
+                            .arg_owned(metric.to_string())
+                    })
+                    .collect();
+                self.connection.pipeline(&commands)?;
+            }
+            PublishMode::PerMetric => (),
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BackendError> {
+        if self.message.is_empty() {
+            return Ok(());
+        }
+        let message = mem::take(&mut self.message);
+        self.connection.query(
+            &Command::new("PUBLISH")
+                .arg(&self.config.channel)
+                .arg(message),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::resp::Value;
+    use crate::backend::testing::FakeRedis;
+    use crate::parser::{parse, ParserConfig};
+
+    #[test]
+    fn it_publishes_metrics() {
+        let batch = parse(
+            b"gorets:1|c\nglork:320|ms|#env:production",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        for (mode, expected) in [
+            (
+                PublishMode::PerFlush,
+                vec![vec![
+                    "PUBLISH",
+                    "dashboards",
+                    "gorets:1|c\nglork:320|ms|#env:production",
+                ]],
+            ),
+            (
+                PublishMode::PerMetric,
+                vec![
+                    vec!["PUBLISH", "dashboards:gorets", "gorets:1|c"],
+                    vec![
+                        "PUBLISH",
+                        "dashboards:glork",
+                        "glork:320|ms|#env:production",
+                    ],
+                ],
+            ),
+        ] {
+            let redis = FakeRedis::with_replies(|_| Value::Integer(0));
+            let config = PubSubConfig {
+                channel: String::from("dashboards"),
+                mode,
+                credentials: None,
+            };
+            let mut backend = PubSubBackend::connect(redis.addr(), config).unwrap();
+            backend.record(&batch.metrics).unwrap();
+            backend.flush().unwrap();
+            backend.flush().unwrap();
+            assert_eq!(redis.commands(), expected, "{:?}", mode);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! Reads back the metrics that `RedisBackend` stores, so that dashboards and
+//! alerting can use them without knowing how keys are named or how each type
+//! is stored. Reads go through the same `RedisConfig` as the backend that
+//! wrote the metrics, which decides the keys and windows.
+
+use super::catalog::{self, Catalog, CatalogEntry};
+use super::connection::{Client, ReconnectingConnection};
+use super::pool::ConnectionPool;
+use super::redis::{
+    bucket_fields, buckets_key, count_set, read_count, read_timer_stats, stats_key,
+    timer_key, timer_stats, window_start, Count,
+};
+use super::resp::{protocol_error, Command, Value};
+use super::rollup::rollup_key;
+use super::{BackendError, RedisConfig, SetMode, TimerStats};
+use crate::parser::{MetricId, MetricType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::ToSocketAddrs;
+use std::str;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The most windows that `RedisQuery::heatmap` reads in a single pipeline, so
+// that a heatmap over a long time doesn't read every window's buckets at
+// once.
+const HEATMAP_WINDOWS: usize = 1000;
+
+/// RedisQuery reads metrics stored by `RedisBackend`. Each read is of the
+/// series that `id` names, and `id`'s type has to be one that the read is
+/// for. Metrics that haven't been flushed yet aren't included.
+pub struct RedisQuery {
+    connection: Box<dyn Client>,
+    config: RedisConfig,
+}
+
+impl RedisQuery {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379"), which
+    /// metrics were written to with `config`, authenticating with
+    /// `config.credentials` if there are any.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: RedisConfig,
+    ) -> Result<RedisQuery, BackendError> {
+        let connection =
+            ReconnectingConnection::to(addr, config.credentials.clone())?.opened()?;
+        Ok(RedisQuery {
+            connection: Box::new(connection),
+            config,
+        })
+    }
+
+    /// Reads with connections from `pool`, which can be shared with backends.
+    pub fn with_pool(pool: ConnectionPool, config: RedisConfig) -> RedisQuery {
+        RedisQuery::with_client(Box::new(pool), config)
+    }
+
+    /// Reads with `client`, which can be any `Client`, like a cluster's or a
+    /// `MemoryClient` that a backend wrote to in tests.
+    pub fn with_client(client: Box<dyn Client>, config: RedisConfig) -> RedisQuery {
+        RedisQuery {
+            connection: client,
+            config,
+        }
+    }
+
+    /// Returns the total of a counter or meter, or `None` if it's never been
+    /// flushed (or its key has expired).
+    pub fn counter(&mut self, id: &MetricId) -> Result<Option<Count>, BackendError> {
+        check_type(id, &[MetricType::Counter, MetricType::Meter])?;
+        self.get(id)?.map(|value| parse_count(&value)).transpose()
+    }
+
+    /// Returns the total of a counter or meter since it was last drained,
+    /// and deletes it with `GETDEL`, or returns `None` if nothing's been
+    /// flushed to it since. Redis reads and deletes the key in one step, and
+    /// backends increment it, so an increment that races with the drain is
+    /// either in the total or counted again from zero. That lets a separate
+    /// process report counters that several backends write to without losing
+    /// or double counting anything. Needs Redis 6.2 or later.
+    pub fn drain_counter(
+        &mut self,
+        id: &MetricId,
+    ) -> Result<Option<Count>, BackendError> {
+        check_type(id, &[MetricType::Counter, MetricType::Meter])?;
+        self.read(Command::new("GETDEL").arg(self.config.keys.key(id)))?
+            .map(|value| parse_count(&value))
+            .transpose()
+    }
+
+    /// Returns the value of a gauge, or `None` if it's never been flushed.
+    pub fn gauge(&mut self, id: &MetricId) -> Result<Option<f64>, BackendError> {
+        check_type(id, &[MetricType::Gauge])?;
+        self.get(id)?.map(|value| parse_float(&value)).transpose()
+    }
+
+    /// Returns the number of unique members of a set in the window that
+    /// contains `at`. See `RedisBackend::count_set`.
+    pub fn set_cardinality(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<u64, BackendError> {
+        check_type(id, &[MetricType::Set])?;
+        read_count(self.connection.query(&count_set(&self.config, id, at))?)
+    }
+
+    /// Returns the statistics, including percentiles, of a sample,
+    /// histogram, or distribution in the window that contains `at`. See
+    /// `RedisBackend::timer_stats`.
+    pub fn timer(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<Option<TimerStats>, BackendError> {
+        check_type(
+            id,
+            &[
+                MetricType::Sample,
+                MetricType::Histogram,
+                MetricType::Distribution,
+            ],
+        )?;
+        read_timer_stats(self.connection.query(&timer_stats(&self.config, id, at))?)
+    }
+
+    /// Returns a heatmap of a sample, histogram, or distribution from `from`
+    /// until `until`: the start of each window in that time, with how many of
+    /// the observations in the window were in each of
+    /// `RedisConfig::heatmap_buckets`, followed by how many were past the
+    /// last bucket. Windows are read in pipelines of up to 1,000.
+    pub fn heatmap(
+        &mut self,
+        id: &MetricId,
+        from: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<(SystemTime, Vec<u64>)>, BackendError> {
+        check_type(
+            id,
+            &[
+                MetricType::Sample,
+                MetricType::Histogram,
+                MetricType::Distribution,
+            ],
+        )?;
+        let window = self.config.window.as_secs().max(1) as usize;
+        let (first, last) = (
+            window_start(&self.config, from),
+            window_start(&self.config, until),
+        );
+        let starts: Vec<_> = (first..=last)
+            .step_by(window)
+            .map(|start| UNIX_EPOCH + Duration::from_secs(start))
+            .collect();
+        if starts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let commands: Vec<_> = starts
+            .iter()
+            .map(|&at| {
+                Command::new("HGETALL").arg(buckets_key(&timer_key(&self.config, id, at)))
+            })
+            .collect();
+        let mut replies = Vec::with_capacity(commands.len());
+        for commands in commands.chunks(HEATMAP_WINDOWS) {
+            replies.extend(self.connection.pipeline(commands)?);
+        }
+        let fields = bucket_fields(&self.config.heatmap_buckets);
+        starts
+            .into_iter()
+            .zip(replies)
+            .map(|(at, reply)| {
+                let counts = read_hash(reply)?;
+                let row = fields.iter().map(|field| match counts.get(field) {
+                    Some(count) => u64::from_str(count).map_err(|_| {
+                        protocol_error(&format!("invalid count {:?}", count))
+                    }),
+                    None => Ok(0),
+                });
+                Ok((at, row.collect::<Result<_, _>>()?))
+            })
+            .collect()
+    }
+
+    /// Like `set_cardinality`, but in the rollup window that's `length` long
+    /// (one of `RedisConfig::rollups`' levels) and contains `at`.
+    pub fn rolled_up_set_cardinality(
+        &mut self,
+        id: &MetricId,
+        length: Duration,
+        at: SystemTime,
+    ) -> Result<u64, BackendError> {
+        check_type(id, &[MetricType::Set])?;
+        let key = rollup_key(&self.config, id, length, at);
+        let command = match self.config.sets {
+            SetMode::HyperLogLog => Command::new("PFCOUNT").arg(key),
+            SetMode::Exact => Command::new("SCARD").arg(key),
+        };
+        read_count(self.connection.query(&command)?)
+    }
+
+    /// Like `timer`, but in the rollup window that's `length` long (one of
+    /// `RedisConfig::rollups`' levels) and contains `at`.
+    pub fn rolled_up_timer(
+        &mut self,
+        id: &MetricId,
+        length: Duration,
+        at: SystemTime,
+    ) -> Result<Option<TimerStats>, BackendError> {
+        check_type(
+            id,
+            &[
+                MetricType::Sample,
+                MetricType::Histogram,
+                MetricType::Distribution,
+            ],
+        )?;
+        let key = stats_key(&rollup_key(&self.config, id, length, at));
+        read_timer_stats(self.connection.query(&Command::new("HGETALL").arg(key))?)
+    }
+
+    /// Returns the latest value of a key/value.
+    pub fn value(&mut self, id: &MetricId) -> Result<Option<String>, BackendError> {
+        check_type(id, &[MetricType::KeyValue])?;
+        self.get(id)
+    }
+
+    /// Returns the names of the metrics in `RedisConfig::catalog`.
+    pub fn catalog(&mut self) -> Result<BTreeSet<String>, BackendError> {
+        let catalog = self.catalog_config()?;
+        let command = Command::new("SMEMBERS").arg(&catalog.key);
+        read_strings(self.connection.query(&command)?)
+    }
+
+    /// Returns what `RedisConfig::catalog` knows about the metric named
+    /// `name`, or `None` if it's not in the catalog.
+    pub fn catalog_entry(
+        &mut self,
+        name: &str,
+    ) -> Result<Option<CatalogEntry>, BackendError> {
+        let catalog = self.catalog_config()?;
+        let commands = [
+            Command::new("HGETALL").arg(catalog::entry_key(catalog, name)),
+            Command::new("SMEMBERS").arg(catalog::tags_key(catalog, name)),
+        ];
+        let mut replies = self.connection.pipeline(&commands)?.into_iter();
+        let (Some(fields), Some(tags)) = (replies.next(), replies.next()) else {
+            return Err(protocol_error("missing replies to the catalog's commands"));
+        };
+        let mut fields = read_hash(fields)?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let time = |fields: &mut BTreeMap<String, String>, field| {
+            let millis = fields
+                .remove(field)
+                .and_then(|millis| u64::from_str(&millis).ok());
+            millis
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+                .ok_or_else(|| protocol_error(&format!("invalid catalog {:?}", field)))
+        };
+        let metric_type = fields
+            .remove("type")
+            .and_then(|t| catalog::metric_type(&t))
+            .ok_or_else(|| protocol_error("invalid catalog type"))?;
+        Ok(Some(CatalogEntry {
+            metric_type,
+            first_seen: time(&mut fields, "first_seen")?,
+            last_seen: time(&mut fields, "last_seen")?,
+            unit: fields.remove("unit"),
+            description: fields.remove("description"),
+            tags: read_strings(tags)?,
+        }))
+    }
+
+    fn catalog_config(&self) -> Result<&Catalog, BackendError> {
+        self.config
+            .catalog
+            .as_ref()
+            .ok_or_else(|| BackendError::Config {
+                message: String::from("there's no catalog configured"),
+            })
+    }
+
+    // Reads the key of a series that isn't split into windows.
+    fn get(&mut self, id: &MetricId) -> Result<Option<String>, BackendError> {
+        self.read(Command::new("GET").arg(self.config.keys.key(id)))
+    }
+
+    // Sends a command whose reply is a string value, or nil.
+    fn read(&mut self, command: Command) -> Result<Option<String>, BackendError> {
+        match self.connection.query(&command)? {
+            Value::Nil => Ok(None),
+            Value::Bulk(bytes) => match String::from_utf8(bytes) {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(protocol_error("value isn't UTF-8")),
+            },
+            reply => Err(protocol_error(&format!("invalid value {:?}", reply))),
+        }
+    }
+}
+
+fn check_type(id: &MetricId, types: &[MetricType]) -> Result<(), BackendError> {
+    if types.contains(&id.metric_type()) {
+        return Ok(());
+    }
+    Err(BackendError::Config {
+        message: format!(
+            "{} is a {:?}, which can't be read this way",
+            id.name(),
+            id.metric_type()
+        ),
+    })
+}
+
+// Reads a reply of strings, like the members of a set.
+fn read_strings(reply: Value) -> Result<BTreeSet<String>, BackendError> {
+    let Value::Array(values) = reply else {
+        return Err(protocol_error(&format!("invalid set {:?}", reply)));
+    };
+    values.into_iter().map(read_string).collect()
+}
+
+// Reads a reply of a hash's fields and values.
+fn read_hash(reply: Value) -> Result<BTreeMap<String, String>, BackendError> {
+    let fields = match reply {
+        Value::Array(fields) => fields,
+        Value::Map(pairs) => pairs
+            .into_iter()
+            .flat_map(|(field, value)| [field, value])
+            .collect(),
+        reply => return Err(protocol_error(&format!("invalid hash {:?}", reply))),
+    };
+    let mut fields = fields.into_iter().map(read_string);
+    let mut hash = BTreeMap::new();
+    while let (Some(field), Some(value)) = (fields.next(), fields.next()) {
+        hash.insert(field?, value?);
+    }
+    Ok(hash)
+}
+
+fn read_string(value: Value) -> Result<String, BackendError> {
+    match value {
+        Value::Bulk(bytes) => {
+            String::from_utf8(bytes).map_err(|_| protocol_error("value isn't UTF-8"))
+        }
+        value => Err(protocol_error(&format!("invalid value {:?}", value))),
+    }
+}
+
+fn parse_count(value: &str) -> Result<Count, BackendError> {
+    if let Ok(i) = i64::from_str(value) {
+        return Ok(Count::Integer(i));
+    }
+    parse_float(value).map(Count::Float)
+}
+
+fn parse_float(value: &str) -> Result<f64, BackendError> {
+    f64::from_str(value)
+        .map_err(|_| protocol_error(&format!("invalid number {:?}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{Backend, MemoryClient, RedisBackend};
+    use crate::parser::{parse, ParserConfig, Tag};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn it_reads_metrics() {
+        let redis = FakeRedis::with_replies(|command| {
+            let bulk = |s: &str| Value::Bulk(s.as_bytes().to_vec());
+            match (command[0].as_str(), command[1].as_str()) {
+                ("GET", "stats.counters.gorets;env=production") => bulk("42"),
+                ("GET", "stats.meters.rate") => bulk("2.5"),
+                ("GET", "stats.gauges.gaugor") => bulk("-6"),
+                ("GET", "stats.values.config.version") => bulk("1.2.3"),
+                ("GET", _) => Value::Nil,
+                ("PFCOUNT", "stats.sets.uniques.1656581400") => Value::Integer(2),
+                ("PFCOUNT", _) => Value::Integer(0),
+                _ => Value::Array(Vec::new()),
+            }
+        });
+        let mut query =
+            RedisQuery::connect(redis.addr(), RedisConfig::default()).unwrap();
+        let id = |name, metric_type| MetricId::new(name, metric_type, Vec::new());
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+
+        let tags = vec![Tag::new("env", Some("production"))];
+        let counter = MetricId::new("gorets", MetricType::Counter, tags);
+        assert_eq!(query.counter(&counter), Ok(Some(Count::Integer(42))));
+        assert_eq!(
+            query.counter(&id("rate", MetricType::Meter)),
+            Ok(Some(Count::Float(2.5)))
+        );
+        assert_eq!(query.counter(&id("missing", MetricType::Counter)), Ok(None));
+        assert_eq!(
+            query.gauge(&id("gaugor", MetricType::Gauge)),
+            Ok(Some(-6.0))
+        );
+        assert_eq!(
+            query.value(&id("config.version", MetricType::KeyValue)),
+            Ok(Some(String::from("1.2.3")))
+        );
+        assert_eq!(
+            query.set_cardinality(&id("uniques", MetricType::Set), at),
+            Ok(2)
+        );
+        assert_eq!(query.timer(&id("glork", MetricType::Sample), at), Ok(None));
+        assert!(matches!(
+            query.gauge(&counter),
+            Err(BackendError::Config { .. })
+        ));
+
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            query.rolled_up_set_cardinality(&id("uniques", MetricType::Set), hour, at),
+            Ok(0)
+        );
+        assert_eq!(
+            query.rolled_up_timer(&id("glork", MetricType::Sample), hour, at),
+            Ok(None)
+        );
+
+        let commands = redis.commands();
+        assert_eq!(
+            commands[commands.len() - 3..],
+            vec![
+                vec!["HGETALL", "{stats.timers.glork.1656581400}:stats"],
+                vec!["PFCOUNT", "{stats.sets.uniques.rollup.3600s.1656579600}"],
+                vec![
+                    "HGETALL",
+                    "{stats.timers.glork.rollup.3600s.1656579600}:stats"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reads_what_backends_wrote() {
+        let memory = MemoryClient::new();
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), RedisConfig::default())
+                .unwrap();
+        let input = b"gorets:2|c\ngorets:3|c\ngaugor:7|g\nuniques:a|s\nuniques:b|s";
+        let batch = parse(input, &ParserConfig::default()).unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+        backend.record(&batch.metrics).unwrap();
+        backend.flush_at(at).unwrap();
+
+        let mut query = RedisQuery::with_client(Box::new(memory), RedisConfig::default());
+        let id = |name, metric_type| MetricId::new(name, metric_type, Vec::new());
+        assert_eq!(
+            query.counter(&id("gorets", MetricType::Counter)),
+            Ok(Some(Count::Integer(5)))
+        );
+        assert_eq!(query.gauge(&id("gaugor", MetricType::Gauge)), Ok(Some(7.0)));
+        assert_eq!(
+            query.set_cardinality(&id("uniques", MetricType::Set), at),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn it_reads_heatmaps() {
+        let memory = MemoryClient::new();
+        let config = RedisConfig {
+            heatmap_buckets: vec![10.0, 100.0],
+            ..RedisConfig::default()
+        };
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), config.clone()).unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (secs, input) in [
+            (5, &b"glork:5|ms\nglork:10|ms\nglork:50|ms"[..]),
+            (25, b"glork:500|ms\nglork:99.5|ms"),
+            (27, b"glork:1|ms"),
+        ] {
+            backend
+                .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+                .unwrap();
+            backend.flush_at(at(secs)).unwrap();
+        }
+
+        let mut query = RedisQuery::with_client(Box::new(memory.clone()), config);
+        let id = MetricId::new("glork", MetricType::Sample, Vec::new());
+        assert_eq!(
+            query.heatmap(&id, at(0), at(29)),
+            Ok(vec![
+                (at(0), vec![2, 1, 0]),
+                (at(10), vec![0, 0, 0]),
+                (at(20), vec![1, 1, 1]),
+            ])
+        );
+        assert_eq!(query.heatmap(&id, at(20), at(10)), Ok(Vec::new()));
+
+        // A day of windows is read in several pipelines.
+        let sent = memory.sent().len();
+        let heatmap = query.heatmap(&id, at(0), at(86_399)).unwrap();
+        assert_eq!(heatmap.len(), 8640);
+        assert_eq!(heatmap[2], (at(20), vec![1, 1, 1]));
+        assert_eq!(memory.sent().len() - sent, 9);
+
+        for buckets in [vec![10.0, 10.0], vec![100.0, 10.0], vec![f64::NAN]] {
+            let config = RedisConfig {
+                heatmap_buckets: buckets,
+                ..RedisConfig::default()
+            };
+            let backend =
+                RedisBackend::with_client(Box::new(MemoryClient::new()), config);
+            assert!(matches!(backend, Err(BackendError::Config { .. })));
+        }
+    }
+
+    #[test]
+    fn it_drains_counters() {
+        let memory = MemoryClient::new();
+        let config = RedisConfig::default();
+        let mut backends: Vec<_> = (0..2)
+            .map(|_| {
+                RedisBackend::with_client(Box::new(memory.clone()), config.clone())
+                    .unwrap()
+            })
+            .collect();
+        let mut query = RedisQuery::with_client(Box::new(memory.clone()), config);
+        let gorets = MetricId::new("gorets", MetricType::Counter, Vec::new());
+        let flush = |backend: &mut RedisBackend, input: &[u8]| {
+            backend
+                .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+                .unwrap();
+            backend.flush().unwrap();
+        };
+
+        flush(&mut backends[0], b"gorets:2|c");
+        flush(&mut backends[1], b"gorets:3|c");
+        assert_eq!(query.drain_counter(&gorets), Ok(Some(Count::Integer(5))));
+        assert_eq!(query.drain_counter(&gorets), Ok(None));
+        flush(&mut backends[0], b"gorets:0.5|c");
+        assert_eq!(query.drain_counter(&gorets), Ok(Some(Count::Float(0.5))));
+        assert_eq!(memory.get("stats.counters.gorets"), None);
+        let gaugor = MetricId::new("gaugor", MetricType::Gauge, Vec::new());
+        assert!(matches!(
+            query.drain_counter(&gaugor),
+            Err(BackendError::Config { .. })
+        ));
+    }
+}
@@ -0,0 +1,448 @@
+//! Limits how many series each namespace of metrics can have, and how many
+//! metrics it can record between flushes, so that a service that puts IDs in
+//! its metrics' names or tags (e.g. "checkout.order.3f2a8c1e.latency"), or a
+//! noisy tenant, can't fill Redis with keys or flushes with writes.
+//!
+//! A metric's namespace is the start of its name, up to `Quotas::depth`
+//! segments separated by dots (e.g. "checkout" for "checkout.latency"), or
+//! with `Quotas::tenant_tag`, the value of its tenant's tag. Each backend
+//! counts the distinct keys that it's written to in each namespace, and once
+//! a namespace has as many as its quota, metrics of new series are dropped or
+//! rerouted (see `QuotaAction`), while the series it already has keep being
+//! written. Backends count on their own, so servers sharing Redis can admit
+//! different series, up to a quota each.
+//!
+//! Dropped metrics are counted in a counter of their namespace,
+//! "quotas.dropped" with a "namespace" tag (e.g.
+//! "stats.counters.quotas.dropped;namespace=checkout"), which is written on
+//! each flush like any other counter. Metrics dropped because their namespace
+//! recorded too many since the last flush are counted in "quotas.throttled"
+//! the same way.
+
+use super::redis::expire;
+use super::resp::Command;
+use super::retention;
+use super::series::SeriesRef;
+use super::RedisConfig;
+use crate::parser::{MetricId, MetricType, Tag};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::time::{Duration, SystemTime};
+
+/// The name of the counter that dropped metrics are counted in.
+const DROPPED_COUNTER: &str = "quotas.dropped";
+
+/// The name of the counter that metrics past namespaces' write limits are
+/// counted in.
+const THROTTLED_COUNTER: &str = "quotas.throttled";
+
+/// Configuration for limiting the series in each namespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quotas {
+    /// The most series that a namespace can have, unless it's in `limits`.
+    /// `None`, which is the default, doesn't limit them.
+    pub max_series: Option<usize>,
+
+    /// The most series that particular namespaces can have, for the ones that
+    /// need more (or fewer) than `max_series`.
+    pub limits: BTreeMap<String, usize>,
+
+    /// How many of a name's segments its namespace is. Defaults to 1.
+    pub depth: usize,
+
+    /// A tag whose value is the tenant that a metric belongs to (e.g.
+    /// "tenant"), which is its namespace instead of the start of its name, so
+    /// that limits are per tenant. Metrics without the tag are namespaced by
+    /// name.
+    pub tenant_tag: Option<String>,
+
+    /// The most metrics that a namespace can record between flushes, unless
+    /// it's in `write_limits`, which bounds how much each flush writes for it.
+    /// Metrics past it are dropped, whatever `action` is. `None`, which is the
+    /// default, doesn't limit them.
+    pub max_writes: Option<usize>,
+
+    /// The most metrics that particular namespaces can record between
+    /// flushes, for the ones that need more (or fewer) than `max_writes`.
+    pub write_limits: BTreeMap<String, usize>,
+
+    /// What happens to the metrics of series past a namespace's quota.
+    pub action: QuotaAction,
+
+    /// How often the series counted against quotas are forgotten, so that
+    /// namespaces whose series change over time (like ones tagged by
+    /// release) aren't stuck with the first ones. `None` never forgets them.
+    pub period: Option<Duration>,
+}
+
+impl Quotas {
+    // Returns whether any namespace has a quota of series or writes.
+    pub(super) fn limited(&self) -> bool {
+        self.max_series.is_some()
+            || !self.limits.is_empty()
+            || self.max_writes.is_some()
+            || !self.write_limits.is_empty()
+    }
+}
+
+impl Default for Quotas {
+    fn default() -> Quotas {
+        Quotas {
+            max_series: None,
+            limits: BTreeMap::new(),
+            depth: 1,
+            tenant_tag: None,
+            max_writes: None,
+            write_limits: BTreeMap::new(),
+            action: QuotaAction::default(),
+            period: None,
+        }
+    }
+}
+
+/// What happens to a metric of a series that's past its namespace's quota.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QuotaAction {
+    /// The metric is dropped and counted.
+    #[default]
+    Drop,
+
+    /// The metric is recorded to its namespace's overflow series instead,
+    /// which is named after the namespace (e.g. "checkout.overflow"), has
+    /// the metric's type, and has no tags. Totals and observations are kept
+    /// that way, but not which series they were of.
+    Overflow,
+}
+
+// Quota tracks the series that each namespace has been written to.
+#[derive(Default)]
+pub(super) struct Quota {
+    // The keys of the series admitted to each namespace with a quota.
+    series: BTreeMap<String, BTreeSet<String>>,
+
+    // The number of metrics dropped in each namespace since the last flush.
+    dropped: BTreeMap<String, u64>,
+
+    // The number of metrics recorded in each namespace with a write limit
+    // since the last flush, and the number dropped for being past it.
+    writes: BTreeMap<String, usize>,
+    throttled: BTreeMap<String, u64>,
+
+    // When the admitted series are next forgotten.
+    reset_at: Option<SystemTime>,
+}
+
+impl Quota {
+    // Returns the series and key that a metric is recorded to, which are its
+    // own unless its namespace is past its quota, or `None` if it's dropped.
+    // Without quotas, it's always its own, and the namespace isn't looked up.
+    pub(super) fn admit<'a>(
+        &mut self,
+        config: &RedisConfig,
+        id: &'a MetricId,
+        key: &'a str,
+    ) -> Option<(Cow<'a, MetricId>, Cow<'a, str>)> {
+        let quotas = &config.quotas;
+        if !quotas.limited() {
+            return Some((Cow::Borrowed(id), Cow::Borrowed(key)));
+        }
+        let namespace = namespace(id, quotas);
+        let admitted = self.admit_series(config, namespace, id, key)?;
+
+        let Some(limit) = quotas
+            .write_limits
+            .get(namespace)
+            .copied()
+            .or(quotas.max_writes)
+        else {
+            return Some(admitted);
+        };
+        let writes = match self.writes.get_mut(namespace) {
+            Some(writes) => writes,
+            None => self.writes.entry(String::from(namespace)).or_insert(0),
+        };
+        if *writes < limit {
+            *writes += 1;
+            return Some(admitted);
+        }
+        *self.throttled.entry(String::from(namespace)).or_insert(0) += 1;
+        None
+    }
+
+    // Like `admit`, but only checks the namespace's quota of series.
+    fn admit_series<'a>(
+        &mut self,
+        config: &RedisConfig,
+        namespace: &str,
+        id: &'a MetricId,
+        key: &'a str,
+    ) -> Option<(Cow<'a, MetricId>, Cow<'a, str>)> {
+        let quotas = &config.quotas;
+        let admitted = (Cow::Borrowed(id), Cow::Borrowed(key));
+        let Some(limit) = quotas.limits.get(namespace).copied().or(quotas.max_series)
+        else {
+            return Some(admitted);
+        };
+        let series = match self.series.get_mut(namespace) {
+            Some(series) => series,
+            None => self.series.entry(String::from(namespace)).or_default(),
+        };
+        if series.contains(key) {
+            return Some(admitted);
+        }
+        if series.len() < limit {
+            series.insert(String::from(key));
+            return Some(admitted);
+        }
+        match quotas.action {
+            QuotaAction::Drop => {
+                *self.dropped.entry(String::from(namespace)).or_insert(0) += 1;
+                None
+            }
+            QuotaAction::Overflow => {
+                let name = format!("{}.overflow", namespace);
+                let overflow = MetricId::new(&name, id.metric_type(), Vec::new());
+                let key = config.keys.key(&overflow);
+                Some((Cow::Owned(overflow), Cow::Owned(key)))
+            }
+        }
+    }
+
+    // Adds the commands that count the metrics dropped since the last flush
+    // to `commands`, resets the namespaces' writes, and forgets the admitted
+    // series if `period` has passed.
+    pub(super) fn flush(
+        &mut self,
+        config: &RedisConfig,
+        now: SystemTime,
+        commands: &mut Vec<Command>,
+    ) {
+        self.writes.clear();
+        let dropped = mem::take(&mut self.dropped)
+            .into_iter()
+            .map(|d| (DROPPED_COUNTER, d));
+        let throttled = mem::take(&mut self.throttled)
+            .into_iter()
+            .map(|t| (THROTTLED_COUNTER, t));
+        for (name, (namespace, count)) in dropped.chain(throttled) {
+            let tags = vec![Tag::new("namespace", Some(&namespace))];
+            let key = config
+                .keys
+                .key(&MetricId::new(name, MetricType::Counter, tags));
+            commands.push(
+                Command::new("INCRBY")
+                    .arg(&key)
+                    .arg_owned(count.to_string()),
+            );
+            expire(commands, &key, retention::ttls(config, name).counters);
+        }
+
+        let Some(period) = config.quotas.period else {
+            return;
+        };
+        match self.reset_at {
+            Some(at) if now < at => {}
+            Some(_) => {
+                self.series.clear();
+                self.reset_at = Some(now + period);
+            }
+            None => self.reset_at = Some(now + period),
+        }
+    }
+}
+
+// Returns a metric's namespace, which is its tenant if it has one, or else
+// the namespace of its name.
+fn namespace<'a>(id: &'a MetricId, quotas: &'a Quotas) -> &'a str {
+    let tags = id
+        .tags()
+        .iter()
+        .map(|tag| (tag.key.as_str(), tag.value.as_deref()));
+    tags_namespace(id.name(), tags, quotas)
+}
+
+// Like `namespace`, but of a series. It's shared with
+// `ConcurrentRedisBackend`, which shards metrics by namespace when namespaces
+// have quotas.
+pub(super) fn series_namespace<'a>(
+    series: &'a SeriesRef<'_>,
+    quotas: &Quotas,
+) -> &'a str {
+    let (_, tags) = series.tags();
+    tags_namespace(series.name(), tags.map(|tag| (tag.key, tag.value)), quotas)
+}
+
+fn tags_namespace<'a>(
+    name: &'a str,
+    mut tags: impl Iterator<Item = (&'a str, Option<&'a str>)>,
+    quotas: &Quotas,
+) -> &'a str {
+    let tenant = quotas.tenant_tag.as_ref().and_then(|tenant_tag| {
+        tags.find(|(key, _)| key == tenant_tag)
+            .and_then(|(_, value)| value)
+    });
+    tenant.unwrap_or_else(|| name_namespace(name, quotas.depth))
+}
+
+// Returns the namespace of a metric's name, which is its first `depth`
+// segments, or all of it if it has fewer.
+fn name_namespace(name: &str, depth: usize) -> &str {
+    match name.match_indices('.').nth(depth.max(1) - 1) {
+        Some((i, _)) => &name[..i],
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{Backend, RedisBackend};
+    use crate::parser::{parse, ParserConfig};
+    use std::time::UNIX_EPOCH;
+
+    fn flush(backend: &mut RedisBackend, input: &[u8], secs: u64) {
+        backend
+            .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+            .unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn it_finds_namespaces() {
+        assert_eq!(name_namespace("checkout.order.latency", 1), "checkout");
+        assert_eq!(
+            name_namespace("checkout.order.latency", 2),
+            "checkout.order"
+        );
+        assert_eq!(name_namespace("checkout", 2), "checkout");
+        assert_eq!(name_namespace("checkout.latency", 0), "checkout");
+
+        let quotas = Quotas {
+            tenant_tag: Some(String::from("tenant")),
+            ..Quotas::default()
+        };
+        let id = |tags| MetricId::new("checkout.latency", MetricType::Sample, tags);
+        assert_eq!(
+            namespace(&id(vec![Tag::new("tenant", Some("acme"))]), &quotas),
+            "acme"
+        );
+        assert_eq!(
+            namespace(&id(vec![Tag::new("tenant", None)]), &quotas),
+            "checkout"
+        );
+    }
+
+    #[test]
+    fn it_drops_series_past_quotas() {
+        let redis = FakeRedis::start();
+        let quotas = Quotas {
+            max_series: Some(2),
+            limits: BTreeMap::from([(String::from("api"), 3)]),
+            period: Some(Duration::from_secs(60)),
+            ..Quotas::default()
+        };
+        let config = RedisConfig {
+            quotas,
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        flush(
+            &mut backend,
+            b"checkout.a:1|c\ncheckout.b:1|c|#id:1\ncheckout.b:1|c|#id:2\n\
+            checkout.a:1|c\ncheckout.c:1|g\napi.a:1|c\napi.b:1|c\napi.c:1|c",
+            0,
+        );
+        // The series admitted are forgotten once the period has passed.
+        flush(&mut backend, b"checkout.c:1|c", 60);
+        flush(&mut backend, b"checkout.c:1|c", 70);
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "INCRBY stats.counters.api.a 1",
+                "INCRBY stats.counters.api.b 1",
+                "INCRBY stats.counters.api.c 1",
+                "INCRBY stats.counters.checkout.a 2",
+                "INCRBY stats.counters.checkout.b;id=1 1",
+                "INCRBY stats.counters.quotas.dropped;namespace=checkout 2",
+                "INCRBY stats.counters.quotas.dropped;namespace=checkout 1",
+                "INCRBY stats.counters.checkout.c 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reroutes_series_past_quotas() {
+        let redis = FakeRedis::start();
+        let quotas = Quotas {
+            max_series: Some(1),
+            action: QuotaAction::Overflow,
+            ..Quotas::default()
+        };
+        let config = RedisConfig {
+            quotas,
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        flush(
+            &mut backend,
+            b"checkout.a:1|c\ncheckout.b:2|c|#id:1\ncheckout.c:3|c\n\
+            checkout.d:4|g",
+            0,
+        );
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "INCRBY stats.counters.checkout.a 1",
+                "INCRBY stats.counters.checkout.overflow 5",
+                "SET stats.gauges.checkout.overflow 4",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_limits_tenants_writes() {
+        let redis = FakeRedis::start();
+        let quotas = Quotas {
+            tenant_tag: Some(String::from("tenant")),
+            max_series: Some(2),
+            max_writes: Some(3),
+            write_limits: BTreeMap::from([(String::from("big"), 10)]),
+            ..Quotas::default()
+        };
+        let config = RedisConfig {
+            quotas,
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        let input = b"a:1|c|#tenant:noisy\na:1|c|#tenant:noisy\nb:1|c|#tenant:noisy\n\
+            c:1|c|#tenant:noisy\nb:1|c|#tenant:noisy\na:1|c|#tenant:big\n\
+            a:1|c|#tenant:big\na:1|c|#tenant:big\na:1|c|#tenant:big";
+        flush(&mut backend, input, 0);
+        // Writes are counted again after each flush.
+        flush(&mut backend, b"a:1|c|#tenant:noisy", 10);
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "INCRBY stats.counters.a;tenant=big 4",
+                "INCRBY stats.counters.a;tenant=noisy 2",
+                "INCRBY stats.counters.b;tenant=noisy 1",
+                "INCRBY stats.counters.quotas.dropped;namespace=noisy 1",
+                "INCRBY stats.counters.quotas.throttled;namespace=noisy 1",
+                "INCRBY stats.counters.a;tenant=noisy 1",
+            ]
+        );
+    }
+}
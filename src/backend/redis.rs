@@ -0,0 +1,1951 @@
+//! Stores metrics in Redis, with one key per series. Keys are named by
+//! `RedisConfig::keys`, which by default names them like etsy's statsd does in
+//! Graphite, followed by their tags (e.g.
+//! "stats.counters.page.views;env=production"). See `KeyScheme`.
+//!
+//! Each type of metric is stored with the Redis type that fits it best:
+//!
+//! * Counters and meters are numbers incremented with `INCRBY`, or with
+//!   `INCRBYFLOAT` once they've been scaled up by a sample rate or had a
+//!   fractional value. They're summed in memory and written on `flush`.
+//! * Gauges are set with `SET`, or incremented with `INCRBYFLOAT` when they're
+//!   signed deltas. Only the latest reading of each gauge is written on
+//!   `flush`, with any deltas that followed it already applied.
+//! * Sets get a key per flush window (see `RedisConfig::window`), which
+//!   their members are added to on `flush`. By default the key is a
+//!   HyperLogLog written with `PFADD`, whose unique count is estimated within
+//!   about 1% in at most 12 KB no matter how many members it has. Small sets
+//!   that need an exact count can be Redis sets instead (see `SetMode`).
+//! * Samples, histograms, and distributions get a sorted set per window too,
+//!   whose scores are their observations. On `flush`, a Lua script (see
+//!   timer_stats.lua) computes statistics over each window that was written to
+//!   and stores them in a hash next to it, so every server writing to the
+//!   window sees the same statistics. Read them with `timer_stats`. Windows'
+//!   keys are wrapped in braces (e.g. "{stats.timers.glork.1656581400}"), which
+//!   keeps their statistics in the same slot of a Redis Cluster. With
+//!   `RedisConfig::heatmap_buckets`, each flush also counts its observations
+//!   into buckets in another hash next to the window (its key followed by
+//!   ":buckets"), whose fields are the buckets' upper bounds and "+Inf" for
+//!   observations past the last one, so that how a timer's distribution
+//!   changes from window to window can be drawn as a heatmap (see
+//!   `RedisQuery::heatmap`).
+//! * Key/values are set with `SET`.
+//!
+//! With `RedisConfig::lateness`, sets and timers that carry a timestamp (e.g.
+//! "|T1656581400") are written to the window that their timestamp is in
+//! rather than to the window of the flush, as long as that window ended no
+//! more than `lateness` ago, so that points sent late still land in the right
+//! window and update its statistics. Points later than that are dropped and
+//! counted in the "late.dropped" counter. Counters and gauges don't have
+//! windows, so they're written like any other.
+//!
+//! Keys can be made to expire once they're no longer written to, with a TTL
+//! for each type (see `Ttls`) and pattern of names (see `Retention`), and the
+//! number of series in each namespace can be limited (see `Quotas`). A catalog
+//! of the metrics flushed can be kept in Redis too (see `Catalog`).
+
+use super::cache::CachingConnection;
+use super::catalog::{Catalog, Cataloged};
+use super::cluster::ClusterConnection;
+use super::connection::{
+    Client, Connection, CredentialsProvider, ReconnectingConnection, SharedCredentials,
+};
+use super::counters::AtomicCounters;
+use super::hash::{Hashing, SeriesState};
+use super::keys::{KeyScheme, StatsdScheme};
+use super::pool::ConnectionPool;
+use super::quota::{Quota, Quotas};
+use super::resp::{protocol_error, Command, Value};
+use super::retention::{self, Retention};
+use super::retry::{FlushBuffer, PipelineConfig, RetryConfig};
+use super::rollup::{self, Rollup, Rollups};
+use super::sentinel::SentinelConnection;
+use super::series::{AsSeries, SeriesKey, SeriesRef};
+use super::sharding::{Shard, ShardedConnection};
+use super::tls::TlsConfig;
+use super::{Backend, BackendError};
+use crate::parser::{
+    GaugeMode, Metric, MetricId, MetricRef, MetricType, MetricValue, Unit,
+};
+use std::borrow::Cow;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::str;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Computes statistics over a window of a timer.
+pub(super) const TIMER_STATS_SCRIPT: &str = include_str!("timer_stats.lua");
+
+// The counter of commands replayed from buffered flushes, and the gauge of
+// flushes still buffered, which report how catching up after an outage is
+// going.
+const REPLAYED_COUNTER: &str = "retry.replayed";
+const BUFFERED_GAUGE: &str = "retry.buffered";
+
+// The counter of set members and timer observations dropped for being later
+// than `RedisConfig::lateness`.
+const LATE_COUNTER: &str = "late.dropped";
+
+// The most series whose keys are cached. Once there are more, the cache is
+// emptied, so that series that are no longer recorded don't stay in it.
+const MAX_CACHED_KEYS: usize = 100_000;
+
+/// Configuration for `RedisBackend`.
+#[derive(Clone, Debug)]
+pub struct RedisConfig {
+    /// Names the keys that metrics are stored under. Defaults to
+    /// `StatsdScheme`.
+    pub keys: Arc<dyn KeyScheme>,
+
+    /// How sets are stored.
+    pub sets: SetMode,
+
+    /// The length of the windows that sets and timers are aggregated over.
+    /// Each window's key has the Unix time that it starts at in it (e.g.
+    /// "stats.sets.uniques.1656581400"). It's usually the same as the flush
+    /// interval.
+    pub window: Duration,
+
+    /// How long after a window has ended that sets and timers timestamped in
+    /// it are still written to it (see the module's documentation). `None`,
+    /// which is the default, ignores their timestamps, and writes them to the
+    /// window of the flush.
+    pub lateness: Option<Duration>,
+
+    /// The upper bounds of the buckets that timers' observations are counted
+    /// into for heatmaps, in ascending order. Each bucket counts the
+    /// observations above the previous bucket's bound and at most its own.
+    /// Bounds that aren't ascending, or that are NaN, are a
+    /// `BackendError::Config`. Empty, which is the default, doesn't count
+    /// them.
+    pub heatmap_buckets: Vec<f64>,
+
+    /// How long keys live after they're last written to, by type.
+    pub ttls: Ttls,
+
+    /// The coarser windows that sets' and timers' windows are rolled up into.
+    pub rollups: Rollups,
+
+    /// TTLs and rollups for the metrics whose names match a pattern, in place
+    /// of `ttls` and `rollups`. Empty by default.
+    pub retention: Vec<Retention>,
+
+    /// Whether each flush is sent as a `MULTI`/`EXEC` transaction, so that
+    /// Redis applies all of it or none of it, even if the backend crashes or
+    /// loses its connection partway through. Commands in a transaction can't
+    /// be split between nodes, so it needs a single server, and connecting to
+    /// a cluster or shards with it is a `BackendError::Config`.
+    pub atomic_flushes: bool,
+
+    /// A key that's set to the Unix time of each flush, in milliseconds, so
+    /// that readers can tell how recent the other keys are. It's written in
+    /// the same pipeline as the flush (or transaction, with `atomic_flushes`).
+    pub last_flush_key: Option<String>,
+
+    /// How flushes are retried while Redis is unreachable. Only
+    /// `RedisBackend` retries them (as does `JsonBackend`, with
+    /// `JsonConfig::retry`).
+    pub retry: RetryConfig,
+
+    /// Limits on how many series each namespace of metrics can have.
+    pub quotas: Quotas,
+
+    /// How flushes are split into pipelines, and how long each can take.
+    pub pipeline: PipelineConfig,
+
+    /// A catalog of the metrics flushed that's kept in Redis (see
+    /// `Catalog`). `None`, which is the default, doesn't keep one.
+    pub catalog: Option<Catalog>,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them, like a `SharedCredentials` of a closure that reads the
+    /// current password. They're asked for again whenever a connection is
+    /// reopened or the server says that it has to authenticate again, so
+    /// that passwords can be rotated. Pools and clients passed to
+    /// `with_pool` and `with_client` authenticate on their own.
+    pub credentials: Option<SharedCredentials>,
+
+    /// The hasher that series are looked up with as they're recorded, which
+    /// is `Hashing::Fast` by default. Servers that take metrics from
+    /// untrusted clients should use `Hashing::DosResistant`, so that names
+    /// can't be chosen to collide.
+    pub hashing: Hashing,
+}
+
+impl Default for RedisConfig {
+    fn default() -> RedisConfig {
+        RedisConfig {
+            keys: Arc::new(StatsdScheme::default()),
+            sets: SetMode::default(),
+            window: Duration::from_secs(10),
+            lateness: None,
+            heatmap_buckets: Vec::new(),
+            ttls: Ttls::default(),
+            rollups: Rollups::default(),
+            retention: Vec::new(),
+            atomic_flushes: false,
+            last_flush_key: None,
+            retry: RetryConfig::default(),
+            quotas: Quotas::default(),
+            pipeline: PipelineConfig::default(),
+            catalog: None,
+            credentials: None,
+            hashing: Hashing::default(),
+        }
+    }
+}
+
+/// How long each type of metric's keys live after they're last written to.
+/// Every write to a key with a TTL is followed by a `PEXPIRE` in the same
+/// pipeline, so keys that stop being written to (like past windows, or series
+/// whose tags have changed) are eventually deleted by Redis. `None` keeps keys
+/// forever.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ttls {
+    /// Counters and meters.
+    pub counters: Option<Duration>,
+
+    pub gauges: Option<Duration>,
+
+    /// The windows of sets.
+    pub sets: Option<Duration>,
+
+    /// The windows of samples, histograms, and distributions, along with their
+    /// statistics.
+    pub timers: Option<Duration>,
+
+    /// Key/values.
+    pub values: Option<Duration>,
+}
+
+/// How set metrics are stored in Redis.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SetMode {
+    /// Each window is a HyperLogLog (`PFADD` and `PFCOUNT`), which estimates
+    /// the number of unique members in constant memory.
+    #[default]
+    HyperLogLog,
+
+    /// Each window is a Redis set (`SADD` and `SCARD`), which counts unique
+    /// members exactly but stores every one of them. Only suitable for sets
+    /// with few members.
+    Exact,
+}
+
+/// RedisBackend writes metrics to Redis.
+pub struct RedisBackend {
+    connection: Box<dyn Client>,
+    aggregator: Aggregator,
+
+    // Flushes that couldn't be sent yet.
+    buffer: FlushBuffer,
+}
+
+// What's recorded to sets or timers, by key and window (see `Aggregator`).
+type Windowed<T> = BTreeMap<String, BTreeMap<Option<u64>, (MetricId, T)>>;
+
+// The key that a series is written to, and its TTLs.
+struct Cached {
+    key: String,
+    ttls: Ttls,
+}
+
+// Aggregator holds the metrics recorded since the last flush and turns them
+// into the commands that write them, which is all of the backend other than
+// the I/O. It's shared with the async backend.
+pub(super) struct Aggregator<S = SeriesState> {
+    config: RedisConfig,
+
+    // The totals of the counters and meters recorded since the last flush, by
+    // key, along with the first series recorded to the key.
+    counters: BTreeMap<String, (MetricId, Count)>,
+
+    // The latest state of the gauges recorded since the last flush, by key,
+    // along with the first series recorded to the key.
+    gauges: BTreeMap<String, (MetricId, Gauge)>,
+
+    // The members of the sets recorded since the last flush, by key (without
+    // the window), then by the start of the window that they were timestamped
+    // in (see `RedisConfig::lateness`), along with the first series recorded
+    // to the key.
+    sets: Windowed<BTreeSet<String>>,
+
+    // The observations of the samples, histograms, and distributions recorded
+    // since the last flush, by key and window like `sets`, along with the
+    // first series recorded to the key.
+    timers: Windowed<Vec<f64>>,
+
+    // Identifies this backend's observations in timers' sorted sets, whose
+    // members have to be unique. Each member is made of a random `node` and a
+    // sequence number.
+    node: u64,
+    sequence: u64,
+
+    // The windows that haven't been rolled up yet.
+    rollup: Rollup,
+
+    // The series admitted to each namespace's quota.
+    quota: Quota,
+
+    // The keys and TTLs of the series recorded, so that `RedisConfig::keys`
+    // renders each series' key once rather than every time it's recorded,
+    // and `RedisConfig::retention` is matched against its name once. They're
+    // looked up by the series borrowed from each metric (see `SeriesRef`).
+    keys: HashMap<SeriesKey, Cached, S>,
+
+    // The metrics recorded since the last flush, for the catalog.
+    catalog: Cataloged,
+
+    // Untagged counters that are added to without recording metrics.
+    atomic_counters: AtomicCounters<S>,
+}
+
+/// Statistics over a window of a sample, histogram, or distribution.
+/// Percentiles use the nearest-rank method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimerStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl RedisBackend {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379"). If
+    /// the connection is lost, it's reopened when commands are next sent.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<RedisBackend, BackendError> {
+        RedisBackend::with_config(addr, RedisConfig::default())
+    }
+
+    /// Like `connect`, but with a configuration other than the default.
+    pub fn with_config(
+        addr: impl ToSocketAddrs,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        let connection =
+            ReconnectingConnection::to(addr, config.credentials.clone())?.opened()?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Connects to the Redis server named by a URL like "redis://10.0.0.1:6379".
+    /// "rediss://" URLs connect over TLS, configured by `tls`, which needs
+    /// the `tls` feature.
+    pub fn connect_url(
+        url: &str,
+        tls: &TlsConfig,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        let (url, tls) = (String::from(url), tls.clone());
+        let connection =
+            ReconnectingConnection::lazy(move || Connection::open(&url, &tls))
+                .authenticated(config.credentials.clone())
+                .opened()?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Like `connect_url`, but connections authenticate with credentials from
+    /// `credentials` rather than `RedisConfig::credentials`.
+    pub fn connect_url_with_credentials(
+        url: &str,
+        tls: &TlsConfig,
+        credentials: impl CredentialsProvider + 'static,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        let (url, tls) = (String::from(url), tls.clone());
+        let connect = move || Connection::open(&url, &tls);
+        let connection = ReconnectingConnection::with_credentials(connect, credentials)?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Like `with_config`, but replies to `count_set` and `timer_stats` are
+    /// cached (up to `max_replies` of them), so that reading the same windows
+    /// again doesn't cost a round trip. Redis keeps the cache up to date by
+    /// telling the backend when the keys that it read change, which needs
+    /// Redis 6 or later (see [client-side caching][caching]).
+    ///
+    /// [caching]: https://redis.io/docs/manual/client-side-caching/
+    pub fn connect_with_cache(
+        addr: impl ToSocketAddrs,
+        max_replies: usize,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        let connection =
+            CachingConnection::connect(addr, max_replies, config.credentials.clone())?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Sends commands on connections from `pool`, which other backends and
+    /// threads can share.
+    pub fn with_pool(
+        pool: ConnectionPool,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        RedisBackend::new(Box::new(pool), config)
+    }
+
+    /// Sends commands with `client`, which can be any `Client`, like a
+    /// `MemoryClient` in tests.
+    pub fn with_client(
+        client: Box<dyn Client>,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        RedisBackend::new(client, config)
+    }
+
+    /// Connects to a Redis Cluster, whose topology is loaded from the first of
+    /// `seeds` (addresses like "10.0.0.1:6379") that answers. Each command is
+    /// sent to the node that serves its key, and redirections are followed as
+    /// slots move between nodes. Atomic flushes and rollups need a single
+    /// server, so they're a `BackendError::Config`.
+    pub fn connect_cluster(
+        seeds: &[&str],
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        check_single_server(&config, "a cluster")?;
+        let connection = ClusterConnection::connect(seeds, config.credentials.clone())?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Connects to standalone Redis servers that keys are sharded across by
+    /// consistent hashing, in proportion to the shards' weights. Flushes are
+    /// split into a pipeline per shard, so like with a cluster, atomic flushes
+    /// and rollups are a `BackendError::Config`.
+    pub fn connect_sharded(
+        shards: &[Shard],
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        check_single_server(&config, "sharding")?;
+        let connection = ShardedConnection::connect(shards, config.credentials.clone())?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    /// Connects to the master that Sentinel monitors as `master_name`, asking
+    /// the first of `sentinels` that answers where it is. When Sentinel fails
+    /// over to a new master, flushes are sent to it, and the ones that can't
+    /// be sent until it's promoted are buffered and retried (see
+    /// `RetryConfig`).
+    pub fn connect_sentinel(
+        sentinels: &[&str],
+        master_name: &str,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        let credentials = config.credentials.clone();
+        let connection =
+            SentinelConnection::connect(sentinels, master_name, credentials)?;
+        RedisBackend::new(Box::new(connection), config)
+    }
+
+    fn new(
+        mut connection: Box<dyn Client>,
+        config: RedisConfig,
+    ) -> Result<RedisBackend, BackendError> {
+        if let Some(timeout) = config.pipeline.timeout {
+            connection.set_timeout(Some(timeout))?;
+        }
+        Ok(RedisBackend {
+            connection,
+            buffer: FlushBuffer::new(config.retry.clone(), config.pipeline.clone()),
+            aggregator: Aggregator::new(config)?,
+        })
+    }
+
+    /// Returns the number of unique members of a set in the window that
+    /// contains `at`, which is an estimate unless sets are stored with
+    /// `SetMode::Exact`. Members recorded since the last flush aren't counted.
+    pub fn count_set(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<u64, BackendError> {
+        let reply = self.connection.query(&self.aggregator.count_set(id, at))?;
+        read_count(reply)
+    }
+
+    /// Returns the statistics of a sample, histogram, or distribution in the
+    /// window that contains `at`, as of the last flush to the window, or
+    /// `None` if none of its metrics were flushed to the window.
+    pub fn timer_stats(
+        &mut self,
+        id: &MetricId,
+        at: SystemTime,
+    ) -> Result<Option<TimerStats>, BackendError> {
+        let reply = self
+            .connection
+            .query(&self.aggregator.timer_stats(id, at))?;
+        read_timer_stats(reply)
+    }
+
+    /// Returns the number of flushes that are buffered because Redis was
+    /// unreachable, or that didn't fit in `PipelineConfig::time_budget`,
+    /// which are sent before the next one.
+    pub fn buffered_flushes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the backend's untagged counters that ingestion threads can add
+    /// to directly, without recording metrics, which are written on each
+    /// flush like any other counters (see `AtomicCounters`).
+    pub fn atomic_counters(&self) -> AtomicCounters {
+        self.aggregator.atomic_counters()
+    }
+
+    /// Sends flushes that are buffered because they didn't fit in
+    /// `PipelineConfig::time_budget`, or because Redis was unreachable and
+    /// the backoff since has passed, within the time budget. Returns whether
+    /// any are left that could be sent right away.
+    pub fn send_buffered(&mut self) -> Result<bool, BackendError> {
+        if !self.buffer.sendable(SystemTime::now()) {
+            return Ok(false);
+        }
+        self.buffer
+            .send(self.connection.as_mut(), Vec::new(), SystemTime::now())?;
+        Ok(self.buffer.sendable(SystemTime::now()))
+    }
+
+    // Returns the backend's connection, aggregator, and buffered flushes.
+    pub(super) fn into_parts(self) -> (Box<dyn Client>, Aggregator, FlushBuffer) {
+        (self.connection, self.aggregator, self.buffer)
+    }
+
+    fn record_all<M: Recorded>(&mut self, metrics: &[M]) -> Result<(), BackendError> {
+        let commands = self.aggregator.key_values(metrics);
+        self.aggregator.record(metrics);
+        if commands.is_empty() {
+            return Ok(());
+        }
+        self.buffer
+            .send_key_values(self.connection.as_mut(), commands, SystemTime::now())
+    }
+
+    // Like `flush`, but with the time to flush sets and timers to the window
+    // of.
+    pub(super) fn flush_at(&mut self, now: SystemTime) -> Result<(), BackendError> {
+        self.aggregator
+            .replayed(self.buffer.take_replayed(), self.buffer.len());
+        let commands = self.aggregator.flush(now);
+        self.buffer.send(self.connection.as_mut(), commands, now)
+    }
+}
+
+impl Backend for RedisBackend {
+    /// Adds metrics to their state in memory to be written on `flush`, except
+    /// for key/values, which are written right away in a single pipeline. If
+    /// Redis is unreachable, the key/values are buffered and sent once it's
+    /// back, like flushes are (see `RetryConfig`), and the error is
+    /// `BackendError::Unavailable`. The other metrics are recorded either way,
+    /// so batches shouldn't be recorded again after an error.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        self.record_all(metrics)
+    }
+
+    /// Records metrics as they are, without copying them into `Metric`s.
+    fn record_refs(&mut self, metrics: &[MetricRef]) -> Result<(), BackendError> {
+        self.record_all(metrics)
+    }
+
+    /// Writes the totals of counters and meters, the latest state of gauges,
+    /// the members of sets, and the observations of timers in a single
+    /// pipeline (unless `PipelineConfig::max_commands` splits it), which ends
+    /// by computing the timers' statistics. They're reset even if the
+    /// pipeline fails. If Redis couldn't be reached, the pipeline is buffered
+    /// and sent before the next flush once Redis is back (see `RetryConfig`),
+    /// but otherwise it isn't sent again, because some of its commands may
+    /// have been applied already and would count twice.
+    fn flush(&mut self) -> Result<(), BackendError> {
+        self.flush_at(SystemTime::now())
+    }
+
+    fn send_buffered(&mut self) -> Result<bool, BackendError> {
+        RedisBackend::send_buffered(self)
+    }
+
+    fn direct_counters(&self) -> Option<AtomicCounters> {
+        Some(self.atomic_counters())
+    }
+}
+
+impl Aggregator {
+    pub(super) fn new(config: RedisConfig) -> Result<Aggregator, BackendError> {
+        let hasher = SeriesState::new(config.hashing);
+        Aggregator::with_hasher(config, hasher)
+    }
+}
+
+impl<S: BuildHasher + Clone> Aggregator<S> {
+    // Returns an aggregator whose maps hash series with `hasher`, in place of
+    // the one chosen by `RedisConfig::hashing`.
+    pub(super) fn with_hasher(
+        config: RedisConfig,
+        hasher: S,
+    ) -> Result<Self, BackendError> {
+        rollup::check(&config)?;
+        check_buckets(&config.heatmap_buckets)?;
+        Ok(Aggregator {
+            config,
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            sets: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            node: random_node(),
+            sequence: 0,
+            rollup: Rollup::default(),
+            quota: Quota::default(),
+            keys: HashMap::with_hasher(hasher.clone()),
+            catalog: Cataloged::default(),
+            atomic_counters: AtomicCounters::with_hasher(hasher),
+        })
+    }
+
+    // Returns the commands that write the key/values among `metrics`, which
+    // are sent as they're recorded rather than on the next flush.
+    pub(super) fn key_values<'a, M: Recorded + 'a>(
+        &mut self,
+        metrics: impl IntoIterator<Item = &'a M>,
+    ) -> Vec<Command> {
+        let mut commands = Vec::new();
+        for metric in metrics
+            .into_iter()
+            .filter(|m| m.metric_type() == MetricType::KeyValue)
+        {
+            let series = metric.series();
+            admit(
+                &mut self.keys,
+                &mut self.quota,
+                &self.config,
+                &series,
+                |id, key, ttls| {
+                    self.catalog
+                        .record(&self.config, id, metric.unit().as_ref());
+                    commands.push(Command::new("SET").arg(key).arg(metric.value()));
+                    expire(&mut commands, key, ttls.values);
+                },
+            );
+        }
+        commands
+    }
+
+    // Adds metrics other than key/values to their state. Keys are only copied
+    // into it for the first metric of a series since the last flush.
+    pub(super) fn record<'a, M: Recorded + 'a>(
+        &mut self,
+        metrics: impl IntoIterator<Item = &'a M>,
+    ) {
+        for metric in metrics {
+            if metric.metric_type() == MetricType::KeyValue {
+                continue;
+            }
+            let window = self.timestamp_window(metric);
+            let series = metric.series();
+            admit(
+                &mut self.keys,
+                &mut self.quota,
+                &self.config,
+                &series,
+                |id, key, _| {
+                    self.catalog
+                        .record(&self.config, id, metric.unit().as_ref());
+                    match metric.metric_type() {
+                        MetricType::Counter | MetricType::Meter => {
+                            match self.counters.get_mut(key) {
+                                Some((_, count)) => *count = count.add(metric),
+                                None => {
+                                    let count = Count::Integer(0).add(metric);
+                                    self.counters
+                                        .insert(String::from(key), (id.clone(), count));
+                                }
+                            }
+                        }
+                        MetricType::Gauge => match self.gauges.get_mut(key) {
+                            Some((_, gauge)) => {
+                                let previous = mem::replace(gauge, Gauge::Add(0.0));
+                                *gauge = Gauge::apply(Some(previous), metric);
+                            }
+                            None => {
+                                let gauge = Gauge::apply(None, metric);
+                                self.gauges
+                                    .insert(String::from(key), (id.clone(), gauge));
+                            }
+                        },
+                        MetricType::Set => {
+                            let windows = match self.sets.get_mut(key) {
+                                Some(windows) => windows,
+                                None => self.sets.entry(String::from(key)).or_default(),
+                            };
+                            let (_, members) = windowed(windows, id, window);
+                            if !members.contains(metric.value()) {
+                                members.insert(String::from(metric.value()));
+                            }
+                        }
+                        MetricType::Sample
+                        | MetricType::Histogram
+                        | MetricType::Distribution => {
+                            let windows = match self.timers.get_mut(key) {
+                                Some(windows) => windows,
+                                None => self.timers.entry(String::from(key)).or_default(),
+                            };
+                            let (_, observations) = windowed(windows, id, window);
+                            observations.push(float_value(metric));
+                        }
+                        MetricType::KeyValue => {}
+                    }
+                },
+            );
+        }
+    }
+
+    // Records the progress of replaying buffered flushes (see
+    // `RetryConfig::max_replayed_commands`), if any were replayed: the
+    // number of commands replayed, and the number of flushes still buffered.
+    pub(super) fn replayed(&mut self, commands: usize, flushes: usize) {
+        if commands == 0 {
+            return;
+        }
+        let replayed = MetricId::new(REPLAYED_COUNTER, MetricType::Counter, vec![]);
+        let buffered = MetricId::new(BUFFERED_GAUGE, MetricType::Gauge, vec![]);
+        let key = self.config.keys.key(&replayed);
+        self.add_count(replayed, key, commands as i64);
+        let key = self.config.keys.key(&buffered);
+        self.gauges
+            .insert(key, (buffered, Gauge::Set(flushes.to_string())));
+    }
+
+    // Returns the start of the window that a metric's timestamp is in, if it
+    // has one and timestamps aren't ignored.
+    fn timestamp_window(&self, metric: &impl Recorded) -> Option<u64> {
+        self.config.lateness?;
+        let at = UNIX_EPOCH + Duration::from_secs(metric.timestamp()?.max(0) as u64);
+        Some(window_start(&self.config, at))
+    }
+
+    // Groups what's been recorded to sets or timers since the last flush by
+    // series and the start of the window that it's written to at `now`.
+    // Anything timestamped in a window that ended more than
+    // `RedisConfig::lateness` ago is dropped and counted, and anything
+    // timestamped in a window that hasn't started yet is written to the
+    // current one.
+    fn windows<T>(
+        &mut self,
+        now: SystemTime,
+        recorded: Windowed<T>,
+    ) -> BTreeMap<(String, u64), (MetricId, T)>
+    where
+        T: IntoIterator + Extend<T::Item>,
+    {
+        let current = window_start(&self.config, now);
+        let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let lateness = self.config.lateness.unwrap_or_default().as_secs();
+        let closed = |start: u64| start + self.config.window.as_secs().max(1) + lateness;
+        let mut windows: BTreeMap<_, (MetricId, T)> = BTreeMap::new();
+        let mut dropped = 0;
+        for (mut series, recorded) in recorded {
+            let last = recorded.len() - 1;
+            for (i, (window, (id, values))) in recorded.into_iter().enumerate() {
+                let start = window.map_or(current, |start| start.min(current));
+                if closed(start) < secs {
+                    dropped += values.into_iter().count();
+                    continue;
+                }
+                let series = if i == last {
+                    mem::take(&mut series)
+                } else {
+                    series.clone()
+                };
+                match windows.entry((series, start)) {
+                    Entry::Occupied(mut entry) => entry.get_mut().1.extend(values),
+                    Entry::Vacant(entry) => {
+                        entry.insert((id, values));
+                    }
+                }
+            }
+        }
+        if dropped > 0 {
+            let late = MetricId::new(LATE_COUNTER, MetricType::Counter, vec![]);
+            let key = self.config.keys.key(&late);
+            self.add_count(late, key, dropped as i64);
+        }
+        windows
+    }
+
+    // Adds `value` to the counter at `key`, outside of any metric.
+    fn add_count(&mut self, id: MetricId, key: String, value: i64) {
+        let (_, count) = self.counters.entry(key).or_insert((id, Count::Integer(0)));
+        *count = match *count {
+            Count::Integer(total) => Count::Integer(total.saturating_add(value)),
+            Count::Float(total) => Count::Float(total + value as f64),
+        };
+    }
+
+    // Returns the commands that write everything recorded since the last
+    // flush, with sets and timers written to the window that contains `now`,
+    // and resets it all. Without a `last_flush_key`, there are no commands if
+    // nothing was recorded.
+    pub(super) fn flush(&mut self, now: SystemTime) -> Vec<Command> {
+        for (name, total) in self.atomic_counters.take() {
+            let series = SeriesRef::new(&name, MetricType::Counter, &[]);
+            let admitted = admit(
+                &mut self.keys,
+                &mut self.quota,
+                &self.config,
+                &series,
+                |id, key, _| (id.clone(), String::from(key)),
+            );
+            if let Some((id, key)) = admitted {
+                self.add_count(id, key, total);
+            }
+        }
+        let sets = mem::take(&mut self.sets);
+        let sets = self.windows(now, sets);
+        let timers = mem::take(&mut self.timers);
+        let timers = self.windows(now, timers);
+        let ttls = |id: &MetricId| match self.keys.get(id as &dyn AsSeries) {
+            Some(cached) => &cached.ttls,
+            None => retention::ttls(&self.config, id.name()),
+        };
+        let mut commands = Vec::new();
+        for (key, (id, count)) in mem::take(&mut self.counters) {
+            commands.push(match count {
+                Count::Integer(i) => {
+                    Command::new("INCRBY").arg(&key).arg_owned(i.to_string())
+                }
+                Count::Float(f) => Command::new("INCRBYFLOAT")
+                    .arg(&key)
+                    .arg_owned(format_float(f)),
+            });
+            expire(&mut commands, &key, ttls(&id).counters);
+        }
+        for (key, (id, gauge)) in mem::take(&mut self.gauges) {
+            commands.push(match gauge {
+                Gauge::Set(value) => Command::new("SET").arg(&key).arg(value),
+                Gauge::Add(delta) => Command::new("INCRBYFLOAT")
+                    .arg(&key)
+                    .arg_owned(format_float(delta)),
+            });
+            expire(&mut commands, &key, ttls(&id).gauges);
+        }
+        let at = |start| UNIX_EPOCH + Duration::from_secs(start);
+        for ((series, start), (id, members)) in sets {
+            let name = match self.config.sets {
+                SetMode::HyperLogLog => "PFADD",
+                SetMode::Exact => "SADD",
+            };
+            let key = window_key(&self.config, &id, at(start));
+            let add = Command::new(name).arg(&key);
+            commands.push(members.into_iter().fold(add, Command::arg_owned));
+            expire(&mut commands, &key, ttls(&id).sets);
+            self.rollup.track(&self.config, &series, &id, start);
+        }
+        for ((series, start), (id, observations)) in timers {
+            self.rollup.track(&self.config, &series, &id, start);
+            let key = timer_key(&self.config, &id, at(start));
+            if !self.config.heatmap_buckets.is_empty() {
+                let buckets = buckets_key(&key);
+                for (bound, count) in
+                    bucket_counts(&self.config.heatmap_buckets, &observations)
+                {
+                    let command = Command::new("HINCRBY").arg(&buckets).arg_owned(bound);
+                    commands.push(command.arg_owned(count.to_string()));
+                }
+                expire(&mut commands, &buckets, ttls(&id).timers);
+            }
+            let mut add = Command::new("ZADD").arg(&key);
+            for observation in observations {
+                self.sequence += 1;
+                let member = format!("{:x}:{}", self.node, self.sequence);
+                add = add.arg_owned(format_float(observation)).arg_owned(member);
+            }
+            commands.push(add);
+            expire(&mut commands, &key, ttls(&id).timers);
+            let stats = stats_key(&key);
+            commands.push(
+                Command::new("EVAL")
+                    .arg(TIMER_STATS_SCRIPT)
+                    .arg_static("2")
+                    .arg(&key)
+                    .arg(&stats),
+            );
+            expire(&mut commands, &stats, ttls(&id).timers);
+        }
+
+        self.rollup.flush(&self.config, now, &mut commands);
+        self.quota.flush(&self.config, now, &mut commands);
+        self.catalog.flush(&self.config, now, &mut commands);
+        finish_flush(&self.config, now, &mut commands);
+        commands
+    }
+
+    pub(super) fn config(&self) -> &RedisConfig {
+        &self.config
+    }
+
+    pub(super) fn atomic_counters(&self) -> AtomicCounters<S> {
+        self.atomic_counters.clone()
+    }
+
+    pub(super) fn count_set(&self, id: &MetricId, at: SystemTime) -> Command {
+        count_set(&self.config, id, at)
+    }
+
+    pub(super) fn timer_stats(&self, id: &MetricId, at: SystemTime) -> Command {
+        timer_stats(&self.config, id, at)
+    }
+}
+
+// Returns the command that counts the members of a set's window, whose reply
+// is read by `read_count`.
+pub(super) fn count_set(config: &RedisConfig, id: &MetricId, at: SystemTime) -> Command {
+    let key = window_key(config, id, at);
+    match config.sets {
+        SetMode::HyperLogLog => Command::new("PFCOUNT").arg(key),
+        SetMode::Exact => Command::new("SCARD").arg(key),
+    }
+}
+
+// Returns the command that reads a timer window's statistics, whose reply is
+// read by `read_timer_stats`.
+pub(super) fn timer_stats(
+    config: &RedisConfig,
+    id: &MetricId,
+    at: SystemTime,
+) -> Command {
+    let key = stats_key(&timer_key(config, id, at));
+    Command::new("HGETALL").arg(key)
+}
+
+pub(super) fn read_count(reply: Value) -> Result<u64, BackendError> {
+    match reply {
+        Value::Integer(count) if count >= 0 => Ok(count as u64),
+        reply => Err(protocol_error(&format!("invalid count {:?}", reply))),
+    }
+}
+
+pub(super) fn read_timer_stats(reply: Value) -> Result<Option<TimerStats>, BackendError> {
+    let fields = match reply {
+        Value::Array(fields) => fields,
+        Value::Map(pairs) => pairs
+            .into_iter()
+            .flat_map(|(field, value)| [field, value])
+            .collect(),
+        reply => return Err(protocol_error(&format!("invalid hash {:?}", reply))),
+    };
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut stats = BTreeMap::new();
+    for pair in fields.chunks(2) {
+        match pair {
+            [Value::Bulk(field), Value::Bulk(value)] => {
+                let value = str::from_utf8(value)
+                    .ok()
+                    .and_then(|v| f64::from_str(v).ok());
+                stats.insert(field.as_slice(), value);
+            }
+            _ => return Err(protocol_error("invalid timer statistics")),
+        }
+    }
+    let stat = |field: &str| {
+        stats
+            .get(field.as_bytes())
+            .copied()
+            .flatten()
+            .ok_or_else(|| {
+                protocol_error(&format!("timer statistics are missing {:?}", field))
+            })
+    };
+    Ok(Some(TimerStats {
+        count: stat("count")? as u64,
+        min: stat("min")?,
+        max: stat("max")?,
+        mean: stat("mean")?,
+        p50: stat("p50")?,
+        p90: stat("p90")?,
+        p99: stat("p99")?,
+    }))
+}
+
+/// The total of a counter or meter. It stays an integer as long as everything
+/// added to it is one, so that it can be written with `INCRBY`, which unlike
+/// `INCRBYFLOAT` is exact for any 64-bit total.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Count {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Count {
+    pub(super) fn add(self, metric: &impl Recorded) -> Count {
+        let integer = match (metric.numeric_value(), metric.sample_rate()) {
+            (_, Some(rate)) if rate != 1.0 => None,
+            (Some(MetricValue::Integer(i)), _) => Some(i),
+            (Some(MetricValue::Unsigned(u)), _) => i64::try_from(u).ok(),
+            _ => None,
+        };
+        match (self, integer) {
+            (Count::Integer(total), Some(i)) if total.checked_add(i).is_some() => {
+                Count::Integer(total + i)
+            }
+            (Count::Integer(total), _) => {
+                Count::Float(total as f64 + scaled_value(metric))
+            }
+            (Count::Float(total), _) => Count::Float(total + scaled_value(metric)),
+        }
+    }
+}
+
+// The state of a gauge since the last flush. It's shared with `JsonBackend`.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Gauge {
+    /// The gauge was set to this value, which deltas since have been applied
+    /// to.
+    Set(String),
+
+    /// Only deltas were received, which add up to this. They're applied to
+    /// whatever the gauge's value in Redis is.
+    Add(f64),
+}
+
+impl Gauge {
+    pub(super) fn apply(gauge: Option<Gauge>, metric: &impl Recorded) -> Gauge {
+        match (gauge, metric.gauge_mode()) {
+            (Some(Gauge::Set(value)), Some(GaugeMode::Delta(_))) => {
+                let value = f64::from_str(&value).unwrap_or(0.0) + float_value(metric);
+                Gauge::Set(format_float(value))
+            }
+            (Some(Gauge::Add(delta)), Some(GaugeMode::Delta(_))) => {
+                Gauge::Add(delta + float_value(metric))
+            }
+            (None, Some(GaugeMode::Delta(_))) => Gauge::Add(float_value(metric)),
+            _ => Gauge::Set(String::from(metric.value())),
+        }
+    }
+}
+
+// Passes the series and key that a metric of `series` is recorded to, and
+// their TTLs, to `record`, unless it's dropped by its namespace's quota. The
+// series' key and TTLs are rendered the first time that it's recorded, and
+// then looked up by the series borrowed from each metric, so that recording
+// it again doesn't copy its name or tags.
+fn admit<S: BuildHasher, R>(
+    keys: &mut HashMap<SeriesKey, Cached, S>,
+    quota: &mut Quota,
+    config: &RedisConfig,
+    series: &SeriesRef,
+    record: impl FnOnce(&MetricId, &str, &Ttls) -> R,
+) -> Option<R> {
+    let (id, cached) = match keys.get_key_value(series as &dyn AsSeries) {
+        Some(hit) => hit,
+        None => {
+            if keys.len() >= MAX_CACHED_KEYS {
+                keys.clear();
+            }
+            let id = series.to_id();
+            let key = config.keys.key(&id);
+            let ttls = retention::ttls(config, id.name()).clone();
+            keys.insert(SeriesKey(id), Cached { key, ttls });
+            keys.get_key_value(series as &dyn AsSeries)
+                .expect("the series was just cached")
+        }
+    };
+    let (id, key) = quota.admit(config, &id.0, &cached.key)?;
+    let ttls = match &id {
+        Cow::Borrowed(_) => &cached.ttls,
+        Cow::Owned(overflow) => retention::ttls(config, overflow.name()),
+    };
+    Some(record(&id, &key, ttls))
+}
+
+// Returns the state of a set or timer in `window`, of a key's `windows`,
+// which starts with `id` if there isn't any yet.
+fn windowed<'a, T: Default>(
+    windows: &'a mut BTreeMap<Option<u64>, (MetricId, T)>,
+    id: &MetricId,
+    window: Option<u64>,
+) -> &'a mut (MetricId, T) {
+    windows
+        .entry(window)
+        .or_insert_with(|| (id.clone(), T::default()))
+}
+
+// Recorded is what backends read of a metric, which is either a `Metric` or a
+// `MetricRef` that borrows from the payload that it was parsed from, so that
+// metrics can be recorded without copying them out of the payload.
+pub(super) trait Recorded {
+    fn metric_type(&self) -> MetricType;
+    fn value(&self) -> &str;
+    fn unit(&self) -> Option<Unit>;
+    fn sample_rate(&self) -> Option<f64>;
+    fn gauge_mode(&self) -> Option<GaugeMode>;
+    fn series(&self) -> SeriesRef<'_>;
+    fn numeric_value(&self) -> Option<MetricValue>;
+    fn timestamp(&self) -> Option<i64>;
+}
+
+impl Recorded for Metric {
+    fn metric_type(&self) -> MetricType {
+        Metric::metric_type(self)
+    }
+
+    fn value(&self) -> &str {
+        Metric::value(self)
+    }
+
+    fn unit(&self) -> Option<Unit> {
+        Metric::unit(self).cloned()
+    }
+
+    fn sample_rate(&self) -> Option<f64> {
+        Metric::sample_rate(self)
+    }
+
+    fn gauge_mode(&self) -> Option<GaugeMode> {
+        Metric::gauge_mode(self)
+    }
+
+    fn series(&self) -> SeriesRef<'_> {
+        SeriesRef::new(
+            Metric::name(self),
+            Metric::metric_type(self),
+            Metric::tags(self),
+        )
+    }
+
+    fn numeric_value(&self) -> Option<MetricValue> {
+        Metric::numeric_value(self)
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        Metric::timestamp(self)
+    }
+}
+
+impl Recorded for MetricRef<'_> {
+    fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    fn value(&self) -> &str {
+        self.value
+    }
+
+    fn unit(&self) -> Option<Unit> {
+        self.unit.map(Unit::from)
+    }
+
+    fn sample_rate(&self) -> Option<f64> {
+        self.sample_rate
+    }
+
+    fn gauge_mode(&self) -> Option<GaugeMode> {
+        match (self.metric_type, self.sign) {
+            (MetricType::Gauge, Some(sign)) => Some(GaugeMode::Delta(sign)),
+            (MetricType::Gauge, None) => Some(GaugeMode::Absolute),
+            _ => None,
+        }
+    }
+
+    fn series(&self) -> SeriesRef<'_> {
+        SeriesRef::of_refs(&self.name, self.metric_type, &self.tags)
+    }
+
+    fn numeric_value(&self) -> Option<MetricValue> {
+        MetricRef::numeric_value(self)
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+}
+
+// Adds the time of the flush to `commands`, if there's a `last_flush_key`, and
+// makes them a transaction, with `atomic_flushes`.
+pub(super) fn finish_flush(
+    config: &RedisConfig,
+    now: SystemTime,
+    commands: &mut Vec<Command>,
+) {
+    if let Some(key) = &config.last_flush_key {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        commands.push(Command::new("SET").arg(key).arg_owned(millis.to_string()));
+    }
+    if config.atomic_flushes && !commands.is_empty() {
+        commands.insert(0, Command::new("MULTI"));
+        commands.push(Command::new("EXEC"));
+    }
+}
+
+// Checks that `config` doesn't need every key on a single server, since
+// `topology` spreads them across several.
+fn check_single_server(config: &RedisConfig, topology: &str) -> Result<(), BackendError> {
+    let feature = if config.atomic_flushes {
+        "atomic flushes"
+    } else if retention::all_rollups(config).any(|rollups| !rollups.levels.is_empty()) {
+        "rollups"
+    } else {
+        return Ok(());
+    };
+    let message = format!("{} need a single server, not {}", feature, topology);
+    Err(BackendError::Config { message })
+}
+
+// Adds a `PEXPIRE` of `key` to `commands` if it has a TTL.
+pub(super) fn expire(commands: &mut Vec<Command>, key: &str, ttl: Option<Duration>) {
+    if let Some(ttl) = ttl {
+        commands.push(
+            Command::new("PEXPIRE")
+                .arg(key)
+                .arg_owned(ttl.as_millis().to_string()),
+        );
+    }
+}
+
+// Returns the key of a set's or timer's window that contains `at`.
+fn window_key(config: &RedisConfig, id: &MetricId, at: SystemTime) -> String {
+    config.keys.window_key(id, window_start(config, at))
+}
+
+// Returns when the window that contains `at` starts, in seconds since the
+// Unix epoch.
+pub(super) fn window_start(config: &RedisConfig, at: SystemTime) -> u64 {
+    let window = config.window.as_secs().max(1);
+    let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    secs - secs % window
+}
+
+// Returns the key of a timer's window that contains `at`. It's a hash tag, so
+// that in a cluster the window's statistics are in the same slot as it, which
+// the script that computes them needs.
+pub(super) fn timer_key(config: &RedisConfig, id: &MetricId, at: SystemTime) -> String {
+    format!("{{{}}}", window_key(config, id, at))
+}
+
+// Returns the key of the hash that holds the statistics of a timer's window.
+pub(super) fn stats_key(window_key: &str) -> String {
+    format!("{}:stats", window_key)
+}
+
+// Returns the key of the hash that counts a timer's window's observations
+// into `RedisConfig::heatmap_buckets`.
+pub(super) fn buckets_key(window_key: &str) -> String {
+    format!("{}:buckets", window_key)
+}
+
+// Returns the fields of a timer's buckets' hash, in order.
+pub(super) fn bucket_fields(bounds: &[f64]) -> Vec<String> {
+    let bounds = bounds.iter().map(|&bound| format_float(bound));
+    bounds.chain([String::from("+Inf")]).collect()
+}
+
+// Checks that the bounds of heatmap buckets are numbers in ascending order,
+// which bucket_counts' binary search needs.
+fn check_buckets(bounds: &[f64]) -> Result<(), BackendError> {
+    if let Some(bound) = bounds.iter().find(|bound| bound.is_nan()) {
+        return Err(BackendError::Config {
+            message: format!("invalid heatmap bucket {}", bound),
+        });
+    }
+    match bounds.windows(2).find(|pair| pair[0] >= pair[1]) {
+        Some(pair) => Err(BackendError::Config {
+            message: format!("heatmap bucket {} isn't above {}", pair[1], pair[0]),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Counts observations into the buckets of `bounds`, returning the field and
+// count of each bucket that any are in.
+fn bucket_counts(bounds: &[f64], observations: &[f64]) -> Vec<(String, u64)> {
+    let mut counts = vec![0; bounds.len() + 1];
+    for &observation in observations {
+        counts[bounds.partition_point(|&bound| bound < observation)] += 1;
+    }
+    bucket_fields(bounds)
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+// Returns a random number to identify a backend, which is random enough for
+// several servers started at the same time to pick different ones.
+fn random_node() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    hasher.finish()
+}
+
+pub(super) fn type_prefix(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::Counter => "counters",
+        MetricType::Gauge => "gauges",
+        MetricType::Sample => "timers",
+        MetricType::Set => "sets",
+        MetricType::KeyValue => "values",
+        MetricType::Histogram => "histograms",
+        MetricType::Distribution => "distributions",
+        MetricType::Meter => "meters",
+    }
+}
+
+// Returns the value of a numeric metric, including its sign.
+pub(super) fn float_value(metric: &impl Recorded) -> f64 {
+    match metric.numeric_value() {
+        Some(MetricValue::Integer(i)) => i as f64,
+        Some(MetricValue::Unsigned(u)) => u as f64,
+        Some(MetricValue::Float(f)) => f,
+        None => 0.0,
+    }
+}
+
+// Returns the value of a sampled metric scaled up to estimate the value of
+// all the metrics that weren't sent.
+pub(super) fn scaled_value(metric: &impl Recorded) -> f64 {
+    match metric.sample_rate() {
+        Some(rate) if rate > 0.0 => float_value(metric) / rate,
+        _ => float_value(metric),
+    }
+}
+
+pub(super) fn format_float(value: f64) -> String {
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::keys::ColonScheme;
+    use crate::backend::resp::Value;
+    use crate::backend::testing::{default_reply, FakeRedis};
+    use crate::backend::{
+        ConnectionPool, Credentials, Fault, Faults, FaultyClient, MemoryClient,
+        PoolConfig, PubSubBackend, PubSubConfig, RedisQuery, StreamBackend, StreamConfig,
+    };
+    use crate::parser::{parse, parse_ref, ParserConfig};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    fn commands(input: &[u8]) -> Vec<Vec<String>> {
+        let redis = FakeRedis::start();
+        let mut backend = RedisBackend::connect(redis.addr()).unwrap();
+        let batch = parse(input, &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend.flush().unwrap();
+        redis.commands()
+    }
+
+    #[test]
+    fn it_writes_key_values_right_away() {
+        let commands = commands(b"config.version:1.2.3|kv|#env:production,canary");
+        assert_eq!(
+            commands,
+            vec![vec![
+                "SET",
+                "stats.values.config.version;canary;env=production",
+                "1.2.3"
+            ],]
+        );
+    }
+
+    #[test]
+    fn it_flushes_counters_in_a_pipeline() {
+        let redis = FakeRedis::start();
+        let mut backend = RedisBackend::connect(redis.addr()).unwrap();
+        let config = ParserConfig::default();
+        backend
+            .record(
+                &parse(b"gorets:1|c\ngorets:2|c\nglork:1|c|@0.5", &config)
+                    .unwrap()
+                    .metrics,
+            )
+            .unwrap();
+        backend
+            .record(
+                &parse(b"gorets:-4|c\nglork:0.5|c\nrate:3|m", &config)
+                    .unwrap()
+                    .metrics,
+            )
+            .unwrap();
+        assert!(redis.commands().is_empty());
+
+        backend.flush().unwrap();
+        backend.flush().unwrap();
+        assert_eq!(
+            redis.commands(),
+            vec![
+                vec!["INCRBYFLOAT", "stats.counters.glork", "2.5"],
+                vec!["INCRBY", "stats.counters.gorets", "-1"],
+                vec!["INCRBY", "stats.meters.rate", "3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_applies_gauge_deltas() {
+        let redis = FakeRedis::start();
+        let keys = ColonScheme {
+            prefix: String::from("stats:"),
+            tags_in_keys: false,
+        };
+        let config = RedisConfig {
+            keys: Arc::new(keys),
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        let batch = parse(
+            b"gaugor:-10|g\ngaugor:+4|g\nglork:333|g\nglork:+1.5|g\n\
+            glork:-2|g|#env:production\nlatest:1|g\nlatest:2|g",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        backend.record(&batch.metrics).unwrap();
+        assert!(redis.commands().is_empty());
+
+        backend.flush().unwrap();
+        assert_eq!(
+            redis.commands(),
+            vec![
+                vec!["INCRBYFLOAT", "stats:gauges:gaugor", "-6"],
+                vec!["SET", "stats:gauges:glork", "332.5"],
+                vec!["SET", "stats:gauges:latest", "2"],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_adds_set_members_per_window() {
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+        let id = MetricId::new("uniques", MetricType::Set, Vec::new());
+        for (sets, add, count) in [
+            (SetMode::HyperLogLog, "PFADD", "PFCOUNT"),
+            (SetMode::Exact, "SADD", "SCARD"),
+        ] {
+            let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+                "PFCOUNT" | "SCARD" => Value::Integer(2),
+                _ => Value::Integer(1),
+            });
+            let config = RedisConfig {
+                sets,
+                ..RedisConfig::default()
+            };
+            let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+            let input = b"uniques:765|s\nuniques:abc|s\nuniques:765|s";
+            let batch = parse(input, &ParserConfig::default()).unwrap();
+            backend.record(&batch.metrics).unwrap();
+            backend.flush_at(at).unwrap();
+            assert_eq!(backend.count_set(&id, at + Duration::from_secs(4)), Ok(2));
+
+            assert_eq!(
+                redis.commands(),
+                vec![
+                    vec![add, "stats.sets.uniques.1656581400", "765", "abc"],
+                    vec![count, "stats.sets.uniques.1656581400"],
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn it_expires_keys() {
+        let redis = FakeRedis::start();
+        let day = Duration::from_secs(86400);
+        let ttls = Ttls {
+            counters: Some(30 * day),
+            timers: Some(Duration::from_secs(7200)),
+            values: Some(day),
+            ..Ttls::default()
+        };
+        let config = RedisConfig {
+            ttls,
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        backend.aggregator.node = 0xab;
+        let input = b"gorets:1|c\ngaugor:333|g\nglork:320|ms\nconfig.version:1.2.3|kv";
+        let batch = parse(input, &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(1656581405))
+            .unwrap();
+
+        let commands: Vec<_> = redis
+            .commands()
+            .into_iter()
+            .filter(|command| command[0] != "EVAL")
+            .map(|command| command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "SET stats.values.config.version 1.2.3",
+                "PEXPIRE stats.values.config.version 86400000",
+                "INCRBY stats.counters.gorets 1",
+                "PEXPIRE stats.counters.gorets 2592000000",
+                "SET stats.gauges.gaugor 333",
+                "ZADD {stats.timers.glork.1656581400} 320 ab:1",
+                "PEXPIRE {stats.timers.glork.1656581400} 7200000",
+                "PEXPIRE {stats.timers.glork.1656581400}:stats 7200000",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_flushes_atomically() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "MULTI" => Value::Status(String::from("OK")),
+            "EXEC" => Value::Array(vec![
+                Value::Integer(1),
+                Value::Error(String::from("WRONGTYPE wrong kind")),
+                Value::Status(String::from("OK")),
+            ]),
+            _ => Value::Status(String::from("QUEUED")),
+        });
+        let config = RedisConfig {
+            atomic_flushes: true,
+            last_flush_key: Some(String::from("stats.last_flush")),
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        let batch = parse(
+            b"gorets:1|c
+gaugor:333|g",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        backend.record(&batch.metrics).unwrap();
+        // An error in the transaction is returned once it's been run.
+        assert_eq!(
+            backend.flush_at(UNIX_EPOCH + Duration::from_millis(1656581405123)),
+            Err(BackendError::Server {
+                message: String::from("WRONGTYPE wrong kind")
+            })
+        );
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        assert_eq!(
+            commands,
+            vec![
+                "MULTI",
+                "INCRBY stats.counters.gorets 1",
+                "SET stats.gauges.gaugor 333",
+                "SET stats.last_flush 1656581405123",
+                "EXEC",
+            ]
+        );
+
+        // A transaction can't span a cluster's nodes or shards.
+        let config = RedisConfig {
+            atomic_flushes: true,
+            ..RedisConfig::default()
+        };
+        let addr = redis.addr().to_string();
+        assert!(matches!(
+            RedisBackend::connect_cluster(&[addr.as_str()], config.clone()),
+            Err(BackendError::Config { .. })
+        ));
+        assert!(matches!(
+            RedisBackend::connect_sharded(&[Shard::new(&addr)], config),
+            Err(BackendError::Config { .. })
+        ));
+    }
+
+    #[test]
+    fn it_computes_timer_stats() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "HGETALL" if command[1] == "{stats.timers.glork.1656581400}:stats" => {
+                Value::Array(
+                    [
+                        "count", "2", "min", "240", "max", "320", "mean", "280", "p50",
+                        "240", "p90", "320", "p99", "320",
+                    ]
+                    .iter()
+                    .map(|s| Value::Bulk(s.as_bytes().to_vec()))
+                    .collect(),
+                )
+            }
+            "HGETALL" => Value::Array(Vec::new()),
+            _ => Value::Integer(1),
+        });
+        let mut backend = RedisBackend::connect(redis.addr()).unwrap();
+        backend.aggregator.node = 0xab;
+        let batch =
+            parse(b"glork:320:240|ms\nglork:1.5|h", &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+        backend.flush_at(at).unwrap();
+        let id = MetricId::new("glork", MetricType::Sample, Vec::new());
+        assert_eq!(
+            backend.timer_stats(&id, at),
+            Ok(Some(TimerStats {
+                count: 2,
+                min: 240.0,
+                max: 320.0,
+                mean: 280.0,
+                p50: 240.0,
+                p90: 320.0,
+                p99: 320.0,
+            }))
+        );
+        assert_eq!(
+            backend.timer_stats(&id, at + Duration::from_secs(10)),
+            Ok(None)
+        );
+
+        let script = String::from(TIMER_STATS_SCRIPT);
+        assert_eq!(
+            redis.commands()[..4],
+            vec![
+                vec!["ZADD", "{stats.histograms.glork.1656581400}", "1.5", "ab:1"],
+                vec![
+                    "EVAL",
+                    &script,
+                    "2",
+                    "{stats.histograms.glork.1656581400}",
+                    "{stats.histograms.glork.1656581400}:stats"
+                ],
+                vec![
+                    "ZADD",
+                    "{stats.timers.glork.1656581400}",
+                    "320",
+                    "ab:2",
+                    "240",
+                    "ab:3"
+                ],
+                vec![
+                    "EVAL",
+                    &script,
+                    "2",
+                    "{stats.timers.glork.1656581400}",
+                    "{stats.timers.glork.1656581400}:stats"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_returns_error_replies() {
+        let redis = FakeRedis::with_replies(|_| {
+            Value::Error(String::from("WRONGTYPE wrong kind"))
+        });
+        let mut backend = RedisBackend::connect(redis.addr()).unwrap();
+        let batch = parse(b"gorets:1|c", &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        assert_eq!(
+            backend.flush(),
+            Err(BackendError::Server {
+                message: String::from("WRONGTYPE wrong kind"),
+            })
+        );
+    }
+
+    #[test]
+    fn it_authenticates_with_configured_credentials() {
+        // Each server says `NOAUTH` until it's been sent the password.
+        let server = || {
+            let authenticated = AtomicBool::new(false);
+            FakeRedis::with_replies(move |command| match command[0].as_str() {
+                "AUTH" if command[1..] == ["metrics", "secret"] => {
+                    authenticated.store(true, Ordering::SeqCst);
+                    Value::Status(String::from("OK"))
+                }
+                _ if !authenticated.load(Ordering::SeqCst) => {
+                    Value::Error(String::from("NOAUTH Authentication required."))
+                }
+                "GET" => Value::Nil,
+                _ => default_reply(command),
+            })
+        };
+        let credentials = Some(SharedCredentials::new(Credentials {
+            username: Some(String::from("metrics")),
+            password: String::from("secret"),
+        }));
+        let config = RedisConfig {
+            credentials: credentials.clone(),
+            ..RedisConfig::default()
+        };
+        let batch = parse(b"gorets:1|c", &ParserConfig::default()).unwrap();
+        let id = &batch.metrics[0].id();
+        let first = |redis: &FakeRedis| redis.commands()[0].join(" ");
+
+        let redis = server();
+        let mut backend =
+            RedisBackend::with_config(redis.addr(), config.clone()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend.flush().unwrap();
+        assert_eq!(first(&redis), "AUTH metrics secret");
+
+        let redis = server();
+        let shards = [Shard::new(&redis.addr().to_string())];
+        let mut backend = RedisBackend::connect_sharded(&shards, config.clone()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend.flush().unwrap();
+        assert_eq!(first(&redis), "AUTH metrics secret");
+
+        let redis = server();
+        let pool_config = PoolConfig {
+            credentials: credentials.clone(),
+            ..PoolConfig::default()
+        };
+        let pool = ConnectionPool::new(redis.addr(), pool_config).unwrap();
+        assert_eq!(
+            RedisQuery::with_pool(pool, config.clone()).counter(id),
+            Ok(None)
+        );
+        assert_eq!(first(&redis), "AUTH metrics secret");
+
+        let redis = server();
+        assert_eq!(
+            RedisQuery::connect(redis.addr(), config)
+                .unwrap()
+                .counter(id),
+            Ok(None)
+        );
+        assert_eq!(first(&redis), "AUTH metrics secret");
+
+        let redis = server();
+        let config = StreamConfig {
+            credentials: credentials.clone(),
+            ..StreamConfig::default()
+        };
+        let mut stream = StreamBackend::connect(redis.addr(), config).unwrap();
+        stream.record(&batch.metrics).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(first(&redis), "AUTH metrics secret");
+
+        let redis = server();
+        let config = PubSubConfig {
+            credentials,
+            ..PubSubConfig::default()
+        };
+        let mut pubsub = PubSubBackend::connect(redis.addr(), config).unwrap();
+        pubsub.record(&batch.metrics).unwrap();
+        pubsub.flush().unwrap();
+        assert_eq!(first(&redis), "AUTH metrics secret");
+    }
+
+    #[test]
+    fn it_records_metrics_while_key_values_are_buffered() {
+        let memory = MemoryClient::new();
+        let faults = Faults::new();
+        let client = FaultyClient::new(memory.clone(), faults.clone());
+        let config = RedisConfig {
+            retry: RetryConfig {
+                min_backoff: Duration::ZERO,
+                ..RetryConfig::default()
+            },
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_client(Box::new(client), config).unwrap();
+        let parser = ParserConfig::default();
+        let batch = parse(b"gorets:1|c\nconfig.version:1.2.3|kv", &parser).unwrap();
+
+        faults.inject_until_cleared(Fault::Error(BackendError::Unavailable {
+            message: String::from("down"),
+        }));
+        let unavailable =
+            |result| matches!(result, Err(BackendError::Unavailable { .. }));
+        assert!(unavailable(backend.record(&batch.metrics)));
+        let batch = parse(b"gorets:2|c\nconfig.version:1.2.4|kv", &parser).unwrap();
+        assert!(unavailable(backend.record(&batch.metrics)));
+        // Key/values buffered during an outage are sent as a single flush.
+        assert_eq!(backend.buffered_flushes(), 1);
+
+        faults.clear();
+        backend.flush().unwrap();
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"3".to_vec()))
+        );
+        assert_eq!(
+            memory.get("stats.values.config.version"),
+            Some(Value::Bulk(b"1.2.4".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_renders_each_key_once() {
+        #[derive(Debug, Default)]
+        struct CountingScheme {
+            keys: AtomicUsize,
+        }
+
+        impl KeyScheme for CountingScheme {
+            fn key(&self, id: &MetricId) -> String {
+                self.keys.fetch_add(1, Ordering::SeqCst);
+                StatsdScheme::default().key(id)
+            }
+
+            fn window_key(&self, id: &MetricId, start: u64) -> String {
+                StatsdScheme::default().window_key(id, start)
+            }
+        }
+
+        let scheme = Arc::new(CountingScheme::default());
+        let config = RedisConfig {
+            keys: scheme.clone(),
+            ..RedisConfig::default()
+        };
+        let memory = MemoryClient::new();
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), config).unwrap();
+        let batch = parse(
+            b"gorets:1|c\ngorets:2|c\nglork:3|g",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        for _ in 0..3 {
+            backend.record(&batch.metrics).unwrap();
+            backend.flush().unwrap();
+        }
+        assert_eq!(scheme.keys.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"9".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_aggregates_with_either_hasher() {
+        for hashing in [Hashing::Fast, Hashing::DosResistant] {
+            let config = RedisConfig {
+                hashing,
+                ..RedisConfig::default()
+            };
+            let memory = MemoryClient::new();
+            let mut backend =
+                RedisBackend::with_client(Box::new(memory.clone()), config).unwrap();
+            backend.atomic_counters().add("glork", 2);
+            let batch =
+                parse(b"gorets:1|c\ngorets:2|c", &ParserConfig::default()).unwrap();
+            backend.record(&batch.metrics).unwrap();
+            backend.flush().unwrap();
+            assert_eq!(
+                memory.get("stats.counters.gorets"),
+                Some(Value::Bulk(b"3".to_vec()))
+            );
+            assert_eq!(
+                memory.get("stats.counters.glork"),
+                Some(Value::Bulk(b"2".to_vec()))
+            );
+        }
+    }
+
+    #[test]
+    fn it_records_borrowed_metrics() {
+        let input =
+            b"gorets:1|c|@0.5\ngaugor:5|g\ngaugor:-2|g\nglork:320|ms\nuniques:a|s\n\
+            config.version:1.2.3|kv";
+        let parser = ParserConfig::default();
+        let owned = MemoryClient::new();
+        let borrowed = MemoryClient::new();
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+
+        let mut backend =
+            RedisBackend::with_client(Box::new(owned.clone()), RedisConfig::default())
+                .unwrap();
+        backend
+            .record(&parse(input, &parser).unwrap().metrics)
+            .unwrap();
+        backend.flush_at(at).unwrap();
+        let mut backend =
+            RedisBackend::with_client(Box::new(borrowed.clone()), RedisConfig::default())
+                .unwrap();
+        backend
+            .record_refs(&parse_ref(input, &parser).unwrap().metrics)
+            .unwrap();
+        backend.flush_at(at).unwrap();
+
+        // Timers' members are unique to each backend, so only their number is
+        // compared.
+        let sent = |memory: &MemoryClient| {
+            let commands = memory.sent().into_iter().flatten();
+            commands
+                .map(|command| match &command.args()[0][..] {
+                    b"ZADD" => vec![
+                        b"ZADD".to_vec(),
+                        command.args().len().to_string().into_bytes(),
+                    ],
+                    _ => command.args().iter().map(|arg| arg.to_vec()).collect(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sent(&owned), sent(&borrowed));
+        assert_eq!(
+            borrowed.get("stats.gauges.gaugor"),
+            Some(Value::Bulk(b"3".to_vec()))
+        );
+        assert_eq!(
+            borrowed.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"2".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_writes_late_metrics_to_their_windows() {
+        let memory = MemoryClient::new();
+        let config = RedisConfig {
+            lateness: Some(Duration::from_secs(5)),
+            sets: SetMode::Exact,
+            ..RedisConfig::default()
+        };
+        let mut backend =
+            RedisBackend::with_client(Box::new(memory.clone()), config).unwrap();
+        let input = b"glork:320|ms|T12\nglork:100|ms|T3\nglork:200|ms\nglork:50|ms|T99\n\
+            uniques:a|s|T14\nuniques:b|s|T4\nuniques:c|s\ngorets:1|c|T3";
+        backend
+            .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+            .unwrap();
+        // The window from 10 to 20 is still open, but not the one from 0 to 10.
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(25))
+            .unwrap();
+
+        let bulk = |value: &str| Value::Bulk(value.as_bytes().to_vec());
+        let scores = |key: &str| match memory.get(key) {
+            Some(Value::Array(ranked)) => ranked.into_iter().skip(1).step_by(2).collect(),
+            _ => Vec::new(),
+        };
+        assert_eq!(scores("{stats.timers.glork.10}"), vec![bulk("320")]);
+        // Points from the future are written to the current window.
+        assert_eq!(
+            scores("{stats.timers.glork.20}"),
+            vec![bulk("50"), bulk("200")]
+        );
+        assert_eq!(
+            memory.get("stats.sets.uniques.10"),
+            Some(Value::Array(vec![bulk("a")]))
+        );
+        assert_eq!(
+            memory.get("stats.sets.uniques.20"),
+            Some(Value::Array(vec![bulk("c")]))
+        );
+        assert_eq!(
+            memory
+                .keys()
+                .iter()
+                .filter(|key| key.ends_with(".0}"))
+                .count(),
+            0
+        );
+        assert_eq!(memory.get("stats.counters.gorets"), Some(bulk("1")));
+        assert_eq!(memory.get("stats.counters.late.dropped"), Some(bulk("2")));
+    }
+}
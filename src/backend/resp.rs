@@ -0,0 +1,572 @@
+//! Encodes commands and decodes replies in RESP, the Redis serialization
+//! protocol. Replies can be RESP2 or RESP3, which a connection switches to
+//! with `HELLO 3`. See [the protocol spec][resp].
+//!
+//! [resp]: https://redis.io/docs/reference/protocol-spec/
+
+use super::BackendError;
+use std::borrow::Cow;
+use std::io::BufRead;
+use std::str;
+use std::str::FromStr;
+
+/// The longest bulk string or array that will be read from a server, which is
+/// Redis's own limit on bulk strings.
+const MAX_LEN: usize = 512 * 1024 * 1024;
+
+/// How big the buffers that connections encode commands into start out,
+/// which is enough for a typical flush's pipeline. Connections keep their
+/// buffers between flushes, so they only grow for a flush bigger than any
+/// before it.
+pub(super) const ENCODE_CAPACITY: usize = 64 * 1024;
+
+/// A reply from a Redis server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A null bulk string or null array, or a RESP3 null.
+    Nil,
+
+    /// A simple string like "OK".
+    Status(String),
+
+    /// An error reply like "ERR unknown command".
+    Error(String),
+
+    Integer(i64),
+
+    /// A bulk string, or a RESP3 big number (as its digits) or verbatim
+    /// string (without its format).
+    Bulk(Vec<u8>),
+
+    /// An array, or a RESP3 set.
+    Array(Vec<Value>),
+
+    /// A RESP3 double.
+    Double(f64),
+
+    /// A RESP3 boolean.
+    Boolean(bool),
+
+    /// A RESP3 map, as its key/value pairs in the order they were sent.
+    Map(Vec<(Value, Value)>),
+
+    /// A RESP3 push message, which the server can send at any time rather
+    /// than in reply to a command, like a client-side caching invalidation.
+    Push(Vec<Value>),
+}
+
+/// A command to send to a Redis server, which is an array of arguments
+/// starting with the command's name.
+///
+/// The name and other arguments that are known when the crate's compiled
+/// (e.g. "NX") are borrowed, and arguments built for the command (e.g. a
+/// formatted value) are moved into it, so that only arguments borrowed from
+/// something that outlives the command are copied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    args: Vec<Cow<'static, [u8]>>,
+}
+
+impl Command {
+    pub fn new(name: &'static str) -> Command {
+        Command {
+            args: vec![Cow::Borrowed(name.as_bytes())],
+        }
+    }
+
+    /// Adds a copy of `arg`.
+    pub fn arg(mut self, arg: impl AsRef<[u8]>) -> Command {
+        self.args.push(Cow::Owned(arg.as_ref().to_vec()));
+        self
+    }
+
+    /// Adds an argument that's known when the crate's compiled, without
+    /// copying it.
+    pub fn arg_static(mut self, arg: &'static str) -> Command {
+        self.args.push(Cow::Borrowed(arg.as_bytes()));
+        self
+    }
+
+    /// Adds an argument that was built for the command, without copying it.
+    pub fn arg_owned(mut self, arg: impl Into<Vec<u8>>) -> Command {
+        self.args.push(Cow::Owned(arg.into()));
+        self
+    }
+
+    /// Returns the command's arguments, starting with its name.
+    pub fn args(&self) -> &[Cow<'static, [u8]>] {
+        &self.args
+    }
+
+    /// Returns how many bytes `write` appends.
+    pub fn encoded_len(&self) -> usize {
+        let header = |len: usize| 1 + digits(len) + 2;
+        let args: usize = self
+            .args
+            .iter()
+            .map(|arg| header(arg.len()) + arg.len() + 2)
+            .sum();
+        header(self.args.len()) + args
+    }
+
+    /// Returns the key that the command is about, which is what decides where
+    /// it's sent in a cluster. `None` for commands without keys, like
+    /// `PUBLISH`.
+    pub fn key(&self) -> Option<&[u8]> {
+        let arg = |i: usize| self.args.get(i).map(|arg| &arg[..]);
+        match self.args[0].to_ascii_uppercase().as_slice() {
+            b"PUBLISH" | b"ASKING" | b"CLUSTER" | b"SCRIPT" => None,
+            b"EVAL" | b"EVALSHA" => match arg(2) {
+                Some(b"0") | None => None,
+                Some(_) => arg(3),
+            },
+            b"XGROUP" => arg(2),
+            b"XREADGROUP" => {
+                let streams = self
+                    .args
+                    .iter()
+                    .position(|a| a.eq_ignore_ascii_case(b"STREAMS"))?;
+                arg(streams + 1)
+            }
+            _ => arg(1),
+        }
+    }
+
+    /// Appends the command to `out` as a RESP array of bulk strings.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.reserve(self.encoded_len());
+        write_header(out, b'*', self.args.len());
+        for arg in &self.args {
+            write_header(out, b'$', arg.len());
+            out.extend_from_slice(arg);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+
+    // Splits a command that adds members to a key (`PFADD`, `SADD`, or
+    // `ZADD`, whose members come with their scores) into commands that each
+    // add up to `max` of them. Other commands are returned whole.
+    pub(super) fn split_members(mut self, max: usize) -> Vec<Command> {
+        let stride = match self.args[0].to_ascii_uppercase().as_slice() {
+            b"PFADD" | b"SADD" => 1,
+            // Without options like "NX", which come before the first score.
+            b"ZADD" if self.args.get(2).is_some_and(|arg| is_score(arg)) => 2,
+            _ => return vec![self],
+        };
+        let args = self.args.len().saturating_sub(2);
+        let (members, max) = (args / stride, max.max(1));
+        if members <= max || !args.is_multiple_of(stride) {
+            return vec![self];
+        }
+        let mut commands = Vec::with_capacity(members.div_ceil(max));
+        let mut args = self.args.split_off(2).into_iter();
+        while args.len() > 0 {
+            let mut command = self.clone();
+            command.args.extend(args.by_ref().take(max * stride));
+            commands.push(command);
+        }
+        commands
+    }
+
+    /// Reads a command written by `write`.
+    pub fn read(reader: &mut impl BufRead) -> Result<Command, BackendError> {
+        let args = match read_value(reader)? {
+            Value::Array(args) if !args.is_empty() => args,
+            value => return Err(protocol_error(&format!("invalid command {:?}", value))),
+        };
+        let args = args.into_iter().map(|arg| match arg {
+            Value::Bulk(arg) => Ok(Cow::Owned(arg)),
+            arg => Err(protocol_error(&format!(
+                "invalid command argument {:?}",
+                arg
+            ))),
+        });
+        Ok(Command {
+            args: args.collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+fn is_score(arg: &[u8]) -> bool {
+    std::str::from_utf8(arg).is_ok_and(|arg| arg.parse::<f64>().is_ok())
+}
+
+/// Replaces what's in `out` with `commands`, encoded.
+pub(super) fn encode(commands: &[Command], out: &mut Vec<u8>) {
+    out.clear();
+    out.reserve(commands.iter().map(Command::encoded_len).sum());
+    for command in commands {
+        command.write(out);
+    }
+}
+
+// Appends the header of an array or bulk string of `len` (e.g. "$5\r\n"),
+// which is written for every argument of every command, so it's formatted
+// by hand rather than with `format!`.
+fn write_header(out: &mut Vec<u8>, kind: u8, len: usize) {
+    let mut buf = [0; 20];
+    let mut i = buf.len();
+    let mut rest = len;
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    out.push(kind);
+    out.extend_from_slice(&buf[i..]);
+    out.extend_from_slice(b"\r\n");
+}
+
+// Returns the number of decimal digits in `n`.
+fn digits(n: usize) -> usize {
+    n.checked_ilog10().map_or(1, |log| log as usize + 1)
+}
+
+/// Reads a single reply from `reader`. If the reader ends before the reply
+/// does, the error is a `BackendError::Io` of kind `UnexpectedEof`. RESP3
+/// attributes are skipped.
+pub fn read_value(reader: &mut impl BufRead) -> Result<Value, BackendError> {
+    let line = read_line(reader)?;
+    let (kind, rest) = match line.split_first() {
+        Some((&kind, rest)) => (kind, rest),
+        None => return Err(protocol_error("empty reply")),
+    };
+    let rest = str::from_utf8(rest).map_err(|_| protocol_error("reply isn't UTF-8"))?;
+
+    match kind {
+        b'+' => Ok(Value::Status(String::from(rest))),
+        b'-' => Ok(Value::Error(String::from(rest))),
+        b':' => i64::from_str(rest)
+            .map(Value::Integer)
+            .map_err(|_| protocol_error(&format!("invalid integer {:?}", rest))),
+        b'$' => match read_len(rest)? {
+            None => Ok(Value::Nil),
+            Some(len) => read_blob(reader, len).map(Value::Bulk),
+        },
+        b'*' | b'~' => match read_len(rest)? {
+            None => Ok(Value::Nil),
+            Some(len) => read_values(reader, len).map(Value::Array),
+        },
+        b'_' => Ok(Value::Nil),
+        b',' => match rest {
+            "inf" => Ok(Value::Double(f64::INFINITY)),
+            "-inf" => Ok(Value::Double(f64::NEG_INFINITY)),
+            _ => f64::from_str(rest)
+                .map(Value::Double)
+                .map_err(|_| protocol_error(&format!("invalid double {:?}", rest))),
+        },
+        b'#' => match rest {
+            "t" => Ok(Value::Boolean(true)),
+            "f" => Ok(Value::Boolean(false)),
+            _ => Err(protocol_error(&format!("invalid boolean {:?}", rest))),
+        },
+        b'(' => match rest.strip_prefix('-').unwrap_or(rest) {
+            digits
+                if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                Ok(Value::Bulk(rest.as_bytes().to_vec()))
+            }
+            _ => Err(protocol_error(&format!("invalid big number {:?}", rest))),
+        },
+        b'!' => {
+            let len = read_len(rest)?.ok_or_else(|| protocol_error("null blob error"))?;
+            let message = read_blob(reader, len)?;
+            String::from_utf8(message)
+                .map(Value::Error)
+                .map_err(|_| protocol_error("reply isn't UTF-8"))
+        }
+        b'=' => {
+            let len =
+                read_len(rest)?.ok_or_else(|| protocol_error("null verbatim string"))?;
+            match read_blob(reader, len)? {
+                text if text.get(3) == Some(&b':') => Ok(Value::Bulk(text[4..].to_vec())),
+                _ => Err(protocol_error("verbatim string has no format")),
+            }
+        }
+        b'%' => {
+            let len = read_len(rest)?.ok_or_else(|| protocol_error("null map"))?;
+            let mut pairs = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                pairs.push((read_value(reader)?, read_value(reader)?));
+            }
+            Ok(Value::Map(pairs))
+        }
+        b'>' => {
+            let len = read_len(rest)?.ok_or_else(|| protocol_error("null push"))?;
+            read_values(reader, len).map(Value::Push)
+        }
+        b'|' => {
+            let len = read_len(rest)?.ok_or_else(|| protocol_error("null attributes"))?;
+            read_values(reader, len * 2)?;
+            read_value(reader)
+        }
+        _ => Err(protocol_error(&format!(
+            "unknown reply type {:?}",
+            kind as char
+        ))),
+    }
+}
+
+// Reads the contents of a bulk string or blob error of `len` bytes.
+fn read_blob(reader: &mut impl BufRead, len: usize) -> Result<Vec<u8>, BackendError> {
+    let mut blob = vec![0; len + 2];
+    reader.read_exact(&mut blob)?;
+    if !blob.ends_with(b"\r\n") {
+        return Err(protocol_error("bulk string isn't terminated"));
+    }
+    blob.truncate(len);
+    Ok(blob)
+}
+
+// Reads the elements of an aggregate like an array.
+fn read_values(
+    reader: &mut impl BufRead,
+    len: usize,
+) -> Result<Vec<Value>, BackendError> {
+    (0..len).map(|_| read_value(reader)).collect()
+}
+
+// Reads a line terminated by "\r\n", without the terminator.
+fn read_line(reader: &mut impl BufRead) -> Result<Vec<u8>, BackendError> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\n") {
+        return Err(BackendError::Io {
+            kind: std::io::ErrorKind::UnexpectedEof,
+            message: String::from("connection closed by server"),
+        });
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_error("reply isn't terminated"));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+// Parses the length of a bulk string or array, where -1 means null.
+pub(super) fn read_len(len: &str) -> Result<Option<usize>, BackendError> {
+    if len == "-1" {
+        return Ok(None);
+    }
+    match usize::from_str(len) {
+        Ok(len) if len <= MAX_LEN => Ok(Some(len)),
+        _ => Err(protocol_error(&format!("invalid length {:?}", len))),
+    }
+}
+
+pub fn protocol_error(message: &str) -> BackendError {
+    BackendError::Protocol {
+        message: String::from(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_writes_commands() {
+        let mut out = Vec::new();
+        let command = Command::new("SET").arg("gaugor").arg("333");
+        command.write(&mut out);
+        assert_eq!(out, b"*3\r\n$3\r\nSET\r\n$6\r\ngaugor\r\n$3\r\n333\r\n");
+        assert_eq!(Command::read(&mut out.as_slice()), Ok(command));
+        assert!(Command::read(&mut &b"*1\r\n:1\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn it_encodes_commands_into_a_buffer() {
+        let value = "x".repeat(1234);
+        let commands = [
+            Command::new("SET").arg("gaugor").arg_owned(value.clone()),
+            Command::new("PEXPIRE").arg("gaugor").arg_static("60000"),
+        ];
+        assert!(matches!(commands[1].args()[2], Cow::Borrowed(_)));
+        let mut out = Vec::from(&b"stale"[..]);
+        encode(&commands, &mut out);
+        let expected = format!(
+            "*3\r\n$3\r\nSET\r\n$6\r\ngaugor\r\n$1234\r\n{}\r\n\
+             *3\r\n$7\r\nPEXPIRE\r\n$6\r\ngaugor\r\n$5\r\n60000\r\n",
+            value
+        );
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), expected);
+        let len: usize = commands.iter().map(Command::encoded_len).sum();
+        assert_eq!(len, out.len());
+        assert_eq!(
+            Command::new("PING").encoded_len(),
+            b"*1\r\n$4\r\nPING\r\n".len()
+        );
+    }
+
+    #[test]
+    fn it_splits_members() {
+        let render = |commands: Vec<Command>| -> Vec<Vec<String>> {
+            let args = |command: &Command| {
+                command
+                    .args()
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect()
+            };
+            commands.iter().map(args).collect()
+        };
+        let sadd = Command::new("SADD")
+            .arg("uniques")
+            .arg("a")
+            .arg("b")
+            .arg("c");
+        assert_eq!(
+            render(sadd.split_members(2)),
+            vec![
+                vec!["SADD", "uniques", "a", "b"],
+                vec!["SADD", "uniques", "c"],
+            ]
+        );
+        let zadd = Command::new("ZADD")
+            .arg("glork")
+            .arg("1")
+            .arg("a")
+            .arg("2")
+            .arg("b");
+        assert_eq!(
+            render(zadd.split_members(1)),
+            vec![
+                vec!["ZADD", "glork", "1", "a"],
+                vec!["ZADD", "glork", "2", "b"],
+            ]
+        );
+
+        for command in [
+            Command::new("SADD").arg("uniques").arg("a").arg("b"),
+            Command::new("ZADD")
+                .arg("glork")
+                .arg("NX")
+                .arg("1")
+                .arg("a")
+                .arg("2")
+                .arg("b"),
+            Command::new("RPUSH").arg("list").arg("a").arg("b").arg("c"),
+        ] {
+            assert_eq!(command.clone().split_members(2), vec![command]);
+        }
+    }
+
+    #[test]
+    fn it_finds_keys() {
+        for (command, key) in [
+            (
+                Command::new("SET").arg("gaugor").arg("333"),
+                Some(&b"gaugor"[..]),
+            ),
+            (
+                Command::new("EVAL")
+                    .arg("return 1")
+                    .arg("2")
+                    .arg("a")
+                    .arg("b"),
+                Some(b"a"),
+            ),
+            (Command::new("EVAL").arg("return 1").arg("0"), None),
+            (
+                Command::new("XREADGROUP")
+                    .arg("GROUP")
+                    .arg("g")
+                    .arg("c")
+                    .arg("STREAMS")
+                    .arg("s")
+                    .arg(">"),
+                Some(b"s"),
+            ),
+            (
+                Command::new("PUBLISH").arg("metrics").arg("gorets:1|c"),
+                None,
+            ),
+        ] {
+            assert_eq!(command.key(), key, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn it_reads_values() {
+        let mut input =
+            &b"+OK\r\n-ERR wrong\r\n:-5\r\n$5\r\nab\r\nc\r\n$-1\r\n*2\r\n:1\r\n\
+                *-1\r\n"[..];
+        let values: Vec<_> = (0..6).map(|_| read_value(&mut input).unwrap()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Value::Status(String::from("OK")),
+                Value::Error(String::from("ERR wrong")),
+                Value::Integer(-5),
+                Value::Bulk(b"ab\r\nc".to_vec()),
+                Value::Nil,
+                Value::Array(vec![Value::Integer(1), Value::Nil]),
+            ]
+        );
+        assert!(matches!(
+            read_value(&mut input),
+            Err(BackendError::Io {
+                kind: std::io::ErrorKind::UnexpectedEof,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn it_reads_resp3_values() {
+        let mut input =
+            &b"_\r\n,1.5\r\n,-inf\r\n#t\r\n(-12345678901234567890\r\n!3\r\nERR\r\n\
+            =8\r\ntxt:Some\r\n~1\r\n:1\r\n%1\r\n$5\r\ncount\r\n:2\r\n\
+            >2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n|1\r\n+ttl\r\n:3\r\n\
+            +OK\r\n"[..];
+        let values: Vec<_> = (0..11).map(|_| read_value(&mut input).unwrap()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Value::Nil,
+                Value::Double(1.5),
+                Value::Double(f64::NEG_INFINITY),
+                Value::Boolean(true),
+                Value::Bulk(b"-12345678901234567890".to_vec()),
+                Value::Error(String::from("ERR")),
+                Value::Bulk(b"Some".to_vec()),
+                Value::Array(vec![Value::Integer(1)]),
+                Value::Map(vec![(Value::Bulk(b"count".to_vec()), Value::Integer(2))]),
+                Value::Push(vec![
+                    Value::Bulk(b"invalidate".to_vec()),
+                    Value::Array(vec![Value::Bulk(b"key".to_vec())])
+                ]),
+                Value::Status(String::from("OK")),
+            ]
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_invalid_values() {
+        for input in [
+            &b"OK\r\n"[..],
+            b"+OK\n",
+            b":x\r\n",
+            b"$3\r\nabcd\r\n",
+            b"$-2\r\n",
+            b"#x\r\n",
+            b",one\r\n",
+            b"(1.5\r\n",
+            b"=3\r\nabc\r\n",
+            b"%-1\r\n",
+        ] {
+            assert!(
+                matches!(
+                    read_value(&mut &input[..]),
+                    Err(BackendError::Protocol { .. })
+                ),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+}
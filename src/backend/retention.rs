@@ -0,0 +1,164 @@
+//! Gives the metrics whose names match a pattern their own TTLs and rollups,
+//! in place of `RedisConfig::ttls` and `RedisConfig::rollups`, so that some
+//! metrics can be kept longer or at a coarser resolution than others (e.g.
+//! "sla.*" rolled up into hours that are kept for 90 days, while "debug.*" is
+//! kept for a day).
+
+use super::redis::Ttls;
+use super::rollup::Rollups;
+use super::RedisConfig;
+
+/// A retention policy for the metrics whose names match `pattern`. A metric
+/// gets the first of `RedisConfig::retention` that it matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Retention {
+    /// A metric's name (e.g. "sla.latency"), or a name followed by ".*",
+    /// which matches every metric under it ("sla.*" matches "sla.latency"
+    /// and "sla.api.errors", but not "slack.messages").
+    pub pattern: String,
+
+    /// The TTLs of the metrics' keys, in place of `RedisConfig::ttls`.
+    pub ttls: Ttls,
+
+    /// The coarser windows that the metrics' windows are rolled up into. If
+    /// `None`, they're rolled up into `RedisConfig::rollups`.
+    pub rollups: Option<Rollups>,
+}
+
+impl Retention {
+    pub(super) fn matches(&self, name: &str) -> bool {
+        match self.pattern.strip_suffix(".*") {
+            Some(prefix) => name
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('.')),
+            None => name == self.pattern,
+        }
+    }
+}
+
+// Returns the TTLs of the metrics named `name`.
+pub(super) fn ttls<'a>(config: &'a RedisConfig, name: &str) -> &'a Ttls {
+    match config
+        .retention
+        .iter()
+        .find(|retention| retention.matches(name))
+    {
+        Some(retention) => &retention.ttls,
+        None => &config.ttls,
+    }
+}
+
+// Returns the rollups of the metrics named `name`.
+pub(super) fn rollups<'a>(config: &'a RedisConfig, name: &str) -> &'a Rollups {
+    let retention = config
+        .retention
+        .iter()
+        .find(|retention| retention.matches(name));
+    retention
+        .and_then(|retention| retention.rollups.as_ref())
+        .unwrap_or(&config.rollups)
+}
+
+// Returns every configuration of rollups, starting with the default.
+pub(super) fn all_rollups(config: &RedisConfig) -> impl Iterator<Item = &Rollups> {
+    let policies = config
+        .retention
+        .iter()
+        .filter_map(|retention| retention.rollups.as_ref());
+    Some(&config.rollups).into_iter().chain(policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{Backend, RedisBackend, RollupLevel};
+    use crate::parser::{parse, ParserConfig};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_matches_patterns() {
+        let retention = |pattern: &str| Retention {
+            pattern: String::from(pattern),
+            ttls: Ttls::default(),
+            rollups: None,
+        };
+        assert!(retention("sla.*").matches("sla.latency"));
+        assert!(retention("sla.*").matches("sla.api.errors"));
+        assert!(!retention("sla.*").matches("sla"));
+        assert!(!retention("sla.*").matches("slack.messages"));
+        assert!(retention("sla.latency").matches("sla.latency"));
+        assert!(!retention("sla.latency").matches("sla.latency.p99"));
+    }
+
+    #[test]
+    fn it_applies_retention_by_name() {
+        let redis = FakeRedis::start();
+        let day = Duration::from_secs(86_400);
+        let config = RedisConfig {
+            retention: vec![
+                Retention {
+                    pattern: String::from("sla.*"),
+                    ttls: Ttls {
+                        counters: Some(90 * day),
+                        timers: Some(day),
+                        ..Ttls::default()
+                    },
+                    rollups: Some(Rollups {
+                        levels: vec![RollupLevel {
+                            length: Duration::from_secs(20),
+                            ttl: Some(90 * day),
+                        }],
+                        trim: false,
+                    }),
+                },
+                Retention {
+                    pattern: String::from("debug.*"),
+                    ttls: Ttls {
+                        counters: Some(day),
+                        ..Ttls::default()
+                    },
+                    rollups: None,
+                },
+            ],
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        let input =
+            b"sla.requests:1|c\ndebug.requests:1|c\nrequests:1|c\nsla.latency:320|ms\n\
+            latency:320|ms";
+        let metrics = parse(input, &ParserConfig::default()).unwrap().metrics;
+        backend.record(&metrics).unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(5))
+            .unwrap();
+        backend
+            .flush_at(UNIX_EPOCH + Duration::from_secs(25))
+            .unwrap();
+
+        let commands: Vec<_> = redis
+            .commands()
+            .into_iter()
+            .filter(|command| !["ZADD", "EVAL"].contains(&command[0].as_str()))
+            .map(|command| command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "INCRBY stats.counters.debug.requests 1",
+                "PEXPIRE stats.counters.debug.requests 86400000",
+                "INCRBY stats.counters.requests 1",
+                "INCRBY stats.counters.sla.requests 1",
+                "PEXPIRE stats.counters.sla.requests 7776000000",
+                "PEXPIRE {stats.timers.sla.latency.0} 86400000",
+                "PEXPIRE {stats.timers.sla.latency.0}:stats 86400000",
+                // Only the timer with a policy's rollups is rolled up.
+                "ZUNIONSTORE {stats.timers.sla.latency.rollup.20s.0} 3 \
+                {stats.timers.sla.latency.rollup.20s.0} {stats.timers.sla.latency.0} \
+                {stats.timers.sla.latency.10} AGGREGATE MAX",
+                "PEXPIRE {stats.timers.sla.latency.rollup.20s.0} 7776000000",
+                "PEXPIRE {stats.timers.sla.latency.rollup.20s.0}:stats 7776000000",
+            ]
+        );
+    }
+}
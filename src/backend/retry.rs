@@ -0,0 +1,729 @@
+//! Holds onto flushes that couldn't be sent because Redis was unreachable,
+//! and sends them once it's back, backing off exponentially between attempts
+//! in the meantime. Flushes are buffered in memory, and optionally spooled to
+//! disk so that they survive a restart. Large flushes can be split into
+//! pipelines of bounded size (see `PipelineConfig`).
+
+use super::connection::Client;
+use super::resp::{self, Command};
+use super::BackendError;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many commands each pipeline has when flushes are split to fit a time
+/// budget without `PipelineConfig::max_commands`.
+const BUDGETED_COMMANDS: usize = 1000;
+
+/// Configuration for how `RedisBackend` and `JsonBackend` retry flushes while
+/// Redis is unreachable.
+///
+/// Only flushes that nothing of was sent are retried, which is the case when
+/// a connection couldn't be opened, or was refused before any of the flush
+/// was written to it, whether to a single server, a pool, a cluster, shards,
+/// or a master found through Sentinel. A flush that fails partway through (e.g.
+/// because the connection is lost while waiting for its replies) may have
+/// been applied already, and isn't retried, because sending its counters again
+/// would count them twice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryConfig {
+    /// The most flushes that are held onto. Once there are more, the oldest
+    /// is dropped. 0 drops every flush that can't be sent.
+    pub max_flushes: usize,
+
+    /// How long to wait before the first retry. The wait doubles after each
+    /// failed retry, up to `max_backoff`, and flushes in the meantime are
+    /// buffered without trying to send them.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+
+    /// A directory that buffered flushes are written to, one file each, so
+    /// that they're sent by the next backend to use the directory if this one
+    /// exits before Redis is back. If `None`, they're only kept in memory.
+    pub spool_dir: Option<PathBuf>,
+
+    /// The most commands of buffered flushes that are replayed each time a
+    /// flush is sent once Redis is back, so that catching up after an outage
+    /// doesn't swamp Redis and slow down every other client's writes too. The
+    /// rest are replayed by the following flushes, and flushes since Redis
+    /// came back are sent after them, to keep gauges from going back in
+    /// time. Transactions are replayed whole. `RedisBackend` counts the
+    /// commands replayed in "retry.replayed", and gauges the flushes still
+    /// buffered in "retry.buffered", which are written with the next flush.
+    /// `None`, which is the default, replays everything at once.
+    pub max_replayed_commands: Option<usize>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            // An hour of flushes at the default window.
+            max_flushes: 360,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            spool_dir: None,
+            max_replayed_commands: None,
+        }
+    }
+}
+
+/// Configuration for how `RedisBackend` sends flushes to Redis.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineConfig {
+    /// The most commands sent in a single pipeline. Flushes with more are
+    /// split into pipelines that are sent one after another, so that a flush
+    /// after a traffic spike doesn't hold Redis up with a multi-megabyte
+    /// write. If Redis becomes unreachable partway through, the pipelines that
+    /// haven't been sent are buffered and retried like a whole flush would
+    /// be. Atomic flushes aren't split. `None`, which is the default, sends
+    /// each flush in a single pipeline. Pipelines are sent one at a time:
+    /// each one's replies are read before the next is written.
+    pub max_commands: Option<usize>,
+
+    /// The most members that a single `PFADD`, `SADD`, or `ZADD` adds (the
+    /// members of a set, or the observations of a timer, in a window).
+    /// Commands with more are split into commands of up to that many, so
+    /// that a set or timer with a huge number of them in a window isn't
+    /// written as a single multi-megabyte command, which `max_commands`
+    /// can't split and Redis would be blocked running. `None`, which is the
+    /// default, doesn't split them.
+    pub max_members: Option<usize>,
+
+    /// How long sending a pipeline waits on Redis for each read or write
+    /// before failing (see `Client::set_timeout`), which connections from
+    /// pools don't. `None` waits as long as it takes.
+    pub timeout: Option<Duration>,
+
+    /// How long a flush spends sending pipelines before it leaves the rest
+    /// of them buffered, so that a flush after a traffic spike doesn't
+    /// monopolize the connection or delay recording. What's left is sent
+    /// first by the next flush, or by `Backend::send_buffered`, which servers
+    /// call between the packets that they receive to send it sooner. At least
+    /// one pipeline is sent each time. Without `max_commands`, flushes are
+    /// split into pipelines of 1,000 commands. `None`, which is the default,
+    /// sends everything that can be sent.
+    pub time_budget: Option<Duration>,
+}
+
+// FlushBuffer sends flushes, buffering the ones that can't be sent.
+pub(super) struct FlushBuffer {
+    config: RetryConfig,
+    pipeline: PipelineConfig,
+
+    // Flushes that haven't been sent, oldest first.
+    flushes: VecDeque<Flush>,
+
+    // Whether the flushes spooled by a previous backend have been loaded.
+    loaded: bool,
+
+    // The number in the name of the next file spooled.
+    next_file: u64,
+
+    // How long to wait after the next failure, and when the next attempt is.
+    backoff: Duration,
+    retry_at: Option<SystemTime>,
+
+    // The number of commands of buffered flushes replayed since it was last
+    // taken.
+    replayed: usize,
+}
+
+struct Flush {
+    commands: Vec<Command>,
+
+    // The file that the flush is spooled to, if it's been spooled.
+    file: Option<PathBuf>,
+
+    // Whether sending the flush has failed, or it was loaded from the spool,
+    // so that sending it is a replay.
+    replay: bool,
+
+    // Whether the flush is of key/values written as they were recorded (see
+    // `send_key_values`).
+    key_values: bool,
+}
+
+impl FlushBuffer {
+    pub(super) fn new(config: RetryConfig, pipeline: PipelineConfig) -> FlushBuffer {
+        FlushBuffer {
+            backoff: config.min_backoff,
+            config,
+            pipeline,
+            flushes: VecDeque::new(),
+            loaded: false,
+            next_file: 0,
+            retry_at: None,
+            replayed: 0,
+        }
+    }
+
+    // Returns the number of flushes waiting to be sent.
+    pub(super) fn len(&self) -> usize {
+        self.flushes.len()
+    }
+
+    // Returns whether there are flushes waiting that can be sent at `now`,
+    // rather than ones waiting for Redis to be back.
+    pub(super) fn sendable(&self, now: SystemTime) -> bool {
+        !self.flushes.is_empty() && self.retry_at.is_none_or(|at| now >= at)
+    }
+
+    // Returns the number of commands of buffered flushes replayed since the
+    // last call.
+    pub(super) fn take_replayed(&mut self) -> usize {
+        mem::take(&mut self.replayed)
+    }
+
+    // Sends any buffered flushes, oldest first, followed by `commands`. If
+    // Redis is unreachable, or the backoff since it last was hasn't passed,
+    // the flushes that haven't been sent are kept for the next call, and the
+    // error is `BackendError::Unavailable`. Once the time budget is spent, or
+    // the most commands of buffered flushes have been replayed, the rest are
+    // kept for the next call too, without an error.
+    pub(super) fn send(
+        &mut self,
+        client: &mut dyn Client,
+        commands: Vec<Command>,
+        now: SystemTime,
+    ) -> Result<(), BackendError> {
+        self.send_flush(client, commands, false, now)
+    }
+
+    // Like `send`, but of key/values, which are sent as they're recorded.
+    // While Redis is unreachable, they're added to the newest flush buffered
+    // if it's of key/values too, rather than buffered on their own, so that
+    // recording them during an outage doesn't push flushes out of the buffer.
+    pub(super) fn send_key_values(
+        &mut self,
+        client: &mut dyn Client,
+        commands: Vec<Command>,
+        now: SystemTime,
+    ) -> Result<(), BackendError> {
+        self.send_flush(client, commands, true, now)
+    }
+
+    fn send_flush(
+        &mut self,
+        client: &mut dyn Client,
+        commands: Vec<Command>,
+        key_values: bool,
+        now: SystemTime,
+    ) -> Result<(), BackendError> {
+        self.load()?;
+        let commands = match self.pipeline.max_members {
+            Some(max) => commands
+                .into_iter()
+                .flat_map(|add| add.split_members(max))
+                .collect(),
+            None => commands,
+        };
+        match self.flushes.back_mut() {
+            _ if commands.is_empty() => {}
+            Some(last) if key_values && last.key_values && last.replay => {
+                // It's spooled again with the key/values added.
+                if let Some(file) = last.file.take() {
+                    fs::remove_file(file)?;
+                }
+                last.commands.extend(commands);
+            }
+            _ => self.flushes.push_back(Flush {
+                commands,
+                file: None,
+                replay: false,
+                key_values,
+            }),
+        }
+        if self.retry_at.is_some_and(|at| now < at) {
+            self.flushes
+                .iter_mut()
+                .for_each(|flush| flush.replay = true);
+            self.buffer()?;
+            return Err(BackendError::Unavailable {
+                message: format!(
+                    "backing off, with {} flushes buffered",
+                    self.flushes.len()
+                ),
+            });
+        }
+
+        let started = Instant::now();
+        let mut replayable = self.config.max_replayed_commands.map(|max| max.max(1));
+        while !self.flushes.is_empty() {
+            let flush = self.flushes.front_mut().unwrap();
+            let replay = flush.replay;
+            if replay && replayable == Some(0) {
+                return self.buffer();
+            }
+            let budgeted = self.pipeline.time_budget.map(|_| BUDGETED_COMMANDS);
+            let mut len = self.pipeline.max_commands.or(budgeted);
+            if replay {
+                len = match (len, replayable) {
+                    (Some(len), Some(replayable)) => Some(len.min(replayable)),
+                    (len, replayable) => len.or(replayable),
+                };
+            }
+            let len = len.filter(|_| !atomic(&flush.commands));
+            let len = len.map_or(flush.commands.len(), |len| {
+                len.max(1).min(flush.commands.len())
+            });
+            let result = client.pipeline(&flush.commands[..len]);
+            if let Err(error @ BackendError::Unavailable { .. }) = result {
+                self.flushes
+                    .iter_mut()
+                    .for_each(|flush| flush.replay = true);
+                self.retry_at = Some(now + self.backoff);
+                self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+                self.buffer()?;
+                return Err(error);
+            }
+            self.backoff = self.config.min_backoff;
+            self.retry_at = None;
+            // What's left of a flush is spooled again if it can't be sent, so
+            // that a restart doesn't send the pipelines that were sent again.
+            if let Some(file) = flush.file.take() {
+                fs::remove_file(file)?;
+            }
+            flush.commands.drain(..len);
+            if replay {
+                self.replayed += len;
+                replayable = replayable.map(|replayable| replayable.saturating_sub(len));
+            }
+            if flush.commands.is_empty() {
+                self.flushes.pop_front();
+            }
+            result?;
+            if self
+                .pipeline
+                .time_budget
+                .is_some_and(|budget| started.elapsed() >= budget)
+                && !self.flushes.is_empty()
+            {
+                return self.buffer();
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the oldest flushes past `max_flushes`, and spools the rest.
+    fn buffer(&mut self) -> Result<(), BackendError> {
+        while self.flushes.len() > self.config.max_flushes {
+            if let Some(file) = self.flushes.pop_front().unwrap().file {
+                fs::remove_file(file)?;
+            }
+        }
+        let Some(dir) = &self.config.spool_dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+        for flush in self.flushes.iter_mut().filter(|flush| flush.file.is_none()) {
+            let mut out = Vec::new();
+            resp::encode(&flush.commands, &mut out);
+            // Written under another name first, so that a crash partway
+            // through doesn't leave part of a flush to be loaded.
+            let file = dir.join(format!("{:020}.resp", self.next_file));
+            let partial = file.with_extension("partial");
+            fs::write(&partial, out)?;
+            fs::rename(&partial, &file)?;
+            flush.file = Some(file);
+            self.next_file += 1;
+        }
+        Ok(())
+    }
+
+    // Loads the flushes in the spool, the first time it's called.
+    fn load(&mut self) -> Result<(), BackendError> {
+        let Some(dir) = self.config.spool_dir.as_ref().filter(|_| !self.loaded) else {
+            return Ok(());
+        };
+        self.loaded = true;
+        let mut files = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        files.retain(|file| {
+            file.extension()
+                .is_some_and(|extension| extension == "resp")
+        });
+        // The names are zero-padded numbers, so they sort in the order that
+        // the flushes were spooled in.
+        files.sort();
+
+        for file in files {
+            let number = file
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok());
+            self.next_file = self.next_file.max(number.map_or(0, |number| number + 1));
+            let bytes = fs::read(&file)?;
+            let mut reader = bytes.as_slice();
+            let mut commands = Vec::new();
+            while !reader.is_empty() {
+                commands.push(Command::read(&mut reader)?);
+            }
+            let flush = Flush {
+                commands,
+                file: Some(file),
+                replay: true,
+                key_values: false,
+            };
+            self.flushes.push_back(flush);
+        }
+        Ok(())
+    }
+}
+
+// Returns whether a flush is a `MULTI`/`EXEC` transaction, which can't be
+// split.
+fn atomic(commands: &[Command]) -> bool {
+    commands
+        .first()
+        .is_some_and(|command| command.args()[0].eq_ignore_ascii_case(b"MULTI"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::connection::Connection;
+    use crate::backend::faults::{Fault, Faults, FaultyClient};
+    use crate::backend::resp::Value;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{
+        Backend, ConnectionPool, MemoryClient, PoolConfig, RedisBackend, RedisConfig,
+    };
+    use crate::parser::{parse, ParserConfig};
+    use std::sync::{Arc, Mutex};
+
+    // Returns a client that's unavailable until its faults are cleared.
+    fn down(redis: &FakeRedis) -> (FaultyClient<Connection>, Faults) {
+        let faults = Faults::new();
+        let unavailable = BackendError::Unavailable {
+            message: String::from("down"),
+        };
+        faults.inject_until_cleared(Fault::Error(unavailable));
+        let connection = Connection::connect(redis.addr()).unwrap();
+        (FaultyClient::new(connection, faults.clone()), faults)
+    }
+
+    fn set(key: &str) -> Vec<Command> {
+        vec![Command::new("SET").arg(key).arg("1")]
+    }
+
+    fn keys(redis: &FakeRedis) -> Vec<String> {
+        redis
+            .commands()
+            .into_iter()
+            .map(|command| command[1].clone())
+            .collect()
+    }
+
+    #[test]
+    fn it_retries_with_backoff() {
+        let redis = FakeRedis::start();
+        let (mut client, faults) = down(&redis);
+        let mut buffer = FlushBuffer::new(
+            RetryConfig {
+                max_flushes: 2,
+                min_backoff: Duration::from_secs(10),
+                max_backoff: Duration::from_secs(15),
+                spool_dir: None,
+                max_replayed_commands: None,
+            },
+            PipelineConfig::default(),
+        );
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let unavailable =
+            |result| matches!(result, Err(BackendError::Unavailable { .. }));
+
+        assert!(unavailable(buffer.send(&mut client, set("a"), at(0))));
+        assert!(unavailable(buffer.send(&mut client, set("b"), at(5))));
+        assert!(unavailable(buffer.send(&mut client, set("c"), at(10))));
+        assert_eq!((faults.sends(), buffer.len()), (2, 2));
+        // The backoff doubled, up to 15 seconds.
+        assert!(unavailable(buffer.send(&mut client, Vec::new(), at(24))));
+        assert_eq!(faults.sends(), 2);
+
+        faults.clear();
+        assert_eq!(buffer.send(&mut client, set("d"), at(25)), Ok(()));
+        assert_eq!(buffer.len(), 0);
+        // "a" was dropped once there were more than two flushes.
+        assert_eq!(keys(&redis), vec!["b", "c", "d"]);
+
+        // A flush that fails partway through isn't buffered.
+        faults.inject(Fault::PartialReply(0));
+        assert!(matches!(
+            buffer.send(&mut client, set("e"), at(26)),
+            Err(BackendError::Io { .. })
+        ));
+        assert_eq!(buffer.len(), 0);
+
+        faults.inject_until_cleared(Fault::Error(BackendError::Unavailable {
+            message: String::from("down"),
+        }));
+        assert!(unavailable(buffer.send(&mut client, set("f"), at(30))));
+        assert!(unavailable(buffer.send(&mut client, Vec::new(), at(39))));
+        assert_eq!(faults.sends(), 7);
+    }
+
+    #[test]
+    fn it_spools_flushes() {
+        let redis = FakeRedis::start();
+        let dir = std::env::temp_dir()
+            .join(format!("redis-metrics-spool-{}", std::process::id()));
+        let config = RetryConfig {
+            min_backoff: Duration::ZERO,
+            spool_dir: Some(dir.clone()),
+            ..RetryConfig::default()
+        };
+        let (mut client, faults) = down(&redis);
+        let mut buffer = FlushBuffer::new(config.clone(), PipelineConfig::default());
+        assert!(buffer
+            .send(&mut client, set("a"), SystemTime::UNIX_EPOCH)
+            .is_err());
+        assert!(buffer
+            .send(&mut client, set("b"), SystemTime::UNIX_EPOCH)
+            .is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        drop(buffer);
+
+        faults.clear();
+        let mut buffer = FlushBuffer::new(config, PipelineConfig::default());
+        assert_eq!(
+            buffer.send(&mut client, set("c"), SystemTime::UNIX_EPOCH),
+            Ok(())
+        );
+        assert_eq!(keys(&redis), vec!["a", "b", "c"]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_retries_flushes_to_pools_and_clusters() {
+        // A single node that serves every slot.
+        let port = Arc::new(Mutex::new(0));
+        let served = Arc::clone(&port);
+        let redis = FakeRedis::with_replies(move |command| match command[0].as_str() {
+            "CLUSTER" => Value::Array(vec![Value::Array(vec![
+                Value::Integer(0),
+                Value::Integer(16383),
+                Value::Array(vec![
+                    Value::Bulk(b"127.0.0.1".to_vec()),
+                    Value::Integer(*served.lock().unwrap()),
+                ]),
+            ])]),
+            _ => Value::Status(String::from("OK")),
+        });
+        *port.lock().unwrap() = i64::from(redis.addr().port());
+        let config = RedisConfig {
+            retry: RetryConfig {
+                min_backoff: Duration::ZERO,
+                ..RetryConfig::default()
+            },
+            ..RedisConfig::default()
+        };
+        let pool = ConnectionPool::new(redis.addr(), PoolConfig::default()).unwrap();
+        let addr = redis.addr().to_string();
+        let mut backends = [
+            RedisBackend::with_pool(pool, config.clone()).unwrap(),
+            RedisBackend::connect_cluster(&[addr.as_str()], config).unwrap(),
+        ];
+
+        let metrics = parse(b"gorets:1|c", &ParserConfig::default())
+            .unwrap()
+            .metrics;
+        redis.stop();
+        for backend in &mut backends {
+            backend.record(&metrics).unwrap();
+            assert!(matches!(
+                backend.flush(),
+                Err(BackendError::Unavailable { .. })
+            ));
+            assert_eq!(backend.buffered_flushes(), 1);
+        }
+        redis.restart();
+        for backend in &mut backends {
+            backend.record(&metrics).unwrap();
+            assert_eq!(backend.flush(), Ok(()));
+            assert_eq!(backend.buffered_flushes(), 0);
+        }
+        let incrs = redis
+            .commands()
+            .into_iter()
+            .filter(|c| c[0] == "INCRBY")
+            .count();
+        assert_eq!(incrs, 4);
+    }
+
+    #[test]
+    fn it_splits_flushes_into_pipelines() {
+        let memory = MemoryClient::new();
+        let faults = Faults::new();
+        let mut client = FaultyClient::new(memory.clone(), faults.clone());
+        let pipeline = PipelineConfig {
+            max_commands: Some(2),
+            ..PipelineConfig::default()
+        };
+        let retry = RetryConfig {
+            min_backoff: Duration::ZERO,
+            ..RetryConfig::default()
+        };
+        let mut buffer = FlushBuffer::new(retry, pipeline);
+        let at = SystemTime::UNIX_EPOCH;
+
+        // Redis becomes unreachable after the first pipeline, so the rest of
+        // the flush is buffered.
+        faults.inject(Fault::Delay(Duration::ZERO));
+        faults.inject(Fault::Error(BackendError::Unavailable {
+            message: String::from("down"),
+        }));
+        let flush: Vec<_> = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .flat_map(set)
+            .collect();
+        assert!(matches!(
+            buffer.send(&mut client, flush, at),
+            Err(BackendError::Unavailable { .. })
+        ));
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.send(&mut client, Vec::new(), at), Ok(()));
+
+        // Transactions aren't split.
+        let transaction = vec![
+            Command::new("MULTI"),
+            set("f").remove(0),
+            set("g").remove(0),
+            Command::new("EXEC"),
+        ];
+        assert_eq!(buffer.send(&mut client, transaction, at), Ok(()));
+
+        let sent: Vec<_> = memory.sent().iter().map(Vec::len).collect();
+        assert_eq!(sent, vec![2, 2, 1, 4]);
+        assert_eq!(memory.keys().len(), 7);
+    }
+
+    #[test]
+    fn it_splits_commands_with_many_members() {
+        let memory = MemoryClient::new();
+        let mut client = memory.clone();
+        let pipeline = PipelineConfig {
+            max_commands: Some(2),
+            max_members: Some(2),
+            ..PipelineConfig::default()
+        };
+        let mut buffer = FlushBuffer::new(RetryConfig::default(), pipeline);
+        let sadd = Command::new("SADD").arg("uniques");
+        let sadd = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .fold(sadd, Command::arg);
+        assert_eq!(
+            buffer.send(&mut client, vec![sadd], SystemTime::UNIX_EPOCH),
+            Ok(())
+        );
+
+        let sent: Vec<_> = memory.sent().iter().map(Vec::len).collect();
+        assert_eq!(sent, vec![2, 1]);
+        let members: usize = memory
+            .sent()
+            .concat()
+            .iter()
+            .map(|add| add.args().len() - 2)
+            .sum();
+        assert_eq!(members, 5);
+    }
+
+    #[test]
+    fn it_sends_pipelines_within_the_time_budget() {
+        let memory = MemoryClient::new();
+        let mut client = memory.clone();
+        let pipeline = PipelineConfig {
+            max_commands: Some(2),
+            time_budget: Some(Duration::ZERO),
+            ..PipelineConfig::default()
+        };
+        let mut buffer = FlushBuffer::new(RetryConfig::default(), pipeline);
+        let at = SystemTime::UNIX_EPOCH;
+
+        // Each send only has time for one pipeline, and what's left isn't an
+        // error or backed off from.
+        let flush: Vec<_> = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .flat_map(set)
+            .collect();
+        assert_eq!(buffer.send(&mut client, flush, at), Ok(()));
+        assert_eq!(buffer.len(), 1);
+        assert!(buffer.sendable(at));
+        assert_eq!(buffer.send(&mut client, Vec::new(), at), Ok(()));
+        assert_eq!(buffer.send(&mut client, Vec::new(), at), Ok(()));
+        assert_eq!(buffer.len(), 0);
+        assert!(!buffer.sendable(at));
+
+        let sent: Vec<_> = memory.sent().iter().map(Vec::len).collect();
+        assert_eq!(sent, vec![2, 2, 1]);
+        assert_eq!(memory.keys().len(), 5);
+    }
+
+    #[test]
+    fn it_throttles_replays() {
+        let memory = MemoryClient::new();
+        let faults = Faults::new();
+        let client = FaultyClient::new(memory.clone(), faults.clone());
+        let config = RedisConfig {
+            retry: RetryConfig {
+                min_backoff: Duration::ZERO,
+                max_replayed_commands: Some(1),
+                ..RetryConfig::default()
+            },
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_client(Box::new(client), config).unwrap();
+        let flush = |backend: &mut RedisBackend, input: &[u8]| {
+            let metrics = parse(input, &ParserConfig::default()).unwrap().metrics;
+            backend.record(&metrics).unwrap();
+            backend.flush()
+        };
+
+        faults.inject_until_cleared(Fault::Error(BackendError::Unavailable {
+            message: String::from("down"),
+        }));
+        assert!(flush(&mut backend, b"a:1|c").is_err());
+        assert!(flush(&mut backend, b"b:1|c").is_err());
+        faults.clear();
+
+        // Only one of the flushes from the outage is replayed at a time. The
+        // flush after it waits behind the other, but isn't throttled itself.
+        assert_eq!(flush(&mut backend, b"c:1|c"), Ok(()));
+        assert_eq!(backend.buffered_flushes(), 2);
+        assert_eq!(backend.flush(), Ok(()));
+        assert_eq!(backend.buffered_flushes(), 0);
+        assert_eq!(backend.flush(), Ok(()));
+
+        let sent: Vec<Vec<String>> = memory
+            .sent()
+            .iter()
+            .flatten()
+            .map(|command| {
+                command
+                    .args()
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                vec!["INCRBY", "stats.counters.a", "1"],
+                vec!["INCRBY", "stats.counters.b", "1"],
+                vec!["INCRBY", "stats.counters.c", "1"],
+                vec!["INCRBY", "stats.counters.retry.replayed", "1"],
+                vec!["SET", "stats.gauges.retry.buffered", "2"],
+                vec!["INCRBY", "stats.counters.retry.replayed", "1"],
+                vec!["SET", "stats.gauges.retry.buffered", "0"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Rolls the windows of sets and timers up into coarser ones (e.g. a minute,
+//! then an hour), so that history can be kept at a coarse resolution long
+//! after the fine windows have expired. Counters, gauges, and key/values
+//! aren't split into windows, so there's nothing to roll up for them.
+//!
+//! Once every window of a rollup has been flushed, the backend merges them
+//! into it in the next flush's pipeline: sets with `PFMERGE` (or `SUNIONSTORE`)
+//! and timers with `ZUNIONSTORE`, after which the timers' statistics are
+//! computed over the merged observations, so that their percentiles are exact
+//! rather than averages of percentiles. Only the series that the backend has
+//! flushed itself are rolled up. Merges include the rollup itself, so when
+//! several backends roll up the same series, they don't undo each other.
+//!
+//! The commands of a rollup span several windows' keys, which are in different
+//! slots of a cluster or on different shards, so rollups need a single server.
+//! Backends for a cluster or shards refuse a configuration with rollups.
+
+use super::redis::{expire, stats_key, TIMER_STATS_SCRIPT};
+use super::resp::Command;
+use super::retention;
+use super::{BackendError, RedisConfig, SetMode};
+use crate::parser::{MetricId, MetricType};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Configuration for rolling windows up into coarser ones, such as levels of
+/// a minute kept for a week and an hour kept for a year.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rollups {
+    /// The levels that windows are rolled up into, finest first. Each is
+    /// rolled up from the one before it (or from the windows themselves, for
+    /// the first). Without any levels, which is the default, windows aren't
+    /// rolled up.
+    pub levels: Vec<RollupLevel>,
+
+    /// Whether the members or observations of sets' and timers' own windows
+    /// are deleted once they've been rolled up into the first level, which
+    /// keeps memory bounded without waiting for them to expire. Timers'
+    /// statistics aren't deleted, and neither are levels that are rolled up
+    /// into the next one, so that they can be read until they expire.
+    pub trim: bool,
+}
+
+/// A resolution that windows are rolled up into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollupLevel {
+    /// The length of each of the level's windows, which has to be a multiple
+    /// of the length of the level before it (or of `RedisConfig::window`).
+    pub length: Duration,
+
+    /// How long the level's keys live after they're last written to. `None`
+    /// keeps them forever.
+    pub ttl: Option<Duration>,
+}
+
+// Rollup tracks the series whose windows haven't been rolled up yet.
+#[derive(Default)]
+pub(super) struct Rollup {
+    // The series to roll up, by the end of the rollup's window, its level,
+    // and the series' key (without a window), along with the start of the
+    // window. Series can have different levels (see `Retention`), so they're
+    // ordered by when their rollups end, which is never before the windows
+    // rolled up into them do, and then by level.
+    pending: BTreeMap<(u64, usize, String), (u64, MetricId)>,
+}
+
+impl Rollup {
+    // Records that a series' window, which starts at `start`, was flushed.
+    pub(super) fn track(
+        &mut self,
+        config: &RedisConfig,
+        key: &str,
+        id: &MetricId,
+        start: u64,
+    ) {
+        let Some(first) = retention::rollups(config, id.name()).levels.first() else {
+            return;
+        };
+        let length = seconds(first.length);
+        let start = start - start % length;
+        let pending = self.pending.entry((start + length, 0, String::from(key)));
+        pending.or_insert_with(|| (start, id.clone()));
+    }
+
+    // Adds the commands that roll up every series whose rollups have ended by
+    // `now` to `commands`.
+    pub(super) fn flush(
+        &mut self,
+        config: &RedisConfig,
+        now: SystemTime,
+        commands: &mut Vec<Command>,
+    ) {
+        let now = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        while let Some(entry) = self.pending.first_entry() {
+            let (end, _, _) = *entry.key();
+            if end > now {
+                break;
+            }
+            let ((_, i, key), (start, id)) = entry.remove_entry();
+            let rollups = retention::rollups(config, id.name());
+            roll_up(config, rollups, i, &id, start, commands);
+            if let Some(next) = rollups.levels.get(i + 1) {
+                let length = seconds(next.length);
+                let next_start = start - start % length;
+                let pending = self.pending.entry((next_start + length, i + 1, key));
+                pending.or_insert((next_start, id));
+            }
+        }
+    }
+}
+
+// Adds the commands that roll the windows of a series up into level `i` of
+// `rollups`, whose window starts at `start`.
+fn roll_up(
+    config: &RedisConfig,
+    rollups: &Rollups,
+    i: usize,
+    id: &MetricId,
+    start: u64,
+    commands: &mut Vec<Command>,
+) {
+    let level = &rollups.levels[i];
+    let length = seconds(level.length);
+    let source_length = match i {
+        0 => seconds(config.window),
+        _ => seconds(rollups.levels[i - 1].length),
+    };
+    let timer = id.metric_type() != MetricType::Set;
+    // Rollups are hash tags like timers' windows are, while sets' own windows
+    // aren't.
+    let key = |length: Option<u64>, start: u64| match length {
+        Some(length) => format!("{{{}}}", config.keys.rollup_key(id, length, start)),
+        None if timer => format!("{{{}}}", config.keys.window_key(id, start)),
+        None => config.keys.window_key(id, start),
+    };
+    let dest = key(Some(length), start);
+    let sources: Vec<_> = (start..start + length)
+        .step_by(source_length as usize)
+        .map(|source| key((i > 0).then_some(source_length), source))
+        .collect();
+
+    if timer {
+        // Observations' members are unique, so taking the maximum score of a
+        // member that's in both the rollup and a source leaves it unchanged.
+        let union = Command::new("ZUNIONSTORE")
+            .arg(&dest)
+            .arg_owned((sources.len() + 1).to_string())
+            .arg(&dest);
+        let union = sources.iter().fold(union, Command::arg);
+        commands.push(union.arg_static("AGGREGATE").arg_static("MAX"));
+        expire(commands, &dest, level.ttl);
+        let stats = stats_key(&dest);
+        commands.push(
+            Command::new("EVAL")
+                .arg(TIMER_STATS_SCRIPT)
+                .arg_static("2")
+                .arg(&dest)
+                .arg(&stats),
+        );
+        expire(commands, &stats, level.ttl);
+    } else {
+        let union = match config.sets {
+            SetMode::HyperLogLog => Command::new("PFMERGE").arg(&dest),
+            SetMode::Exact => Command::new("SUNIONSTORE").arg(&dest).arg(&dest),
+        };
+        commands.push(sources.iter().fold(union, Command::arg));
+        expire(commands, &dest, level.ttl);
+    }
+    if rollups.trim && i == 0 {
+        commands.push(sources.iter().fold(Command::new("DEL"), Command::arg));
+    }
+}
+
+// Returns the key of a series' rollup window that's `length` long and
+// contains `at`, which is a hash tag.
+pub(super) fn rollup_key(
+    config: &RedisConfig,
+    id: &MetricId,
+    length: Duration,
+    at: SystemTime,
+) -> String {
+    let length = seconds(length);
+    let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    format!(
+        "{{{}}}",
+        config.keys.rollup_key(id, length, secs - secs % length)
+    )
+}
+
+// Checks that each level's length is a multiple of the one before it, so
+// that every window is rolled up whole.
+pub(super) fn check(config: &RedisConfig) -> Result<(), BackendError> {
+    for rollups in retention::all_rollups(config) {
+        let mut previous = seconds(config.window);
+        for level in &rollups.levels {
+            let length = seconds(level.length);
+            if !length.is_multiple_of(previous) {
+                return Err(BackendError::Config {
+                    message: format!(
+                        "a rollup of {}s isn't a multiple of {}s",
+                        length, previous
+                    ),
+                });
+            }
+            previous = length;
+        }
+    }
+    Ok(())
+}
+
+fn seconds(length: Duration) -> u64 {
+    length.as_secs().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{Backend, RedisBackend, Shard};
+    use crate::parser::{parse, ParserConfig};
+
+    #[test]
+    fn it_rolls_up_windows() {
+        let redis = FakeRedis::start();
+        let rollups = Rollups {
+            levels: vec![
+                RollupLevel {
+                    length: Duration::from_secs(20),
+                    ttl: Some(Duration::from_secs(60)),
+                },
+                RollupLevel {
+                    length: Duration::from_secs(40),
+                    ttl: None,
+                },
+            ],
+            trim: true,
+        };
+        let config = RedisConfig {
+            rollups,
+            ..RedisConfig::default()
+        };
+        let mut backend = RedisBackend::with_config(redis.addr(), config).unwrap();
+        let parser = ParserConfig::default();
+        for (secs, input) in [
+            (5, "uniques:a|s"),
+            (15, "uniques:b|s\nglork:320|ms"),
+            (25, ""),
+            (45, ""),
+        ] {
+            if !input.is_empty() {
+                backend
+                    .record(&parse(input.as_bytes(), &parser).unwrap().metrics)
+                    .unwrap();
+            }
+            backend
+                .flush_at(UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        }
+
+        let commands: Vec<_> = redis
+            .commands()
+            .into_iter()
+            .filter(|command| !["PFADD", "ZADD", "EVAL"].contains(&command[0].as_str()))
+            .map(|command| command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+            "PFMERGE {stats.sets.uniques.rollup.20s.0} stats.sets.uniques.0 \
+                stats.sets.uniques.10",
+            "PEXPIRE {stats.sets.uniques.rollup.20s.0} 60000",
+            "DEL stats.sets.uniques.0 stats.sets.uniques.10",
+            "ZUNIONSTORE {stats.timers.glork.rollup.20s.0} 3 \
+                {stats.timers.glork.rollup.20s.0} {stats.timers.glork.0} \
+                {stats.timers.glork.10} AGGREGATE MAX",
+            "PEXPIRE {stats.timers.glork.rollup.20s.0} 60000",
+            "PEXPIRE {stats.timers.glork.rollup.20s.0}:stats 60000",
+            "DEL {stats.timers.glork.0} {stats.timers.glork.10}",
+            // Only windows of the sets and timers themselves are trimmed.
+            "PFMERGE {stats.sets.uniques.rollup.40s.0} {stats.sets.uniques.rollup.20s.0} \
+                {stats.sets.uniques.rollup.20s.20}",
+            "ZUNIONSTORE {stats.timers.glork.rollup.40s.0} 3 \
+                {stats.timers.glork.rollup.40s.0} {stats.timers.glork.rollup.20s.0} \
+                {stats.timers.glork.rollup.20s.20} AGGREGATE MAX",
+        ]
+        );
+    }
+
+    #[test]
+    fn it_checks_levels() {
+        let redis = FakeRedis::start();
+        let level = |secs| RollupLevel {
+            length: Duration::from_secs(secs),
+            ttl: None,
+        };
+        for (levels, ok) in [
+            (vec![level(60), level(3600)], true),
+            (vec![level(25)], false),
+            (vec![level(60), level(90)], false),
+        ] {
+            let rollups = Rollups {
+                levels,
+                ..Rollups::default()
+            };
+            let config = RedisConfig {
+                rollups,
+                ..RedisConfig::default()
+            };
+            assert_eq!(RedisBackend::with_config(redis.addr(), config).is_ok(), ok);
+        }
+
+        // Windows are spread across a cluster's nodes and shards.
+        let rollups = Rollups {
+            levels: vec![level(60)],
+            ..Rollups::default()
+        };
+        let config = RedisConfig {
+            rollups,
+            ..RedisConfig::default()
+        };
+        let config_error = |result| matches!(result, Err(BackendError::Config { .. }));
+        let addr = redis.addr().to_string();
+        assert!(config_error(RedisBackend::connect_cluster(
+            &[addr.as_str()],
+            config.clone()
+        )));
+        assert!(config_error(RedisBackend::connect_sharded(
+            &[Shard::new(&addr)],
+            config
+        )));
+    }
+}
@@ -0,0 +1,306 @@
+//! Finds a Redis master through Redis Sentinel, and follows it when Sentinel
+//! fails over to a replica. See [the Sentinel client spec][spec].
+//!
+//! [spec]: https://redis.io/docs/reference/sentinel-clients/
+
+use super::connection::{
+    unavailable, Client, Connect, Connection, ReconnectingConnection, SharedCredentials,
+};
+use super::resp::{protocol_error, Command, Value};
+use super::BackendError;
+use std::str;
+use std::time::Duration;
+
+/// SentinelConnection is a connection to whichever server Sentinel says is
+/// the master of a group of Redis servers.
+pub struct SentinelConnection {
+    // Sentinels' addresses ("host:port"), with the one that last answered
+    // first.
+    sentinels: Vec<String>,
+
+    // The name that Sentinel monitors the master under.
+    master_name: String,
+
+    connect: Box<Connect>,
+    master: Option<Box<dyn Client>>,
+
+    // How long the master's reads and writes wait (see
+    // `Client::set_timeout`).
+    timeout: Option<Duration>,
+}
+
+impl SentinelConnection {
+    /// Asks the first of `sentinels` that answers for the address of
+    /// `master_name`'s master, and connects to it. Connections to masters
+    /// authenticate with `credentials`, if there are any, while Sentinel is
+    /// asked without authenticating, since it usually doesn't share them.
+    pub fn connect(
+        sentinels: &[&str],
+        master_name: &str,
+        credentials: Option<SharedCredentials>,
+    ) -> Result<SentinelConnection, BackendError> {
+        SentinelConnection::open(sentinels, master_name, move |addr| {
+            let addr = String::from(addr);
+            let connect = move || Connection::connect(addr.as_str());
+            Box::new(
+                ReconnectingConnection::lazy(connect).authenticated(credentials.clone()),
+            )
+        })
+    }
+
+    // Like `connect`, but with `connect` returning the clients for masters.
+    fn open(
+        sentinels: &[&str],
+        master_name: &str,
+        connect: impl FnMut(&str) -> Box<dyn Client> + Send + 'static,
+    ) -> Result<SentinelConnection, BackendError> {
+        let mut connection = SentinelConnection {
+            sentinels: sentinels
+                .iter()
+                .map(|sentinel| String::from(*sentinel))
+                .collect(),
+            master_name: String::from(master_name),
+            connect: Box::new(connect),
+            master: None,
+            timeout: None,
+        };
+        connection.master()?;
+        Ok(connection)
+    }
+
+    // Returns the client for the master, connecting to it if there isn't
+    // one. A server that Sentinel names but that doesn't say it's a master
+    // (e.g. because it hasn't finished being promoted) is an error.
+    fn master(&mut self) -> Result<&mut dyn Client, BackendError> {
+        if self.master.is_none() {
+            let addr = self.master_addr()?;
+            let mut master = (self.connect)(&addr);
+            master.set_timeout(self.timeout)?;
+            match master.query(&Command::new("ROLE"))? {
+                Value::Array(role)
+                    if role.first() == Some(&Value::Bulk(b"master".to_vec())) => {}
+                _ => return Err(protocol_error(&format!("{} isn't a master", addr))),
+            }
+            self.master = Some(master);
+        }
+        Ok(self.master.as_deref_mut().unwrap())
+    }
+
+    // Asks each sentinel in turn for the master's address.
+    fn master_addr(&mut self) -> Result<String, BackendError> {
+        let command = Command::new("SENTINEL")
+            .arg_static("get-master-addr-by-name")
+            .arg(&self.master_name);
+        let mut last_error = protocol_error("no sentinels to ask for the master");
+        for i in 0..self.sentinels.len() {
+            let reply = Connection::connect(self.sentinels[i].as_str())
+                .and_then(|mut sentinel| sentinel.query(&command));
+            match reply {
+                Ok(Value::Array(addr)) => match addr.as_slice() {
+                    [Value::Bulk(host), Value::Bulk(port)] => {
+                        let sentinel = self.sentinels.remove(i);
+                        self.sentinels.insert(0, sentinel);
+                        let invalid = |_| protocol_error("invalid master address");
+                        let host = str::from_utf8(host).map_err(invalid)?;
+                        let port = str::from_utf8(port).map_err(invalid)?;
+                        return Ok(format!("{}:{}", host, port));
+                    }
+                    _ => last_error = protocol_error("invalid master address"),
+                },
+                Ok(_) => {
+                    last_error =
+                        protocol_error(&format!("unknown master {}", self.master_name));
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl Client for SentinelConnection {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), BackendError> {
+        self.timeout = timeout;
+        match &mut self.master {
+            Some(master) => master.set_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends commands to the master. If it couldn't be reached, or it's been
+    /// demoted to a replica and refused the first command, none of the
+    /// commands were applied, so Sentinel is asked for the new master and
+    /// they're sent to it. If that fails too, the error is
+    /// `BackendError::Unavailable`, and it's up to the caller to send them
+    /// again once the failover has finished, like `RedisBackend` does with
+    /// flushes (see `RetryConfig`). If the master is lost partway through,
+    /// the commands aren't sent again, since some of them may have been
+    /// applied.
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        let mut retried = false;
+        loop {
+            let result = match self.master() {
+                Ok(master) => master.send(commands),
+                Err(error) => Err(unavailable(error)),
+            };
+            let error = match result {
+                Ok(replies) if replies.first().is_some_and(is_readonly) => {
+                    BackendError::Unavailable {
+                        message: String::from("the master is read-only"),
+                    }
+                }
+                Ok(replies) => {
+                    // A master that was demoted partway through is found
+                    // again for the next commands.
+                    if replies.iter().any(is_readonly) {
+                        self.master = None;
+                    }
+                    return Ok(replies);
+                }
+                Err(error) => error,
+            };
+
+            self.master = None;
+            if retried || !matches!(error, BackendError::Unavailable { .. }) {
+                return Err(error);
+            }
+            retried = true;
+        }
+    }
+}
+
+// Whether a reply says that the server is a replica, which is what a master
+// says once it's been demoted.
+fn is_readonly(reply: &Value) -> bool {
+    matches!(reply, Value::Error(message) if message.starts_with("READONLY"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::faults::{Fault, Faults, FaultyClient};
+    use crate::backend::testing::FakeRedis;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    // Starts a fake server that's a master until `demoted` is set.
+    fn server(demoted: &Arc<Mutex<bool>>) -> FakeRedis {
+        let demoted = Arc::clone(demoted);
+        FakeRedis::with_replies(move |command| {
+            let demoted = *demoted.lock().unwrap();
+            match command[0].as_str() {
+                "ROLE" if demoted => Value::Array(vec![Value::Bulk(b"slave".to_vec())]),
+                "ROLE" => Value::Array(vec![Value::Bulk(b"master".to_vec())]),
+                _ if demoted => Value::Error(String::from("READONLY You can't write")),
+                _ => Value::Status(String::from("OK")),
+            }
+        })
+    }
+
+    // Starts a fake sentinel that says the master is at `master`.
+    fn sentinel(master: &Arc<Mutex<String>>) -> FakeRedis {
+        let master = Arc::clone(master);
+        FakeRedis::with_replies(move |_| {
+            let master = master.lock().unwrap();
+            let (host, port) = master.rsplit_once(':').unwrap();
+            Value::Array(vec![
+                Value::Bulk(host.as_bytes().to_vec()),
+                Value::Bulk(port.as_bytes().to_vec()),
+            ])
+        })
+    }
+
+    #[test]
+    fn it_follows_failovers() {
+        let (a_demoted, b_demoted) =
+            (Arc::new(Mutex::new(false)), Arc::new(Mutex::new(false)));
+        let (a, b) = (server(&a_demoted), server(&b_demoted));
+        let master = Arc::new(Mutex::new(a.addr().to_string()));
+        let sentinel = sentinel(&master);
+
+        let down = "127.0.0.1:1";
+        let mut connection = SentinelConnection::connect(
+            &[down, &sentinel.addr().to_string()],
+            "metrics",
+            None,
+        )
+        .unwrap();
+        let set = |value: &str| [Command::new("SET").arg("gaugor").arg(value)];
+        assert!(connection.pipeline(&set("1")).is_ok());
+
+        *a_demoted.lock().unwrap() = true;
+        *master.lock().unwrap() = b.addr().to_string();
+        assert!(connection.pipeline(&set("2")).is_ok());
+
+        assert_eq!(
+            sentinel.commands(),
+            vec![
+                vec!["SENTINEL", "get-master-addr-by-name", "metrics"],
+                vec!["SENTINEL", "get-master-addr-by-name", "metrics"],
+            ]
+        );
+        assert_eq!(
+            a.commands(),
+            vec![
+                vec!["ROLE"],
+                vec!["SET", "gaugor", "1"],
+                vec!["SET", "gaugor", "2"],
+            ]
+        );
+        assert_eq!(b.commands(), vec![vec!["ROLE"], vec!["SET", "gaugor", "2"]]);
+    }
+
+    #[test]
+    fn it_sends_commands_at_most_once() {
+        let a = server(&Arc::new(Mutex::new(false)));
+        let master = Arc::new(Mutex::new(a.addr().to_string()));
+        let sentinel = sentinel(&master);
+        let faults = Faults::new();
+        let injected = faults.clone();
+        let mut connection = SentinelConnection::open(
+            &[&sentinel.addr().to_string()],
+            "metrics",
+            move |addr| {
+                let addr = String::from(addr);
+                let master =
+                    ReconnectingConnection::lazy(move || Connection::connect(&*addr));
+                Box::new(FaultyClient::new(master, injected.clone()))
+            },
+        )
+        .unwrap();
+        let set = |key: &str| Command::new("SET").arg(key).arg("1");
+
+        // The master is lost after applying the first command.
+        faults.inject(Fault::PartialReply(1));
+        assert!(matches!(
+            connection.send(&[set("a"), set("b")]),
+            Err(BackendError::Io { .. })
+        ));
+        // The master couldn't be reached, so the commands are sent to the one
+        // that Sentinel names next.
+        faults.inject(Fault::Error(BackendError::Unavailable {
+            message: String::from("down"),
+        }));
+        assert!(connection.pipeline(&[set("c")]).is_ok());
+        assert_eq!(
+            a.commands(),
+            vec![
+                vec!["ROLE"],
+                vec!["SET", "a", "1"],
+                vec!["ROLE"],
+                vec!["SET", "c", "1"],
+            ]
+        );
+
+        // Without a master to send them to, they're left to the caller to
+        // send again, rather than held onto.
+        a.stop();
+        *master.lock().unwrap() = String::from("127.0.0.1:1");
+        let start = Instant::now();
+        assert!(matches!(
+            connection.send(&[set("d")]),
+            Err(BackendError::Unavailable { .. })
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
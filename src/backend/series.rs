@@ -0,0 +1,236 @@
+//! Borrowed views of series, which look them up in maps keyed by `MetricId`
+//! without building one for each metric recorded. Building an id copies the
+//! metric's name and tags, which is most of what recording a metric to a
+//! series that was recorded to before would allocate.
+
+use crate::parser::{MetricId, MetricType, Tag, TagRef};
+use std::borrow::{Borrow, Cow};
+use std::hash::{Hash, Hasher};
+
+// SeriesRef is a series' name, type, and tags, borrowed from a metric or an
+// id. Its tags are in canonical order, like an id's, so a metric's are only
+// copied if they weren't sent sorted and without duplicates.
+pub(super) struct SeriesRef<'a> {
+    name: &'a str,
+    metric_type: MetricType,
+    tags: Tags<'a>,
+}
+
+enum Tags<'a> {
+    Owned(&'a [Tag]),
+    Borrowed(Cow<'a, [TagRef<'a>]>),
+}
+
+impl<'a> SeriesRef<'a> {
+    // Returns the series of a metric with owned tags in any order.
+    pub(super) fn new(name: &'a str, metric_type: MetricType, tags: &'a [Tag]) -> Self {
+        let tags = if canonical(tags) {
+            Tags::Owned(tags)
+        } else {
+            Tags::Borrowed(Cow::Owned(sorted(tags.iter().map(tag_ref).collect())))
+        };
+        SeriesRef {
+            name,
+            metric_type,
+            tags,
+        }
+    }
+
+    // Returns the series of a metric with borrowed tags in any order.
+    pub(super) fn of_refs(
+        name: &'a str,
+        metric_type: MetricType,
+        tags: &'a [TagRef<'a>],
+    ) -> Self {
+        let tags = if canonical(tags) {
+            Cow::Borrowed(tags)
+        } else {
+            Cow::Owned(sorted(tags.to_vec()))
+        };
+        SeriesRef {
+            name,
+            metric_type,
+            tags: Tags::Borrowed(tags),
+        }
+    }
+
+    // Returns an id of the series.
+    pub(super) fn to_id(&self) -> MetricId {
+        let tags = self
+            .tags()
+            .1
+            .map(|tag| Tag::new(tag.key, tag.value))
+            .collect();
+        MetricId::new(self.name, self.metric_type, tags)
+    }
+
+    pub(super) fn name(&self) -> &'a str {
+        self.name
+    }
+
+    // Returns the series' tags, and how many there are.
+    pub(super) fn tags(&self) -> (usize, impl Iterator<Item = TagRef<'_>>) {
+        let (owned, borrowed): (&[Tag], &[TagRef]) = match &self.tags {
+            Tags::Owned(tags) => (tags, &[]),
+            Tags::Borrowed(tags) => (&[], tags),
+        };
+        let tags = owned.iter().map(tag_ref).chain(borrowed.iter().copied());
+        (owned.len() + borrowed.len(), tags)
+    }
+}
+
+// AsSeries is any type that a series can be borrowed from, which maps keyed
+// by `SeriesKey` are looked up by (as `&dyn AsSeries`).
+pub(super) trait AsSeries {
+    fn series(&self) -> SeriesRef<'_>;
+}
+
+impl AsSeries for SeriesRef<'_> {
+    fn series(&self) -> SeriesRef<'_> {
+        let tags = match &self.tags {
+            Tags::Owned(tags) => Tags::Owned(tags),
+            Tags::Borrowed(tags) => Tags::Borrowed(Cow::Borrowed(tags)),
+        };
+        SeriesRef {
+            name: self.name,
+            metric_type: self.metric_type,
+            tags,
+        }
+    }
+}
+
+impl AsSeries for MetricId {
+    fn series(&self) -> SeriesRef<'_> {
+        let tags = Tags::Owned(self.tags());
+        SeriesRef {
+            name: self.name(),
+            metric_type: self.metric_type(),
+            tags,
+        }
+    }
+}
+
+impl Hash for dyn AsSeries + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let series = self.series();
+        series.name.hash(state);
+        series.metric_type.hash(state);
+        let (len, tags) = series.tags();
+        state.write_usize(len);
+        tags.for_each(|tag| tag.hash(state));
+    }
+}
+
+impl PartialEq for dyn AsSeries + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        let (series, other) = (self.series(), other.series());
+        let ((len, tags), (other_len, other_tags)) = (series.tags(), other.tags());
+        series.name == other.name
+            && series.metric_type == other.metric_type
+            && len == other_len
+            && tags.eq(other_tags)
+    }
+}
+
+impl Eq for dyn AsSeries + '_ {}
+
+// SeriesKey is an id that hashes like the series borrowed from it, so that a
+// map keyed by it can be looked up by a `SeriesRef`.
+#[derive(Clone, Debug)]
+pub(super) struct SeriesKey(pub(super) MetricId);
+
+impl Hash for SeriesKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self as &dyn AsSeries).hash(state);
+    }
+}
+
+impl PartialEq for SeriesKey {
+    fn eq(&self, other: &SeriesKey) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for SeriesKey {}
+
+impl AsSeries for SeriesKey {
+    fn series(&self) -> SeriesRef<'_> {
+        self.0.series()
+    }
+}
+
+impl<'a> Borrow<dyn AsSeries + 'a> for SeriesKey {
+    fn borrow(&self) -> &(dyn AsSeries + 'a) {
+        self
+    }
+}
+
+fn tag_ref(tag: &Tag) -> TagRef<'_> {
+    TagRef {
+        key: &tag.key,
+        value: tag.value.as_deref(),
+    }
+}
+
+// Returns whether tags are in canonical order: sorted, without duplicates.
+fn canonical<T: Ord>(tags: &[T]) -> bool {
+    tags.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+fn sorted(mut tags: Vec<TagRef<'_>>) -> Vec<TagRef<'_>> {
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn it_looks_ids_up_by_borrowed_series() {
+        let tags = [
+            Tag::new("env", Some("production")),
+            Tag::new("canary", None),
+        ];
+        let id = MetricId::new("gorets", MetricType::Counter, tags.to_vec());
+        let mut keys = HashMap::new();
+        keys.insert(SeriesKey(id.clone()), "stats.counters.gorets");
+
+        let refs = [
+            TagRef {
+                key: "env",
+                value: Some("production"),
+            },
+            TagRef {
+                key: "canary",
+                value: None,
+            },
+            TagRef {
+                key: "env",
+                value: Some("production"),
+            },
+        ];
+        let series = SeriesRef::of_refs("gorets", MetricType::Counter, &refs);
+        assert_eq!(
+            keys.get(&series as &dyn AsSeries),
+            Some(&"stats.counters.gorets")
+        );
+        assert_eq!(series.to_id(), id);
+        let series = SeriesRef::new("gorets", MetricType::Counter, &tags);
+        assert_eq!(
+            keys.get(&series as &dyn AsSeries),
+            Some(&"stats.counters.gorets")
+        );
+        assert_eq!(
+            keys.get(&id as &dyn AsSeries),
+            Some(&"stats.counters.gorets")
+        );
+
+        let gauge = SeriesRef::new("gorets", MetricType::Gauge, &tags);
+        assert_eq!(keys.get(&gauge as &dyn AsSeries), None);
+        let untagged = SeriesRef::of_refs("gorets", MetricType::Counter, &[]);
+        assert_eq!(keys.get(&untagged as &dyn AsSeries), None);
+    }
+}
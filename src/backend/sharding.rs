@@ -0,0 +1,238 @@
+//! Shards keys across standalone Redis servers that don't form a cluster.
+//! Each key is assigned to a shard by consistent hashing: every shard has
+//! points on a ring in proportion to its weight, and a key goes to the shard
+//! with the first point after the key's hash. Points are hashed from shards'
+//! names rather than their positions in the list, so adding or removing a
+//! shard only moves the keys between it and its neighbours on the ring, and
+//! reordering the list moves none.
+//!
+//! Keys with hash tags are sharded by their hash tags like in a cluster, so
+//! the keys of a timer's window and its statistics stay together.
+
+use super::cluster::hash_tag;
+use super::connection::{
+    partly_sent, Client, Connection, ReconnectingConnection, SharedCredentials,
+};
+use super::resp::{Command, Value};
+use super::BackendError;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The points on the ring per unit of weight, which is enough for keys to be
+/// spread within a few percent of shards' weights.
+const POINTS_PER_WEIGHT: u32 = 160;
+
+/// A Redis server that some of the keys are sharded to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shard {
+    /// The server's address (e.g. "10.0.0.1:6379").
+    pub addr: String,
+
+    /// What the shard's points on the ring are hashed from. If `None`, it's
+    /// `addr`. Giving shards names keeps their keys in place when a shard
+    /// moves to a new address.
+    pub name: Option<String>,
+
+    /// How many keys the shard gets relative to the others. A shard with a
+    /// weight of 2 gets twice as many as one with a weight of 1.
+    pub weight: u32,
+}
+
+impl Shard {
+    pub fn new(addr: &str) -> Shard {
+        Shard {
+            addr: String::from(addr),
+            name: None,
+            weight: 1,
+        }
+    }
+}
+
+/// ShardedConnection is a client for standalone Redis servers that keys are
+/// sharded across.
+///
+/// Commands without keys (like `MULTI` and `EXEC`) go to the first shard, so
+/// transactions can't be used across shards.
+pub struct ShardedConnection {
+    connections: Vec<ReconnectingConnection>,
+    ring: Ring,
+}
+
+impl ShardedConnection {
+    /// Connects to each of `shards`, authenticating with `credentials` if
+    /// there are any.
+    pub fn connect(
+        shards: &[Shard],
+        credentials: Option<SharedCredentials>,
+    ) -> Result<ShardedConnection, BackendError> {
+        let mut connections = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let addr = shard.addr.clone();
+            let connect = move || Connection::connect(addr.as_str());
+            connections.push(
+                ReconnectingConnection::lazy(connect)
+                    .authenticated(credentials.clone())
+                    .opened()?,
+            );
+        }
+        Ok(ShardedConnection {
+            connections,
+            ring: Ring::new(shards)?,
+        })
+    }
+}
+
+impl Client for ShardedConnection {
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), BackendError> {
+        self.connections
+            .iter_mut()
+            .try_for_each(|connection| connection.set_timeout(timeout))
+    }
+
+    /// Sends each shard the commands for its keys in a single pipeline. If
+    /// some shards couldn't be reached but others were sent their commands,
+    /// the error is a `BackendError::Io` rather than `Unavailable`, since
+    /// sending all the commands again would apply some of them twice.
+    fn send(&mut self, commands: &[Command]) -> Result<Vec<Value>, BackendError> {
+        let mut by_shard: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, command) in commands.iter().enumerate() {
+            let shard = command.key().map_or(0, |key| self.ring.shard(key));
+            by_shard.entry(shard).or_default().push(i);
+        }
+
+        let mut replies = vec![Value::Nil; commands.len()];
+        let (mut sent, mut error) = (false, None);
+        for (shard, indexes) in by_shard {
+            let pipeline: Vec<_> = indexes.iter().map(|&i| commands[i].clone()).collect();
+            match self.connections[shard].send(&pipeline) {
+                Ok(shard_replies) => {
+                    sent = true;
+                    for (i, reply) in indexes.into_iter().zip(shard_replies) {
+                        replies[i] = reply;
+                    }
+                }
+                Err(e) => {
+                    sent |= !matches!(e, BackendError::Unavailable { .. });
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(error) if sent => Err(partly_sent(error)),
+            Some(error) => Err(error),
+            None => Ok(replies),
+        }
+    }
+}
+
+// Ring maps keys to shards by consistent hashing.
+struct Ring {
+    // Points and the shard that each belongs to, by index, in order.
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(shards: &[Shard]) -> Result<Ring, BackendError> {
+        let mut points = Vec::new();
+        for (i, shard) in shards.iter().enumerate() {
+            let name = shard.name.as_deref().unwrap_or(&shard.addr);
+            for point in 0..shard.weight.saturating_mul(POINTS_PER_WEIGHT) {
+                points.push((hash(format!("{}-{}", name, point).as_bytes()), i));
+            }
+        }
+        if points.is_empty() {
+            return Err(BackendError::Config {
+                message: String::from("sharding needs at least one shard with a weight"),
+            });
+        }
+        points.sort_unstable();
+        Ok(Ring { points })
+    }
+
+    // Returns the index of the shard that a key is stored on.
+    fn shard(&self, key: &[u8]) -> usize {
+        let hash = hash(hash_tag(key));
+        let i = self.points.partition_point(|&(point, _)| point < hash);
+        self.points[i % self.points.len()].1
+    }
+}
+
+// FNV-1a, followed by SplitMix64's finalizer so that similar inputs (like
+// the names of a shard's points) are spread across the whole ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+
+    fn shard(name: &str, weight: u32) -> Shard {
+        Shard {
+            weight,
+            ..Shard::new(name)
+        }
+    }
+
+    #[test]
+    fn it_shards_keys_by_weight() {
+        let ring = Ring::new(&[shard("a", 1), shard("b", 3)]).unwrap();
+        let keys: Vec<_> = (0..10000)
+            .map(|i| format!("stats.counters.{}", i))
+            .collect();
+        let on_b = keys
+            .iter()
+            .filter(|key| ring.shard(key.as_bytes()) == 1)
+            .count();
+        assert!((7000..8000).contains(&on_b), "{}", on_b);
+
+        // Adding a shard only moves keys to it, and reordering the shards
+        // doesn't move any.
+        let added = Ring::new(&[shard("a", 1), shard("b", 3), shard("c", 1)]).unwrap();
+        let reordered = Ring::new(&[shard("b", 3), shard("a", 1)]).unwrap();
+        for key in &keys {
+            let shard = ring.shard(key.as_bytes());
+            assert!([shard, 2].contains(&added.shard(key.as_bytes())));
+            assert_eq!(reordered.shard(key.as_bytes()), 1 - shard);
+        }
+
+        assert_eq!(
+            ring.shard(b"{stats.timers.glork.1656581400}:stats"),
+            ring.shard(b"{stats.timers.glork.1656581400}")
+        );
+        assert!(Ring::new(&[shard("a", 0)]).is_err());
+    }
+
+    #[test]
+    fn it_routes_commands_by_key() {
+        let (a, b) = (FakeRedis::start(), FakeRedis::start());
+        let shards = [
+            Shard::new(&a.addr().to_string()),
+            Shard::new(&b.addr().to_string()),
+        ];
+        let mut connection = ShardedConnection::connect(&shards, None).unwrap();
+        let commands: Vec<_> = (0..20)
+            .map(|i| {
+                Command::new("INCRBY")
+                    .arg(format!("stats.counters.{}", i))
+                    .arg("1")
+            })
+            .collect();
+        assert_eq!(connection.pipeline(&commands).unwrap().len(), 20);
+
+        let (on_a, on_b) = (a.commands(), b.commands());
+        assert_eq!(on_a.len() + on_b.len(), 20);
+        assert!(!on_a.is_empty() && !on_b.is_empty());
+        for command in on_a {
+            assert_eq!(connection.ring.shard(command[1].as_bytes()), 0);
+        }
+    }
+}
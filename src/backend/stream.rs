@@ -0,0 +1,438 @@
+//! Uses a Redis Stream as a transport between the servers that receive metrics
+//! and the ones that aggregate them, so that each side can be scaled on its
+//! own. `StreamBackend` appends payloads to the stream with `XADD`, and any
+//! number of `StreamConsumer`s in a consumer group read them with
+//! `XREADGROUP`, each getting a share of the entries, and acknowledge them
+//! with `XACK` once they've been handled.
+//!
+//! Each entry has a single "payload" field holding StatsD lines: either a
+//! packet exactly as it was received (see `StreamBackend::append_raw`), or the
+//! metrics recorded since the last flush, encoded again.
+
+use super::connection::{Client, ReconnectingConnection, SharedCredentials};
+use super::resp::{protocol_error, Command, Value};
+use super::{Backend, BackendError};
+use crate::parser::{parse, Batch, Metric, ParseError, ParserConfig};
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// The field of a stream entry that holds its payload.
+const PAYLOAD_FIELD: &str = "payload";
+
+/// Configuration for `StreamBackend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+    /// The key of the stream.
+    pub key: String,
+
+    /// If set, the stream is trimmed to about this many entries as new ones
+    /// are added (`XADD MAXLEN ~`), so that entries that no consumer gets to
+    /// don't accumulate forever.
+    pub max_len: Option<u64>,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them (see `RedisConfig::credentials`).
+    pub credentials: Option<SharedCredentials>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> StreamConfig {
+        StreamConfig {
+            key: String::from("metrics"),
+            max_len: Some(1_000_000),
+            credentials: None,
+        }
+    }
+}
+
+/// StreamBackend appends metrics to a Redis Stream.
+pub struct StreamBackend {
+    connection: ReconnectingConnection,
+    config: StreamConfig,
+
+    // The metrics recorded since the last flush, encoded as StatsD lines.
+    payload: String,
+}
+
+impl StreamBackend {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379").
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: StreamConfig,
+    ) -> Result<StreamBackend, BackendError> {
+        let connection =
+            ReconnectingConnection::to(addr, config.credentials.clone())?.opened()?;
+        Ok(StreamBackend {
+            connection,
+            config,
+            payload: String::new(),
+        })
+    }
+
+    /// Appends a payload to the stream right away, exactly as it was
+    /// received and without parsing it, so that parsing is left to consumers
+    /// too. Returns the ID of the new entry.
+    pub fn append_raw(&mut self, payload: &[u8]) -> Result<String, BackendError> {
+        match self.connection.query(&self.add(payload))? {
+            Value::Bulk(id) => {
+                String::from_utf8(id).map_err(|_| protocol_error("invalid entry ID"))
+            }
+            reply => Err(protocol_error(&format!("invalid entry ID {:?}", reply))),
+        }
+    }
+
+    fn add(&self, payload: &[u8]) -> Command {
+        let command = Command::new("XADD").arg(&self.config.key);
+        let command = match self.config.max_len {
+            Some(max_len) => command
+                .arg_static("MAXLEN")
+                .arg_static("~")
+                .arg_owned(max_len.to_string()),
+            None => command,
+        };
+        command.arg_static("*").arg(PAYLOAD_FIELD).arg(payload)
+    }
+}
+
+impl Backend for StreamBackend {
+    /// Encodes metrics to be appended on `flush`.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        for metric in metrics {
+            if !self.payload.is_empty() {
+                self.payload.push('\n');
+            }
+            self.payload.push_str(&metric.to_string());
+        }
+        Ok(())
+    }
+
+    /// Appends the metrics recorded since the last flush as a single entry.
+    fn flush(&mut self) -> Result<(), BackendError> {
+        if self.payload.is_empty() {
+            return Ok(());
+        }
+        let payload = std::mem::take(&mut self.payload);
+        self.connection.query(&self.add(payload.as_bytes()))?;
+        Ok(())
+    }
+}
+
+/// Configuration for `StreamConsumer`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConsumerConfig {
+    /// The key of the stream.
+    pub key: String,
+
+    /// The consumer group, which is created (along with the stream) if it
+    /// doesn't exist yet. A new group starts with the entries added after it's
+    /// created.
+    pub group: String,
+
+    /// The name of this consumer, which must be unique within the group.
+    pub consumer: String,
+
+    /// The most entries returned by a single `read`.
+    pub count: usize,
+
+    /// How long `read` waits for new entries when there aren't any. `None`
+    /// returns right away.
+    pub block: Option<Duration>,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them (see `RedisConfig::credentials`).
+    pub credentials: Option<SharedCredentials>,
+}
+
+/// StreamConsumer reads entries from a Redis Stream as part of a consumer
+/// group.
+pub struct StreamConsumer {
+    connection: ReconnectingConnection,
+    config: StreamConsumerConfig,
+}
+
+/// An entry read from a stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamEntry {
+    /// The entry's ID, which is what acknowledges it in `StreamConsumer::ack`.
+    pub id: String,
+
+    /// The StatsD lines that the entry holds.
+    pub payload: Vec<u8>,
+}
+
+impl StreamEntry {
+    /// Parses the entry's payload.
+    pub fn parse(&self, config: &ParserConfig) -> Result<Batch, ParseError> {
+        parse(&self.payload, config)
+    }
+}
+
+impl StreamConsumer {
+    /// Connects to the Redis server at `addr` and joins the consumer group,
+    /// creating it if it doesn't exist.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: StreamConsumerConfig,
+    ) -> Result<StreamConsumer, BackendError> {
+        let credentials = config.credentials.clone();
+        let mut connection = ReconnectingConnection::to(addr, credentials)?.opened()?;
+        let create = Command::new("XGROUP")
+            .arg_static("CREATE")
+            .arg(&config.key)
+            .arg(&config.group)
+            .arg_static("$")
+            .arg_static("MKSTREAM");
+        match connection.query(&create) {
+            Err(BackendError::Server { message }) if message.starts_with("BUSYGROUP") => {
+                // The group was created by another consumer.
+            }
+            result => {
+                result?;
+            }
+        }
+        Ok(StreamConsumer { connection, config })
+    }
+
+    /// Reads entries that haven't been delivered to any consumer in the group
+    /// yet. They stay pending until they're acknowledged with `ack`.
+    pub fn read(&mut self) -> Result<Vec<StreamEntry>, BackendError> {
+        self.read_from(">")
+    }
+
+    /// Reads this consumer's entries that were delivered but never
+    /// acknowledged, such as ones that were being handled when it last
+    /// stopped.
+    pub fn read_pending(&mut self) -> Result<Vec<StreamEntry>, BackendError> {
+        self.read_from("0")
+    }
+
+    /// Acknowledges entries, which removes them from the group's pending
+    /// entries.
+    pub fn ack(&mut self, ids: &[String]) -> Result<(), BackendError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let command = Command::new("XACK")
+            .arg(&self.config.key)
+            .arg(&self.config.group);
+        self.connection
+            .query(&ids.iter().fold(command, Command::arg))?;
+        Ok(())
+    }
+
+    fn read_from(&mut self, id: &str) -> Result<Vec<StreamEntry>, BackendError> {
+        let command = Command::new("XREADGROUP")
+            .arg_static("GROUP")
+            .arg(&self.config.group)
+            .arg(&self.config.consumer)
+            .arg_static("COUNT")
+            .arg_owned(self.config.count.to_string());
+        let command = match self.config.block {
+            Some(block) => command
+                .arg_static("BLOCK")
+                .arg_owned(block.as_millis().to_string()),
+            None => command,
+        };
+        let command = command.arg_static("STREAMS").arg(&self.config.key).arg(id);
+
+        // The reply is an array of streams, each of which is its key followed
+        // by an array of entries, or nil if nothing was read.
+        let streams = match self.connection.query(&command)? {
+            Value::Nil => return Ok(Vec::new()),
+            Value::Array(streams) => streams,
+            reply => return Err(protocol_error(&format!("invalid streams {:?}", reply))),
+        };
+        let mut entries = Vec::new();
+        for stream in streams {
+            match stream {
+                Value::Array(mut stream) if stream.len() == 2 => match stream.pop() {
+                    Some(Value::Array(stream_entries)) => {
+                        for entry in stream_entries {
+                            entries.push(read_entry(entry)?);
+                        }
+                    }
+                    reply => {
+                        return Err(protocol_error(&format!(
+                            "invalid entries {:?}",
+                            reply
+                        )))
+                    }
+                },
+                reply => {
+                    return Err(protocol_error(&format!("invalid stream {:?}", reply)))
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Reads an entry, which is its ID followed by an array of field names and
+// values.
+fn read_entry(entry: Value) -> Result<StreamEntry, BackendError> {
+    let invalid = || protocol_error("invalid stream entry");
+    let mut entry = match entry {
+        Value::Array(entry) if entry.len() == 2 => entry,
+        _ => return Err(invalid()),
+    };
+    let (fields, id) = match (entry.pop(), entry.pop()) {
+        (Some(Value::Array(fields)), Some(Value::Bulk(id))) => (fields, id),
+        _ => return Err(invalid()),
+    };
+
+    let id = String::from_utf8(id).map_err(|_| invalid())?;
+    for pair in fields.chunks(2) {
+        if let [Value::Bulk(field), Value::Bulk(value)] = pair {
+            if field == PAYLOAD_FIELD.as_bytes() {
+                return Ok(StreamEntry {
+                    id,
+                    payload: value.clone(),
+                });
+            }
+        }
+    }
+    Err(protocol_error(&format!(
+        "stream entry {} has no payload",
+        id
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+
+    fn bulk(s: &str) -> Value {
+        Value::Bulk(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn it_appends_to_streams() {
+        let redis = FakeRedis::with_replies(|_| bulk("1656581400000-0"));
+        let config = StreamConfig {
+            max_len: Some(1000),
+            ..StreamConfig::default()
+        };
+        let mut backend = StreamBackend::connect(redis.addr(), config).unwrap();
+
+        let id = backend.append_raw(b"gorets:1|c\nglork:x|ms");
+        assert_eq!(id, Ok(String::from("1656581400000-0")));
+        let batch =
+            parse(b"gorets:1|c\nglork:320|ms|@0.1", &ParserConfig::default()).unwrap();
+        backend.record(&batch.metrics).unwrap();
+        backend.flush().unwrap();
+        backend.flush().unwrap();
+
+        assert_eq!(
+            redis.commands(),
+            vec![
+                vec![
+                    "XADD",
+                    "metrics",
+                    "MAXLEN",
+                    "~",
+                    "1000",
+                    "*",
+                    "payload",
+                    "gorets:1|c\nglork:x|ms"
+                ],
+                vec![
+                    "XADD",
+                    "metrics",
+                    "MAXLEN",
+                    "~",
+                    "1000",
+                    "*",
+                    "payload",
+                    "gorets:1|c\nglork:320|ms|@0.1"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reads_from_consumer_groups() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "XGROUP" => {
+                Value::Error(String::from("BUSYGROUP Consumer Group name already exists"))
+            }
+            "XREADGROUP" if command.last().unwrap() == ">" => {
+                Value::Array(vec![Value::Array(vec![
+                    bulk("metrics"),
+                    Value::Array(vec![
+                        Value::Array(vec![
+                            bulk("1-0"),
+                            Value::Array(vec![bulk("payload"), bulk("gorets:1|c")]),
+                        ]),
+                        Value::Array(vec![
+                            bulk("2-0"),
+                            Value::Array(vec![
+                                bulk("payload"),
+                                bulk("glork:320|ms\ngaugor:333|g"),
+                            ]),
+                        ]),
+                    ]),
+                ])])
+            }
+            "XREADGROUP" => Value::Nil,
+            _ => Value::Integer(2),
+        });
+        let config = StreamConsumerConfig {
+            key: String::from("metrics"),
+            group: String::from("aggregators"),
+            consumer: String::from("aggregator-1"),
+            count: 100,
+            block: Some(Duration::from_secs(1)),
+            credentials: None,
+        };
+        let mut consumer = StreamConsumer::connect(redis.addr(), config).unwrap();
+
+        let entries = consumer.read().unwrap();
+        let ids: Vec<_> = entries.iter().map(|e| e.id.clone()).collect();
+        assert_eq!(ids, vec!["1-0", "2-0"]);
+        assert_eq!(entries[1].parse(&ParserConfig::default()).unwrap().len(), 2);
+        consumer.ack(&ids).unwrap();
+        assert_eq!(consumer.read_pending(), Ok(Vec::new()));
+
+        assert_eq!(
+            redis.commands(),
+            vec![
+                vec![
+                    "XGROUP",
+                    "CREATE",
+                    "metrics",
+                    "aggregators",
+                    "$",
+                    "MKSTREAM"
+                ],
+                vec![
+                    "XREADGROUP",
+                    "GROUP",
+                    "aggregators",
+                    "aggregator-1",
+                    "COUNT",
+                    "100",
+                    "BLOCK",
+                    "1000",
+                    "STREAMS",
+                    "metrics",
+                    ">"
+                ],
+                vec!["XACK", "metrics", "aggregators", "1-0", "2-0"],
+                vec![
+                    "XREADGROUP",
+                    "GROUP",
+                    "aggregators",
+                    "aggregator-1",
+                    "COUNT",
+                    "100",
+                    "BLOCK",
+                    "1000",
+                    "STREAMS",
+                    "metrics",
+                    "0"
+                ],
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! A fake Redis server for testing backends. It records every command that it
+//! receives and answers them with a reply function.
+
+use super::resp::{read_value, Value};
+use std::io::{self, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Reply = dyn FnMut(&[String]) -> Value + Send;
+
+pub struct FakeRedis {
+    addr: SocketAddr,
+    commands: Arc<Mutex<Vec<Vec<String>>>>,
+
+    // The connections that clients have opened, for `push`.
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+
+    // Whether the server should be listening, and whether it is.
+    up: Arc<AtomicBool>,
+    listening: Arc<AtomicBool>,
+}
+
+impl FakeRedis {
+    /// Starts a server that gives the replies that Redis gives to the
+    /// commands that backends use on an empty database.
+    pub fn start() -> FakeRedis {
+        FakeRedis::with_replies(default_reply)
+    }
+
+    /// Starts a server that answers each command with `reply`.
+    pub fn with_replies(
+        reply: impl FnMut(&[String]) -> Value + Send + 'static,
+    ) -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let reply: Arc<Mutex<Reply>> = Arc::new(Mutex::new(reply));
+
+        let streams = Arc::new(Mutex::new(Vec::new()));
+        let (up, listening) = (
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(AtomicBool::new(true)),
+        );
+
+        let (received, opened) = (Arc::clone(&commands), Arc::clone(&streams));
+        let (should_listen, is_listening) = (Arc::clone(&up), Arc::clone(&listening));
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let mut listener = Some(listener);
+            loop {
+                // The listener is closed while the server is stopped, so that
+                // connecting to it is refused.
+                if !should_listen.load(Ordering::SeqCst) {
+                    listener = None;
+                    is_listening.store(false, Ordering::SeqCst);
+                } else if listener.is_none() {
+                    listener = TcpListener::bind(addr).ok();
+                    if let Some(listener) = &listener {
+                        listener.set_nonblocking(true).unwrap();
+                        is_listening.store(true, Ordering::SeqCst);
+                    }
+                }
+                let stream = match listener.as_ref().map(TcpListener::accept) {
+                    Some(Ok((stream, _))) => stream,
+                    Some(Err(error)) if error.kind() != io::ErrorKind::WouldBlock => {
+                        return
+                    }
+                    _ => {
+                        thread::sleep(Duration::from_millis(1));
+                        continue;
+                    }
+                };
+                stream.set_nonblocking(false).unwrap();
+                opened.lock().unwrap().push(stream.try_clone().unwrap());
+                let (received, reply) = (Arc::clone(&received), Arc::clone(&reply));
+                thread::spawn(move || serve(stream, received, reply));
+            }
+        });
+        FakeRedis {
+            addr,
+            commands,
+            streams,
+            up,
+            listening,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the commands received so far, each with its name first.
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    /// Sends a RESP3 push message to every client that's connected.
+    pub fn push(&self, message: Vec<Value>) {
+        let mut out = Vec::new();
+        write_value(&Value::Push(message), &mut out);
+        for stream in self.streams.lock().unwrap().iter_mut() {
+            let _ = stream.write_all(&out);
+        }
+    }
+
+    /// Closes every client's connection, like a server that restarts.
+    pub fn disconnect(&self) {
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Closes every client's connection and stops listening, so that new
+    /// connections are refused until `restart`.
+    pub fn stop(&self) {
+        self.up.store(false, Ordering::SeqCst);
+        self.wait_until_listening(false);
+        self.disconnect();
+    }
+
+    /// Listens again on the same address after `stop`.
+    pub fn restart(&self) {
+        self.up.store(true, Ordering::SeqCst);
+        self.wait_until_listening(true);
+    }
+
+    fn wait_until_listening(&self, listening: bool) {
+        while self.listening.load(Ordering::SeqCst) != listening {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    received: Arc<Mutex<Vec<Vec<String>>>>,
+    reply: Arc<Mutex<Reply>>,
+) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    while let Ok(Value::Array(args)) = read_value(&mut reader) {
+        let command: Vec<_> = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Bulk(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                _ => panic!("command arguments should be bulk strings"),
+            })
+            .collect();
+        received.lock().unwrap().push(command.clone());
+
+        let mut out = Vec::new();
+        write_value(&(reply.lock().unwrap())(&command), &mut out);
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+/// The reply that Redis gives to `command` on an empty database.
+pub fn default_reply(command: &[String]) -> Value {
+    match command[0].as_str() {
+        "INCRBYFLOAT" => Value::Bulk(command[2].as_bytes().to_vec()),
+        "INCRBY" => Value::Integer(command[2].parse().unwrap()),
+        "SADD" | "PFADD" => Value::Integer(1),
+        "ZADD" => Value::Integer((command.len() as i64 - 2) / 2),
+        _ => Value::Status(String::from("OK")),
+    }
+}
+
+pub fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Nil => out.extend_from_slice(b"$-1\r\n"),
+        Value::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+        Value::Error(s) => out.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+        Value::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+        Value::Bulk(bytes) => {
+            out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+            out.extend_from_slice(bytes);
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::Array(values) | Value::Push(values) => {
+            let kind = if matches!(value, Value::Push(_)) {
+                '>'
+            } else {
+                '*'
+            };
+            out.extend_from_slice(format!("{}{}\r\n", kind, values.len()).as_bytes());
+            for value in values {
+                write_value(value, out);
+            }
+        }
+        Value::Double(d) => out.extend_from_slice(format!(",{}\r\n", d).as_bytes()),
+        Value::Boolean(b) => {
+            out.extend_from_slice(if *b { b"#t\r\n" } else { b"#f\r\n" })
+        }
+        Value::Map(pairs) => {
+            out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+            for (key, value) in pairs {
+                write_value(key, out);
+                write_value(value, out);
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+-- Computes statistics over the observations of a timer, which are the scores
+-- of the sorted set KEYS[1]. Stores them in the hash KEYS[2] and returns them
+-- as a flat list of field names and values.
+--
+-- Percentiles use the nearest-rank method. Values are returned as strings,
+-- because Redis would truncate Lua numbers to integers.
+local scores = redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')
+local count = #scores / 2
+if count == 0 then
+  return {}
+end
+
+local function percentile(p)
+  local rank = math.max(math.ceil(p / 100 * count), 1)
+  return scores[rank * 2]
+end
+
+local sum = 0
+for i = 2, #scores, 2 do
+  sum = sum + tonumber(scores[i])
+end
+
+local stats = {
+  'count', tostring(count),
+  'min', scores[2],
+  'max', scores[#scores],
+  'mean', tostring(sum / count),
+  'p50', percentile(50),
+  'p90', percentile(90),
+  'p99', percentile(99),
+}
+redis.call('DEL', KEYS[2])
+redis.call('HSET', KEYS[2], unpack(stats))
+return stats
@@ -0,0 +1,411 @@
+//! Stores metrics in [RedisTimeSeries][ts] series, which keep a sample per
+//! flush and can be queried over any range of time (e.g. by Grafana's Redis
+//! data source) instead of one window at a time. It needs the RedisTimeSeries
+//! module, which Redis Stack includes.
+//!
+//! Each flush writes one sample to each series that was recorded to since the
+//! last one, all with the flush's time, in a single `TS.MADD`:
+//!
+//! * Counters and meters get their total over the flush interval.
+//! * Gauges get their latest value, with deltas applied to the value that the
+//!   backend last saw.
+//! * Sets get the number of unique members recorded over the interval.
+//! * Samples, histograms, and distributions get a series for each of their
+//!   statistics over the interval (e.g. "timers:glork:p99"), like the ones in
+//!   `TimerStats`.
+//!
+//! Key/values aren't numbers, so they're not stored.
+//!
+//! Series are created with `TS.CREATE` the first time that the backend writes
+//! to them, with labels that series can be found by with `TS.MRANGE`'s
+//! filters: "name" and "type" (e.g. "name=glork type=timers"), "stat" for
+//! timers' statistics, and the metric's tags. A tag without a value gets a
+//! label of "true", and tags named after one of the other labels are left out.
+//!
+//! [ts]: https://redis.io/docs/stack/timeseries/
+
+use super::connection::{Client, ReconnectingConnection, SharedCredentials};
+use super::keys::with_tags;
+use super::redis::{float_value, format_float, scaled_value, type_prefix};
+use super::resp::{Command, Value};
+use super::{Backend, BackendError};
+use crate::parser::{GaugeMode, Metric, MetricId, MetricType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The labels that the backend sets itself, which tags can't override.
+const RESERVED_LABELS: [&str; 3] = ["name", "type", "stat"];
+
+/// Configuration for `TimeSeriesBackend`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeSeriesConfig {
+    /// Prepended to every key.
+    pub prefix: String,
+
+    /// How long the samples of the series that the backend creates are kept.
+    /// `None` leaves it to the module's default, which keeps them forever.
+    pub retention: Option<Duration>,
+
+    /// What happens when a sample is written to a series at a time that it
+    /// already has a sample for, such as when two servers flush at the same
+    /// millisecond.
+    pub duplicate_policy: DuplicatePolicy,
+
+    /// The credentials that connections authenticate with, if the server
+    /// needs them (see `RedisConfig::credentials`).
+    pub credentials: Option<SharedCredentials>,
+}
+
+/// How a series handles a sample for a time that it already has one for.
+/// See `TS.CREATE`'s `DUPLICATE_POLICY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// The new sample is rejected.
+    Block,
+
+    /// The existing sample is kept.
+    First,
+
+    /// The new sample replaces the existing one.
+    #[default]
+    Last,
+
+    /// The lower of the two is kept.
+    Min,
+
+    /// The higher of the two is kept.
+    Max,
+
+    /// The two are added together.
+    Sum,
+}
+
+impl DuplicatePolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            DuplicatePolicy::Block => "BLOCK",
+            DuplicatePolicy::First => "FIRST",
+            DuplicatePolicy::Last => "LAST",
+            DuplicatePolicy::Min => "MIN",
+            DuplicatePolicy::Max => "MAX",
+            DuplicatePolicy::Sum => "SUM",
+        }
+    }
+}
+
+/// TimeSeriesBackend writes metrics to RedisTimeSeries series.
+pub struct TimeSeriesBackend {
+    connection: ReconnectingConnection,
+    config: TimeSeriesConfig,
+
+    // The totals of the counters and meters recorded since the last flush.
+    counters: BTreeMap<MetricId, f64>,
+
+    // The latest value of every gauge that's been recorded, and which of
+    // them were recorded since the last flush.
+    gauges: BTreeMap<MetricId, f64>,
+    updated_gauges: BTreeSet<MetricId>,
+
+    // The members of the sets recorded since the last flush.
+    sets: BTreeMap<MetricId, BTreeSet<String>>,
+
+    // The observations of the samples, histograms, and distributions recorded
+    // since the last flush.
+    timers: BTreeMap<MetricId, Vec<f64>>,
+
+    // The keys of the series that the backend has created, or found to exist
+    // already.
+    created: BTreeSet<String>,
+}
+
+// A sample to be written to a series, along with the labels that the series
+// is created with.
+struct Sample {
+    key: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl TimeSeriesBackend {
+    /// Connects to the Redis server at `addr` (e.g. "127.0.0.1:6379").
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        config: TimeSeriesConfig,
+    ) -> Result<TimeSeriesBackend, BackendError> {
+        Ok(TimeSeriesBackend {
+            connection: ReconnectingConnection::to(addr, config.credentials.clone())?
+                .opened()?,
+            config,
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            updated_gauges: BTreeSet::new(),
+            sets: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            created: BTreeSet::new(),
+        })
+    }
+
+    // Like `flush`, but with the time of the samples.
+    fn flush_at(&mut self, now: SystemTime) -> Result<(), BackendError> {
+        let samples = self.samples();
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let mut commands: Vec<_> = samples
+            .iter()
+            .filter(|sample| !self.created.contains(&sample.key))
+            .map(|sample| self.create(sample))
+            .collect();
+        let creates = commands.len();
+        let timestamp = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis())
+            .to_string();
+        commands.push(
+            samples
+                .iter()
+                .fold(Command::new("TS.MADD"), |command, sample| {
+                    command
+                        .arg(&sample.key)
+                        .arg(&timestamp)
+                        .arg_owned(format_float(sample.value))
+                }),
+        );
+
+        let mut replies = self.connection.send(&commands)?;
+        let added = replies.pop();
+        for (command, reply) in commands[..creates].iter().zip(replies) {
+            match reply {
+                Value::Error(message) if !message.contains("already exists") => {
+                    return Err(BackendError::Server { message });
+                }
+                _ => {
+                    let key = String::from_utf8_lossy(&command.args()[1]).into_owned();
+                    self.created.insert(key);
+                }
+            }
+        }
+
+        // `TS.MADD` replies with the timestamp of each sample, or an error for
+        // the ones that couldn't be added.
+        let errors = match added {
+            Some(Value::Array(replies)) => replies,
+            Some(reply) => vec![reply],
+            None => Vec::new(),
+        };
+        for (sample, reply) in samples.iter().zip(errors) {
+            if let Value::Error(message) = reply {
+                // The series may have been deleted since it was created, so
+                // it's created again on the next flush.
+                self.created.remove(&sample.key);
+                return Err(BackendError::Server { message });
+            }
+        }
+        Ok(())
+    }
+
+    // Returns a sample for each series recorded to since the last flush, and
+    // resets them.
+    fn samples(&mut self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for (id, total) in mem::take(&mut self.counters) {
+            samples.push(self.sample(&id, None, total));
+        }
+        for id in mem::take(&mut self.updated_gauges) {
+            samples.push(self.sample(&id, None, self.gauges[&id]));
+        }
+        for (id, members) in mem::take(&mut self.sets) {
+            samples.push(self.sample(&id, None, members.len() as f64));
+        }
+        for (id, mut observations) in mem::take(&mut self.timers) {
+            for (stat, value) in stats(&mut observations) {
+                samples.push(self.sample(&id, Some(stat), value));
+            }
+        }
+        samples
+    }
+
+    fn sample(&self, id: &MetricId, stat: Option<&str>, value: f64) -> Sample {
+        let metric_type = type_prefix(id.metric_type());
+        let key = format!("{}{}:{}", self.config.prefix, metric_type, id.name());
+        let mut key = with_tags(key, id, true);
+
+        let mut labels = vec![
+            (String::from("name"), String::from(id.name())),
+            (String::from("type"), String::from(metric_type)),
+        ];
+        if let Some(stat) = stat {
+            key.push(':');
+            key.push_str(stat);
+            labels.push((String::from("stat"), String::from(stat)));
+        }
+        for tag in id.tags() {
+            if !RESERVED_LABELS.contains(&tag.key.as_str()) {
+                let value = tag.value.clone().unwrap_or_else(|| String::from("true"));
+                labels.push((tag.key.clone(), value));
+            }
+        }
+        Sample { key, labels, value }
+    }
+
+    fn create(&self, sample: &Sample) -> Command {
+        let mut command = Command::new("TS.CREATE").arg(&sample.key);
+        if let Some(retention) = self.config.retention {
+            command = command
+                .arg_static("RETENTION")
+                .arg_owned(retention.as_millis().to_string());
+        }
+        command = command
+            .arg_static("DUPLICATE_POLICY")
+            .arg(self.config.duplicate_policy.as_str());
+        command = command.arg_static("LABELS");
+        for (label, value) in &sample.labels {
+            command = command.arg(label).arg(value);
+        }
+        command
+    }
+}
+
+impl Backend for TimeSeriesBackend {
+    /// Adds metrics to their state in memory to be written on `flush`.
+    fn record(&mut self, metrics: &[Metric]) -> Result<(), BackendError> {
+        for metric in metrics {
+            let id = metric.id();
+            match metric.metric_type() {
+                MetricType::Counter | MetricType::Meter => {
+                    *self.counters.entry(id).or_insert(0.0) += scaled_value(metric);
+                }
+                MetricType::Gauge => {
+                    let value = match metric.gauge_mode() {
+                        Some(GaugeMode::Delta(_)) => {
+                            self.gauges.get(&id).copied().unwrap_or(0.0)
+                                + float_value(metric)
+                        }
+                        _ => f64::from_str(metric.value()).unwrap_or(0.0),
+                    };
+                    self.gauges.insert(id.clone(), value);
+                    self.updated_gauges.insert(id);
+                }
+                MetricType::Set => {
+                    self.sets
+                        .entry(id)
+                        .or_default()
+                        .insert(String::from(metric.value()));
+                }
+                MetricType::Sample | MetricType::Histogram | MetricType::Distribution => {
+                    self.timers.entry(id).or_default().push(float_value(metric));
+                }
+                MetricType::KeyValue => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a sample for each series recorded to since the last flush,
+    /// creating the series that the backend hasn't written to before. As with
+    /// `RedisBackend`, what was recorded is reset even if writing fails.
+    fn flush(&mut self) -> Result<(), BackendError> {
+        self.flush_at(SystemTime::now())
+    }
+}
+
+// Returns the statistics of a timer's observations, which there must be at
+// least one of, by name. The observations are sorted.
+pub(super) fn stats(observations: &mut [f64]) -> [(&'static str, f64); 7] {
+    observations.sort_by(f64::total_cmp);
+    let count = observations.len() as f64;
+    let sum: f64 = observations.iter().sum();
+    [
+        ("count", count),
+        ("min", observations[0]),
+        ("max", observations[observations.len() - 1]),
+        ("mean", sum / count),
+        ("p50", percentile(observations, 50.0)),
+        ("p90", percentile(observations, 90.0)),
+        ("p99", percentile(observations, 99.0)),
+    ]
+}
+
+// Returns the `p`th percentile of sorted observations by the nearest-rank
+// method.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::parser::{parse, ParserConfig};
+
+    #[test]
+    fn it_writes_samples() {
+        let redis = FakeRedis::with_replies(|command| match command[0].as_str() {
+            "TS.CREATE" if command[1] == "gauges:gaugor" => {
+                Value::Error(String::from("ERR TSDB: key already exists"))
+            }
+            "TS.MADD" => {
+                Value::Array(vec![Value::Integer(1656581405000); command.len() / 3])
+            }
+            _ => Value::Status(String::from("OK")),
+        });
+        let config = TimeSeriesConfig {
+            retention: Some(Duration::from_secs(86400)),
+            ..TimeSeriesConfig::default()
+        };
+        let mut backend = TimeSeriesBackend::connect(redis.addr(), config).unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1656581405);
+        let mut flush = |input: &[u8]| {
+            backend
+                .record(&parse(input, &ParserConfig::default()).unwrap().metrics)
+                .unwrap();
+            backend.flush_at(at).unwrap();
+        };
+        flush(
+            b"gorets:1|c|#env:production,canary\n\
+            gorets:2|c|@0.5|#env:production,canary\ngaugor:333|g\nuniques:765|s\n\
+            uniques:abc|s\nuniques:765|s\nconfig.version:1.2.3|kv",
+        );
+        flush(b"gaugor:-10|g\nglork:320|ms\nglork:240|ms");
+
+        let commands: Vec<_> =
+            redis.commands().into_iter().map(|c| c.join(" ")).collect();
+        let create = |key: &str, labels: &str| {
+            format!(
+                "TS.CREATE {} RETENTION 86400000 DUPLICATE_POLICY LAST LABELS {}",
+                key, labels
+            )
+        };
+        let mut expected = vec![
+            create(
+                "counters:gorets;canary;env=production",
+                "name gorets type counters canary true env production",
+            ),
+            create("gauges:gaugor", "name gaugor type gauges"),
+            create("sets:uniques", "name uniques type sets"),
+            String::from(
+                "TS.MADD counters:gorets;canary;env=production 1656581405000 5 \
+                gauges:gaugor 1656581405000 333 sets:uniques 1656581405000 2",
+            ),
+        ];
+        for stat in ["count", "min", "max", "mean", "p50", "p90", "p99"] {
+            expected.push(create(
+                &format!("timers:glork:{}", stat),
+                &format!("name glork type timers stat {}", stat),
+            ));
+        }
+        expected.push(String::from(
+            "TS.MADD gauges:gaugor 1656581405000 323 \
+            timers:glork:count 1656581405000 2 timers:glork:min 1656581405000 240 \
+            timers:glork:max 1656581405000 320 timers:glork:mean 1656581405000 280 \
+            timers:glork:p50 1656581405000 240 timers:glork:p90 1656581405000 320 \
+            timers:glork:p99 1656581405000 320",
+        ));
+        assert_eq!(commands, expected);
+    }
+}
@@ -0,0 +1,230 @@
+//! TLS for connections to `rediss://` URLs, which managed Redis offerings
+//! often require. Connections are encrypted with rustls, which is only built
+//! with the `tls` feature.
+
+#[cfg(feature = "tls")]
+use super::BackendError;
+#[cfg(feature = "tls")]
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+/// Configuration for connections over TLS.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    /// A PEM file of the certificate authorities that servers' certificates
+    /// are verified against. If `None`, the Mozilla root certificates (from
+    /// webpki-roots) are trusted, which is what managed offerings' certificates
+    /// are usually issued by.
+    pub ca_file: Option<PathBuf>,
+
+    /// A PEM file of a certificate chain that's presented to servers that
+    /// authenticate clients by their certificates (`tls-auth-clients`).
+    pub client_cert_file: Option<PathBuf>,
+
+    /// A PEM file of the private key of `client_cert_file`.
+    pub client_key_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "tls")]
+pub type TlsStream = rustls::StreamOwned<rustls::ClientConnection, TcpStream>;
+
+/// Completes a TLS handshake with the server at the other end of `stream`,
+/// whose certificate has to be valid for `host`.
+#[cfg(feature = "tls")]
+pub fn connect(
+    mut stream: TcpStream,
+    host: &str,
+    config: &TlsConfig,
+) -> Result<TlsStream, BackendError> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::sync::Arc;
+
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(path) => {
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| invalid(format!("invalid CA certificate: {}", e)))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_root_certificates(roots);
+    let client_config = match (&config.client_cert_file, &config.client_key_file) {
+        (Some(cert_path), Some(key_path)) => {
+            let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+                invalid(format!("couldn't read {}: {}", key_path.display(), e))
+            })?;
+            builder
+                .with_client_auth_cert(read_certs(cert_path)?, key)
+                .map_err(|e| invalid(format!("invalid client certificate: {}", e)))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(invalid(String::from(
+                "a client certificate needs a key, and vice versa",
+            )))
+        }
+    };
+
+    let name = ServerName::try_from(String::from(host))
+        .map_err(|_| invalid(format!("invalid server name {}", host)))?;
+    let mut connection = ClientConnection::new(Arc::new(client_config), name)
+        .map_err(|e| invalid(e.to_string()))?;
+    // Handshake now rather than on the first command, so that a certificate
+    // that isn't trusted is an error connecting.
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+#[cfg(feature = "tls")]
+fn read_certs(
+    path: &std::path::Path,
+) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, BackendError> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect())
+        .map_err(|e| invalid(format!("couldn't read {}: {}", path.display(), e)))
+}
+
+#[cfg(feature = "tls")]
+fn invalid(message: String) -> BackendError {
+    BackendError::Config { message }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+    use crate::backend::connection::{Client, Connection};
+    use crate::backend::resp::{read_value, Command, Value};
+    use crate::backend::testing::write_value;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+    use std::fs;
+    use std::io::{BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn issue(name: &str, ca: Option<&CertifiedKey>) -> CertifiedKey {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![String::from(name)]).unwrap();
+        let cert = match ca {
+            Some(ca) => params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap(),
+            None => {
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params.self_signed(&key_pair).unwrap()
+            }
+        };
+        CertifiedKey { cert, key_pair }
+    }
+
+    // Starts a server for "localhost" that requires clients to have a
+    // certificate issued by `ca`, and answers every command with OK.
+    fn server(ca: &CertifiedKey) -> SocketAddr {
+        let cert = issue("localhost", Some(ca));
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::clone(&provider),
+        )
+        .build()
+        .unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![cert.cert.der().clone()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let connection = ServerConnection::new(Arc::clone(&config)).unwrap();
+                let mut reader =
+                    BufReader::new(StreamOwned::new(connection, stream.unwrap()));
+                thread::spawn(move || {
+                    while let Ok(Value::Array(_)) = read_value(&mut reader) {
+                        let mut out = Vec::new();
+                        write_value(&Value::Status(String::from("OK")), &mut out);
+                        if reader.get_mut().write_all(&out).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn it_connects_over_tls() {
+        let ca = issue("metrics CA", None);
+        let client = issue("metrics", Some(&ca));
+        let addr = server(&ca);
+
+        let dir = std::env::temp_dir()
+            .join(format!("redis-metrics-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (ca_file, client_cert_file, client_key_file) = (
+            dir.join("ca.pem"),
+            dir.join("client.pem"),
+            dir.join("client.key"),
+        );
+        fs::write(&ca_file, ca.cert.pem()).unwrap();
+        fs::write(&client_cert_file, client.cert.pem()).unwrap();
+        fs::write(&client_key_file, client.key_pair.serialize_pem()).unwrap();
+
+        let url = format!("rediss://localhost:{}", addr.port());
+        let ping = |config: &TlsConfig| {
+            Connection::open(&url, config)
+                .and_then(|mut c| c.query(&Command::new("PING")))
+        };
+        let config = TlsConfig {
+            ca_file: Some(ca_file.clone()),
+            client_cert_file: Some(client_cert_file),
+            client_key_file: Some(client_key_file),
+        };
+        assert_eq!(ping(&config), Ok(Value::Status(String::from("OK"))));
+
+        // The server's certificate isn't issued by a trusted CA.
+        let untrusted = TlsConfig {
+            ca_file: None,
+            ..config.clone()
+        };
+        assert!(matches!(ping(&untrusted), Err(BackendError::Io { .. })));
+
+        // The client doesn't have a certificate.
+        let anonymous = TlsConfig {
+            ca_file: Some(ca_file),
+            ..TlsConfig::default()
+        };
+        assert!(matches!(ping(&anonymous), Err(BackendError::Io { .. })));
+
+        let missing = TlsConfig {
+            ca_file: Some(dir.join("missing.pem")),
+            ..config
+        };
+        assert!(matches!(ping(&missing), Err(BackendError::Config { .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Encodes metrics back into the StatsD line format that they were parsed
+//! from. Any metric produced by the parser can be encoded and parsed again
+//! without loss, which is what allows metrics to be relayed to another StatsD
+//! server. `roundtrip_check` verifies that property for a given metric.
+//!
+//! Events, service checks, and whole batches can be encoded too.
+
+use crate::parser::{
+    Batch, Event, EventAlertType, EventPriority, Metric, MetricSign, MetricType,
+    ParseError, ServiceCheck, ServiceCheckStatus, Tag,
+};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use core::fmt;
+use thiserror::Error;
+
+/// Encodes the metric as a single StatsD line (without a trailing newline).
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.name)?;
+        match self.sign {
+            Some(MetricSign::Minus) => write!(f, "-")?,
+            Some(MetricSign::Plus) => write!(f, "+")?,
+            None => (),
+        }
+        write!(f, "{}|{}", self.value, type_code(self))?;
+        if let Some(rate) = self.sample_rate {
+            write!(f, "|@{}", rate)?;
+        }
+        write_tags(f, &self.tags)?;
+        if let Some(ref id) = self.container_id {
+            write!(f, "|c:{}", id)?;
+        }
+        if let Some(timestamp) = self.timestamp {
+            write!(f, "|T{}", timestamp)?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the event as a single DogStatsD event line.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = self.text.replace('\n', "\\n");
+        write!(
+            f,
+            "_e{{{},{}}}:{}|{}",
+            self.title.len(),
+            text.len(),
+            self.title,
+            text
+        )?;
+        if let Some(timestamp) = self.timestamp {
+            write!(f, "|d:{}", timestamp)?;
+        }
+        if let Some(ref hostname) = self.hostname {
+            write!(f, "|h:{}", hostname)?;
+        }
+        if let Some(ref key) = self.aggregation_key {
+            write!(f, "|k:{}", key)?;
+        }
+        if let Some(priority) = self.priority {
+            write!(
+                f,
+                "|p:{}",
+                match priority {
+                    EventPriority::Low => "low",
+                    EventPriority::Normal => "normal",
+                }
+            )?;
+        }
+        if let Some(ref source) = self.source_type_name {
+            write!(f, "|s:{}", source)?;
+        }
+        if let Some(alert_type) = self.alert_type {
+            write!(
+                f,
+                "|t:{}",
+                match alert_type {
+                    EventAlertType::Error => "error",
+                    EventAlertType::Info => "info",
+                    EventAlertType::Success => "success",
+                    EventAlertType::Warning => "warning",
+                }
+            )?;
+        }
+        write_tags(f, &self.tags)
+    }
+}
+
+/// Encodes the service check as a single DogStatsD service check line.
+impl fmt::Display for ServiceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            ServiceCheckStatus::Ok => 0,
+            ServiceCheckStatus::Warning => 1,
+            ServiceCheckStatus::Critical => 2,
+            ServiceCheckStatus::Unknown => 3,
+        };
+        write!(f, "_sc|{}|{}", self.name, status)?;
+        if let Some(timestamp) = self.timestamp {
+            write!(f, "|d:{}", timestamp)?;
+        }
+        if let Some(ref hostname) = self.hostname {
+            write!(f, "|h:{}", hostname)?;
+        }
+        write_tags(f, &self.tags)?;
+        if let Some(ref message) = self.message {
+            // The message must come last because it may contain "|".
+            write!(f, "|m:{}", message.replace('\n', "\\n"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the batch as a payload of "\n" delimited lines: its metrics, then
+/// its events, then its service checks. Diagnostics aren't encoded.
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines = self
+            .metrics
+            .iter()
+            .map(|m| m as &dyn fmt::Display)
+            .chain(self.events.iter().map(|e| e as &dyn fmt::Display))
+            .chain(self.service_checks.iter().map(|c| c as &dyn fmt::Display));
+        for (i, line) in lines.enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors produced by `roundtrip_check` when a metric doesn't survive being
+/// encoded and parsed again.
+#[derive(Debug, Error, PartialEq)]
+pub enum RoundtripError {
+    /// The encoded metric couldn't be parsed.
+    #[error("encoded metric \"{line}\" failed to parse: {error}")]
+    Parse { line: String, error: ParseError },
+
+    /// The encoded metric parsed into a different metric.
+    #[error("encoded metric \"{line}\" parsed into a different metric: {parsed:?}")]
+    Mismatch { line: String, parsed: Box<Metric> },
+}
+
+/// Checks that encoding the metric and parsing the result produces a metric
+/// identical to the original.
+pub fn roundtrip_check(metric: &Metric) -> Result<(), RoundtripError> {
+    let line = metric.to_string();
+    match Metric::try_from(line.as_str()) {
+        Ok(ref parsed) if parsed == metric => Ok(()),
+        Ok(parsed) => Err(RoundtripError::Mismatch {
+            line,
+            parsed: Box::new(parsed),
+        }),
+        Err(error) => Err(RoundtripError::Parse { line, error }),
+    }
+}
+
+fn write_tags(f: &mut fmt::Formatter, tags: &[Tag]) -> fmt::Result {
+    for (i, tag) in tags.iter().enumerate() {
+        write!(f, "{}{}", if i == 0 { "|#" } else { "," }, tag.key)?;
+        if let Some(ref value) = tag.value {
+            write!(f, ":{}", value)?;
+        }
+    }
+    Ok(())
+}
+
+fn type_code(metric: &Metric) -> &str {
+    match metric.metric_type {
+        MetricType::Counter => "c",
+        MetricType::Gauge => "g",
+        MetricType::Sample => metric.unit.as_ref().map_or("ms", |u| u.code()),
+        MetricType::Set => "s",
+        MetricType::KeyValue => "kv",
+        MetricType::Histogram => "h",
+        MetricType::Distribution => "d",
+        MetricType::Meter => "m",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_metrics() {
+        let lines = [
+            "gorets:1|c",
+            "gorets:-5|c|@0.1",
+            "glork:320|ms|@0.1",
+            "gaugor:+4|g",
+            "uniques:765|s",
+            "config.version:1.2.3|kv",
+            "page.views:1|c|@0.5|#env:production,canary",
+            "request.size:512|h|@0.5",
+            "request.latency:42.5|d",
+            "page.views:1|c|#env:production|c:83c0a9",
+            "page.views:1|c|T1656581400",
+            "requests:1|m|@0.5",
+        ];
+        for line in lines.iter() {
+            assert_eq!(Metric::try_from(*line).unwrap().to_string(), *line);
+        }
+    }
+
+    #[test]
+    fn it_encodes_batches() {
+        let lines = [
+            "gorets:1|c",
+            "glork:320|ms|@0.1",
+            "_e{6,13}:deploy|web\\nfinished|d:1500000000|h:web.1|k:deploys|p:low|s:git|\
+                t:success|#env:prod",
+            "_e{6,0}:deploy|",
+            "_sc|redis.can_connect|2|d:1500000000|h:cache.1|#env:prod|\
+                m:timed out\\n|retrying",
+            "_sc|redis.can_connect|0",
+        ];
+        let payload = lines.join("\n");
+        assert_eq!(
+            Batch::try_from(payload.as_str()).unwrap().to_string(),
+            payload
+        );
+    }
+
+    #[test]
+    fn it_roundtrips_metrics() {
+        let metric = Metric::try_from("glork:320|ms|@0.1").unwrap();
+        assert_eq!(roundtrip_check(&metric), Ok(()));
+
+        // A name containing a delimiter is legal to construct, but can't be
+        // encoded losslessly.
+        let mut metric = Metric::try_from("gorets:1|c").unwrap();
+        metric.name = String::from("gor:ets");
+        assert!(roundtrip_check(&metric).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "proptest"))]
+mod proptests {
+    use super::*;
+    use crate::strategies;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn it_roundtrips_generated_metrics(metric in strategies::metric()) {
+            prop_assert_eq!(roundtrip_check(&metric), Ok(()));
+        }
+    }
+}
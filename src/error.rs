@@ -0,0 +1,51 @@
+//! The crate-wide error type. Each subsystem has its own more specific error
+//! type, all of which convert into `Error` so that callers working across
+//! subsystems can handle them uniformly with `?`.
+
+#[cfg(feature = "std")]
+use crate::backend::BackendError;
+use crate::parser::ParseError;
+#[cfg(feature = "server")]
+use crate::server::ServerError;
+
+/// All errors that may be produced by this crate.
+#[derive(Debug, thiserror::Error, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A payload failed to parse or was rejected by the parser's
+    /// configuration.
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+
+    /// Metrics couldn't be stored in a backend.
+    #[cfg(feature = "std")]
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+
+    /// An embedded server failed.
+    #[cfg(feature = "server")]
+    #[error(transparent)]
+    Server(#[from] ServerError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, ParseError, ParserConfig};
+
+    #[test]
+    fn it_converts_parse_errors() {
+        fn parse_all(input: &[u8]) -> Result<usize, Error> {
+            Ok(parser::parse(input, &ParserConfig::default())?
+                .metrics
+                .len())
+        }
+
+        assert_eq!(parse_all(b"gorets:1|c"), Ok(1));
+        assert_eq!(parse_all(b""), Err(Error::Parse(ParseError::Invalid)));
+        assert_eq!(
+            Error::from(ParseError::Invalid).to_string(),
+            "invalid StatsD payload"
+        );
+    }
+}
@@ -0,0 +1,445 @@
+//! C bindings for parsing and encoding metrics, and for recording them to
+//! Redis with `RedisBackend`, so that C and C++ services can share this parser
+//! and client. Only available with the `ffi` feature. The matching
+//! declarations are in `include/redis_metrics.h`, and the `redis-metrics-ffi`
+//! crate's cdylib exports them.
+//!
+//! Metrics and clients are handed to C as opaque pointers that must be
+//! released with `redis_metrics_metric_free` and `redis_metrics_client_free`.
+//! Strings returned by accessors point into the metric, are not
+//! NUL-terminated, and are only valid until it's freed.
+
+use crate::backend::{Backend, RedisBackend};
+use crate::parser::{Metric, MetricSign, MetricType};
+use libc::{c_char, c_double, c_int, size_t};
+use std::ffi::CStr;
+use std::ptr;
+use std::slice;
+
+/// Metric types as exposed to C.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedisMetricsType {
+    Counter = 0,
+    Gauge = 1,
+    Sample = 2,
+    Set = 3,
+    KeyValue = 4,
+    Histogram = 5,
+    Distribution = 6,
+    Meter = 7,
+}
+
+/// Parses a single StatsD line of `len` bytes. Returns NULL if the line isn't
+/// a valid metric.
+///
+/// # Safety
+///
+/// `line` must point to at least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_parse_line(
+    line: *const c_char,
+    len: size_t,
+) -> *mut Metric {
+    if line.is_null() {
+        return ptr::null_mut();
+    }
+    let input = slice::from_raw_parts(line as *const u8, len);
+    match Metric::try_from(input) {
+        Ok(metric) => Box::into_raw(Box::new(metric)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Frees a metric returned by `redis_metrics_parse_line`. Passing NULL is a
+/// no-op.
+///
+/// # Safety
+///
+/// `metric` must be NULL or a pointer returned by `redis_metrics_parse_line`
+/// that hasn't already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_free(metric: *mut Metric) {
+    if !metric.is_null() {
+        drop(Box::from_raw(metric));
+    }
+}
+
+/// Returns the metric's name and stores its length in `len`.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_name(
+    metric: *const Metric,
+    len: *mut size_t,
+) -> *const c_char {
+    export_str(&(*metric).name, len)
+}
+
+/// Returns the metric's value (without its sign) and stores its length in
+/// `len`.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_value(
+    metric: *const Metric,
+    len: *mut size_t,
+) -> *const c_char {
+    export_str(&(*metric).value, len)
+}
+
+/// Returns the metric's unit and stores its length in `len`, or returns NULL
+/// if it doesn't have one.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_unit(
+    metric: *const Metric,
+    len: *mut size_t,
+) -> *const c_char {
+    match (*metric).unit {
+        Some(ref unit) => export_str(unit.code(), len),
+        None => ptr::null(),
+    }
+}
+
+/// Returns the metric's container ID and stores its length in `len`, or
+/// returns NULL if it doesn't have one.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_container_id(
+    metric: *const Metric,
+    len: *mut size_t,
+) -> *const c_char {
+    match (*metric).container_id {
+        Some(ref id) => export_str(id, len),
+        None => ptr::null(),
+    }
+}
+
+/// Returns the metric's type.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_type(
+    metric: *const Metric,
+) -> RedisMetricsType {
+    match (*metric).metric_type {
+        MetricType::Counter => RedisMetricsType::Counter,
+        MetricType::Gauge => RedisMetricsType::Gauge,
+        MetricType::Sample => RedisMetricsType::Sample,
+        MetricType::Set => RedisMetricsType::Set,
+        MetricType::KeyValue => RedisMetricsType::KeyValue,
+        MetricType::Histogram => RedisMetricsType::Histogram,
+        MetricType::Distribution => RedisMetricsType::Distribution,
+        MetricType::Meter => RedisMetricsType::Meter,
+    }
+}
+
+/// Returns the metric's sample rate, or 1.0 if it isn't sampled.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_sample_rate(
+    metric: *const Metric,
+) -> c_double {
+    (*metric).sample_rate.unwrap_or(1.0)
+}
+
+/// Returns -1 if the metric's value has a minus sign, 1 if it has a plus sign,
+/// and 0 if it's unsigned.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_sign(metric: *const Metric) -> c_int {
+    match (*metric).sign {
+        Some(MetricSign::Minus) => -1,
+        Some(MetricSign::Plus) => 1,
+        None => 0,
+    }
+}
+
+/// Returns the number of tags on the metric.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_tag_count(metric: *const Metric) -> size_t {
+    let metric = &*metric;
+    metric.tags.len()
+}
+
+/// Reads the tag at `index` into `key`/`key_len` and `value`/`value_len`.
+/// `value` is set to NULL for a bare tag. Returns 0 on success and -1 if
+/// `index` is out of range.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and all out pointers must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_tag(
+    metric: *const Metric,
+    index: size_t,
+    key: *mut *const c_char,
+    key_len: *mut size_t,
+    value: *mut *const c_char,
+    value_len: *mut size_t,
+) -> c_int {
+    let metric = &*metric;
+    let tag = match metric.tags.get(index) {
+        Some(tag) => tag,
+        None => return -1,
+    };
+    *key = export_str(&tag.key, key_len);
+    *value = match tag.value {
+        Some(ref v) => export_str(v, value_len),
+        None => {
+            *value_len = 0;
+            ptr::null()
+        }
+    };
+    0
+}
+
+/// Encodes the metric as a StatsD line into `buf` with `snprintf` semantics:
+/// at most `buf_len - 1` bytes are written followed by a NUL, and the full
+/// length of the line is returned so that callers can detect truncation.
+///
+/// # Safety
+///
+/// `metric` must be a valid metric and `buf` must point to at least `buf_len`
+/// writable bytes (it may be NULL if `buf_len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_metric_encode(
+    metric: *const Metric,
+    buf: *mut c_char,
+    buf_len: size_t,
+) -> size_t {
+    let line = (*metric).to_string();
+    if buf_len > 0 {
+        let n = line.len().min(buf_len - 1);
+        ptr::copy_nonoverlapping(line.as_ptr(), buf as *mut u8, n);
+        *buf.add(n) = 0;
+    }
+    line.len()
+}
+
+/// Connects a client to the Redis server at `addr`, a NUL-terminated address
+/// like "127.0.0.1:6379". Returns NULL if it can't connect.
+///
+/// # Safety
+///
+/// `addr` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_client_connect(
+    addr: *const c_char,
+) -> *mut RedisBackend {
+    if addr.is_null() {
+        return ptr::null_mut();
+    }
+    let Ok(addr) = CStr::from_ptr(addr).to_str() else {
+        return ptr::null_mut();
+    };
+    match RedisBackend::connect(addr) {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Records a metric to be written on the next flush (or right away, for a
+/// key/value). Returns 0 on success and -1 if it couldn't be recorded.
+///
+/// # Safety
+///
+/// `client` must be a valid client and `metric` a valid metric.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_client_record(
+    client: *mut RedisBackend,
+    metric: *const Metric,
+) -> c_int {
+    match (*client).record(slice::from_ref(&*metric)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Writes the metrics recorded since the last flush. Returns 0 on success and
+/// -1 if they couldn't be written.
+///
+/// # Safety
+///
+/// `client` must be a valid client.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_client_flush(client: *mut RedisBackend) -> c_int {
+    match (*client).flush() {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Frees a client returned by `redis_metrics_client_connect` without flushing
+/// it. Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `client` must be NULL or a client that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn redis_metrics_client_free(client: *mut RedisBackend) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+unsafe fn export_str(s: &str, len: *mut size_t) -> *const c_char {
+    *len = s.len();
+    s.as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use std::ffi::CString;
+
+    unsafe fn read(s: *const c_char, len: size_t) -> String {
+        String::from_utf8(slice::from_raw_parts(s as *const u8, len).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn it_parses_and_encodes_through_ffi() {
+        unsafe {
+            let line = b"glork:320|ms|@0.1";
+            let metric =
+                redis_metrics_parse_line(line.as_ptr() as *const c_char, line.len());
+            assert!(!metric.is_null());
+
+            let mut len = 0;
+            let name = redis_metrics_metric_name(metric, &mut len);
+            assert_eq!(read(name, len), "glork");
+            let value = redis_metrics_metric_value(metric, &mut len);
+            assert_eq!(read(value, len), "320");
+            let unit = redis_metrics_metric_unit(metric, &mut len);
+            assert_eq!(read(unit, len), "ms");
+            assert_eq!(redis_metrics_metric_type(metric), RedisMetricsType::Sample);
+            assert_eq!(redis_metrics_metric_sample_rate(metric), 0.1);
+            assert_eq!(redis_metrics_metric_sign(metric), 0);
+
+            let mut buf = [0 as c_char; 8];
+            assert_eq!(
+                redis_metrics_metric_encode(metric, buf.as_mut_ptr(), buf.len()),
+                17
+            );
+            assert_eq!(read(buf.as_ptr(), 7), "glork:3");
+            assert_eq!(buf[7], 0);
+
+            redis_metrics_metric_free(metric);
+        }
+    }
+
+    #[test]
+    fn it_returns_null_for_invalid_lines() {
+        unsafe {
+            let line = b"gorets";
+            assert!(
+                redis_metrics_parse_line(line.as_ptr() as *const c_char, line.len())
+                    .is_null()
+            );
+            assert!(redis_metrics_parse_line(ptr::null(), 0).is_null());
+            redis_metrics_metric_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn it_reads_tags_through_ffi() {
+        unsafe {
+            let line = b"page.views:1|c|#env:production,canary";
+            let metric =
+                redis_metrics_parse_line(line.as_ptr() as *const c_char, line.len());
+            assert_eq!(redis_metrics_metric_tag_count(metric), 2);
+
+            let (mut key, mut key_len) = (ptr::null(), 0);
+            let (mut value, mut value_len) = (ptr::null(), 0);
+            assert_eq!(
+                redis_metrics_metric_tag(
+                    metric,
+                    0,
+                    &mut key,
+                    &mut key_len,
+                    &mut value,
+                    &mut value_len
+                ),
+                0
+            );
+            assert_eq!(read(key, key_len), "env");
+            assert_eq!(read(value, value_len), "production");
+            assert_eq!(
+                redis_metrics_metric_tag(
+                    metric,
+                    1,
+                    &mut key,
+                    &mut key_len,
+                    &mut value,
+                    &mut value_len
+                ),
+                0
+            );
+            assert_eq!(read(key, key_len), "canary");
+            assert!(value.is_null());
+            assert_eq!(
+                redis_metrics_metric_tag(
+                    metric,
+                    2,
+                    &mut key,
+                    &mut key_len,
+                    &mut value,
+                    &mut value_len
+                ),
+                -1
+            );
+
+            redis_metrics_metric_free(metric);
+        }
+    }
+
+    #[test]
+    fn it_records_through_ffi() {
+        let redis = FakeRedis::start();
+        let addr = CString::new(redis.addr().to_string()).unwrap();
+        unsafe {
+            let client = redis_metrics_client_connect(addr.as_ptr());
+            assert!(!client.is_null());
+            for line in [&b"gorets:1|c"[..], b"gorets:2|c"] {
+                let metric =
+                    redis_metrics_parse_line(line.as_ptr() as *const c_char, line.len());
+                assert_eq!(redis_metrics_client_record(client, metric), 0);
+                redis_metrics_metric_free(metric);
+            }
+            assert_eq!(redis_metrics_client_flush(client), 0);
+            redis_metrics_client_free(client);
+
+            let down = CString::new("127.0.0.1:1").unwrap();
+            assert!(redis_metrics_client_connect(down.as_ptr()).is_null());
+            assert!(redis_metrics_client_connect(ptr::null()).is_null());
+        }
+        assert_eq!(
+            redis.commands(),
+            vec![vec!["INCRBY", "stats.counters.gorets", "3"]]
+        );
+    }
+}
@@ -1,7 +1,22 @@
-#[macro_use]
-extern crate nom;
+// The parser and encoder only need `alloc`, so the standard library is
+// optional for environments that don't have one.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-mod parser;
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod backend;
+pub mod encoder;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod parser;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+
+pub use error::Error;
 
 #[cfg(test)]
 mod tests {
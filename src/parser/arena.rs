@@ -0,0 +1,67 @@
+//! Parses payloads into a caller-provided bump arena for servers that want to
+//! hold onto metrics after their receive buffer has been reused, without
+//! paying for a heap allocation per metric string.
+
+use super::{parse_ref, BatchRef, ParseError, ParserConfig};
+use alloc::borrow::Cow;
+use bumpalo::Bump;
+
+/// Like `parse_ref`, but copies `input` into `arena` first so that the
+/// returned metrics borrow from the arena rather than from `input`. A server
+/// can then reuse its receive buffer for the next packet and keep the metrics
+/// around until it resets the arena (e.g. once per flush), which frees all of
+/// their strings at once.
+///
+/// Names rewritten by `ParserConfig::sanitize_names` are moved into the arena
+/// too, so no metric's strings are left on the heap. Only the batch itself
+/// and each metric's list of tags are still heap allocated.
+pub fn parse_in<'a>(
+    arena: &'a Bump,
+    input: &[u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    let mut batch = parse_ref(arena.alloc_slice_copy(input), config)?;
+    for metric in &mut batch.metrics {
+        if let Cow::Owned(name) = &metric.name {
+            metric.name = Cow::Borrowed(arena.alloc_str(name));
+        }
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_into_arena() {
+        let mut arena = Bump::new();
+        let config = ParserConfig {
+            sanitize_names: true,
+            ..ParserConfig::default()
+        };
+
+        {
+            let mut buffer = b"gorets:1|c\nglork:320|ms|#env:production".to_vec();
+            let first = parse_in(&arena, &buffer, &config).unwrap();
+
+            // The receive buffer can be reused while the first batch lives on.
+            buffer.clear();
+            buffer.extend_from_slice(b"disk/sda1:333|g");
+            let second = parse_in(&arena, &buffer, &config).unwrap();
+
+            let names: Vec<_> = first
+                .metrics
+                .iter()
+                .chain(&second.metrics)
+                .map(|m| m.name.as_ref())
+                .collect();
+            assert_eq!(names, vec!["gorets", "glork", "disk-sda1"]);
+            assert!(matches!(second.metrics[0].name, Cow::Borrowed(_)));
+            assert_eq!(first.metrics[1].tags[0].value, Some("production"));
+        }
+
+        arena.reset();
+        assert_eq!(parse_in(&arena, b"", &config), Err(ParseError::Invalid));
+    }
+}
@@ -0,0 +1,204 @@
+//! Builds metrics by hand rather than by parsing them.
+
+use super::{
+    is_plain_value, is_valid_sample_rate, parse_sign, parse_value, Metric, MetricType,
+    ParseError, Tag, Unit,
+};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// MetricBuilder constructs a `Metric` field by field. It applies the same
+/// checks as the parser when it's built, so a built metric is always one that
+/// could have been parsed. See `Metric::builder`.
+#[derive(Clone, Debug, Default)]
+pub struct MetricBuilder {
+    name: String,
+    value: Option<String>,
+    metric_type: Option<MetricType>,
+    unit: Option<Unit>,
+    sample_rate: Option<f64>,
+    tags: Vec<Tag>,
+    container_id: Option<String>,
+    timestamp: Option<i64>,
+}
+
+impl MetricBuilder {
+    pub fn name(mut self, name: &str) -> MetricBuilder {
+        self.name = String::from(name);
+        self
+    }
+
+    /// Sets the metric's value as it would be sent (e.g. "320" or "-5"). A
+    /// leading sign is split off unless the metric is a key/value.
+    pub fn value(mut self, value: &str) -> MetricBuilder {
+        self.value = Some(String::from(value));
+        self
+    }
+
+    pub fn metric_type(mut self, metric_type: MetricType) -> MetricBuilder {
+        self.metric_type = Some(metric_type);
+        self
+    }
+
+    /// Sets the unit of a sample, which otherwise defaults to milliseconds.
+    /// Ignored for other metric types.
+    pub fn unit(mut self, unit: Unit) -> MetricBuilder {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: f64) -> MetricBuilder {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Adds a tag. Tags are kept in the order that they're added.
+    pub fn tag(mut self, key: &str, value: Option<&str>) -> MetricBuilder {
+        self.tags.push(Tag::new(key, value));
+        self
+    }
+
+    pub fn container_id(mut self, container_id: &str) -> MetricBuilder {
+        self.container_id = Some(String::from(container_id));
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: i64) -> MetricBuilder {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Builds the metric, returning the same error that the parser would for
+    /// a missing name, value, or type, an invalid value, or a sample rate
+    /// that's out of range or on a type that doesn't support one.
+    pub fn build(self) -> Result<Metric, ParseError> {
+        if self.name.is_empty() {
+            return Err(ParseError::EmptyName);
+        }
+        let value = match self.value {
+            Some(value) => value,
+            None => return Err(ParseError::MissingValue { name: self.name }),
+        };
+        let metric_type = match self.metric_type {
+            Some(metric_type) => metric_type,
+            None => return Err(ParseError::MissingType { name: self.name }),
+        };
+
+        let (value, sign) = match metric_type {
+            MetricType::KeyValue => (value.as_str(), None),
+            _ => parse_sign(&value),
+        };
+        if value.is_empty() {
+            return Err(ParseError::MissingValue { name: self.name });
+        }
+        // Built metrics are held to the default configuration's policies, which
+        // don't allow exponents or values that aren't finite.
+        let plain = is_plain_value(value) && parse_value(value, sign).is_some();
+        if metric_type.is_numeric() && !plain {
+            return Err(ParseError::InvalidValue {
+                name: self.name,
+                value: String::from(value),
+            });
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            if !metric_type.supports_sample_rate() {
+                return Err(ParseError::UnsupportedSampleRate {
+                    name: self.name,
+                    metric_type,
+                });
+            }
+            if !is_valid_sample_rate(sample_rate) {
+                return Err(ParseError::SampleRateOutOfRange {
+                    name: self.name,
+                    sample_rate,
+                });
+            }
+        }
+
+        Ok(Metric {
+            value: String::from(value),
+            metric_type,
+            unit: match metric_type {
+                MetricType::Sample => Some(self.unit.unwrap_or(Unit::Milliseconds)),
+                _ => None,
+            },
+            sample_rate: self.sample_rate,
+            sign,
+            tags: self.tags,
+            container_id: self.container_id,
+            timestamp: self.timestamp,
+            name: self.name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_metrics() {
+        let metric = Metric::builder()
+            .name("glork")
+            .value("320")
+            .metric_type(MetricType::Sample)
+            .sample_rate(0.1)
+            .tag("env", Some("production"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            metric,
+            Metric::try_from("glork:320|ms|@0.1|#env:production").unwrap()
+        );
+        assert_eq!(metric.name(), "glork");
+        assert_eq!(metric.unit(), Some(&Unit::Milliseconds));
+        assert_eq!(metric.tags(), &[Tag::new("env", Some("production"))]);
+
+        let metric = Metric::builder()
+            .name("gaugor")
+            .value("-4")
+            .metric_type(MetricType::Gauge)
+            .build();
+        assert_eq!(metric, Metric::try_from("gaugor:-4|g"));
+    }
+
+    #[test]
+    fn it_rejects_incomplete_metrics() {
+        let builder = Metric::builder();
+        assert_eq!(builder.clone().build(), Err(ParseError::EmptyName));
+
+        let builder = builder.name("gorets");
+        assert_eq!(
+            builder.clone().build(),
+            Err(ParseError::MissingValue {
+                name: String::from("gorets")
+            })
+        );
+        assert_eq!(
+            builder.clone().value("1").build(),
+            Err(ParseError::MissingType {
+                name: String::from("gorets")
+            })
+        );
+
+        let builder = builder.metric_type(MetricType::Counter);
+        assert_eq!(
+            builder.clone().value("x").build(),
+            Err(ParseError::InvalidValue {
+                name: String::from("gorets"),
+                value: String::from("x"),
+            })
+        );
+        assert_eq!(
+            builder
+                .value("1")
+                .metric_type(MetricType::Set)
+                .sample_rate(0.5)
+                .build(),
+            Err(ParseError::UnsupportedSampleRate {
+                name: String::from("gorets"),
+                metric_type: MetricType::Set,
+            })
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Parses DogStatsD events, which share a datagram with metrics but have a
+//! format of their own (wrapped here):
+//!
+//! ```text
+//! _e{title.length,text.length}:title|text|d:timestamp|h:hostname|p:priority
+//!     |t:alert_type|#tag1,tag2
+//! ```
+//!
+//! Lengths are in bytes. Newlines in an event's text are sent escaped as "\n"
+//! so that an event always fits on one line.
+
+use super::{parse_tags, ParseError, Tag};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+
+/// The prefix that distinguishes an event line from a metric.
+pub(super) const EVENT_PREFIX: &[u8] = b"_e{";
+
+/// Event is a DogStatsD event: a titled record of something that happened,
+/// like a deploy or an outage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// The event's title.
+    pub title: String,
+
+    /// The event's text, with escaped newlines restored.
+    pub text: String,
+
+    /// When the event happened as a Unix timestamp (the "d:" field), if the
+    /// client supplied one.
+    pub timestamp: Option<i64>,
+
+    /// The host that the event is associated with (the "h:" field).
+    pub hostname: Option<String>,
+
+    /// A key used to group related events together (the "k:" field).
+    pub aggregation_key: Option<String>,
+
+    /// The event's priority (the "p:" field).
+    pub priority: Option<EventPriority>,
+
+    /// The type of source that emitted the event (the "s:" field).
+    pub source_type_name: Option<String>,
+
+    /// The event's severity (the "t:" field).
+    pub alert_type: Option<EventAlertType>,
+
+    /// Tags attached to the event in the order that they were sent.
+    pub tags: Vec<Tag>,
+}
+
+/// Priorities that an event may be sent with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventPriority {
+    Low,
+    Normal,
+}
+
+/// Severities that an event may be sent with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventAlertType {
+    Error,
+    Info,
+    Success,
+    Warning,
+}
+
+// A decimal length in an event's header, returned along with the input that
+// follows it.
+fn length(input: &[u8]) -> Option<(usize, &[u8])> {
+    let digits = input.iter().take_while(|b| b.is_ascii_digit()).count();
+    let len = str::from_utf8(&input[..digits]).ok()?;
+    Some((usize::from_str(len).ok()?, &input[digits..]))
+}
+
+// The "_e{title.length,text.length}:" header of an event, returned along with
+// the input that follows it.
+fn header(line: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let (title_len, rest) = length(line.strip_prefix(EVENT_PREFIX)?)?;
+    let (text_len, rest) = length(rest.strip_prefix(b",")?)?;
+    Some((title_len, text_len, rest.strip_prefix(b"}:")?))
+}
+
+/// Parses a single event line (without its trailing newline).
+pub(super) fn parse_event(line: &[u8]) -> Result<Event, ParseError> {
+    let (title_len, text_len, rest) = header(line).ok_or(ParseError::Invalid)?;
+
+    // The title and text are located by their lengths rather than by
+    // delimiters, so they're free to contain "|". Lengths come from the
+    // client, so guard against them overflowing.
+    let end = title_len
+        .checked_add(1)
+        .and_then(|n| n.checked_add(text_len))
+        .ok_or(ParseError::Invalid)?;
+    if rest.len() < end || rest[title_len] != b'|' {
+        return Err(ParseError::Invalid);
+    }
+    let title = to_str(&rest[..title_len])?;
+    let text = to_str(&rest[title_len + 1..end])?;
+    let rest = &rest[end..];
+
+    let mut event = Event {
+        title: String::from(title),
+        text: text.replace("\\n", "\n"),
+        timestamp: None,
+        hostname: None,
+        aggregation_key: None,
+        priority: None,
+        source_type_name: None,
+        alert_type: None,
+        tags: Vec::new(),
+    };
+
+    if rest.is_empty() {
+        return Ok(event);
+    }
+    let rest = match rest.strip_prefix(b"|") {
+        Some(rest) => to_str(rest)?,
+        None => return Err(ParseError::Invalid),
+    };
+
+    let mut seen_tags = false;
+    for field in rest.split('|') {
+        let unexpected = || ParseError::UnexpectedField {
+            name: String::from(title),
+            field: String::from(field),
+        };
+
+        if let Some(t) = field.strip_prefix('#') {
+            if seen_tags {
+                return Err(ParseError::DuplicateTags {
+                    name: String::from(title),
+                });
+            }
+            seen_tags = true;
+            event.tags = parse_tags(t);
+            continue;
+        }
+
+        let (key, value) = match field.find(':') {
+            Some(i) => (&field[..i], &field[i + 1..]),
+            None => return Err(unexpected()),
+        };
+        match key {
+            "d" => {
+                event.timestamp = Some(i64::from_str(value).map_err(|_| unexpected())?)
+            }
+            "h" => event.hostname = Some(String::from(value)),
+            "k" => event.aggregation_key = Some(String::from(value)),
+            "p" => {
+                event.priority = Some(match value {
+                    "low" => EventPriority::Low,
+                    "normal" => EventPriority::Normal,
+                    _ => return Err(unexpected()),
+                })
+            }
+            "s" => event.source_type_name = Some(String::from(value)),
+            "t" => {
+                event.alert_type = Some(match value {
+                    "error" => EventAlertType::Error,
+                    "info" => EventAlertType::Info,
+                    "success" => EventAlertType::Success,
+                    "warning" => EventAlertType::Warning,
+                    _ => return Err(unexpected()),
+                })
+            }
+            _ => return Err(unexpected()),
+        }
+    }
+
+    Ok(event)
+}
+
+fn to_str(b: &[u8]) -> Result<&str, ParseError> {
+    str::from_utf8(b).map_err(|_| ParseError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_event() {
+        assert_eq!(
+            parse_event(b"_e{6,15}:deploy|api v2\\nshipped"),
+            Ok(Event {
+                title: String::from("deploy"),
+                text: String::from("api v2\nshipped"),
+                timestamp: None,
+                hostname: None,
+                aggregation_key: None,
+                priority: None,
+                source_type_name: None,
+                alert_type: None,
+                tags: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_event_with_fields() {
+        let line = b"_e{5,2}:a|out|ok|d:1500000000|h:web.1|k:outages|p:low|s:nagios|\
+            t:error|#env:prod";
+        let event = parse_event(line).unwrap();
+        assert_eq!(event.title, "a|out");
+        assert_eq!(event.text, "ok");
+        assert_eq!(event.timestamp, Some(1500000000));
+        assert_eq!(event.hostname, Some(String::from("web.1")));
+        assert_eq!(event.aggregation_key, Some(String::from("outages")));
+        assert_eq!(event.priority, Some(EventPriority::Low));
+        assert_eq!(event.source_type_name, Some(String::from("nagios")));
+        assert_eq!(event.alert_type, Some(EventAlertType::Error));
+        assert_eq!(event.tags, vec![Tag::new("env", Some("prod"))]);
+    }
+
+    #[test]
+    fn it_rejects_invalid_events() {
+        // Lengths that run past the end of the line.
+        assert_eq!(parse_event(b"_e{10,2}:deploy|ok"), Err(ParseError::Invalid));
+        assert_eq!(
+            parse_event(b"_e{18446744073709551615,2}:deploy|ok"),
+            Err(ParseError::Invalid)
+        );
+        assert_eq!(
+            parse_event(b"_e{6,2}:deploy|ok|x:y"),
+            Err(ParseError::UnexpectedField {
+                name: String::from("deploy"),
+                field: String::from("x:y"),
+            })
+        );
+        assert_eq!(
+            parse_event(b"_e{6,2}:deploy|ok|p:urgent"),
+            Err(ParseError::UnexpectedField {
+                name: String::from("deploy"),
+                field: String::from("p:urgent"),
+            })
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Parses Graphite's plaintext (Carbon) protocol, so that a relay can ingest
+//! Graphite traffic alongside StatsD:
+//!
+//! ```text
+//! metric.path value timestamp
+//! metric.path;tag1=value1;tag2=value2 value timestamp
+//! ```
+//!
+//! Each datapoint becomes a timestamped gauge with the same `Metric` and
+//! `Batch` types as StatsD input, and the same `ParserConfig` applies.
+//!
+//! StatsD treats a signed gauge as a delta, so a negative Graphite value has
+//! no equivalent and is rejected as invalid rather than silently changing
+//! meaning.
+
+use super::{
+    check_line_len, parse_lines, parse_value, push_metric, Batch, BatchRef, MetricRef,
+    MetricType, ParseError, ParserConfig, TagRef,
+};
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+
+/// Parses a payload of "\n" delimited Graphite datapoints. Like
+/// `parser::parse`, the entire payload must be valid for the parse to succeed
+/// unless `ParserConfig::invalid_lines` says otherwise.
+pub fn parse(input: &[u8], config: &ParserConfig) -> Result<Batch, ParseError> {
+    parse_ref(input, config).map(Batch::from)
+}
+
+/// Like `parse`, but returns metrics that borrow from `input`.
+pub fn parse_ref<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    parse_lines(input, config, parse_line)
+}
+
+fn parse_line<'a>(
+    batch: &mut BatchRef<'a>,
+    config: &ParserConfig,
+    line: &'a [u8],
+) -> Result<bool, ParseError> {
+    check_line_len(line, config)?;
+    let metric = parse_datapoint(line)?;
+    push_metric(batch, config, metric)?;
+    Ok(false)
+}
+
+// Parses a single datapoint line (without its trailing newline).
+fn parse_datapoint(line: &[u8]) -> Result<MetricRef<'_>, ParseError> {
+    let line = str::from_utf8(line).map_err(|_| ParseError::InvalidUtf8)?;
+
+    let mut fields = line.split_ascii_whitespace();
+    let path = fields.next().ok_or(ParseError::Invalid)?;
+    let mut segments = path.split(';');
+    let name = segments.next().unwrap();
+    if name.is_empty() {
+        return Err(ParseError::EmptyName);
+    }
+
+    let tags = segments
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => Ok(TagRef {
+                key,
+                value: Some(value),
+            }),
+            _ => Err(ParseError::InvalidTag {
+                name: String::from(name),
+                tag: String::from(tag),
+            }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let value = fields.next().ok_or_else(|| ParseError::MissingValue {
+        name: String::from(name),
+    })?;
+    if parse_value(value, None).is_none() {
+        return Err(ParseError::InvalidValue {
+            name: String::from(name),
+            value: String::from(value),
+        });
+    }
+
+    let timestamp = fields.next().ok_or_else(|| ParseError::MissingTimestamp {
+        name: String::from(name),
+    })?;
+    let timestamp =
+        i64::from_str(timestamp).map_err(|_| ParseError::InvalidTimestamp {
+            name: String::from(name),
+            timestamp: String::from(timestamp),
+        })?;
+
+    if let Some(field) = fields.next() {
+        return Err(ParseError::UnexpectedField {
+            name: String::from(name),
+            field: String::from(field),
+        });
+    }
+
+    Ok(MetricRef {
+        name: Cow::Borrowed(name),
+        value,
+        metric_type: MetricType::Gauge,
+        unit: None,
+        sample_rate: None,
+        sign: None,
+        tags,
+        container_id: None,
+        timestamp: Some(timestamp),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Metric, ParserConfig, Tag};
+
+    #[test]
+    fn it_parses_datapoints() {
+        let input = b"carbon.agents.cpu 12.5 1656581400\r\n\
+            weather.temp;city=berlin;unit=c 21 1656581460\n";
+        let batch = parse(input, &ParserConfig::default()).unwrap();
+        assert_eq!(
+            batch.metrics,
+            vec![
+                Metric {
+                    name: String::from("carbon.agents.cpu"),
+                    value: String::from("12.5"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: Some(1656581400),
+                },
+                Metric {
+                    name: String::from("weather.temp"),
+                    value: String::from("21"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: vec![
+                        Tag::new("city", Some("berlin")),
+                        Tag::new("unit", Some("c"))
+                    ],
+                    container_id: None,
+                    timestamp: Some(1656581460),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_datapoints() {
+        for (line, error) in [
+            (
+                &b"a.b"[..],
+                ParseError::MissingValue {
+                    name: String::from("a.b"),
+                },
+            ),
+            (
+                b"a.b 1",
+                ParseError::MissingTimestamp {
+                    name: String::from("a.b"),
+                },
+            ),
+            (
+                b"a.b -1 1656581400",
+                ParseError::InvalidValue {
+                    name: String::from("a.b"),
+                    value: String::from("-1"),
+                },
+            ),
+            (
+                b"a.b 1 soon",
+                ParseError::InvalidTimestamp {
+                    name: String::from("a.b"),
+                    timestamp: String::from("soon"),
+                },
+            ),
+            (
+                b"a.b 1 1656581400 x",
+                ParseError::UnexpectedField {
+                    name: String::from("a.b"),
+                    field: String::from("x"),
+                },
+            ),
+            (
+                b"a.b;city 1 1656581400",
+                ParseError::InvalidTag {
+                    name: String::from("a.b"),
+                    tag: String::from("city"),
+                },
+            ),
+            (b";city=berlin 1 1656581400", ParseError::EmptyName),
+        ] {
+            assert_eq!(parse_datapoint(line), Err(error));
+        }
+    }
+
+    #[test]
+    fn it_applies_parser_config() {
+        let config = ParserConfig {
+            max_metrics: Some(1),
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"a 1 1\nb 2 2", &config),
+            Err(ParseError::TooManyMetrics { max: 1 })
+        );
+
+        let config = ParserConfig {
+            sanitize_names: true,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"disk/sda1 1 1", &config).unwrap();
+        assert_eq!(batch.metrics[0].name, "disk-sda1");
+
+        assert_eq!(
+            parse(b"a 1 1\na 1", &ParserConfig::default()),
+            Err(ParseError::Line {
+                line: 2,
+                offset: 6,
+                error: Box::new(ParseError::MissingTimestamp {
+                    name: String::from("a")
+                }),
+            })
+        );
+    }
+}
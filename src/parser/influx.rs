@@ -0,0 +1,364 @@
+//! Parses InfluxDB's line protocol, so that a pipeline can ingest Influx
+//! traffic alongside StatsD:
+//!
+//! ```text
+//! measurement,tag1=value1,tag2=value2 field1=1.5,field2=3i 1656581400000000000
+//! ```
+//!
+//! Each field becomes a metric of its own named "measurement.field" that
+//! carries the line's tags. Numbers are gauges (with booleans as 1 or 0) and
+//! strings are key/values. Timestamps are sent in nanoseconds and are
+//! truncated to the seconds that `Metric::timestamp` holds.
+//!
+//! As with Graphite, a negative number has no equivalent since StatsD treats a
+//! signed gauge as a delta, so it's rejected as invalid. Escaped characters
+//! are understood in measurement and field names, but tags and string values
+//! are borrowed from the input as-is and so are rejected if they contain any.
+
+use super::{
+    check_line_len, limit_reached, parse_lines, parse_value, push_metric, Batch,
+    BatchRef, MetricRef, MetricType, ParseError, ParserConfig, TagRef,
+};
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+
+/// Parses a payload of "\n" delimited Influx lines. Like `parser::parse`, the
+/// entire payload must be valid for the parse to succeed unless
+/// `ParserConfig::invalid_lines` says otherwise.
+pub fn parse(input: &[u8], config: &ParserConfig) -> Result<Batch, ParseError> {
+    parse_ref(input, config).map(Batch::from)
+}
+
+/// Like `parse`, but returns metrics that borrow from `input` where they can.
+pub fn parse_ref<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    parse_lines(input, config, parse_line)
+}
+
+fn parse_line<'a>(
+    batch: &mut BatchRef<'a>,
+    config: &ParserConfig,
+    line: &'a [u8],
+) -> Result<bool, ParseError> {
+    check_line_len(line, config)?;
+    for (i, metric) in parse_point(line)?.into_iter().enumerate() {
+        // Like packed StatsD values, each field counts towards the limit.
+        if i > 0 && limit_reached(batch, config)? {
+            return Ok(true);
+        }
+        push_metric(batch, config, metric)?;
+    }
+    Ok(false)
+}
+
+// Parses a single line (without its trailing newline) into one metric per
+// field.
+fn parse_point(line: &[u8]) -> Result<Vec<MetricRef<'_>>, ParseError> {
+    let line = str::from_utf8(line).map_err(|_| ParseError::InvalidUtf8)?;
+
+    let (series, rest) = split_unescaped(line, b' ', false);
+    let (measurement, tags) = split_unescaped(series, b',', false);
+    let measurement = unescape(measurement);
+    if measurement.is_empty() {
+        return Err(ParseError::EmptyName);
+    }
+
+    let mut tag_refs = Vec::new();
+    let mut tags = tags;
+    while let Some(rest) = tags {
+        let (tag, remaining) = split_unescaped(rest, b',', false);
+        tags = remaining;
+        match tag.split_once('=') {
+            Some((key, value))
+                if !key.is_empty() && !value.is_empty() && !tag.contains('\\') =>
+            {
+                tag_refs.push(TagRef {
+                    key,
+                    value: Some(value),
+                });
+            }
+            _ => {
+                return Err(ParseError::InvalidTag {
+                    name: String::from(measurement),
+                    tag: String::from(tag),
+                })
+            }
+        }
+    }
+
+    let missing_value = || ParseError::MissingValue {
+        name: String::from(measurement.as_ref()),
+    };
+    let (fields, rest) = split_unescaped(rest.ok_or_else(missing_value)?, b' ', true);
+    if fields.is_empty() {
+        return Err(missing_value());
+    }
+
+    let timestamp = match rest.map(|rest| split_unescaped(rest, b' ', false)) {
+        None => None,
+        Some((_, Some(field))) => {
+            return Err(ParseError::UnexpectedField {
+                name: String::from(measurement),
+                field: String::from(field),
+            });
+        }
+        Some((timestamp, None)) => Some(
+            i64::from_str(timestamp)
+                .map_err(|_| ParseError::InvalidTimestamp {
+                    name: String::from(measurement.as_ref()),
+                    timestamp: String::from(timestamp),
+                })?
+                .div_euclid(1_000_000_000),
+        ),
+    };
+
+    let mut metrics = Vec::new();
+    let mut fields = Some(fields);
+    while let Some(rest) = fields {
+        let (field, remaining) = split_unescaped(rest, b',', true);
+        fields = remaining;
+        let (key, value) = match split_unescaped(field, b'=', false) {
+            (key, Some(value)) if !key.is_empty() => (unescape(key), value),
+            _ => {
+                return Err(ParseError::UnexpectedField {
+                    name: String::from(measurement),
+                    field: String::from(field),
+                })
+            }
+        };
+
+        let name = format!("{}.{}", measurement, key);
+        let (value, metric_type) = match parse_field_value(value) {
+            Some(parsed) => parsed,
+            None => {
+                return Err(ParseError::InvalidValue {
+                    name,
+                    value: String::from(value),
+                })
+            }
+        };
+        metrics.push(MetricRef {
+            name: Cow::Owned(name),
+            value,
+            metric_type,
+            unit: None,
+            sample_rate: None,
+            sign: None,
+            tags: tag_refs.clone(),
+            container_id: None,
+            timestamp,
+        });
+    }
+    Ok(metrics)
+}
+
+// Interprets a field's value, returning it as a metric value along with the
+// metric type that it maps onto.
+fn parse_field_value(value: &str) -> Option<(&str, MetricType)> {
+    if let Some(s) = value.strip_prefix('"') {
+        let s = s.strip_suffix('"')?;
+        return match s.contains('\\') {
+            true => None,
+            false => Some((s, MetricType::KeyValue)),
+        };
+    }
+
+    let number = match value {
+        "t" | "T" | "true" | "True" | "TRUE" => "1",
+        "f" | "F" | "false" | "False" | "FALSE" => "0",
+        _ => match value.strip_suffix(['i', 'u']) {
+            Some(n) if !n.contains('.') => n,
+            Some(_) => return None,
+            None => value,
+        },
+    };
+    parse_value(number, None)?;
+    Some((number, MetricType::Gauge))
+}
+
+// Splits off everything up to the first `delim` that isn't escaped with a
+// backslash (or, if `quotes` is set, inside a double quoted string), returning
+// it along with whatever follows the delimiter.
+fn split_unescaped(s: &str, delim: u8, quotes: bool) -> (&str, Option<&str>) {
+    let bytes = s.as_bytes();
+    let mut quoted = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'"' if quotes => quoted = !quoted,
+            b if b == delim && !quoted => return (&s[..i], Some(&s[i + 1..])),
+            _ => (),
+        }
+        i += 1;
+    }
+    (s, None)
+}
+
+// Removes the backslashes from escaped characters in a measurement or field
+// name.
+fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Metric, ParserConfig, Tag};
+
+    #[test]
+    fn it_parses_points() {
+        let line =
+            b"cpu,host=a,region=us-west usage=12.5,cores=8i,up=t 1656581400123456789";
+        let batch = parse(line, &ParserConfig::default()).unwrap();
+        let tags = vec![
+            Tag::new("host", Some("a")),
+            Tag::new("region", Some("us-west")),
+        ];
+        assert_eq!(
+            batch.metrics,
+            vec![
+                Metric {
+                    name: String::from("cpu.usage"),
+                    value: String::from("12.5"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: tags.clone(),
+                    container_id: None,
+                    timestamp: Some(1656581400),
+                },
+                Metric {
+                    name: String::from("cpu.cores"),
+                    value: String::from("8"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: tags.clone(),
+                    container_id: None,
+                    timestamp: Some(1656581400),
+                },
+                Metric {
+                    name: String::from("cpu.up"),
+                    value: String::from("1"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags,
+                    container_id: None,
+                    timestamp: Some(1656581400),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_strings_and_escapes() {
+        let batch = parse(
+            br#"deploy\ log,app=web message="shipped v1.2, finally""#,
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            batch.metrics,
+            vec![Metric {
+                name: String::from("deploy log.message"),
+                value: String::from("shipped v1.2, finally"),
+                metric_type: MetricType::KeyValue,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: vec![Tag::new("app", Some("web"))],
+                container_id: None,
+                timestamp: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_points() {
+        for (line, error) in [
+            (
+                &b"cpu"[..],
+                ParseError::MissingValue {
+                    name: String::from("cpu"),
+                },
+            ),
+            (b",host=a usage=1", ParseError::EmptyName),
+            (
+                b"cpu,host usage=1",
+                ParseError::InvalidTag {
+                    name: String::from("cpu"),
+                    tag: String::from("host"),
+                },
+            ),
+            (
+                b"cpu usage",
+                ParseError::UnexpectedField {
+                    name: String::from("cpu"),
+                    field: String::from("usage"),
+                },
+            ),
+            (
+                b"cpu usage=-1",
+                ParseError::InvalidValue {
+                    name: String::from("cpu.usage"),
+                    value: String::from("-1"),
+                },
+            ),
+            (
+                b"cpu usage=1.5i",
+                ParseError::InvalidValue {
+                    name: String::from("cpu.usage"),
+                    value: String::from("1.5i"),
+                },
+            ),
+            (
+                b"cpu usage=1 soon",
+                ParseError::InvalidTimestamp {
+                    name: String::from("cpu"),
+                    timestamp: String::from("soon"),
+                },
+            ),
+            (
+                b"cpu usage=1 1 x",
+                ParseError::UnexpectedField {
+                    name: String::from("cpu"),
+                    field: String::from("x"),
+                },
+            ),
+        ] {
+            assert_eq!(parse_point(line).map(|_| ()), Err(error));
+        }
+
+        let config = ParserConfig {
+            max_metrics: Some(2),
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"cpu a=1,b=2,c=3", &config),
+            Err(ParseError::TooManyMetrics { max: 2 })
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Interns metric names so that long-running servers, which see the same few
+//! thousand names over and over, can hold onto them without allocating a new
+//! string for every metric.
+
+use super::MetricRef;
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+
+/// NameInterner is a pool of metric names. Interning a name that's already in
+/// the pool returns a cheap clone of the pooled `Arc<str>` rather than
+/// allocating a new one, so interned names can also be compared by pointer
+/// with `Arc::ptr_eq`.
+///
+/// Names are never evicted, so a server accepting names from untrusted
+/// clients should bound the pool with `len` and `clear`.
+#[derive(Clone, Debug, Default)]
+pub struct NameInterner {
+    names: BTreeSet<Arc<str>>,
+}
+
+impl NameInterner {
+    pub fn new() -> NameInterner {
+        NameInterner::default()
+    }
+
+    /// Returns the pooled copy of a name, adding it to the pool if it's not
+    /// already there.
+    pub fn intern(&mut self, name: &str) -> Arc<str> {
+        if let Some(name) = self.names.get(name) {
+            return Arc::clone(name);
+        }
+        let name: Arc<str> = Arc::from(name);
+        self.names.insert(Arc::clone(&name));
+        name
+    }
+
+    /// Like `intern`, but for the name of a metric from `parse_ref`. Together
+    /// they avoid allocating for any name that's already pooled.
+    pub fn intern_metric(&mut self, metric: &MetricRef<'_>) -> Arc<str> {
+        self.intern(&metric.name)
+    }
+
+    /// The number of names in the pool.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Removes every name from the pool. Names that have already been handed
+    /// out stay valid.
+    pub fn clear(&mut self) {
+        self.names.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_ref, ParserConfig};
+
+    #[test]
+    fn it_interns_names() {
+        let mut interner = NameInterner::new();
+        let batch = parse_ref(
+            b"gorets:1|c\nglork:320|ms\ngorets:2|c",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = batch
+            .metrics
+            .iter()
+            .map(|m| interner.intern_metric(m))
+            .collect();
+        assert_eq!(
+            names.iter().map(|n| &**n).collect::<Vec<_>>(),
+            vec!["gorets", "glork", "gorets"]
+        );
+        assert!(Arc::ptr_eq(&names[0], &names[2]));
+        assert_eq!(interner.len(), 2);
+
+        interner.clear();
+        assert!(interner.is_empty());
+        assert!(!Arc::ptr_eq(&names[0], &interner.intern("gorets")));
+    }
+}
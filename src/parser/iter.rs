@@ -0,0 +1,196 @@
+//! Parses a payload lazily, one metric at a time.
+
+use super::{
+    next_line, parse_line, BatchRef, ExcessMetricsPolicy, Metric, MetricRef, ParseError,
+    ParserConfig,
+};
+use alloc::boxed::Box;
+use alloc::vec::{self, Vec};
+
+/// Returns an iterator over the metrics in a payload, which parses each line
+/// only when its metrics are asked for.
+///
+/// A line that fails to parse produces an error wrapped in `ParseError::Line`,
+/// after which iteration carries on with the next line, so it's up to the
+/// caller whether to stop. `ParserConfig::invalid_lines` doesn't apply.
+/// Exceeding `ParserConfig::max_metrics` ends iteration, after producing
+/// `ParseError::TooManyMetrics` if excess metrics are rejected.
+///
+/// Events and service checks are parsed (and count towards `max_metrics`),
+/// but aren't produced. Neither are diagnostics. Use `parse` for those.
+pub fn parse_iter<'a>(input: &'a [u8], config: &ParserConfig) -> ParseIter<'a> {
+    ParseIter {
+        // The limit is enforced across lines by the iterator itself.
+        config: ParserConfig {
+            max_metrics: None,
+            ..config.clone()
+        },
+        max_metrics: config.max_metrics,
+        input,
+        rest: input,
+        line_number: 0,
+        count: 0,
+        pending: Vec::new().into_iter(),
+        done: false,
+    }
+}
+
+/// The iterator returned by `parse_iter`.
+#[derive(Debug)]
+pub struct ParseIter<'a> {
+    config: ParserConfig,
+    max_metrics: Option<usize>,
+    input: &'a [u8],
+    rest: &'a [u8],
+    line_number: usize,
+
+    // The number of metrics, events, and service checks parsed so far.
+    count: usize,
+
+    // Metrics from the last line parsed that haven't been produced yet, of
+    // which there may be several if the line had packed values.
+    pending: vec::IntoIter<MetricRef<'a>>,
+
+    done: bool,
+}
+
+impl<'a> ParseIter<'a> {
+    // Checks whether another metric would exceed the limit, ending iteration
+    // if it would.
+    fn limit_reached(&mut self) -> Option<Result<Metric, ParseError>> {
+        let max = self.max_metrics?;
+        if self.count < max {
+            return None;
+        }
+
+        self.done = true;
+        match self.config.excess_metrics {
+            ExcessMetricsPolicy::Reject => Some(Err(ParseError::TooManyMetrics { max })),
+            ExcessMetricsPolicy::Truncate => None,
+        }
+    }
+}
+
+impl<'a> Iterator for ParseIter<'a> {
+    type Item = Result<Metric, ParseError>;
+
+    fn next(&mut self) -> Option<Result<Metric, ParseError>> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if let Some(metric) = self.pending.next() {
+                if let Some(error) = self.limit_reached() {
+                    return Some(error);
+                }
+                if self.done {
+                    return None;
+                }
+                self.count += 1;
+                return Some(Ok(Metric::from(metric)));
+            }
+
+            if self.rest.is_empty() {
+                // An empty payload is invalid just like it is for `parse`.
+                self.done = true;
+                return if self.input.is_empty() {
+                    Some(Err(ParseError::Invalid))
+                } else {
+                    None
+                };
+            }
+
+            let offset = self.input.len() - self.rest.len();
+            let (line, remaining) = next_line(self.rest);
+            self.rest = remaining;
+            self.line_number += 1;
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut batch = BatchRef::default();
+            if let Err(error) = parse_line(&mut batch, &self.config, line) {
+                return Some(Err(ParseError::Line {
+                    line: self.line_number,
+                    offset,
+                    error: Box::new(error),
+                }));
+            }
+
+            for _ in 0..batch.events.len() + batch.service_checks.len() {
+                if let Some(error) = self.limit_reached() {
+                    return Some(error);
+                }
+                if self.done {
+                    return None;
+                }
+                self.count += 1;
+            }
+            self.pending = batch.metrics.into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_metrics_lazily() {
+        let config = ParserConfig::default();
+        let mut iter = parse_iter(
+            b"gorets:1|c\ngorets\nglork:320:240|ms\n_sc|redis.can_connect|0",
+            &config,
+        );
+        assert_eq!(
+            iter.next().map(|m| m.unwrap().name),
+            Some(String::from("gorets"))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(Err(ParseError::Line {
+                line: 2,
+                offset: 11,
+                error: Box::new(ParseError::MissingValue {
+                    name: String::from("gorets")
+                }),
+            }))
+        );
+        assert_eq!(
+            iter.next().map(|m| m.unwrap().value),
+            Some(String::from("320"))
+        );
+        assert_eq!(
+            iter.next().map(|m| m.unwrap().value),
+            Some(String::from("240"))
+        );
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(
+            parse_iter(b"", &config).collect::<Vec<_>>(),
+            vec![Err(ParseError::Invalid)]
+        );
+    }
+
+    #[test]
+    fn it_stops_at_max_metrics() {
+        let config = ParserConfig {
+            max_metrics: Some(2),
+            ..ParserConfig::default()
+        };
+        let results: Vec<_> =
+            parse_iter(b"glork:320:240:120|ms\ngorets:1|c", &config).collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2], Err(ParseError::TooManyMetrics { max: 2 }));
+
+        let config = ParserConfig {
+            excess_metrics: ExcessMetricsPolicy::Truncate,
+            ..config
+        };
+        assert_eq!(
+            parse_iter(b"glork:320:240:120|ms\ngorets:1|c", &config).count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,3153 @@
+//! Implements a parser for StatsD metrics including counters,
+//! gauges, samples, and sets. See [this document][metric-types] for more
+//! details. Some examples of input that this package will parse are:
+//!
+//! ```text
+//! gorets:1|c
+//! glork:320|ms|@0.1
+//! gaugor:333|g
+//! uniques:765|s
+//! config.version:1.2.3|kv
+//! page.views:1|c|#env:production,canary
+//! glork:320:240:120|ms
+//! glork:320,240,120|ms
+//! ```
+//!
+//! DogStatsD events (see `Event`) and service checks (see `ServiceCheck`) may
+//! be mixed in with metrics in the same payload.
+//!
+//! See the tests for example, but generally speaking, `parse` is the only
+//! thing that needs to be used from this package. Single metrics can also be
+//! parsed with `Metric::try_from`. Where allocation matters, `parse_ref` and
+//! `MetricRef::try_from` return metrics that borrow from their input instead,
+//! and `NameInterner` pools their names for servers that keep them around.
+//! With the `bumpalo` feature, `parse_in` parses into a bump arena so that
+//! metrics can outlive the buffer that they were received into.
+//! `parse_iter` parses a payload lazily, one metric at a time. For streams
+//! like TCP connections where lines may be split across reads, use
+//! `StreamParser`. With the `rayon` feature, `parse_parallel` parses large
+//! payloads across threads.
+//!
+//! Graphite's plaintext protocol, InfluxDB's line protocol, and Prometheus's
+//! text exposition format are parsed into the same types by `graphite`,
+//! `influx`, and `prometheus`.
+//!
+//! [metric-types]: https://github.com/etsy/statsd/blob/master/docs/metric_types.md
+
+#[cfg(feature = "bumpalo")]
+mod arena;
+mod builder;
+mod event;
+pub mod graphite;
+pub mod influx;
+mod intern;
+mod iter;
+#[cfg(feature = "rayon")]
+mod parallel;
+pub mod prometheus;
+mod sanitize;
+mod service_check;
+mod stream;
+
+#[cfg(feature = "bumpalo")]
+pub use self::arena::parse_in;
+pub use self::builder::MetricBuilder;
+pub use self::event::{Event, EventAlertType, EventPriority};
+pub use self::intern::NameInterner;
+pub use self::iter::{parse_iter, ParseIter};
+#[cfg(feature = "rayon")]
+pub use self::parallel::parse_parallel;
+pub use self::sanitize::sanitize_name;
+pub use self::service_check::{ServiceCheck, ServiceCheckStatus};
+pub use self::stream::StreamParser;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+use thiserror::Error;
+
+/// Metric represents a single emitted metric including a name, value, and type
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metric {
+    /// The metric's name.
+    pub(crate) name: String,
+
+    /// The metric's value as it was sent, without its sign. For numeric
+    /// metric types see `Metric::numeric_value`.
+    pub(crate) value: String,
+
+    /// Type of the metric (e.g. counter, gauge, ...).
+    pub(crate) metric_type: MetricType,
+
+    /// Unit is the unit of measurement of a sample (e.g. "ms"). It has a value
+    /// for samples, but is `None` for all other metric types.
+    pub(crate) unit: Option<Unit>,
+
+    /// The frequency at which the metric is being sampled, expressed as a
+    /// fraction of the per period time (e.g. 0.1 means that the metric is
+    /// being sent sampled every 1/10th of the time). Only applies to types
+    /// for which `MetricType::supports_sample_rate` is true, and is optional
+    /// even for those.
+    pub(crate) sample_rate: Option<f64>,
+
+    /// Sign is a sign assigned to a metric value. It may have a value for
+    /// gauges (and may not). Counters may also carry a negative sign, which is
+    /// handled according to the parser's `NegativeCounterPolicy`. It is `None`
+    /// for all other metric types.
+    pub(crate) sign: Option<MetricSign>,
+
+    /// Tags attached to the metric using the DogStatsD extension (e.g.
+    /// "|#env:production,canary") in the order that they were sent. Empty if
+    /// the metric wasn't tagged.
+    pub(crate) tags: Vec<Tag>,
+
+    /// The ID of the container that sent the metric (e.g. "|c:83c0a9"), which
+    /// newer DogStatsD clients send for origin detection.
+    pub(crate) container_id: Option<String>,
+
+    /// When the metric was recorded as a Unix timestamp (e.g. "|T1656581400"),
+    /// which DogStatsD clients send for metrics submitted late. `None` means
+    /// that the metric should be treated as having been recorded when it was
+    /// received.
+    pub(crate) timestamp: Option<i64>,
+}
+
+impl Metric {
+    /// Returns a builder for constructing a metric by hand, like a client
+    /// that's about to emit one would.
+    pub fn builder() -> MetricBuilder {
+        MetricBuilder::default()
+    }
+
+    /// The metric's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The metric's value as it was sent, without its sign.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// The metric's type.
+    pub fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    /// The unit of measurement of a sample.
+    pub fn unit(&self) -> Option<&Unit> {
+        self.unit.as_ref()
+    }
+
+    /// The rate at which the metric was sampled.
+    pub fn sample_rate(&self) -> Option<f64> {
+        self.sample_rate
+    }
+
+    /// The sign of the metric's value.
+    pub fn sign(&self) -> Option<MetricSign> {
+        self.sign
+    }
+
+    /// Tags attached to the metric in the order that they were sent.
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    /// The ID of the container that sent the metric.
+    pub fn container_id(&self) -> Option<&str> {
+        self.container_id.as_deref()
+    }
+
+    /// When the metric was recorded as a Unix timestamp.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// Returns the identity of the series that this metric belongs to.
+    pub fn id(&self) -> MetricId {
+        MetricId::new(&self.name, self.metric_type, self.tags.clone())
+    }
+
+    /// Returns the metric's value as a number, including its sign. Values of
+    /// all numeric types are checked while parsing, so this is only `None`
+    /// for sets and key/values, whose values are opaque strings.
+    pub fn numeric_value(&self) -> Option<MetricValue> {
+        if !self.metric_type.is_numeric() {
+            return None;
+        }
+        parse_value(&self.value, self.sign)
+    }
+
+    /// Returns how a gauge's value should be applied: a signed value (e.g.
+    /// "gaugor:+10|g") is a delta to add to the current value, and an unsigned
+    /// one replaces it. `None` for all metric types other than gauges.
+    pub fn gauge_mode(&self) -> Option<GaugeMode> {
+        if self.metric_type != MetricType::Gauge {
+            return None;
+        }
+
+        match self.sign {
+            Some(sign) => Some(GaugeMode::Delta(sign)),
+            None => Some(GaugeMode::Absolute),
+        }
+    }
+}
+
+/// A metric's value interpreted as a number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricValue {
+    /// A negative integer (e.g. "gorets:-5|c").
+    Integer(i64),
+
+    /// A value with a decimal point or an exponent (e.g. "glork:320.5|ms"), or
+    /// one that isn't finite if `ParserConfig::non_finite_values` allows it.
+    Float(f64),
+
+    /// A non-negative integer (e.g. "gorets:1|c").
+    Unsigned(u64),
+}
+
+/// How a gauge's value is applied to the gauge's current value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GaugeMode {
+    /// The value replaces the gauge's current value.
+    Absolute,
+
+    /// The value is added to (for `MetricSign::Plus`) or subtracted from (for
+    /// `MetricSign::Minus`) the gauge's current value.
+    Delta(MetricSign),
+}
+
+/// MetricRef is a borrowed version of `Metric` whose strings are slices of the
+/// payload that it was parsed from, which avoids allocating for every metric
+/// in a hot receive loop. Convert it to a `Metric` with `Metric::from` to keep
+/// it around longer than the payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricRef<'a> {
+    /// The metric's name, which is only owned if it had to be sanitized (see
+    /// `ParserConfig::sanitize_names`).
+    pub name: Cow<'a, str>,
+
+    /// The metric's value as it was sent, without its sign.
+    pub value: &'a str,
+
+    /// Type of the metric (e.g. counter, gauge, ...).
+    pub metric_type: MetricType,
+
+    /// The unit of measurement of a sample exactly as it was sent (e.g.
+    /// "msec"). `Unit::from` normalizes it.
+    pub unit: Option<&'a str>,
+
+    /// The metric's sample rate. See `Metric`.
+    pub sample_rate: Option<f64>,
+
+    /// The sign of the metric's value. See `Metric`.
+    pub sign: Option<MetricSign>,
+
+    /// Tags attached to the metric in the order that they were sent.
+    pub tags: Vec<TagRef<'a>>,
+
+    /// The ID of the container that sent the metric. See `Metric`.
+    pub container_id: Option<&'a str>,
+
+    /// When the metric was recorded as a Unix timestamp. See `Metric`.
+    pub timestamp: Option<i64>,
+}
+
+impl<'a> MetricRef<'a> {
+    /// Returns the identity of the series that this metric belongs to. See
+    /// `Metric::id`.
+    pub fn id(&self) -> MetricId {
+        let tags = self.tags.iter().map(|&tag| Tag::from(tag)).collect();
+        MetricId::new(&self.name, self.metric_type, tags)
+    }
+
+    /// Returns the metric's value as a number. See `Metric::numeric_value`.
+    pub fn numeric_value(&self) -> Option<MetricValue> {
+        if !self.metric_type.is_numeric() {
+            return None;
+        }
+        parse_value(self.value, self.sign)
+    }
+}
+
+impl<'a> From<MetricRef<'a>> for Metric {
+    fn from(metric: MetricRef<'a>) -> Metric {
+        Metric {
+            name: metric.name.into_owned(),
+            value: String::from(metric.value),
+            metric_type: metric.metric_type,
+            unit: metric.unit.map(Unit::from),
+            sample_rate: metric.sample_rate,
+            sign: metric.sign,
+            tags: metric.tags.into_iter().map(Tag::from).collect(),
+            container_id: metric.container_id.map(String::from),
+            timestamp: metric.timestamp,
+        }
+    }
+}
+
+/// A DogStatsD tag, which is either a bare key (e.g. "canary") or a key/value
+/// pair separated by the first ":" (e.g. "env:production").
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Tag {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl Tag {
+    pub fn new(key: &str, value: Option<&str>) -> Tag {
+        Tag {
+            key: String::from(key),
+            value: value.map(String::from),
+        }
+    }
+}
+
+/// A borrowed version of `Tag` used by `MetricRef`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TagRef<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+
+impl<'a> From<TagRef<'a>> for Tag {
+    fn from(tag: TagRef<'a>) -> Tag {
+        Tag::new(tag.key, tag.value)
+    }
+}
+
+/// Parses a single metric. Trailing input (including a second metric) is an
+/// error.
+impl<'a> TryFrom<&'a [u8]> for Metric {
+    type Error = ParseError;
+
+    fn try_from(input: &'a [u8]) -> Result<Metric, ParseError> {
+        MetricRef::try_from(input).map(Metric::from)
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Metric {
+    type Error = ParseError;
+
+    fn try_from(input: &'a str) -> Result<Metric, ParseError> {
+        Metric::try_from(input.as_bytes())
+    }
+}
+
+impl TryFrom<String> for Metric {
+    type Error = ParseError;
+
+    fn try_from(input: String) -> Result<Metric, ParseError> {
+        Metric::try_from(input.as_bytes())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Metric, ParseError> {
+        Metric::try_from(input.as_bytes())
+    }
+}
+
+/// Parses a single metric without copying it out of its input. Trailing input
+/// (including a second metric) is an error.
+impl<'a> TryFrom<&'a [u8]> for MetricRef<'a> {
+    type Error = ParseError;
+
+    fn try_from(input: &'a [u8]) -> Result<MetricRef<'a>, ParseError> {
+        // A single trailing newline is tolerated.
+        let (line, rest) = next_line(input);
+        if !rest.is_empty() {
+            return Err(ParseError::Invalid);
+        }
+        let metric = build_metric(parse_raw_metric(line)?)?;
+        let value = metric.value;
+        let config = ParserConfig::default();
+        apply_value_policies(set_value(metric, value)?, &config, &mut Vec::new())
+    }
+}
+
+impl<'a> TryFrom<&'a str> for MetricRef<'a> {
+    type Error = ParseError;
+
+    fn try_from(input: &'a str) -> Result<MetricRef<'a>, ParseError> {
+        MetricRef::try_from(input.as_bytes())
+    }
+}
+
+/// MetricId identifies a series. Metrics with equal ids belong to the same
+/// series and should be aggregated together, so it's suitable for use as a
+/// map key.
+///
+/// A series is identified by its name, type, and tags, so a counter and a
+/// gauge that share a name are separate series. Tags are canonicalized by
+/// sorting them and removing duplicates, so the order that a client sends
+/// them in doesn't matter. Ids order by name, then type, then tags.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MetricId {
+    name: String,
+    metric_type: MetricType,
+    tags: Vec<Tag>,
+}
+
+impl MetricId {
+    pub fn new(name: &str, metric_type: MetricType, mut tags: Vec<Tag>) -> MetricId {
+        tags.sort();
+        tags.dedup();
+        MetricId {
+            name: String::from(name),
+            metric_type,
+            tags,
+        }
+    }
+
+    /// The name of the series.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The type of the series' metrics.
+    pub fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    /// The series' tags in canonical order.
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+}
+
+/// Signs on a metric's value. Only meaningful for the gauge metric type, and
+/// for negative counters.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricSign {
+    Minus,
+    Plus,
+}
+
+/// All possible types of a metric.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum MetricType {
+    /// Counter add the value sent with the metric to a bucket as a new
+    /// increment.
+    Counter,
+
+    /// Gauges are arbitrary values that can be recorded and then added to or
+    /// subtracted from with a subsequent signed metric.
+    Gauge,
+
+    /// Samples represent timing. For each flush interval the backend will
+    /// calculated aggregates like percentiles, mean, standard deviation, etc.
+    Sample,
+
+    /// Sets track unique occurrences of values between flushes.
+    Set,
+
+    /// Key/values report an arbitrary value for a key (e.g. a version
+    /// number). Their values aren't interpreted numerically, and only the
+    /// latest value for a key is meaningful.
+    KeyValue,
+
+    /// Histograms are DogStatsD's equivalent of samples, aggregated into
+    /// statistics like percentiles and mean by the server that receives them.
+    Histogram,
+
+    /// Distributions are like histograms, but are meant to be aggregated
+    /// globally (e.g. into mergeable sketches) rather than per server.
+    Distribution,
+
+    /// Meters measure the rate at which something happens, like counters that
+    /// are reported per second. Supported by statsite and brubeck.
+    Meter,
+}
+
+impl MetricType {
+    /// Whether a metric of this type may be sent with a sample rate.
+    pub fn supports_sample_rate(self) -> bool {
+        match self {
+            MetricType::Counter
+            | MetricType::Sample
+            | MetricType::Histogram
+            | MetricType::Distribution
+            | MetricType::Meter => true,
+            MetricType::Gauge | MetricType::Set | MetricType::KeyValue => false,
+        }
+    }
+
+    /// Whether a metric of this type carries a numeric value. Sets and
+    /// key/values have opaque values that may be any string.
+    pub fn is_numeric(self) -> bool {
+        !matches!(self, MetricType::Set | MetricType::KeyValue)
+    }
+}
+
+/// The unit of measurement of a sample. Common spellings of time units are
+/// normalized (e.g. "ms" and "msec" are both `Milliseconds`).
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Unit {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+
+    /// Any other unit exactly as it was sent.
+    Other(String),
+}
+
+impl Unit {
+    /// Returns the canonical code for the unit, which is what's used to
+    /// encode it.
+    pub fn code(&self) -> &str {
+        match self {
+            Unit::Seconds => "sec",
+            Unit::Milliseconds => "ms",
+            Unit::Microseconds => "us",
+            Unit::Nanoseconds => "ns",
+            Unit::Other(code) => code,
+        }
+    }
+
+    /// Returns the length of the unit in seconds, or `None` if it isn't a unit
+    /// of time.
+    pub fn seconds(&self) -> Option<f64> {
+        match self {
+            Unit::Seconds => Some(1.0),
+            Unit::Milliseconds => Some(1e-3),
+            Unit::Microseconds => Some(1e-6),
+            Unit::Nanoseconds => Some(1e-9),
+            Unit::Other(_) => None,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Unit {
+    fn from(code: &'a str) -> Unit {
+        match code {
+            "sec" | "secs" | "seconds" => Unit::Seconds,
+            "ms" | "msec" | "msecs" | "millis" => Unit::Milliseconds,
+            "us" | "usec" | "usecs" | "micros" => Unit::Microseconds,
+            "ns" | "nsec" | "nsecs" | "nanos" => Unit::Nanoseconds,
+            _ => Unit::Other(String::from(code)),
+        }
+    }
+}
+
+/// What to do with a counter that's sent with a negative value (e.g.
+/// `gorets:-5|c`). StatsD implementations disagree on whether these are legal,
+/// so the choice is left to configuration.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NegativeCounterPolicy {
+    /// The counter is accepted with its negative value. A diagnostic is still
+    /// raised so that the decrement doesn't go unnoticed.
+    #[default]
+    Accept,
+
+    /// The counter is accepted, but its value is clamped to zero and a
+    /// diagnostic is raised.
+    ClampToZero,
+
+    /// The payload fails to parse with `ParseError::NegativeCounter`.
+    Reject,
+}
+
+/// What to do with a sample rate sent on a metric type that doesn't support
+/// one (e.g. `gaugor:333|g|@0.1`). See `MetricType::supports_sample_rate`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnsupportedSampleRatePolicy {
+    /// The metric is accepted, but its meaningless sample rate is discarded
+    /// and a diagnostic is raised.
+    #[default]
+    Discard,
+
+    /// The payload fails to parse with `ParseError::UnsupportedSampleRate`.
+    Reject,
+}
+
+/// What to do with a sample rate outside of the range (0, 1] (e.g.
+/// `gorets:1|c|@7`), which would otherwise blow up when a counter is scaled by
+/// it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidSampleRatePolicy {
+    /// The payload fails to parse with `ParseError::SampleRateOutOfRange`.
+    #[default]
+    Reject,
+
+    /// The metric is accepted, but the sample rate is discarded so that the
+    /// metric is treated as unsampled. A diagnostic is raised.
+    Discard,
+}
+
+/// What to do with a value written with an exponent (e.g. `gaugor:1e6|g`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExponentPolicy {
+    /// The payload fails to parse with `ParseError::InvalidValue`.
+    #[default]
+    Reject,
+
+    /// The value is accepted as a float.
+    Accept,
+}
+
+/// What to do with a value that isn't a finite number: `NaN`, `Inf`, or
+/// `Infinity` in any case and with an optional sign, or an exponent too large
+/// for an `f64`. These would otherwise poison any aggregate that they're
+/// added to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonFiniteValuePolicy {
+    /// The payload fails to parse with `ParseError::InvalidValue`.
+    #[default]
+    Reject,
+
+    /// Infinities are clamped to the largest finite `f64` of the same sign and
+    /// a diagnostic is raised. `NaN` has nothing to clamp to, so it's still
+    /// rejected.
+    Clamp,
+
+    /// The value is accepted as is.
+    Accept,
+}
+
+/// What to do with a payload that contains more metrics (including events and
+/// service checks) than `ParserConfig::max_metrics` allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExcessMetricsPolicy {
+    /// The payload fails to parse with `ParseError::TooManyMetrics`.
+    #[default]
+    Reject,
+
+    /// Metrics up to the limit are kept and the rest of the payload is
+    /// skipped without being parsed. A diagnostic is raised.
+    Truncate,
+}
+
+/// What to do with a line in a payload that fails to parse.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InvalidLinePolicy {
+    /// The whole payload fails to parse with the line's error.
+    #[default]
+    Reject,
+
+    /// The line is skipped and the rest of the payload is still parsed, so
+    /// that one misbehaving client doesn't spoil a datagram shared with
+    /// others. The line's error is raised as a diagnostic.
+    Skip,
+}
+
+/// What to do with a type code that isn't a known type or unit (e.g.
+/// `glork:320|foo`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnknownTypePolicy {
+    /// The metric is treated as a sample with the type code as its unit, which
+    /// is how etsy statsd treats it.
+    #[default]
+    Sample,
+
+    /// The payload fails to parse with `ParseError::UnknownType`.
+    Reject,
+}
+
+/// Which characters are allowed in metric names.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NameCharset {
+    /// Any character other than the delimiters that the format itself uses.
+    #[default]
+    Any,
+
+    /// Only ASCII letters, digits, "_", "-", and ".", which are the characters
+    /// that are safe to use in a Graphite key.
+    Strict,
+}
+
+impl NameCharset {
+    /// Whether the character is allowed in a name.
+    pub fn allows(self, c: char) -> bool {
+        match self {
+            NameCharset::Any => true,
+            NameCharset::Strict => {
+                c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+            }
+        }
+    }
+}
+
+/// Configuration for `parse`. The default configuration accepts anything that
+/// looks like a valid metric. `ParserConfig::strict` returns one that rejects
+/// anything questionable instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParserConfig {
+    /// How counters with negative values are handled.
+    pub negative_counters: NegativeCounterPolicy,
+
+    /// How sample rates on gauges and sets are handled.
+    pub unsupported_sample_rates: UnsupportedSampleRatePolicy,
+
+    /// How sample rates outside of the range (0, 1] are handled.
+    pub invalid_sample_rates: InvalidSampleRatePolicy,
+
+    /// The maximum number of metrics that will be parsed out of a single
+    /// payload, which bounds the work that a hostile sender can cause with one
+    /// datagram. Events and service checks count towards the limit. `None`
+    /// means that there's no limit.
+    pub max_metrics: Option<usize>,
+
+    /// How payloads with more than `max_metrics` metrics are handled.
+    pub excess_metrics: ExcessMetricsPolicy,
+
+    /// How lines that fail to parse are handled. Exceeding `max_metrics` is a
+    /// problem with the payload rather than a line, so it's not affected.
+    pub invalid_lines: InvalidLinePolicy,
+
+    /// How type codes that aren't a known type or unit are handled.
+    pub unknown_types: UnknownTypePolicy,
+
+    /// The maximum length of a metric name in bytes. `None` means that
+    /// there's no limit.
+    pub max_name_len: Option<usize>,
+
+    /// Which characters are allowed in metric names.
+    pub name_charset: NameCharset,
+
+    /// Whether metric names are rewritten with `sanitize_name` before being
+    /// checked against `max_name_len` and `name_charset`.
+    pub sanitize_names: bool,
+
+    /// The maximum length of a line in bytes (without its newline). This also
+    /// bounds how much of a partial line `StreamParser` will buffer. `None`
+    /// means that there's no limit.
+    pub max_line_len: Option<usize>,
+
+    /// The maximum number of tags on a metric, event, or service check. `None`
+    /// means that there's no limit.
+    pub max_tags: Option<usize>,
+
+    /// How values written with an exponent are handled.
+    pub exponents: ExponentPolicy,
+
+    /// How values that aren't finite numbers are handled.
+    pub non_finite_values: NonFiniteValuePolicy,
+}
+
+impl ParserConfig {
+    /// Returns a configuration that rejects anything questionable: negative
+    /// counters, sample rates that are out of range or on types that don't
+    /// support them, unknown types, and names that are longer than 255 bytes
+    /// or that contain characters outside of `NameCharset::Strict`.
+    pub fn strict() -> ParserConfig {
+        ParserConfig {
+            negative_counters: NegativeCounterPolicy::Reject,
+            unsupported_sample_rates: UnsupportedSampleRatePolicy::Reject,
+            invalid_sample_rates: InvalidSampleRatePolicy::Reject,
+            unknown_types: UnknownTypePolicy::Reject,
+            max_name_len: Some(255),
+            name_charset: NameCharset::Strict,
+            ..ParserConfig::default()
+        }
+    }
+}
+
+/// Batch is the set of metrics, events, and service checks parsed out of a
+/// single payload along with any diagnostics that were raised while parsing
+/// them.
+#[derive(Debug, Default, PartialEq)]
+pub struct Batch {
+    /// The metrics in the payload in the order that they appeared.
+    pub metrics: Vec<Metric>,
+
+    /// The events in the payload in the order that they appeared.
+    pub events: Vec<Event>,
+
+    /// The service checks in the payload in the order that they appeared.
+    pub service_checks: Vec<ServiceCheck>,
+
+    /// Problems noticed while parsing that weren't serious enough to fail the
+    /// parse.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Batch {
+    /// The total number of metrics, events, and service checks in the batch.
+    pub fn len(&self) -> usize {
+        self.metrics.len() + self.events.len() + self.service_checks.len()
+    }
+
+    /// Whether the batch contains nothing at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sorts the batch's metrics by name and then by type. The sort is stable
+    /// so metrics within a series keep their relative order, which matters for
+    /// metrics like gauges where the last value wins.
+    pub fn sort(&mut self) {
+        self.metrics
+            .sort_by(|a, b| (&a.name, a.metric_type).cmp(&(&b.name, b.metric_type)));
+    }
+
+    /// Groups the batch's metrics by the series that they belong to. Metrics
+    /// within each group keep the order that they appeared in the batch.
+    pub fn group_by_series(&self) -> BTreeMap<MetricId, Vec<&Metric>> {
+        let mut groups = BTreeMap::new();
+        for metric in &self.metrics {
+            groups
+                .entry(metric.id())
+                .or_insert_with(Vec::new)
+                .push(metric);
+        }
+        groups
+    }
+}
+
+/// A borrowed version of `Batch` returned by `parse_ref`, whose metrics are
+/// `MetricRef`s. Events and service checks are rare enough that they're still
+/// owned.
+#[derive(Debug, Default, PartialEq)]
+pub struct BatchRef<'a> {
+    /// The metrics in the payload in the order that they appeared.
+    pub metrics: Vec<MetricRef<'a>>,
+
+    /// The events in the payload in the order that they appeared.
+    pub events: Vec<Event>,
+
+    /// The service checks in the payload in the order that they appeared.
+    pub service_checks: Vec<ServiceCheck>,
+
+    /// Problems noticed while parsing that weren't serious enough to fail the
+    /// parse.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> BatchRef<'a> {
+    /// The total number of metrics, events, and service checks in the batch.
+    pub fn len(&self) -> usize {
+        self.metrics.len() + self.events.len() + self.service_checks.len()
+    }
+
+    /// Whether the batch contains nothing at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> From<BatchRef<'a>> for Batch {
+    fn from(batch: BatchRef<'a>) -> Batch {
+        Batch {
+            metrics: batch.metrics.into_iter().map(Metric::from).collect(),
+            events: batch.events,
+            service_checks: batch.service_checks,
+            diagnostics: batch.diagnostics,
+        }
+    }
+}
+
+/// Parses a payload with the default `ParserConfig`.
+impl<'a> TryFrom<&'a [u8]> for Batch {
+    type Error = ParseError;
+
+    fn try_from(input: &'a [u8]) -> Result<Batch, ParseError> {
+        parse(input, &ParserConfig::default())
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Batch {
+    type Error = ParseError;
+
+    fn try_from(input: &'a str) -> Result<Batch, ParseError> {
+        Batch::try_from(input.as_bytes())
+    }
+}
+
+impl TryFrom<String> for Batch {
+    type Error = ParseError;
+
+    fn try_from(input: String) -> Result<Batch, ParseError> {
+        Batch::try_from(input.as_bytes())
+    }
+}
+
+impl FromStr for Batch {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Batch, ParseError> {
+        Batch::try_from(input.as_bytes())
+    }
+}
+
+/// A problem noticed in a payload that wasn't serious enough to fail its
+/// parse, but which probably indicates a misbehaving client.
+#[derive(Debug, PartialEq)]
+pub enum Diagnostic {
+    /// A counter was sent with a negative value and was accepted as is.
+    NegativeCounterAccepted { name: String },
+
+    /// A counter was sent with a negative value and was clamped to zero.
+    NegativeCounterClamped { name: String },
+
+    /// An infinite value was clamped to the largest finite `f64`.
+    NonFiniteValueClamped { name: String, value: String },
+
+    /// A sample rate was sent on a metric type that doesn't support one and
+    /// was discarded.
+    UnsupportedSampleRateDiscarded {
+        name: String,
+        metric_type: MetricType,
+    },
+
+    /// A sample rate outside of the range (0, 1] was discarded.
+    SampleRateOutOfRangeDiscarded { name: String, sample_rate: f64 },
+
+    /// A payload contained more than the maximum number of metrics and the
+    /// remainder of it was skipped.
+    MetricsTruncated { max: usize },
+
+    /// A line failed to parse and was skipped. See `ParseError::Line` for
+    /// what `line` and `offset` mean.
+    LineSkipped {
+        line: usize,
+        offset: usize,
+        error: ParseError,
+    },
+}
+
+/// Errors that may occur while running `parse`. Errors from `parse` that are
+/// caused by a particular line are wrapped in `ParseError::Line` to say where
+/// the line is, while errors from parsing a single metric aren't.
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    /// The payload isn't valid StatsD, and none of the more specific errors
+    /// apply.
+    #[error("invalid StatsD payload")]
+    Invalid,
+
+    /// A line of a payload failed to parse. `line` is numbered from 1 and
+    /// `offset` is the byte offset of the start of the line in the payload.
+    #[error("line {line} (byte {offset}): {error}")]
+    Line {
+        line: usize,
+        offset: usize,
+        error: Box<ParseError>,
+    },
+
+    /// A line isn't valid UTF-8.
+    #[error("invalid UTF-8")]
+    InvalidUtf8,
+
+    /// A metric's name is empty (e.g. `:1|c`).
+    #[error("empty metric name")]
+    EmptyName,
+
+    /// A metric is missing its value (e.g. `gorets` or `gorets:|c`).
+    #[error("missing value for metric \"{name}\"")]
+    MissingValue { name: String },
+
+    /// A metric is missing its type (e.g. `gorets:1`).
+    #[error("missing type for metric \"{name}\"")]
+    MissingType { name: String },
+
+    /// A metric's type isn't a valid type code or unit (e.g. `gorets:1|@c`).
+    #[error("invalid type \"{metric_type}\" for metric \"{name}\"")]
+    InvalidType { name: String, metric_type: String },
+
+    /// A metric's sample rate isn't a number (e.g. `gorets:1|c|@x`).
+    #[error("invalid sample rate \"{sample_rate}\" for metric \"{name}\"")]
+    InvalidSampleRate { name: String, sample_rate: String },
+
+    /// A metric's timestamp isn't an integer (e.g. `gorets:1|c|Tsoon`).
+    #[error("invalid timestamp \"{timestamp}\" for metric \"{name}\"")]
+    InvalidTimestamp { name: String, timestamp: String },
+
+    /// A metric in a format that requires a timestamp (like Graphite's) was
+    /// sent without one.
+    #[error("missing timestamp for metric \"{name}\"")]
+    MissingTimestamp { name: String },
+
+    /// An OpenMetrics payload doesn't end with `# EOF`.
+    #[error("missing \"# EOF\" at the end of an OpenMetrics payload")]
+    MissingEof,
+
+    /// An OpenMetrics `# UNIT` line names a unit that its metric family's
+    /// name doesn't end with.
+    #[error("invalid unit \"{unit}\" for metric \"{name}\"")]
+    InvalidUnit { name: String, unit: String },
+
+    /// A sample of an OpenMetrics counter isn't suffixed with `_total` or
+    /// `_created`.
+    #[error("counter sample \"{name}\" doesn't end with \"_total\"")]
+    MissingCounterSuffix { name: String },
+
+    /// A metric's tag isn't in the form its format requires (e.g. a Graphite
+    /// tag without a "=").
+    #[error("invalid tag \"{tag}\" for metric \"{name}\"")]
+    InvalidTag { name: String, tag: String },
+
+    /// A counter was sent with a negative value while negative counters are
+    /// configured to be rejected.
+    #[error("negative value for counter \"{name}\"")]
+    NegativeCounter { name: String },
+
+    /// A sample rate was sent on a metric type that doesn't support one while
+    /// such sample rates are configured to be rejected.
+    #[error("sample rate isn't supported for metric \"{name}\" of type {metric_type:?}")]
+    UnsupportedSampleRate {
+        name: String,
+        metric_type: MetricType,
+    },
+
+    /// A metric was sent with a type code that isn't a known type or unit
+    /// while unknown types are configured to be rejected.
+    #[error("unknown type \"{metric_type}\" for metric \"{name}\"")]
+    UnknownType { name: String, metric_type: String },
+
+    /// A line is longer than `ParserConfig::max_line_len`.
+    #[error("line is longer than {max} bytes")]
+    LineTooLong { max: usize },
+
+    /// A metric, event, or service check has more tags than
+    /// `ParserConfig::max_tags`.
+    #[error("\"{name}\" has more than {max} tags")]
+    TooManyTags { name: String, max: usize },
+
+    /// A metric's name is longer than `ParserConfig::max_name_len`.
+    #[error("name of metric \"{name}\" is longer than {max} bytes")]
+    NameTooLong { name: String, max: usize },
+
+    /// A metric's name contains a character that isn't allowed by
+    /// `ParserConfig::name_charset`.
+    #[error("metric \"{name}\" contains disallowed character {character:?}")]
+    DisallowedNameChar { name: String, character: char },
+
+    /// A sample rate outside of the range (0, 1] was sent while such sample
+    /// rates are configured to be rejected.
+    #[error("sample rate {sample_rate} for metric \"{name}\" isn't in the range (0, 1]")]
+    SampleRateOutOfRange { name: String, sample_rate: f64 },
+
+    /// A payload contained more than the maximum number of metrics while
+    /// excess metrics are configured to be rejected.
+    #[error("payload contains more than {max} metrics")]
+    TooManyMetrics { max: usize },
+
+    /// A metric specified its type more than once (e.g. `a:1|c|c`).
+    #[error("duplicate type for metric \"{name}\"")]
+    DuplicateType { name: String },
+
+    /// A metric specified its sample rate more than once (e.g.
+    /// `a:1|c|@0.1|@0.5`).
+    #[error("duplicate sample rate for metric \"{name}\"")]
+    DuplicateSampleRate { name: String },
+
+    /// A metric specified its tags more than once (e.g. `a:1|c|#x|#y`).
+    #[error("duplicate tags for metric \"{name}\"")]
+    DuplicateTags { name: String },
+
+    /// A metric specified its container ID more than once (e.g.
+    /// `a:1|c|c:x|c:y`).
+    #[error("duplicate container ID for metric \"{name}\"")]
+    DuplicateContainerId { name: String },
+
+    /// A metric specified its timestamp more than once (e.g.
+    /// `a:1|c|T1|T2`).
+    #[error("duplicate timestamp for metric \"{name}\"")]
+    DuplicateTimestamp { name: String },
+
+    /// A metric contained a trailing field that wasn't recognized (e.g.
+    /// `a:1|c|junk`).
+    #[error("unexpected field \"{field}\" for metric \"{name}\"")]
+    UnexpectedField { name: String, field: String },
+
+    /// A metric of a numeric type was sent with a value that isn't a number
+    /// (e.g. `a:x|c`) or that's out of range.
+    #[error("invalid value \"{value}\" for metric \"{name}\"")]
+    InvalidValue { name: String, value: String },
+}
+
+// A metric line that's been split into its component parts, but whose
+// trailing "|" delimited fields haven't been interpreted yet.
+struct RawMetric<'a> {
+    name: &'a str,
+    // Includes any leading sign, which is only split off once the metric's type
+    // is known.
+    value: &'a str,
+    // Everything after the value's "|", like "c|@0.1|#env:production". Always
+    // holds at least one field, and none of its fields are empty.
+    fields: &'a str,
+}
+
+// Splits a single metric line into its parts. A line with a name but no value
+// or type is reported as such rather than as being generally invalid so that
+// there's something useful for someone debugging a client to go on.
+fn parse_raw_metric(line: &[u8]) -> Result<RawMetric<'_>, ParseError> {
+    let line = str::from_utf8(line).map_err(|_| ParseError::InvalidUtf8)?;
+
+    let (name, rest) = match line.find(':') {
+        Some(0) => return Err(ParseError::EmptyName),
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => {
+            return Err(ParseError::MissingValue {
+                name: String::from(line),
+            })
+        }
+    };
+
+    // A value that's only a sign is caught later by `set_value`, once it's
+    // known whether the metric's type allows one.
+    let (value, fields) = match rest.find('|') {
+        Some(0) => {
+            return Err(ParseError::MissingValue {
+                name: String::from(name),
+            })
+        }
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None if rest.is_empty() || rest == "-" || rest == "+" => {
+            return Err(ParseError::MissingValue {
+                name: String::from(name),
+            })
+        }
+        None => {
+            return Err(ParseError::MissingType {
+                name: String::from(name),
+            })
+        }
+    };
+    if fields.split('|').any(str::is_empty) {
+        return Err(ParseError::Invalid);
+    }
+
+    Ok(RawMetric {
+        name,
+        value,
+        fields,
+    })
+}
+
+/// Parses a payload of "\n" delimited metrics, events, and service checks and
+/// applies the given configuration to them. The entire payload must be valid
+/// for the parse to succeed.
+pub fn parse(input: &[u8], config: &ParserConfig) -> Result<Batch, ParseError> {
+    parse_ref(input, config).map(Batch::from)
+}
+
+/// Like `parse`, but for input that's already a string.
+pub fn parse_str(input: &str, config: &ParserConfig) -> Result<Batch, ParseError> {
+    parse(input.as_bytes(), config)
+}
+
+/// Like `parse`, but returns metrics that borrow from `input` rather than
+/// copying their names, values, units, and tags out of it.
+pub fn parse_ref<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    parse_lines(input, config, parse_line)
+}
+
+// Splits a payload into lines and parses each of them with `parse_line`,
+// applying the configuration's limits and `InvalidLinePolicy`. Shared by all
+// of the line based formats.
+fn parse_lines<'a, F>(
+    input: &'a [u8],
+    config: &ParserConfig,
+    mut parse_line: F,
+) -> Result<BatchRef<'a>, ParseError>
+where
+    F: FnMut(&mut BatchRef<'a>, &ParserConfig, &'a [u8]) -> Result<bool, ParseError>,
+{
+    if input.is_empty() {
+        return Err(ParseError::Invalid);
+    }
+
+    let mut batch = BatchRef::default();
+    let mut rest = input;
+    let mut line_number = 0;
+    while !rest.is_empty() {
+        let offset = input.len() - rest.len();
+        let (line, remaining) = next_line(rest);
+        rest = remaining;
+        line_number += 1;
+
+        // Blank lines (like the one after a trailing newline) are ignored.
+        if line.is_empty() {
+            continue;
+        }
+        if limit_reached(&mut batch, config)? {
+            break;
+        }
+
+        let (num_metrics, num_diagnostics) =
+            (batch.metrics.len(), batch.diagnostics.len());
+        match parse_line(&mut batch, config, line) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(error @ ParseError::TooManyMetrics { .. }) => return Err(error),
+            Err(error) => match config.invalid_lines {
+                InvalidLinePolicy::Reject => {
+                    return Err(ParseError::Line {
+                        line: line_number,
+                        offset,
+                        error: Box::new(error),
+                    });
+                }
+                InvalidLinePolicy::Skip => {
+                    // Anything that the line added before failing goes with it.
+                    batch.metrics.truncate(num_metrics);
+                    batch.diagnostics.truncate(num_diagnostics);
+                    batch.diagnostics.push(Diagnostic::LineSkipped {
+                        line: line_number,
+                        offset,
+                        error,
+                    });
+                }
+            },
+        }
+    }
+    Ok(batch)
+}
+
+// Moves the line numbers and offsets in a parse's errors and diagnostics along
+// by `lines` and `offset`, for when the payload that was parsed was a piece of
+// a larger one.
+fn shift_lines(
+    result: Result<Batch, ParseError>,
+    lines: usize,
+    offset: usize,
+) -> Result<Batch, ParseError> {
+    match result {
+        Ok(mut batch) => {
+            for diagnostic in &mut batch.diagnostics {
+                if let Diagnostic::LineSkipped {
+                    line,
+                    offset: line_offset,
+                    ..
+                } = diagnostic
+                {
+                    *line += lines;
+                    *line_offset += offset;
+                }
+            }
+            Ok(batch)
+        }
+        Err(ParseError::Line {
+            line,
+            offset: line_offset,
+            error,
+        }) => Err(ParseError::Line {
+            line: line + lines,
+            offset: line_offset + offset,
+            error,
+        }),
+        Err(error) => Err(error),
+    }
+}
+
+// Parses a single line into the batch, returning true if the batch filled up
+// and the rest of the payload should be skipped.
+fn parse_line<'a>(
+    batch: &mut BatchRef<'a>,
+    config: &ParserConfig,
+    line: &'a [u8],
+) -> Result<bool, ParseError> {
+    check_line_len(line, config)?;
+
+    if line.starts_with(event::EVENT_PREFIX) {
+        let event = event::parse_event(line)?;
+        check_tag_count(&event.title, event.tags.len(), config)?;
+        batch.events.push(event);
+        return Ok(false);
+    }
+
+    if line.starts_with(service_check::SERVICE_CHECK_PREFIX) {
+        let check = service_check::parse_service_check(line)?;
+        check_tag_count(&check.name, check.tags.len(), config)?;
+        batch.service_checks.push(check);
+        return Ok(false);
+    }
+
+    let metric = build_metric(parse_raw_metric(line)?)?;
+    let values = metric.value;
+    let separator = value_separator(metric.metric_type);
+    if !metric.metric_type.is_numeric() || !values.contains(separator) {
+        push_metric(batch, config, set_value(metric, values)?)?;
+        return Ok(false);
+    }
+
+    // DogStatsD clients may pack several values for the same metric into one
+    // line (e.g. "glork:320:240|ms"). Each becomes a metric of its own.
+    for (i, value) in values.split(separator).enumerate() {
+        if i > 0 && limit_reached(batch, config)? {
+            return Ok(true);
+        }
+        push_metric(batch, config, set_value(metric.clone(), value)?)?;
+    }
+    Ok(false)
+}
+
+// Returns a pattern matching the characters that separate packed values for a
+// metric type. Besides DogStatsD's ":", several StatsD servers accept timer
+// observations separated by commas (e.g. "glork:320,240|ms").
+fn value_separator(metric_type: MetricType) -> impl Fn(char) -> bool + Copy {
+    move |c| c == ':' || (c == ',' && metric_type == MetricType::Sample)
+}
+
+// Checks whether the batch already holds `ParserConfig::max_metrics` metrics,
+// returning an error if excess metrics are rejected and true if the rest of
+// the payload should be skipped.
+fn limit_reached(
+    batch: &mut BatchRef<'_>,
+    config: &ParserConfig,
+) -> Result<bool, ParseError> {
+    match config.max_metrics {
+        Some(max) if batch.len() >= max => match config.excess_metrics {
+            ExcessMetricsPolicy::Reject => Err(ParseError::TooManyMetrics { max }),
+            ExcessMetricsPolicy::Truncate => {
+                batch.diagnostics.push(Diagnostic::MetricsTruncated { max });
+                Ok(true)
+            }
+        },
+        _ => Ok(false),
+    }
+}
+
+// Applies the configured policies to a metric and adds it to the batch.
+fn push_metric<'a>(
+    batch: &mut BatchRef<'a>,
+    config: &ParserConfig,
+    mut metric: MetricRef<'a>,
+) -> Result<(), ParseError> {
+    if config.sanitize_names {
+        if let Cow::Borrowed(name) = metric.name {
+            metric.name = sanitize_name(name);
+        }
+    }
+    check_name(&metric.name, config)?;
+    check_tag_count(&metric.name, metric.tags.len(), config)?;
+    check_type(&metric, config.unknown_types)?;
+    let metric = apply_value_policies(metric, config, &mut batch.diagnostics)?;
+    let metric = apply_negative_counter_policy(
+        metric,
+        config.negative_counters,
+        &mut batch.diagnostics,
+    )?;
+    let metric = apply_unsupported_sample_rate_policy(
+        metric,
+        config.unsupported_sample_rates,
+        &mut batch.diagnostics,
+    )?;
+    let metric = apply_invalid_sample_rate_policy(
+        metric,
+        config.invalid_sample_rates,
+        &mut batch.diagnostics,
+    )?;
+    batch.metrics.push(metric);
+    Ok(())
+}
+
+fn check_line_len(line: &[u8], config: &ParserConfig) -> Result<(), ParseError> {
+    match config.max_line_len {
+        Some(max) if line.len() > max => Err(ParseError::LineTooLong { max }),
+        _ => Ok(()),
+    }
+}
+
+fn check_name(name: &str, config: &ParserConfig) -> Result<(), ParseError> {
+    if let Some(max) = config.max_name_len {
+        if name.len() > max {
+            return Err(ParseError::NameTooLong {
+                name: String::from(name),
+                max,
+            });
+        }
+    }
+    if let Some(character) = name.chars().find(|&c| !config.name_charset.allows(c)) {
+        return Err(ParseError::DisallowedNameChar {
+            name: String::from(name),
+            character,
+        });
+    }
+    Ok(())
+}
+
+fn check_tag_count(
+    name: &str,
+    count: usize,
+    config: &ParserConfig,
+) -> Result<(), ParseError> {
+    match config.max_tags {
+        Some(max) if count > max => Err(ParseError::TooManyTags {
+            name: String::from(name),
+            max,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn check_type(
+    metric: &MetricRef<'_>,
+    policy: UnknownTypePolicy,
+) -> Result<(), ParseError> {
+    if policy == UnknownTypePolicy::Sample {
+        return Ok(());
+    }
+    match metric.unit {
+        Some(unit) if matches!(Unit::from(unit), Unit::Other(_)) => {
+            Err(ParseError::UnknownType {
+                name: metric.name.to_string(),
+                metric_type: String::from(unit),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Splits off the next line, returning it (without its "\n" or "\r\n") and the
+// input that follows it.
+fn next_line(input: &[u8]) -> (&[u8], &[u8]) {
+    let (line, rest) = match input.iter().position(|&b| b == b'\n') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => (input, &[][..]),
+    };
+    (line.strip_suffix(b"\r").unwrap_or(line), rest)
+}
+
+// Interprets a raw metric's fields. The first field is always its type (or
+// unit), and it may optionally be followed by a sample rate, tags, a container
+// ID, and a timestamp in any order. Anything else is an error rather than
+// being ignored so that buggy clients get noticed.
+//
+// The returned metric's value is left exactly as it was sent (including any
+// sign and packed values) and must be interpreted with `set_value`.
+fn build_metric(raw: RawMetric<'_>) -> Result<MetricRef<'_>, ParseError> {
+    let mut fields = raw.fields.split('|');
+
+    // `parse_raw_metric` guarantees that there's at least one field.
+    let type_or_unit = fields.next().unwrap();
+    if !is_type_code(type_or_unit) {
+        return Err(ParseError::InvalidType {
+            name: String::from(raw.name),
+            metric_type: String::from(type_or_unit),
+        });
+    }
+
+    let mut sample_rate = None;
+    let mut tags = None;
+    let mut container_id = None;
+    let mut timestamp = None;
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            if sample_rate.is_some() {
+                return Err(ParseError::DuplicateSampleRate {
+                    name: String::from(raw.name),
+                });
+            }
+            sample_rate =
+                Some(
+                    f64::from_str(rate).map_err(|_| ParseError::InvalidSampleRate {
+                        name: String::from(raw.name),
+                        sample_rate: String::from(rate),
+                    })?,
+                );
+        } else if let Some(t) = field.strip_prefix('#') {
+            if tags.is_some() {
+                return Err(ParseError::DuplicateTags {
+                    name: String::from(raw.name),
+                });
+            }
+            tags = Some(parse_tag_refs(t));
+        } else if let Some(id) = field.strip_prefix("c:") {
+            if container_id.is_some() {
+                return Err(ParseError::DuplicateContainerId {
+                    name: String::from(raw.name),
+                });
+            }
+            container_id = Some(id);
+        } else if let Some(ts) = field.strip_prefix('T') {
+            if timestamp.is_some() {
+                return Err(ParseError::DuplicateTimestamp {
+                    name: String::from(raw.name),
+                });
+            }
+            timestamp =
+                Some(i64::from_str(ts).map_err(|_| ParseError::InvalidTimestamp {
+                    name: String::from(raw.name),
+                    timestamp: String::from(ts),
+                })?);
+        } else if is_known_type_code(field) {
+            return Err(ParseError::DuplicateType {
+                name: String::from(raw.name),
+            });
+        } else {
+            return Err(ParseError::UnexpectedField {
+                name: String::from(raw.name),
+                field: String::from(field),
+            });
+        }
+    }
+
+    Ok(MetricRef {
+        name: Cow::Borrowed(raw.name),
+        value: raw.value,
+        metric_type: parse_metric_type(type_or_unit),
+        unit: parse_unit(type_or_unit),
+        sample_rate,
+        sign: None,
+        tags: tags.unwrap_or_default(),
+        container_id,
+        timestamp,
+    })
+}
+
+// Sets a metric's value to a single value as it was sent, splitting off its
+// sign and checking that it's a number for numeric metric types.
+fn set_value<'a>(
+    mut metric: MetricRef<'a>,
+    value: &'a str,
+) -> Result<MetricRef<'a>, ParseError> {
+    // Set members and key/value values are opaque, so a leading "+" or "-" is
+    // part of the value rather than a sign.
+    let (value, sign) = match metric.metric_type {
+        MetricType::Set | MetricType::KeyValue => (value, None),
+        _ => parse_sign(value),
+    };
+    if value.is_empty() {
+        return Err(ParseError::MissingValue {
+            name: metric.name.to_string(),
+        });
+    }
+
+    if metric.metric_type.is_numeric() && parse_value(value, sign).is_none() {
+        return Err(ParseError::InvalidValue {
+            name: metric.name.to_string(),
+            value: String::from(value),
+        });
+    }
+
+    metric.value = value;
+    metric.sign = sign;
+    Ok(metric)
+}
+
+fn apply_negative_counter_policy<'a>(
+    mut metric: MetricRef<'a>,
+    policy: NegativeCounterPolicy,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<MetricRef<'a>, ParseError> {
+    if metric.metric_type != MetricType::Counter || metric.sign != Some(MetricSign::Minus)
+    {
+        return Ok(metric);
+    }
+
+    match policy {
+        NegativeCounterPolicy::Accept => {
+            diagnostics.push(Diagnostic::NegativeCounterAccepted {
+                name: metric.name.to_string(),
+            });
+        }
+        NegativeCounterPolicy::ClampToZero => {
+            diagnostics.push(Diagnostic::NegativeCounterClamped {
+                name: metric.name.to_string(),
+            });
+            metric.value = "0";
+            metric.sign = None;
+        }
+        NegativeCounterPolicy::Reject => {
+            return Err(ParseError::NegativeCounter {
+                name: metric.name.to_string(),
+            });
+        }
+    }
+    Ok(metric)
+}
+
+// Applies `ParserConfig::exponents` and `ParserConfig::non_finite_values` to a
+// numeric metric's value.
+fn apply_value_policies<'a>(
+    mut metric: MetricRef<'a>,
+    config: &ParserConfig,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<MetricRef<'a>, ParseError> {
+    if !metric.metric_type.is_numeric() || is_plain_value(metric.value) {
+        return Ok(metric);
+    }
+
+    let invalid = |metric: &MetricRef<'_>| ParseError::InvalidValue {
+        name: metric.name.to_string(),
+        value: String::from(metric.value),
+    };
+    if is_exponent_value(metric.value) && config.exponents == ExponentPolicy::Reject {
+        return Err(invalid(&metric));
+    }
+    match metric.numeric_value() {
+        Some(MetricValue::Float(float)) if !float.is_finite() => (),
+        _ => return Ok(metric),
+    }
+
+    match config.non_finite_values {
+        NonFiniteValuePolicy::Accept => (),
+        NonFiniteValuePolicy::Clamp if !metric.value.eq_ignore_ascii_case("nan") => {
+            diagnostics.push(Diagnostic::NonFiniteValueClamped {
+                name: metric.name.to_string(),
+                value: String::from(metric.value),
+            });
+            metric.value = F64_MAX;
+        }
+        NonFiniteValuePolicy::Clamp | NonFiniteValuePolicy::Reject => {
+            return Err(invalid(&metric));
+        }
+    }
+    Ok(metric)
+}
+
+// The largest finite `f64`, which infinite values are clamped to.
+const F64_MAX: &str = "1.7976931348623157e308";
+
+// Whether a field could be a metric's type. Any alphanumeric field is
+// accepted because samples may use arbitrary units.
+fn is_type_code(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+// Whether a field is one of the type codes that's commonly sent by clients,
+// which is used to tell a duplicated type apart from an unrecognized field.
+fn is_known_type_code(s: &str) -> bool {
+    matches!(s, "c" | "d" | "g" | "h" | "kv" | "m" | "ms" | "s")
+}
+
+fn apply_invalid_sample_rate_policy<'a>(
+    mut metric: MetricRef<'a>,
+    policy: InvalidSampleRatePolicy,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<MetricRef<'a>, ParseError> {
+    let sample_rate = match metric.sample_rate {
+        Some(rate) if !is_valid_sample_rate(rate) => rate,
+        _ => return Ok(metric),
+    };
+
+    match policy {
+        InvalidSampleRatePolicy::Discard => {
+            diagnostics.push(Diagnostic::SampleRateOutOfRangeDiscarded {
+                name: metric.name.to_string(),
+                sample_rate,
+            });
+            metric.sample_rate = None;
+        }
+        InvalidSampleRatePolicy::Reject => {
+            return Err(ParseError::SampleRateOutOfRange {
+                name: metric.name.to_string(),
+                sample_rate,
+            });
+        }
+    }
+    Ok(metric)
+}
+
+// Whether a sample rate is in the range (0, 1]. NaN isn't.
+fn is_valid_sample_rate(rate: f64) -> bool {
+    rate > 0.0 && rate <= 1.0
+}
+
+fn apply_unsupported_sample_rate_policy<'a>(
+    mut metric: MetricRef<'a>,
+    policy: UnsupportedSampleRatePolicy,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<MetricRef<'a>, ParseError> {
+    if metric.sample_rate.is_none() || metric.metric_type.supports_sample_rate() {
+        return Ok(metric);
+    }
+
+    match policy {
+        UnsupportedSampleRatePolicy::Discard => {
+            diagnostics.push(Diagnostic::UnsupportedSampleRateDiscarded {
+                name: metric.name.to_string(),
+                metric_type: metric.metric_type,
+            });
+            metric.sample_rate = None;
+        }
+        UnsupportedSampleRatePolicy::Reject => {
+            return Err(ParseError::UnsupportedSampleRate {
+                name: metric.name.to_string(),
+                metric_type: metric.metric_type,
+            });
+        }
+    }
+    Ok(metric)
+}
+
+fn parse_metric_type(s: &str) -> MetricType {
+    match s {
+        "c" => MetricType::Counter,
+        "d" => MetricType::Distribution,
+        "g" => MetricType::Gauge,
+        "h" => MetricType::Histogram,
+        "kv" => MetricType::KeyValue,
+        "m" => MetricType::Meter,
+        "s" => MetricType::Set,
+        _ => MetricType::Sample,
+    }
+}
+
+// Splits a leading "+" or "-" off of a value.
+fn parse_sign(value: &str) -> (&str, Option<MetricSign>) {
+    if let Some(v) = value.strip_prefix('-') {
+        (v, Some(MetricSign::Minus))
+    } else if let Some(v) = value.strip_prefix('+') {
+        (v, Some(MetricSign::Plus))
+    } else {
+        (value, None)
+    }
+}
+
+// Parses the value of a numeric metric. Integers are parsed as `Unsigned`
+// unless they're negative, and anything with a decimal point, an exponent, or
+// that isn't finite as `Float`. Whether the latter two are allowed is up to
+// `apply_value_policies`.
+fn parse_value(value: &str, sign: Option<MetricSign>) -> Option<MetricValue> {
+    let negative = sign == Some(MetricSign::Minus);
+    if !is_plain_value(value) {
+        if !is_exponent_value(value) && !is_non_finite_value(value) {
+            return None;
+        }
+        let float = f64::from_str(value).ok()?;
+        return Some(MetricValue::Float(if negative { -float } else { float }));
+    }
+
+    if value.contains('.') {
+        let float = f64::from_str(value).ok()?;
+        return Some(MetricValue::Float(if negative { -float } else { float }));
+    }
+
+    if negative {
+        i64::from_str(&format!("-{}", value))
+            .ok()
+            .map(MetricValue::Integer)
+    } else {
+        u64::from_str(value).ok().map(MetricValue::Unsigned)
+    }
+}
+
+// Whether an unsigned value is a plain decimal number like "1" or "320.5".
+fn is_plain_value(value: &str) -> bool {
+    let digits = value.bytes().filter(|b| b.is_ascii_digit()).count();
+    let points = value.bytes().filter(|&b| b == b'.').count();
+    digits > 0 && digits + points == value.len() && points <= 1
+}
+
+// Whether an unsigned value is a decimal number with an exponent like "1e6" or
+// "2.5E-3".
+fn is_exponent_value(value: &str) -> bool {
+    match value.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => {
+            let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            is_plain_value(mantissa)
+                && !exponent.is_empty()
+                && exponent.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// Whether an unsigned value is one of the words for a value that isn't a
+// finite number.
+fn is_non_finite_value(value: &str) -> bool {
+    ["nan", "inf", "infinity"]
+        .iter()
+        .any(|word| value.eq_ignore_ascii_case(word))
+}
+
+// Parses a comma-separated list of tags. Empty entries (e.g. from a trailing
+// comma) are skipped.
+fn parse_tags(s: &str) -> Vec<Tag> {
+    parse_tag_refs(s).into_iter().map(Tag::from).collect()
+}
+
+fn parse_tag_refs(s: &str) -> Vec<TagRef<'_>> {
+    s.split(',')
+        .filter(|t| !t.is_empty())
+        .map(|t| match t.find(':') {
+            Some(i) => TagRef {
+                key: &t[..i],
+                value: Some(&t[i + 1..]),
+            },
+            None => TagRef {
+                key: t,
+                value: None,
+            },
+        })
+        .collect()
+}
+
+fn parse_unit(s: &str) -> Option<&str> {
+    match s {
+        "c" => None,
+        "d" => None,
+        "g" => None,
+        "h" => None,
+        "kv" => None,
+        "m" => None,
+        "s" => None,
+        a => Some(a),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Wraps an error the way that `parse` does for an error on the given line.
+    fn on_line(line: usize, offset: usize, error: ParseError) -> ParseError {
+        ParseError::Line {
+            line,
+            offset,
+            error: Box::new(error),
+        }
+    }
+
+    #[test]
+    fn it_parses_counter() {
+        assert_eq!(
+            Metric::try_from(&b"gorets:1|c"[..]),
+            Ok(Metric {
+                name: String::from("gorets"),
+                value: String::from("1"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_counter_with_sample_rate() {
+        assert_eq!(
+            Metric::try_from(&b"gorets:1|c|@0.1"[..]),
+            Ok(Metric {
+                name: String::from("gorets"),
+                value: String::from("1"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: Some(0.1),
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_sample() {
+        assert_eq!(
+            Metric::try_from(&b"glork:320|ms"[..]),
+            Ok(Metric {
+                name: String::from("glork"),
+                value: String::from("320"),
+                metric_type: MetricType::Sample,
+                unit: Some(Unit::Milliseconds),
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_sample_with_sample_rate() {
+        assert_eq!(
+            Metric::try_from(&b"glork:320|ms|@0.1"[..]),
+            Ok(Metric {
+                name: String::from("glork"),
+                value: String::from("320"),
+                metric_type: MetricType::Sample,
+                unit: Some(Unit::Milliseconds),
+                sample_rate: Some(0.1),
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_gauge() {
+        assert_eq!(
+            Metric::try_from(&b"gaugor:333|g"[..]),
+            Ok(Metric {
+                name: String::from("gaugor"),
+                value: String::from("333"),
+                metric_type: MetricType::Gauge,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_signed_gauge() {
+        assert_eq!(
+            Metric::try_from(&b"gaugor:-10|g"[..]),
+            Ok(Metric {
+                name: String::from("gaugor"),
+                value: String::from("10"),
+                metric_type: MetricType::Gauge,
+                unit: None,
+                sample_rate: None,
+                sign: Some(MetricSign::Minus),
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+
+        assert_eq!(
+            Metric::try_from(&b"gaugor:+4|g"[..]),
+            Ok(Metric {
+                name: String::from("gaugor"),
+                value: String::from("4"),
+                metric_type: MetricType::Gauge,
+                unit: None,
+                sample_rate: None,
+                sign: Some(MetricSign::Plus),
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_set() {
+        assert_eq!(
+            Metric::try_from(&b"uniques:765|s"[..]),
+            Ok(Metric {
+                name: String::from("uniques"),
+                value: String::from("765"),
+                metric_type: MetricType::Set,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+
+        // Members are opaque, so a leading sign is kept as part of the member.
+        let metric = Metric::try_from(&b"users:-abc|s"[..]).unwrap();
+        assert_eq!((metric.value.as_str(), metric.sign), ("-abc", None));
+    }
+
+    #[test]
+    fn it_parses_single_metric_with_parse() {
+        assert_eq!(
+            parse(b"gorets:1|c", &ParserConfig::default()).map(|b| b.metrics),
+            Ok(vec![Metric {
+                name: String::from("gorets"),
+                value: String::from("1"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            }])
+        );
+    }
+
+    #[test]
+    fn it_parses_multiple_metrics_with_parse() {
+        let data = b"gorets:1|c\nglork:320|ms\ngaugor:333|g\nuniques:765|s";
+        assert_eq!(
+            parse(data, &ParserConfig::default()).map(|b| b.metrics),
+            Ok(vec![
+                Metric {
+                    name: String::from("gorets"),
+                    value: String::from("1"),
+                    metric_type: MetricType::Counter,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+                Metric {
+                    name: String::from("glork"),
+                    value: String::from("320"),
+                    metric_type: MetricType::Sample,
+                    unit: Some(Unit::Milliseconds),
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+                Metric {
+                    name: String::from("gaugor"),
+                    value: String::from("333"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+                Metric {
+                    name: String::from("uniques"),
+                    value: String::from("765"),
+                    metric_type: MetricType::Set,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+            ])
+        )
+    }
+
+    #[test]
+    fn it_accepts_negative_counters_with_a_diagnostic() {
+        let batch = parse(b"gorets:-5|c", &ParserConfig::default()).unwrap();
+        assert_eq!(
+            batch.metrics,
+            vec![Metric {
+                name: String::from("gorets"),
+                value: String::from("5"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: None,
+                sign: Some(MetricSign::Minus),
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            }]
+        );
+        assert_eq!(
+            batch.diagnostics,
+            vec![Diagnostic::NegativeCounterAccepted {
+                name: String::from("gorets")
+            },]
+        );
+    }
+
+    #[test]
+    fn it_clamps_negative_counters() {
+        let config = ParserConfig {
+            negative_counters: NegativeCounterPolicy::ClampToZero,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"gorets:-5|c\ngaugor:-10|g", &config).unwrap();
+        assert_eq!(
+            batch.metrics[0],
+            Metric {
+                name: String::from("gorets"),
+                value: String::from("0"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            }
+        );
+        // Gauges are allowed to be negative and are left alone.
+        assert_eq!(batch.metrics[1].sign, Some(MetricSign::Minus));
+        assert_eq!(
+            batch.diagnostics,
+            vec![Diagnostic::NegativeCounterClamped {
+                name: String::from("gorets")
+            },]
+        );
+    }
+
+    #[test]
+    fn it_rejects_negative_counters() {
+        let config = ParserConfig {
+            negative_counters: NegativeCounterPolicy::Reject,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"gorets:-5|c", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::NegativeCounter {
+                    name: String::from("gorets")
+                }
+            ))
+        );
+        assert!(parse(b"gorets:5|c", &config).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_invalid_payloads() {
+        let config = ParserConfig::default();
+        assert_eq!(parse(b"", &config), Err(ParseError::Invalid));
+        assert_eq!(
+            parse(b"gorets:1|c\ngorets", &config),
+            Err(on_line(
+                2,
+                11,
+                ParseError::MissingValue {
+                    name: String::from("gorets")
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"gorets:1|c\ngorets:1|c\n:1|c", &config),
+            Err(on_line(3, 22, ParseError::EmptyName))
+        );
+
+        for (line, error) in [
+            (
+                &b"gorets:|c"[..],
+                ParseError::MissingValue {
+                    name: String::from("gorets"),
+                },
+            ),
+            (
+                b"gorets:-|c",
+                ParseError::MissingValue {
+                    name: String::from("gorets"),
+                },
+            ),
+            (
+                b"gorets:1",
+                ParseError::MissingType {
+                    name: String::from("gorets"),
+                },
+            ),
+            (b"gorets:1||c", ParseError::Invalid),
+            (
+                b"gorets:1|@c",
+                ParseError::InvalidType {
+                    name: String::from("gorets"),
+                    metric_type: String::from("@c"),
+                },
+            ),
+            (
+                b"gorets:1|c|@x",
+                ParseError::InvalidSampleRate {
+                    name: String::from("gorets"),
+                    sample_rate: String::from("x"),
+                },
+            ),
+            (b"gor\xffets:1|c", ParseError::InvalidUtf8),
+        ] {
+            assert_eq!(Metric::try_from(line), Err(error));
+        }
+
+        let error = parse(b"gorets:1|c\ngorets", &config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2 (byte 11): missing value for metric \"gorets\""
+        );
+    }
+
+    #[test]
+    fn it_rejects_duplicate_fields() {
+        let config = ParserConfig::default();
+        assert_eq!(
+            parse(b"a:1|c|c", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::DuplicateType {
+                    name: String::from("a")
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"a:1|ms|@0.1|@0.5", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::DuplicateSampleRate {
+                    name: String::from("a")
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"gorets:1|c\na:1|c|c", &config),
+            Err(on_line(
+                2,
+                11,
+                ParseError::DuplicateType {
+                    name: String::from("a")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_rejects_unexpected_fields() {
+        assert_eq!(
+            parse(b"a:1|g|@0.5|junk", &ParserConfig::default()),
+            Err(on_line(
+                1,
+                0,
+                ParseError::UnexpectedField {
+                    name: String::from("a"),
+                    field: String::from("junk"),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_identifies_metrics_by_series() {
+        let batch = parse(
+            b"gorets:1|c\ngorets:2|c\nglork:320|ms",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            batch.metrics[0].id(),
+            MetricId::new("gorets", MetricType::Counter, Vec::new())
+        );
+        assert_eq!(batch.metrics[0].id(), batch.metrics[1].id());
+        assert!(batch.metrics[2].id() < batch.metrics[0].id());
+
+        let mut series = HashSet::new();
+        for metric in &batch.metrics {
+            series.insert(metric.id());
+        }
+        assert_eq!(series.len(), 2);
+
+        // Metrics of different types are different series even if they share a
+        // name.
+        let counter = Metric::try_from("gorets:1|c").unwrap();
+        let gauge = Metric::try_from("gorets:1|g").unwrap();
+        assert_ne!(counter.id(), gauge.id());
+        assert!(counter.id() < gauge.id());
+        assert_eq!(
+            MetricRef::try_from("gorets:1|c").unwrap().id(),
+            counter.id()
+        );
+    }
+
+    #[test]
+    fn it_sorts_batches() {
+        let mut batch = parse(
+            b"glork:320|ms\ngorets:2|c\nglork:1|c\ngorets:1|c",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        batch.sort();
+        let sorted: Vec<(&str, &str, MetricType)> = batch
+            .metrics
+            .iter()
+            .map(|m| (m.name.as_str(), m.value.as_str(), m.metric_type))
+            .collect();
+        assert_eq!(
+            sorted,
+            vec![
+                ("glork", "1", MetricType::Counter),
+                ("glork", "320", MetricType::Sample),
+                ("gorets", "2", MetricType::Counter),
+                ("gorets", "1", MetricType::Counter),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_groups_batches_by_series() {
+        let batch = parse(
+            b"gorets:2|c\nglork:320|ms\ngorets:1|c",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        let groups = batch.group_by_series();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[&MetricId::new("glork", MetricType::Sample, Vec::new())],
+            vec![&batch.metrics[1]]
+        );
+        assert_eq!(
+            groups[&MetricId::new("gorets", MetricType::Counter, Vec::new())],
+            vec![&batch.metrics[0], &batch.metrics[2]]
+        );
+    }
+
+    #[test]
+    fn it_converts_input_into_metrics() {
+        let expected = Metric {
+            name: String::from("gorets"),
+            value: String::from("1"),
+            metric_type: MetricType::Counter,
+            unit: None,
+            sample_rate: Some(0.1),
+            sign: None,
+            tags: Vec::new(),
+            container_id: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            Metric::try_from(&b"gorets:1|c|@0.1"[..]),
+            Ok(expected.clone())
+        );
+        assert_eq!(Metric::try_from("gorets:1|c|@0.1"), Ok(expected.clone()));
+        assert_eq!(
+            Metric::try_from(String::from("gorets:1|c|@0.1")),
+            Ok(expected)
+        );
+
+        assert_eq!(
+            Metric::try_from("gorets:1|c\nglork:320|ms"),
+            Err(ParseError::Invalid)
+        );
+        assert_eq!(
+            Metric::try_from("gorets:1|c|c"),
+            Err(ParseError::DuplicateType {
+                name: String::from("gorets")
+            })
+        );
+    }
+
+    #[test]
+    fn it_converts_input_into_batches() {
+        let batch = Batch::try_from("gorets:1|c\nglork:320|ms").unwrap();
+        assert_eq!(batch.metrics.len(), 2);
+        assert_eq!(
+            Batch::try_from(String::from("gorets:1|c\nglork:320|ms")),
+            Ok(batch)
+        );
+        assert_eq!(Batch::try_from(&b""[..]), Err(ParseError::Invalid));
+    }
+
+    #[test]
+    fn it_discards_sample_rates_on_unsupported_types() {
+        let batch = parse(
+            b"gaugor:333|g|@0.1\nuniques:765|s|@0.5\ngorets:1|c|@0.1",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(batch.metrics[0].sample_rate, None);
+        assert_eq!(batch.metrics[1].sample_rate, None);
+        assert_eq!(batch.metrics[2].sample_rate, Some(0.1));
+        assert_eq!(
+            batch.diagnostics,
+            vec![
+                Diagnostic::UnsupportedSampleRateDiscarded {
+                    name: String::from("gaugor"),
+                    metric_type: MetricType::Gauge,
+                },
+                Diagnostic::UnsupportedSampleRateDiscarded {
+                    name: String::from("uniques"),
+                    metric_type: MetricType::Set,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_sample_rates_on_unsupported_types() {
+        let config = ParserConfig {
+            unsupported_sample_rates: UnsupportedSampleRatePolicy::Reject,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"gaugor:333|g|@0.1", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::UnsupportedSampleRate {
+                    name: String::from("gaugor"),
+                    metric_type: MetricType::Gauge,
+                }
+            ))
+        );
+        assert!(parse(b"glork:320|ms|@0.1", &config).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_payloads_with_too_many_metrics() {
+        let config = ParserConfig {
+            max_metrics: Some(2),
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"a:1|c\nb:2|c\nc:3|c", &config),
+            Err(ParseError::TooManyMetrics { max: 2 })
+        );
+        assert_eq!(parse(b"a:1|c\nb:2|c", &config).unwrap().metrics.len(), 2);
+    }
+
+    #[test]
+    fn it_truncates_payloads_with_too_many_metrics() {
+        let config = ParserConfig {
+            max_metrics: Some(1),
+            excess_metrics: ExcessMetricsPolicy::Truncate,
+            ..ParserConfig::default()
+        };
+
+        // Everything after the limit is skipped, so it isn't even parsed.
+        let batch = parse(b"a:1|c\nnot a metric", &config).unwrap();
+        assert_eq!(batch.metrics.len(), 1);
+        assert_eq!(batch.metrics[0].name, "a");
+        assert_eq!(
+            batch.diagnostics,
+            vec![Diagnostic::MetricsTruncated { max: 1 }]
+        );
+    }
+
+    #[test]
+    fn it_parses_key_value() {
+        assert_eq!(
+            Metric::try_from(&b"config.version:1.2.3|kv"[..]),
+            Ok(Metric {
+                name: String::from("config.version"),
+                value: String::from("1.2.3"),
+                metric_type: MetricType::KeyValue,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+
+        // Values are opaque, so a leading sign is kept as part of the value.
+        assert_eq!(
+            Metric::try_from(&b"offset:-0500|kv"[..]).map(|m| m.value),
+            Ok(String::from("-0500"))
+        );
+    }
+
+    #[test]
+    fn it_parses_tags() {
+        assert_eq!(
+            Metric::try_from(
+                &b"page.views:1|c|@0.5|#env:production,canary,url:http://x"[..]
+            ),
+            Ok(Metric {
+                name: String::from("page.views"),
+                value: String::from("1"),
+                metric_type: MetricType::Counter,
+                unit: None,
+                sample_rate: Some(0.5),
+                sign: None,
+                tags: vec![
+                    Tag::new("env", Some("production")),
+                    Tag::new("canary", None),
+                    Tag::new("url", Some("http://x")),
+                ],
+                container_id: None,
+                timestamp: None,
+            })
+        );
+
+        // Tags may come before the sample rate as well.
+        let metric = Metric::try_from(&b"page.views:1|c|#canary|@0.5"[..]).unwrap();
+        assert_eq!(metric.sample_rate, Some(0.5));
+        assert_eq!(metric.tags, vec![Tag::new("canary", None)]);
+
+        assert_eq!(
+            parse(b"a:1|g|@0.5|#tags|junk", &ParserConfig::default()),
+            Err(on_line(
+                1,
+                0,
+                ParseError::UnexpectedField {
+                    name: String::from("a"),
+                    field: String::from("junk"),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"a:1|c|#x|#y", &ParserConfig::default()),
+            Err(on_line(
+                1,
+                0,
+                ParseError::DuplicateTags {
+                    name: String::from("a")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_identifies_series_by_canonical_tags() {
+        let a = Metric::try_from("gorets:1|c|#env:production,canary").unwrap();
+        let b = Metric::try_from("gorets:1|c|#canary,env:production,canary").unwrap();
+        let c = Metric::try_from("gorets:1|c|#env:staging").unwrap();
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a.id(), c.id());
+        assert_eq!(
+            a.id().tags(),
+            &[
+                Tag::new("canary", None),
+                Tag::new("env", Some("production"))
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_histogram() {
+        assert_eq!(
+            Metric::try_from(&b"request.size:512|h|@0.5"[..]),
+            Ok(Metric {
+                name: String::from("request.size"),
+                value: String::from("512"),
+                metric_type: MetricType::Histogram,
+                unit: None,
+                sample_rate: Some(0.5),
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_distribution() {
+        assert_eq!(
+            Metric::try_from(&b"request.latency:42.5|d|#env:production"[..]),
+            Ok(Metric {
+                name: String::from("request.latency"),
+                value: String::from("42.5"),
+                metric_type: MetricType::Distribution,
+                unit: None,
+                sample_rate: None,
+                sign: None,
+                tags: vec![Tag::new("env", Some("production"))],
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_meter() {
+        assert_eq!(
+            Metric::try_from(&b"requests:1|m|@0.5"[..]),
+            Ok(Metric {
+                name: String::from("requests"),
+                value: String::from("1"),
+                metric_type: MetricType::Meter,
+                unit: None,
+                sample_rate: Some(0.5),
+                sign: None,
+                tags: Vec::new(),
+                container_id: None,
+                timestamp: None,
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_events_mixed_with_metrics() {
+        let batch = parse(
+            b"gorets:1|c\n_e{6,2}:deploy|ok|t:success\nglork:320|ms",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(batch.metrics.len(), 2);
+        assert_eq!(batch.events.len(), 1);
+        assert_eq!(batch.events[0].title, "deploy");
+        assert_eq!(batch.events[0].alert_type, Some(EventAlertType::Success));
+
+        let config = ParserConfig {
+            max_metrics: Some(1),
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"_e{6,2}:deploy|ok\ngorets:1|c", &config),
+            Err(ParseError::TooManyMetrics { max: 1 })
+        );
+    }
+
+    #[test]
+    fn it_parses_service_checks_mixed_with_metrics() {
+        let batch = parse(
+            b"gorets:1|c\n_sc|redis.can_connect|1|m:slow\n_e{6,2}:deploy|ok",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.service_checks.len(), 1);
+        assert_eq!(batch.service_checks[0].status, ServiceCheckStatus::Warning);
+        assert_eq!(batch.service_checks[0].message, Some(String::from("slow")));
+    }
+
+    #[test]
+    fn it_distinguishes_absolute_and_delta_gauges() {
+        let gauge_mode = |s: &str| Metric::try_from(s).unwrap().gauge_mode();
+        assert_eq!(gauge_mode("gaugor:333|g"), Some(GaugeMode::Absolute));
+        assert_eq!(
+            gauge_mode("gaugor:+10|g"),
+            Some(GaugeMode::Delta(MetricSign::Plus))
+        );
+        assert_eq!(
+            gauge_mode("gaugor:-4|g"),
+            Some(GaugeMode::Delta(MetricSign::Minus))
+        );
+        assert_eq!(gauge_mode("gorets:-1|c"), None);
+    }
+
+    #[test]
+    fn it_parses_numeric_values() {
+        let numeric_value = |s: &str| Metric::try_from(s).unwrap().numeric_value();
+        assert_eq!(numeric_value("gorets:1|c"), Some(MetricValue::Unsigned(1)));
+        assert_eq!(numeric_value("gorets:-5|c"), Some(MetricValue::Integer(-5)));
+        assert_eq!(
+            numeric_value("glork:320.5|ms"),
+            Some(MetricValue::Float(320.5))
+        );
+        assert_eq!(
+            numeric_value("gaugor:-.5|g"),
+            Some(MetricValue::Float(-0.5))
+        );
+        assert_eq!(
+            numeric_value("gaugor:+10|g"),
+            Some(MetricValue::Unsigned(10))
+        );
+        assert_eq!(numeric_value("uniques:765|s"), None);
+        assert_eq!(numeric_value("config.version:1.2.3|kv"), None);
+    }
+
+    #[test]
+    fn it_rejects_non_numeric_values() {
+        for (line, value) in [
+            ("gorets:x|c", "x"),
+            ("glork:1.2.3|ms", "1.2.3"),
+            ("gaugor:.|g", "."),
+            ("gorets:99999999999999999999|c", "99999999999999999999"),
+        ] {
+            assert_eq!(
+                Metric::try_from(line),
+                Err(ParseError::InvalidValue {
+                    name: String::from(line.split(':').next().unwrap()),
+                    value: String::from(value),
+                })
+            );
+        }
+
+        // Sets are opaque, so any value is fine.
+        assert!(Metric::try_from("users:bob|s").is_ok());
+    }
+
+    #[test]
+    fn it_parses_borrowed_metrics() {
+        let input = b"glork:320|ms|@0.1|#env:production\nconfig.version:-1.2|kv";
+        let batch = parse_ref(input, &ParserConfig::default()).unwrap();
+        assert_eq!(
+            batch.metrics,
+            vec![
+                MetricRef {
+                    name: Cow::Borrowed("glork"),
+                    value: "320",
+                    metric_type: MetricType::Sample,
+                    unit: Some("ms"),
+                    sample_rate: Some(0.1),
+                    sign: None,
+                    tags: vec![TagRef {
+                        key: "env",
+                        value: Some("production")
+                    }],
+                    container_id: None,
+                    timestamp: None,
+                },
+                MetricRef {
+                    name: Cow::Borrowed("config.version"),
+                    value: "-1.2",
+                    metric_type: MetricType::KeyValue,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+            ]
+        );
+        assert_eq!(
+            Batch::from(batch),
+            parse(input, &ParserConfig::default()).unwrap()
+        );
+
+        let metric = MetricRef::try_from("gorets:-5|c").unwrap();
+        assert_eq!((metric.value, metric.sign), ("5", Some(MetricSign::Minus)));
+        assert_eq!(metric.numeric_value(), Some(MetricValue::Integer(-5)));
+    }
+
+    #[test]
+    fn it_unpacks_packed_values() {
+        let batch = parse(
+            b"glork:320:-240:120.5|ms|#env:production\nusers:a:b|s",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        let values: Vec<_> = batch
+            .metrics
+            .iter()
+            .map(|m| (m.name.as_str(), m.numeric_value()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("glork", Some(MetricValue::Unsigned(320))),
+                ("glork", Some(MetricValue::Integer(-240))),
+                ("glork", Some(MetricValue::Float(120.5))),
+                ("users", None),
+            ]
+        );
+        assert!(batch.metrics[..3]
+            .iter()
+            .all(|m| m.tags == vec![Tag::new("env", Some("production"))]));
+
+        // Sets are opaque, so packing doesn't apply to them.
+        assert_eq!(batch.metrics[3].value, "a:b");
+
+        // Each packed value counts towards the limit.
+        let config = ParserConfig {
+            max_metrics: Some(2),
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse(b"glork:1:2:3|ms", &config),
+            Err(ParseError::TooManyMetrics { max: 2 })
+        );
+
+        assert_eq!(
+            parse(b"glork:1::3|ms", &ParserConfig::default()),
+            Err(on_line(
+                1,
+                0,
+                ParseError::MissingValue {
+                    name: String::from("glork")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_applies_value_policies() {
+        let invalid = |value: &str| {
+            Err(on_line(
+                1,
+                0,
+                ParseError::InvalidValue {
+                    name: String::from("gaugor"),
+                    value: String::from(value),
+                },
+            ))
+        };
+        for line in ["gaugor:1e6|g", "gaugor:NaN|g", "gaugor:-inf|g"] {
+            let value = line[7..].split('|').next().unwrap().trim_start_matches('-');
+            assert_eq!(
+                parse(line.as_bytes(), &ParserConfig::default()),
+                invalid(value)
+            );
+        }
+        assert_eq!(
+            Metric::try_from("gaugor:Infinity|g"),
+            Err(ParseError::InvalidValue {
+                name: String::from("gaugor"),
+                value: String::from("Infinity"),
+            })
+        );
+
+        let config = ParserConfig {
+            exponents: ExponentPolicy::Accept,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"gaugor:2.5E-3|g", &config).unwrap();
+        assert_eq!(
+            batch.metrics[0].numeric_value(),
+            Some(MetricValue::Float(2.5e-3))
+        );
+        assert_eq!(parse(b"gaugor:1e999|g", &config), invalid("1e999"));
+
+        let config = ParserConfig {
+            non_finite_values: NonFiniteValuePolicy::Clamp,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"glork:-Inf|ms", &config).unwrap();
+        assert_eq!(
+            batch.metrics[0].numeric_value(),
+            Some(MetricValue::Float(f64::MIN))
+        );
+        assert_eq!(
+            batch.diagnostics,
+            vec![Diagnostic::NonFiniteValueClamped {
+                name: String::from("glork"),
+                value: String::from("Inf"),
+            }]
+        );
+        assert_eq!(parse(b"gaugor:NaN|g", &config), invalid("NaN"));
+
+        let config = ParserConfig {
+            non_finite_values: NonFiniteValuePolicy::Accept,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"gaugor:nan|g", &config).unwrap();
+        let value = batch.metrics[0].numeric_value();
+        assert!(matches!(value, Some(MetricValue::Float(f)) if f.is_nan()));
+    }
+
+    #[test]
+    fn it_unpacks_comma_separated_timer_values() {
+        let batch = parse(
+            b"glork:320,240:120|ms|@0.1\ngaugor:1,2|g",
+            &ParserConfig::default(),
+        );
+        assert_eq!(
+            batch,
+            Err(on_line(
+                2,
+                26,
+                ParseError::InvalidValue {
+                    name: String::from("gaugor"),
+                    value: String::from("1,2"),
+                }
+            ))
+        );
+
+        let batch =
+            parse(b"glork:320,240:120|ms|@0.1", &ParserConfig::default()).unwrap();
+        let values: Vec<_> = batch
+            .metrics
+            .iter()
+            .map(|m| (m.value.as_str(), m.sample_rate))
+            .collect();
+        assert_eq!(
+            values,
+            vec![("320", Some(0.1)), ("240", Some(0.1)), ("120", Some(0.1))]
+        );
+
+        assert_eq!(
+            parse(b"glork:320,|ms", &ParserConfig::default()),
+            Err(on_line(
+                1,
+                0,
+                ParseError::MissingValue {
+                    name: String::from("glork")
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_parses_container_id() {
+        let metric = Metric::try_from("page.views:1|c|#env:production|c:83c0a9").unwrap();
+        assert_eq!(metric.container_id, Some(String::from("83c0a9")));
+        assert_eq!(metric.tags, vec![Tag::new("env", Some("production"))]);
+
+        assert_eq!(
+            Metric::try_from("page.views:1|c|c:a|c:b"),
+            Err(ParseError::DuplicateContainerId {
+                name: String::from("page.views")
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_timestamp() {
+        for line in [
+            "page.views:1|c|@0.5|#env:production|c:83c0a9|T1656581400",
+            "page.views:1|c|T1656581400|c:83c0a9|#env:production|@0.5",
+        ] {
+            let metric = Metric::try_from(line).unwrap();
+            assert_eq!(metric.timestamp, Some(1656581400));
+            assert_eq!(metric.sample_rate, Some(0.5));
+            assert_eq!(metric.container_id, Some(String::from("83c0a9")));
+            assert_eq!(metric.tags, vec![Tag::new("env", Some("production"))]);
+        }
+
+        assert_eq!(
+            Metric::try_from("page.views:1|c|Tsoon"),
+            Err(ParseError::InvalidTimestamp {
+                name: String::from("page.views"),
+                timestamp: String::from("soon"),
+            })
+        );
+        assert_eq!(
+            Metric::try_from("page.views:1|c|T1|T2"),
+            Err(ParseError::DuplicateTimestamp {
+                name: String::from("page.views")
+            })
+        );
+    }
+
+    #[test]
+    fn it_skips_invalid_lines() {
+        let config = ParserConfig {
+            invalid_lines: InvalidLinePolicy::Skip,
+            ..ParserConfig::default()
+        };
+        let batch =
+            parse(b"gorets:1|c\ngorets\nglork:1:x|ms\ngaugor:333|g", &config).unwrap();
+        let names: Vec<_> = batch.metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["gorets", "gaugor"]);
+        assert_eq!(
+            batch.diagnostics,
+            vec![
+                Diagnostic::LineSkipped {
+                    line: 2,
+                    offset: 11,
+                    error: ParseError::MissingValue {
+                        name: String::from("gorets")
+                    },
+                },
+                Diagnostic::LineSkipped {
+                    line: 3,
+                    offset: 18,
+                    error: ParseError::InvalidValue {
+                        name: String::from("glork"),
+                        value: String::from("x"),
+                    },
+                },
+            ]
+        );
+
+        // Exceeding the limit still fails the whole payload.
+        let config = ParserConfig {
+            max_metrics: Some(1),
+            ..config
+        };
+        assert_eq!(
+            parse(b"gorets:1|c\ngorets:1|c", &config),
+            Err(ParseError::TooManyMetrics { max: 1 })
+        );
+    }
+
+    #[test]
+    fn it_parses_strings() {
+        let metric: Metric = "gorets:1|c".parse().unwrap();
+        assert_eq!(metric, Metric::try_from("gorets:1|c").unwrap());
+        assert_eq!(
+            "gorets".parse::<Metric>(),
+            Err(ParseError::MissingValue {
+                name: String::from("gorets")
+            })
+        );
+
+        let batch: Batch = "gorets:1|c\nglork:320|ms".parse().unwrap();
+        assert_eq!(
+            parse_str("gorets:1|c\nglork:320|ms", &ParserConfig::default()),
+            Ok(batch)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_metrics() {
+        let metric = Metric::try_from("glork:320|ms|@0.1|#env:production").unwrap();
+        let json = serde_json::to_string(&metric).unwrap();
+        assert_eq!(serde_json::from_str::<Metric>(&json).unwrap(), metric);
+        assert_eq!(
+            serde_json::to_string(&MetricType::Counter).unwrap(),
+            "\"Counter\""
+        );
+    }
+
+    #[test]
+    fn it_normalizes_units() {
+        let unit = |s: &str| Metric::try_from(s).unwrap().unit;
+        assert_eq!(unit("glork:320|ms"), Some(Unit::Milliseconds));
+        assert_eq!(unit("glork:320|msec"), Some(Unit::Milliseconds));
+        assert_eq!(unit("glork:320|us"), Some(Unit::Microseconds));
+        assert_eq!(unit("glork:320|nanos"), Some(Unit::Nanoseconds));
+        assert_eq!(unit("glork:2|sec"), Some(Unit::Seconds));
+        assert_eq!(
+            unit("glork:2|bytes"),
+            Some(Unit::Other(String::from("bytes")))
+        );
+        assert_eq!(unit("gorets:1|c"), None);
+
+        assert_eq!(Unit::Microseconds.seconds(), Some(1e-6));
+        assert_eq!(Unit::from("msec").code(), "ms");
+    }
+
+    #[test]
+    fn it_rejects_sample_rates_out_of_range() {
+        let config = ParserConfig::default();
+        for (line, sample_rate) in [
+            ("gorets:1|c|@0", 0.0),
+            ("gorets:1|c|@-3", -3.0),
+            ("gorets:1|c|@7", 7.0),
+        ] {
+            assert_eq!(
+                parse(line.as_bytes(), &config),
+                Err(on_line(
+                    1,
+                    0,
+                    ParseError::SampleRateOutOfRange {
+                        name: String::from("gorets"),
+                        sample_rate,
+                    }
+                ))
+            );
+        }
+        assert!(matches!(parse(b"gorets:1|c|@NaN", &config),
+            Err(ParseError::Line { error, .. })
+                if matches!(*error, ParseError::SampleRateOutOfRange { .. })));
+        assert!(parse(b"gorets:1|c|@1", &config).is_ok());
+
+        let config = ParserConfig {
+            invalid_sample_rates: InvalidSampleRatePolicy::Discard,
+            ..ParserConfig::default()
+        };
+        let batch = parse(b"gorets:1|c|@7", &config).unwrap();
+        assert_eq!(batch.metrics[0].sample_rate, None);
+        assert_eq!(
+            batch.diagnostics,
+            vec![Diagnostic::SampleRateOutOfRangeDiscarded {
+                name: String::from("gorets"),
+                sample_rate: 7.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_applies_strict_config() {
+        let config = ParserConfig::strict();
+        assert!(parse(b"glork:320|ms|@0.1\ngaugor:333|g", &config).is_ok());
+        assert_eq!(
+            parse(b"glork:320|foo", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::UnknownType {
+                    name: String::from("glork"),
+                    metric_type: String::from("foo"),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"glork/x:320|ms", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::DisallowedNameChar {
+                    name: String::from("glork/x"),
+                    character: '/',
+                }
+            ))
+        );
+
+        let name = "a".repeat(256);
+        assert_eq!(
+            parse(format!("{}:1|c", name).as_bytes(), &config),
+            Err(on_line(1, 0, ParseError::NameTooLong { name, max: 255 }))
+        );
+
+        // All of these are fine by default.
+        assert!(
+            parse(b"glork:320|foo\nglork/x:320|ms", &ParserConfig::default()).is_ok()
+        );
+    }
+
+    #[test]
+    fn it_sanitizes_names() {
+        let config = ParserConfig {
+            sanitize_names: true,
+            ..ParserConfig::strict()
+        };
+        let batch = parse(b"my app/page views!:1|c\ngorets:1|c", &config).unwrap();
+        assert_eq!(batch.metrics[0].name, "my_app-page_views");
+        assert_eq!(batch.metrics[1].name, "gorets");
+
+        let batch = parse_ref(b"gorets:1|c", &config).unwrap();
+        assert!(matches!(batch.metrics[0].name, Cow::Borrowed("gorets")));
+    }
+
+    #[test]
+    fn it_enforces_input_limits() {
+        let config = ParserConfig {
+            max_line_len: Some(16),
+            max_tags: Some(2),
+            ..ParserConfig::default()
+        };
+        assert!(parse(b"gorets:1|c|#a,b", &config).is_ok());
+        assert_eq!(
+            parse(b"gorets:1|c\ngorets:1|c|#a,b,c", &config),
+            Err(on_line(2, 11, ParseError::LineTooLong { max: 16 }))
+        );
+        assert_eq!(
+            parse(b"a:1|c|#a,b,c", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::TooManyTags {
+                    name: String::from("a"),
+                    max: 2
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"_sc|a|0|#a,b,c", &config),
+            Err(on_line(
+                1,
+                0,
+                ParseError::TooManyTags {
+                    name: String::from("a"),
+                    max: 2
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn it_accepts_crlf_and_blank_lines() {
+        let config = ParserConfig {
+            max_metrics: Some(2),
+            ..ParserConfig::default()
+        };
+        for input in [
+            &b"gorets:1|c\r\nglork:320|ms\r\n"[..],
+            b"gorets:1|c\nglork:320|ms\n\n",
+            b"\ngorets:1|c\r\n\r\nglork:320|ms",
+        ] {
+            let batch = parse(input, &config).unwrap();
+            assert_eq!(
+                batch.metrics,
+                parse(b"gorets:1|c\nglork:320|ms", &config).unwrap().metrics
+            );
+        }
+
+        assert_eq!(
+            Metric::try_from("gorets:1|c\r\n"),
+            Metric::try_from("gorets:1|c")
+        );
+
+        // Line numbers still count blank lines.
+        assert_eq!(
+            parse(b"\r\ngorets", &config),
+            Err(on_line(
+                2,
+                2,
+                ParseError::MissingValue {
+                    name: String::from("gorets")
+                }
+            ))
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Parses large payloads, like files of metrics being replayed or backfilled,
+//! by splitting them on line boundaries and parsing the pieces concurrently
+//! with rayon.
+
+use super::{parse, shift_lines, Batch, Diagnostic, ParseError, ParserConfig};
+use alloc::vec::Vec;
+use rayon::prelude::*;
+
+/// Pieces are at least this long so that small payloads aren't split up for
+/// no gain.
+const MIN_PIECE_LEN: usize = 64 * 1024;
+
+/// Like `parse`, but splits large payloads into pieces that are parsed in
+/// parallel on rayon's global thread pool. The result is exactly what `parse`
+/// would return, including the line numbers in errors and diagnostics, where
+/// `ParserConfig::max_metrics` cuts the payload off, and which error is
+/// returned when several lines are invalid.
+pub fn parse_parallel(input: &[u8], config: &ParserConfig) -> Result<Batch, ParseError> {
+    let len = (input.len() / rayon::current_num_threads()).max(MIN_PIECE_LEN);
+    parse_pieces(input, config, len)
+}
+
+// A piece of a payload, which starts at the beginning of a line.
+struct Piece<'a> {
+    input: &'a [u8],
+    // The number of lines in the payload before the piece.
+    lines: usize,
+    // The offset of the piece in the payload.
+    offset: usize,
+}
+
+fn parse_pieces(
+    input: &[u8],
+    config: &ParserConfig,
+    len: usize,
+) -> Result<Batch, ParseError> {
+    let pieces = split_lines(input, len);
+    if pieces.len() <= 1 {
+        return parse(input, config);
+    }
+
+    // The limit on metrics applies to the payload as a whole, so it's enforced
+    // as the pieces are combined.
+    let unlimited = ParserConfig {
+        max_metrics: None,
+        ..config.clone()
+    };
+    let results: Vec<_> = pieces
+        .par_iter()
+        .map(|piece| parse(piece.input, &unlimited))
+        .collect();
+
+    let mut batch = Batch::default();
+    for (piece, result) in pieces.iter().zip(results) {
+        // When a piece might run into the limit, or fails in a way that the
+        // limit might have preempted, it's parsed again with whatever's left of
+        // the limit to find out exactly where a sequential parse would stop.
+        let result = match (result, config.max_metrics) {
+            (Ok(parsed), Some(max)) if parsed.len() >= max - batch.len() => None,
+            (Err(_), Some(_)) => None,
+            (result, _) => Some(result),
+        }
+        .unwrap_or_else(|| parse_remaining(piece.input, config, batch.len()));
+
+        let parsed = shift_lines(result, piece.lines, piece.offset)?;
+        let truncated = parsed
+            .diagnostics
+            .iter()
+            .any(|d| matches!(d, Diagnostic::MetricsTruncated { .. }));
+        batch.metrics.extend(parsed.metrics);
+        batch.events.extend(parsed.events);
+        batch.service_checks.extend(parsed.service_checks);
+        batch.diagnostics.extend(parsed.diagnostics);
+        if truncated {
+            break;
+        }
+    }
+    Ok(batch)
+}
+
+// Parses a piece of a payload whose earlier pieces held `parsed` metrics,
+// limited to the metrics that are left of `ParserConfig::max_metrics`. Errors
+// and diagnostics about the limit report the limit of the whole payload.
+fn parse_remaining(
+    input: &[u8],
+    config: &ParserConfig,
+    parsed: usize,
+) -> Result<Batch, ParseError> {
+    let max = config.max_metrics.unwrap_or(usize::MAX);
+    let remaining = ParserConfig {
+        max_metrics: Some(max - parsed),
+        ..config.clone()
+    };
+    match parse(input, &remaining) {
+        Ok(mut batch) => {
+            for diagnostic in &mut batch.diagnostics {
+                if let Diagnostic::MetricsTruncated { max: limit } = diagnostic {
+                    *limit = max;
+                }
+            }
+            Ok(batch)
+        }
+        Err(ParseError::TooManyMetrics { .. }) => Err(ParseError::TooManyMetrics { max }),
+        Err(error) => Err(error),
+    }
+}
+
+// Splits a payload into pieces of about `len` bytes, each of which ends with a
+// newline (other than possibly the last one).
+fn split_lines(input: &[u8], len: usize) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let (mut offset, mut lines) = (0, 0);
+    while offset < input.len() {
+        let target = (offset + len.max(1)).min(input.len());
+        let end = match input[target - 1..].iter().position(|&b| b == b'\n') {
+            Some(i) => target + i,
+            None => input.len(),
+        };
+        let piece = &input[offset..end];
+        pieces.push(Piece {
+            input: piece,
+            lines,
+            offset,
+        });
+        lines += piece.iter().filter(|&&b| b == b'\n').count();
+        offset = end;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ExcessMetricsPolicy, InvalidLinePolicy};
+
+    #[test]
+    fn it_splits_on_line_boundaries() {
+        let input = b"gorets:1|c\nglork:320|ms\r\n\ngaugor:333|g";
+        let pieces = split_lines(input, 4);
+        let split: Vec<_> = pieces
+            .iter()
+            .map(|p| (p.input, p.lines, p.offset))
+            .collect();
+        assert_eq!(
+            split,
+            vec![
+                (&b"gorets:1|c\n"[..], 0, 0),
+                (b"glork:320|ms\r\n", 1, 11),
+                (b"\ngaugor:333|g", 2, 25),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_parses_the_same_as_parse() {
+        let payloads: [&[u8]; 4] = [
+            b"gorets:1|c\nglork:320:240|ms\n\n_e{5,4}:title|text\ngaugor:333|g\n\
+                uniques:765|s\n",
+            b"gorets:1|c\nglork:320|ms\ngorets:x|c\ngaugor:333|g\ngaugor:|g\n\
+                uniques:765|s",
+            b"gorets:1|c\n\n\n\ngorets:2|c\ngorets:3:4:5|c\ngorets:6|c",
+            b"",
+        ];
+        let mut configs = Vec::new();
+        for max_metrics in [None, Some(0), Some(2), Some(3), Some(4)] {
+            for excess_metrics in
+                [ExcessMetricsPolicy::Reject, ExcessMetricsPolicy::Truncate]
+            {
+                for invalid_lines in [InvalidLinePolicy::Reject, InvalidLinePolicy::Skip]
+                {
+                    configs.push(ParserConfig {
+                        max_metrics,
+                        excess_metrics,
+                        invalid_lines,
+                        ..ParserConfig::default()
+                    });
+                }
+            }
+        }
+
+        for payload in payloads {
+            for config in &configs {
+                for len in [1, 7, 16, 1024] {
+                    assert_eq!(
+                        parse_pieces(payload, config, len),
+                        parse(payload, config),
+                        "payload {:?}, config {:?}, piece length {}",
+                        String::from_utf8_lossy(payload),
+                        config,
+                        len
+                    );
+                }
+            }
+        }
+        assert!(parse_parallel(payloads[0], &ParserConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,591 @@
+//! Parses Prometheus's text exposition format, so that scraped targets can be
+//! forwarded into the same pipeline as StatsD:
+//!
+//! ```text
+//! # HELP http_requests_total The total number of HTTP requests.
+//! # TYPE http_requests_total counter
+//! http_requests_total{method="post",code="200"} 1027 1395066363000
+//! ```
+//!
+//! Samples are absolute values, including those of counters (which are
+//! cumulative rather than the deltas that StatsD counters are), so each one
+//! becomes a gauge. Its labels become tags and its timestamp, sent in
+//! milliseconds, is truncated to seconds.
+//!
+//! `# TYPE` lines are checked for a known type and for not being repeated for
+//! the same metric family. `# HELP` lines and other comments are ignored.
+//! Label values are borrowed from the input, so ones containing escapes are
+//! rejected, and as with Graphite a negative value is rejected since StatsD
+//! treats a signed gauge as a delta.
+//!
+//! `parse_openmetrics` parses the stricter OpenMetrics flavor of the format
+//! instead. Its payloads must end with `# EOF`, its timestamps are in
+//! (possibly fractional) seconds, its counters' samples must be suffixed with
+//! `_total` or `_created`, and its `# UNIT` lines must name a unit that the
+//! family's name ends with. Exemplars (`# {trace_id="abc"} 0.5`) are checked
+//! but dropped since metrics have nowhere to keep them.
+
+use super::{
+    check_line_len, parse_lines, parse_value, push_metric, Batch, BatchRef, Diagnostic,
+    MetricRef, MetricType, ParseError, ParserConfig, TagRef,
+};
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+
+/// The types that a `# TYPE` line may declare for a metric family.
+const FAMILY_TYPES: &[&str] = &["counter", "gauge", "histogram", "summary", "untyped"];
+
+/// The types that a `# TYPE` line may declare for a metric family in
+/// OpenMetrics.
+const OPENMETRICS_FAMILY_TYPES: &[&str] = &[
+    "counter",
+    "gauge",
+    "gaugehistogram",
+    "histogram",
+    "info",
+    "stateset",
+    "summary",
+    "unknown",
+];
+
+/// The suffixes that a sample's name may have on top of its family's name.
+const SAMPLE_SUFFIXES: &[&str] = &[
+    "_total", "_created", "_bucket", "_count", "_sum", "_gcount", "_gsum", "_info",
+];
+
+// What's been seen so far in a payload.
+#[derive(Default)]
+struct Exposition<'a> {
+    // Whether the payload is OpenMetrics rather than plain Prometheus.
+    openmetrics: bool,
+    // The types declared by `# TYPE` lines, by family name.
+    families: BTreeMap<&'a str, &'a str>,
+    // Whether an OpenMetrics payload's `# EOF` has been seen.
+    eof: bool,
+}
+
+/// Parses a Prometheus text exposition payload. Like `parser::parse`, the
+/// entire payload must be valid for the parse to succeed unless
+/// `ParserConfig::invalid_lines` says otherwise.
+pub fn parse(input: &[u8], config: &ParserConfig) -> Result<Batch, ParseError> {
+    parse_ref(input, config).map(Batch::from)
+}
+
+/// Like `parse`, but returns metrics that borrow from `input`.
+pub fn parse_ref<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    let mut exposition = Exposition::default();
+    parse_lines(input, config, |batch, config, line| {
+        parse_line(batch, config, &mut exposition, line)
+    })
+}
+
+/// Parses an OpenMetrics text payload, which must end with `# EOF`.
+pub fn parse_openmetrics(
+    input: &[u8],
+    config: &ParserConfig,
+) -> Result<Batch, ParseError> {
+    parse_openmetrics_ref(input, config).map(Batch::from)
+}
+
+/// Like `parse_openmetrics`, but returns metrics that borrow from `input`.
+pub fn parse_openmetrics_ref<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> Result<BatchRef<'a>, ParseError> {
+    let mut exposition = Exposition {
+        openmetrics: true,
+        ..Exposition::default()
+    };
+    let batch = parse_lines(input, config, |batch, config, line| {
+        parse_line(batch, config, &mut exposition, line)
+    })?;
+
+    // A payload that's been truncated by `max_metrics` may not have reached
+    // its `# EOF`.
+    let truncated = batch
+        .diagnostics
+        .iter()
+        .any(|d| matches!(d, Diagnostic::MetricsTruncated { .. }));
+    if !exposition.eof && !truncated {
+        return Err(ParseError::MissingEof);
+    }
+    Ok(batch)
+}
+
+// Parses a single line, recording what it declared in `exposition`.
+fn parse_line<'a>(
+    batch: &mut BatchRef<'a>,
+    config: &ParserConfig,
+    exposition: &mut Exposition<'a>,
+    line: &'a [u8],
+) -> Result<bool, ParseError> {
+    check_line_len(line, config)?;
+    let line = str::from_utf8(line)
+        .map_err(|_| ParseError::InvalidUtf8)?
+        .trim_matches([' ', '\t']);
+
+    if line.is_empty() {
+        return Ok(false);
+    }
+    if exposition.eof {
+        return Err(ParseError::Invalid);
+    }
+    if let Some(comment) = line.strip_prefix('#') {
+        parse_comment(exposition, comment)?;
+        return Ok(false);
+    }
+
+    let metric = parse_sample(line, exposition.openmetrics)?;
+    if exposition.openmetrics {
+        check_counter_suffix(&exposition.families, &metric.name)?;
+    }
+    push_metric(batch, config, metric)?;
+    Ok(false)
+}
+
+// Checks a `# TYPE` comment and records the type that it declares. OpenMetrics
+// `# UNIT` and `# EOF` comments are checked too, and other comments are
+// ignored.
+fn parse_comment<'a>(
+    exposition: &mut Exposition<'a>,
+    comment: &'a str,
+) -> Result<(), ParseError> {
+    let mut tokens = comment.split_ascii_whitespace();
+    let keyword = tokens.next();
+    if exposition.openmetrics && keyword == Some("EOF") {
+        exposition.eof = true;
+        return match tokens.next() {
+            Some(_) => Err(ParseError::Invalid),
+            None => Ok(()),
+        };
+    }
+    if exposition.openmetrics && keyword == Some("UNIT") {
+        return match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(name), Some(unit), None) if has_unit_suffix(name, unit) => Ok(()),
+            (Some(name), unit, _) => Err(ParseError::InvalidUnit {
+                name: String::from(name),
+                unit: String::from(unit.unwrap_or("")),
+            }),
+            _ => Err(ParseError::Invalid),
+        };
+    }
+    if keyword != Some("TYPE") {
+        return Ok(());
+    }
+
+    let (name, family_type) = match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some(name), Some(family_type), None) => (name, family_type),
+        _ => return Err(ParseError::Invalid),
+    };
+    let types = match exposition.openmetrics {
+        true => OPENMETRICS_FAMILY_TYPES,
+        false => FAMILY_TYPES,
+    };
+    if !types.contains(&family_type) {
+        return Err(ParseError::InvalidType {
+            name: String::from(name),
+            metric_type: String::from(family_type),
+        });
+    }
+    if exposition.families.insert(name, family_type).is_some() {
+        return Err(ParseError::DuplicateType {
+            name: String::from(name),
+        });
+    }
+    Ok(())
+}
+
+// Whether a family's name ends with "_" followed by its unit, as OpenMetrics
+// requires (e.g. "http_request_duration_seconds" for "seconds").
+fn has_unit_suffix(name: &str, unit: &str) -> bool {
+    match name
+        .strip_suffix(unit)
+        .and_then(|name| name.strip_suffix('_'))
+    {
+        Some(family) => !family.is_empty(),
+        None => false,
+    }
+}
+
+// Checks that an OpenMetrics sample of a counter family is named with one of
+// the suffixes that counters' samples must have.
+fn check_counter_suffix(
+    families: &BTreeMap<&str, &str>,
+    name: &str,
+) -> Result<(), ParseError> {
+    let family = SAMPLE_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name);
+    match families.get(family) {
+        Some(&"counter") if !name.ends_with("_total") && !name.ends_with("_created") => {
+            Err(ParseError::MissingCounterSuffix {
+                name: String::from(name),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Parses a sample line like `name{label="value"} 1 1395066363000`. OpenMetrics
+// samples have their timestamps in seconds and may be followed by an
+// exemplar.
+fn parse_sample(line: &str, openmetrics: bool) -> Result<MetricRef<'_>, ParseError> {
+    let end = line.find(['{', ' ', '\t']).unwrap_or(line.len());
+    let (name, rest) = line.split_at(end);
+    if name.is_empty() {
+        return Err(ParseError::EmptyName);
+    }
+
+    let (tags, rest) = match rest.strip_prefix('{') {
+        Some(labels) => parse_labels(name, labels)?,
+        None => (Vec::new(), rest),
+    };
+
+    let rest = match rest.split_once(" # ") {
+        Some((rest, exemplar)) if openmetrics => {
+            check_exemplar(name, exemplar)?;
+            rest
+        }
+        _ => rest,
+    };
+
+    let mut tokens = rest.split_ascii_whitespace();
+    let value = tokens.next().ok_or_else(|| ParseError::MissingValue {
+        name: String::from(name),
+    })?;
+    if parse_value(value, None).is_none() {
+        return Err(ParseError::InvalidValue {
+            name: String::from(name),
+            value: String::from(value),
+        });
+    }
+
+    let timestamp = match tokens.next() {
+        Some(seconds) if openmetrics => Some(parse_seconds(name, seconds)?),
+        Some(ms) => Some(
+            i64::from_str(ms)
+                .map_err(|_| ParseError::InvalidTimestamp {
+                    name: String::from(name),
+                    timestamp: String::from(ms),
+                })?
+                .div_euclid(1000),
+        ),
+        None => None,
+    };
+
+    if let Some(field) = tokens.next() {
+        return Err(ParseError::UnexpectedField {
+            name: String::from(name),
+            field: String::from(field),
+        });
+    }
+
+    Ok(MetricRef {
+        name: Cow::Borrowed(name),
+        value,
+        metric_type: MetricType::Gauge,
+        unit: None,
+        sample_rate: None,
+        sign: None,
+        tags,
+        container_id: None,
+        timestamp,
+    })
+}
+
+// Parses an OpenMetrics timestamp in seconds, truncating any fraction.
+fn parse_seconds(name: &str, timestamp: &str) -> Result<i64, ParseError> {
+    let (seconds, fraction) = timestamp.split_once('.').unwrap_or((timestamp, "0"));
+    match i64::from_str(seconds) {
+        Ok(seconds)
+            if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            Ok(seconds)
+        }
+        _ => Err(ParseError::InvalidTimestamp {
+            name: String::from(name),
+            timestamp: String::from(timestamp),
+        }),
+    }
+}
+
+// Checks an exemplar like `{trace_id="abc"} 0.5 1520879607.789`, which follows
+// an OpenMetrics sample's " # ".
+fn check_exemplar(name: &str, exemplar: &str) -> Result<(), ParseError> {
+    let labels = exemplar.strip_prefix('{').ok_or(ParseError::Invalid)?;
+    let (_, rest) = parse_labels(name, labels)?;
+    let mut tokens = rest.split_ascii_whitespace();
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some(value), timestamp, None) if parse_value(value, None).is_some() => timestamp
+            .map_or(Ok(0), |timestamp| parse_seconds(name, timestamp))
+            .map(|_| ()),
+        _ => Err(ParseError::Invalid),
+    }
+}
+
+// Parses the labels following a sample's "{", returning them along with the
+// input after the closing "}".
+fn parse_labels<'a>(
+    name: &str,
+    mut s: &'a str,
+) -> Result<(Vec<TagRef<'a>>, &'a str), ParseError> {
+    let invalid = |label: &str| {
+        let end = label.find([',', '}']).unwrap_or(label.len());
+        ParseError::InvalidTag {
+            name: String::from(name),
+            tag: String::from(&label[..end]),
+        }
+    };
+
+    let mut tags = Vec::new();
+    loop {
+        s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('}') {
+            return Ok((tags, rest));
+        }
+
+        let (key, rest) = s.split_once('=').ok_or_else(|| invalid(s))?;
+        let key = key.trim_end();
+        let value = rest
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| invalid(s))?;
+        let end = value.find(['"', '\\']).ok_or_else(|| invalid(s))?;
+        if key.is_empty() || value.as_bytes()[end] == b'\\' {
+            return Err(invalid(s));
+        }
+        tags.push(TagRef {
+            key,
+            value: Some(&value[..end]),
+        });
+
+        let rest = value[end + 1..].trim_start();
+        s = match rest.strip_prefix(',') {
+            Some(rest) => rest,
+            None if rest.starts_with('}') => rest,
+            None => return Err(invalid(s)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Metric, ParserConfig, Tag};
+
+    #[test]
+    fn it_parses_exposition() {
+        let payload = b"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method=\"post\",code=\"200\",} 1027 1395066363000
+
+  # A comment.
+up 1
+";
+        let batch = parse(payload, &ParserConfig::default()).unwrap();
+        assert_eq!(
+            batch.metrics,
+            vec![
+                Metric {
+                    name: String::from("http_requests_total"),
+                    value: String::from("1027"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: vec![
+                        Tag::new("method", Some("post")),
+                        Tag::new("code", Some("200"))
+                    ],
+                    container_id: None,
+                    timestamp: Some(1395066363),
+                },
+                Metric {
+                    name: String::from("up"),
+                    value: String::from("1"),
+                    metric_type: MetricType::Gauge,
+                    unit: None,
+                    sample_rate: None,
+                    sign: None,
+                    tags: Vec::new(),
+                    container_id: None,
+                    timestamp: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_checks_type_comments() {
+        let mut exposition = Exposition::default();
+        assert_eq!(parse_comment(&mut exposition, " TYPE up gauge"), Ok(()));
+        assert_eq!(
+            parse_comment(&mut exposition, " HELP up Whether the target is up."),
+            Ok(())
+        );
+        assert_eq!(
+            parse_comment(&mut exposition, " TYPE up gauge"),
+            Err(ParseError::DuplicateType {
+                name: String::from("up")
+            })
+        );
+        assert_eq!(
+            parse_comment(&mut exposition, " TYPE up gauge extra"),
+            Err(ParseError::Invalid)
+        );
+        assert_eq!(
+            parse_comment(&mut exposition, " TYPE down meter"),
+            Err(ParseError::InvalidType {
+                name: String::from("down"),
+                metric_type: String::from("meter"),
+            })
+        );
+        assert_eq!(
+            parse_comment(&mut exposition, " TYPE down unknown"),
+            Err(ParseError::InvalidType {
+                name: String::from("down"),
+                metric_type: String::from("unknown"),
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_samples() {
+        for (line, error) in [
+            (
+                "up",
+                ParseError::MissingValue {
+                    name: String::from("up"),
+                },
+            ),
+            ("{job=\"a\"} 1", ParseError::EmptyName),
+            (
+                "up{job} 1",
+                ParseError::InvalidTag {
+                    name: String::from("up"),
+                    tag: String::from("job"),
+                },
+            ),
+            (
+                "up{job=\"a\\\"b\"} 1",
+                ParseError::InvalidTag {
+                    name: String::from("up"),
+                    tag: String::from("job=\"a\\\"b\""),
+                },
+            ),
+            (
+                "up{job=\"a\" x} 1",
+                ParseError::InvalidTag {
+                    name: String::from("up"),
+                    tag: String::from("job=\"a\" x"),
+                },
+            ),
+            (
+                "temp -1",
+                ParseError::InvalidValue {
+                    name: String::from("temp"),
+                    value: String::from("-1"),
+                },
+            ),
+            (
+                "up 1 soon",
+                ParseError::InvalidTimestamp {
+                    name: String::from("up"),
+                    timestamp: String::from("soon"),
+                },
+            ),
+            (
+                "up 1 1 x",
+                ParseError::UnexpectedField {
+                    name: String::from("up"),
+                    field: String::from("x"),
+                },
+            ),
+        ] {
+            assert_eq!(parse_sample(line, false).map(|_| ()), Err(error));
+        }
+    }
+
+    #[test]
+    fn it_parses_openmetrics() {
+        let payload = b"# TYPE http_requests counter
+# UNIT http_requests_seconds seconds
+http_requests_total{code=\"200\"} 1027 1520879607.789 # {trace_id=\"abc\"} 1 1520879607.7
+http_requests_created{code=\"200\"} 1520879600
+# EOF
+";
+        let batch = parse_openmetrics(payload, &ParserConfig::default()).unwrap();
+        let samples: Vec<_> = batch
+            .metrics
+            .iter()
+            .map(|m| (m.name.as_str(), m.value.as_str(), m.timestamp))
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                ("http_requests_total", "1027", Some(1520879607)),
+                ("http_requests_created", "1520879600", None),
+            ]
+        );
+
+        // Plain Prometheus doesn't know about exemplars.
+        assert_eq!(
+            parse(b"up 1 # {trace_id=\"abc\"} 1", &ParserConfig::default()),
+            Err(ParseError::Line {
+                line: 1,
+                offset: 0,
+                error: Box::new(ParseError::InvalidTimestamp {
+                    name: String::from("up"),
+                    timestamp: String::from("#"),
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_openmetrics() {
+        let config = ParserConfig::default();
+        assert_eq!(
+            parse_openmetrics(b"up 1\n", &config),
+            Err(ParseError::MissingEof)
+        );
+
+        for (payload, error) in [
+            (&b"# EOF\nup 1\n"[..], ParseError::Invalid),
+            (
+                b"# TYPE http_requests counter\nhttp_requests 1\n# EOF",
+                ParseError::MissingCounterSuffix {
+                    name: String::from("http_requests"),
+                },
+            ),
+            (
+                b"# UNIT http_requests bytes\n# EOF",
+                ParseError::InvalidUnit {
+                    name: String::from("http_requests"),
+                    unit: String::from("bytes"),
+                },
+            ),
+            (
+                b"up 1 soon\n# EOF",
+                ParseError::InvalidTimestamp {
+                    name: String::from("up"),
+                    timestamp: String::from("soon"),
+                },
+            ),
+            (b"up 1 # trace_id=\"abc\" 1\n# EOF", ParseError::Invalid),
+        ] {
+            match parse_openmetrics(payload, &config) {
+                Err(ParseError::Line { error: e, .. }) => assert_eq!(*e, error),
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+}
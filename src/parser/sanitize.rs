@@ -0,0 +1,52 @@
+//! Sanitizes metric names the same way that etsy statsd does before handing
+//! them to Graphite, so that keys match the ones that it would have produced.
+
+use alloc::borrow::Cow;
+use alloc::string::String;
+
+/// Rewrites a metric name like etsy statsd: runs of whitespace become "_", "/"
+/// becomes "-", and anything else that isn't an ASCII letter, digit, "_", "-",
+/// or "." is removed. A name that's already clean is returned as is without
+/// being copied.
+pub fn sanitize_name(name: &str) -> Cow<'_, str> {
+    if name.chars().all(is_allowed) {
+        return Cow::Borrowed(name);
+    }
+
+    let mut sanitized = String::with_capacity(name.len());
+    let mut in_whitespace = false;
+    for c in name.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                sanitized.push('_');
+            }
+            in_whitespace = true;
+            continue;
+        }
+        in_whitespace = false;
+
+        if c == '/' {
+            sanitized.push('-');
+        } else if is_allowed(c) {
+            sanitized.push(c);
+        }
+    }
+    Cow::Owned(sanitized)
+}
+
+fn is_allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sanitizes_names() {
+        assert_eq!(sanitize_name("page.views"), "page.views");
+        assert!(matches!(sanitize_name("page.views"), Cow::Borrowed(_)));
+        assert_eq!(sanitize_name("page  views\t/home"), "page_views_-home");
+        assert_eq!(sanitize_name("héllo:wörld!"), "hllowrld");
+    }
+}
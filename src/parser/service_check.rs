@@ -0,0 +1,167 @@
+//! Parses DogStatsD service checks, which share a datagram with metrics but
+//! have a format of their own:
+//!
+//! ```text
+//! _sc|name|status|d:timestamp|h:hostname|#tag1,tag2|m:message
+//! ```
+//!
+//! The message must be the last field, and everything after "m:" belongs to
+//! it. Newlines in the message are sent escaped as "\n".
+
+use super::{parse_tags, ParseError, Tag};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+use core::str::FromStr;
+
+/// The prefix that distinguishes a service check line from a metric.
+pub(super) const SERVICE_CHECK_PREFIX: &[u8] = b"_sc|";
+
+/// ServiceCheck reports the status of a service, like whether a health check
+/// is passing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceCheck {
+    /// The name of the check.
+    pub name: String,
+
+    /// The status that the check is reporting.
+    pub status: ServiceCheckStatus,
+
+    /// When the check ran as a Unix timestamp (the "d:" field), if the client
+    /// supplied one.
+    pub timestamp: Option<i64>,
+
+    /// The host that the check ran against (the "h:" field).
+    pub hostname: Option<String>,
+
+    /// A message describing the status (the "m:" field), with escaped
+    /// newlines restored.
+    pub message: Option<String>,
+
+    /// Tags attached to the check in the order that they were sent.
+    pub tags: Vec<Tag>,
+}
+
+/// Statuses that a service check may report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceCheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+/// Parses a single service check line (without its trailing newline).
+pub(super) fn parse_service_check(line: &[u8]) -> Result<ServiceCheck, ParseError> {
+    let rest = match line.strip_prefix(SERVICE_CHECK_PREFIX) {
+        Some(rest) => str::from_utf8(rest).map_err(|_| ParseError::Invalid)?,
+        None => return Err(ParseError::Invalid),
+    };
+
+    // Split off the message first since it's allowed to contain "|".
+    let (rest, message) = match rest.find("|m:") {
+        Some(i) => (&rest[..i], Some(rest[i + 3..].replace("\\n", "\n"))),
+        None => (rest, None),
+    };
+
+    let mut fields = rest.split('|');
+    let name = match fields.next() {
+        Some(name) if !name.is_empty() => name,
+        _ => return Err(ParseError::Invalid),
+    };
+    let status = match fields.next() {
+        Some("0") => ServiceCheckStatus::Ok,
+        Some("1") => ServiceCheckStatus::Warning,
+        Some("2") => ServiceCheckStatus::Critical,
+        Some("3") => ServiceCheckStatus::Unknown,
+        _ => return Err(ParseError::Invalid),
+    };
+
+    let mut check = ServiceCheck {
+        name: String::from(name),
+        status,
+        timestamp: None,
+        hostname: None,
+        message,
+        tags: Vec::new(),
+    };
+
+    let mut seen_tags = false;
+    for field in fields {
+        let unexpected = || ParseError::UnexpectedField {
+            name: String::from(name),
+            field: String::from(field),
+        };
+
+        if let Some(t) = field.strip_prefix('#') {
+            if seen_tags {
+                return Err(ParseError::DuplicateTags {
+                    name: String::from(name),
+                });
+            }
+            seen_tags = true;
+            check.tags = parse_tags(t);
+        } else if let Some(timestamp) = field.strip_prefix("d:") {
+            check.timestamp = Some(i64::from_str(timestamp).map_err(|_| unexpected())?);
+        } else if let Some(hostname) = field.strip_prefix("h:") {
+            check.hostname = Some(String::from(hostname));
+        } else {
+            return Err(unexpected());
+        }
+    }
+
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_service_check() {
+        assert_eq!(
+            parse_service_check(b"_sc|redis.can_connect|0"),
+            Ok(ServiceCheck {
+                name: String::from("redis.can_connect"),
+                status: ServiceCheckStatus::Ok,
+                timestamp: None,
+                hostname: None,
+                message: None,
+                tags: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_parses_service_check_with_fields() {
+        let line = b"_sc|redis.can_connect|2|d:1500000000|h:cache.1|#env:prod|\
+            m:timed out|retrying";
+        assert_eq!(
+            parse_service_check(line),
+            Ok(ServiceCheck {
+                name: String::from("redis.can_connect"),
+                status: ServiceCheckStatus::Critical,
+                timestamp: Some(1500000000),
+                hostname: Some(String::from("cache.1")),
+                message: Some(String::from("timed out|retrying")),
+                tags: vec![Tag::new("env", Some("prod"))],
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_service_checks() {
+        assert_eq!(
+            parse_service_check(b"_sc|redis.can_connect|4"),
+            Err(ParseError::Invalid)
+        );
+        assert_eq!(parse_service_check(b"_sc||0"), Err(ParseError::Invalid));
+        assert_eq!(
+            parse_service_check(b"_sc|redis.can_connect|0|x:y"),
+            Err(ParseError::UnexpectedField {
+                name: String::from("redis.can_connect"),
+                field: String::from("x:y"),
+            })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Parses metrics out of a stream like a TCP connection, where lines may be
+//! split across reads.
+
+use super::{
+    parse, shift_lines, Batch, Diagnostic, InvalidLinePolicy, ParseError, ParserConfig,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// StreamParser is fed chunks of a stream and parses the complete lines in
+/// them, holding onto any partial line at the end of a chunk until the rest of
+/// it arrives.
+///
+/// Each call to `feed` parses the lines that it completes as one payload, so
+/// `ParserConfig::max_metrics` applies to each call rather than to the stream
+/// as a whole. Line numbers and offsets in errors and diagnostics are counted
+/// from the start of the stream.
+///
+/// A partial line that grows past `ParserConfig::max_line_len` fails with
+/// `ParseError::LineTooLong` (or is skipped, depending on
+/// `ParserConfig::invalid_lines`) as soon as it does, and the rest of it is
+/// thrown away as it arrives rather than being buffered.
+#[derive(Debug)]
+pub struct StreamParser {
+    config: ParserConfig,
+
+    // Input that's been fed, but that doesn't end in a newline yet.
+    buffer: Vec<u8>,
+
+    // The number of lines and bytes that have been parsed so far.
+    lines: usize,
+    offset: usize,
+
+    // Whether the rest of the current line is being thrown away because it
+    // was too long.
+    discarding: bool,
+}
+
+impl StreamParser {
+    pub fn new(config: ParserConfig) -> StreamParser {
+        StreamParser {
+            config,
+            buffer: Vec::new(),
+            lines: 0,
+            offset: 0,
+            discarding: false,
+        }
+    }
+
+    /// Feeds a chunk of the stream to the parser and returns a batch of the
+    /// lines that it completed, which is empty if it didn't complete any.
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Result<Batch, ParseError> {
+        if self.discarding {
+            match chunk.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    self.discarding = false;
+                    self.lines += 1;
+                    self.offset += i + 1;
+                    chunk = &chunk[i + 1..];
+                }
+                None => {
+                    self.offset += chunk.len();
+                    return Ok(Batch::default());
+                }
+            }
+        }
+
+        self.buffer.extend_from_slice(chunk);
+        let mut batch = match self.buffer.iter().rposition(|&b| b == b'\n') {
+            Some(i) => self.parse_buffered(i + 1)?,
+            None => Batch::default(),
+        };
+
+        match self.config.max_line_len {
+            Some(max) if self.buffer.len() > max => {
+                let (line, offset) = (self.lines + 1, self.offset);
+                self.offset += self.buffer.len();
+                self.buffer.clear();
+                self.discarding = true;
+
+                let error = ParseError::LineTooLong { max };
+                match self.config.invalid_lines {
+                    InvalidLinePolicy::Reject => {
+                        return Err(ParseError::Line {
+                            line,
+                            offset,
+                            error: Box::new(error),
+                        });
+                    }
+                    InvalidLinePolicy::Skip => {
+                        batch.diagnostics.push(Diagnostic::LineSkipped {
+                            line,
+                            offset,
+                            error,
+                        });
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(batch)
+    }
+
+    /// Parses whatever partial line is left once the stream has ended.
+    pub fn finish(mut self) -> Result<Batch, ParseError> {
+        match self.buffer.len() {
+            0 => Ok(Batch::default()),
+            len => self.parse_buffered(len),
+        }
+    }
+
+    /// The partial line that's waiting for the rest of it to arrive.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    // Parses the first `len` bytes in the buffer and removes them from it.
+    fn parse_buffered(&mut self, len: usize) -> Result<Batch, ParseError> {
+        let (lines, offset) = (self.lines, self.offset);
+        let input: Vec<u8> = self.buffer.drain(..len).collect();
+        self.lines += input.iter().filter(|&&b| b == b'\n').count();
+        if input.last() != Some(&b'\n') {
+            self.lines += 1;
+        }
+        self.offset += len;
+
+        shift_lines(parse(&input, &self.config), lines, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_lines_split_across_chunks() {
+        let mut parser = StreamParser::new(ParserConfig::default());
+        assert_eq!(parser.feed(b"gorets:1|c\nglo").unwrap().metrics.len(), 1);
+        assert_eq!(parser.buffered(), b"glo");
+        assert!(parser.feed(b"rk:320").unwrap().is_empty());
+
+        let batch = parser.feed(b"|ms\ngaugor:333|g\n").unwrap();
+        let names: Vec<_> = batch.metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["glork", "gaugor"]);
+        assert_eq!(parser.buffered(), b"");
+    }
+
+    #[test]
+    fn it_parses_partial_line_when_finished() {
+        let mut parser = StreamParser::new(ParserConfig::default());
+        assert!(parser.feed(b"gorets:1").unwrap().is_empty());
+        assert!(parser.feed(b"|c").unwrap().is_empty());
+        assert_eq!(parser.finish().unwrap().metrics.len(), 1);
+
+        assert!(StreamParser::new(ParserConfig::default())
+            .finish()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_counts_lines_from_start_of_stream() {
+        let mut parser = StreamParser::new(ParserConfig::default());
+        parser.feed(b"gorets:1|c\ngorets:1|c\n").unwrap();
+        assert_eq!(
+            parser.feed(b"gorets\n"),
+            Err(ParseError::Line {
+                line: 3,
+                offset: 22,
+                error: Box::new(ParseError::MissingValue {
+                    name: String::from("gorets")
+                }),
+            })
+        );
+
+        // The bad line is dropped, so parsing carries on after it.
+        assert_eq!(parser.feed(b"gorets:1|c\n").unwrap().metrics.len(), 1);
+    }
+
+    #[test]
+    fn it_discards_lines_that_are_too_long() {
+        let config = ParserConfig {
+            max_line_len: Some(12),
+            ..ParserConfig::default()
+        };
+        let mut parser = StreamParser::new(config);
+        assert_eq!(
+            parser
+                .feed(b"gorets:1|c\ngorets:1000")
+                .unwrap()
+                .metrics
+                .len(),
+            1
+        );
+        assert_eq!(
+            parser.feed(b"000"),
+            Err(ParseError::Line {
+                line: 2,
+                offset: 11,
+                error: Box::new(ParseError::LineTooLong { max: 12 }),
+            })
+        );
+        assert_eq!(parser.buffered(), b"");
+
+        // The rest of the line is thrown away, but the next one is parsed.
+        assert!(parser.feed(b"0000").unwrap().is_empty());
+        let batch = parser.feed(b"|c\nglork:320|ms\n").unwrap();
+        assert_eq!(batch.metrics.len(), 1);
+        assert_eq!(
+            parser.feed(b"gorets\n").unwrap_err(),
+            ParseError::Line {
+                line: 4,
+                offset: 45,
+                error: Box::new(ParseError::MissingValue {
+                    name: String::from("gorets")
+                }),
+            }
+        );
+    }
+}
@@ -0,0 +1,619 @@
+//! Alerts when the metrics that a `Pipeline` receives cross thresholds, and
+//! sends each alert to webhooks, so that alerts can be acted on without an
+//! alerting stack of their own.
+//!
+//! Each flush interval, a threshold is checked against its metric's value
+//! over the interval: the sum of a counter's or meter's values (scaled by
+//! their sample rates), a gauge's last value, or the largest of a sample's,
+//! histogram's, or distribution's values, across all of the metric's tags.
+//! Gauge deltas aren't checked, and neither are intervals that the metric
+//! wasn't received in. An alert is sent when the value crosses the threshold,
+//! and not again until a value's back within it.
+
+use crate::backend::{json_number, json_string};
+use crate::parser::{MetricRef, MetricType, MetricValue};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::ServerError;
+
+/// Threshold is a limit on a metric's value that's alerted on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Threshold {
+    /// The alert's name, which is in its payload (e.g. "high error rate").
+    pub name: String,
+
+    /// The name of the metric that's checked.
+    pub metric: String,
+
+    pub condition: Condition,
+    pub value: f64,
+}
+
+/// Condition is which side of a threshold's value is alerted on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Condition {
+    Above,
+    Below,
+}
+
+/// An alert sent when a metric crossed a threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    /// The name of the threshold.
+    pub threshold: String,
+
+    pub metric: String,
+    pub condition: Condition,
+
+    /// The threshold's value.
+    pub limit: f64,
+
+    /// The metric's value over the flush interval.
+    pub value: f64,
+
+    /// When the flush that crossed the threshold was.
+    pub time: SystemTime,
+}
+
+/// Webhook is a URL that alerts are posted to. "https://" URLs need the
+/// `tls` feature. Each alert is retried with exponential backoff until it's
+/// accepted with a 2xx status, it's rejected with a 4xx status other than
+/// 408 or 429, or it's been sent `attempts` times. Alerts waiting to be
+/// retried don't hold up the ones after them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    pub format: WebhookFormat,
+
+    /// How many times an alert is sent before it's given up on. Defaults to
+    /// 3.
+    pub attempts: u32,
+
+    /// How long to wait before the second attempt, which doubles before each
+    /// attempt after. Defaults to a second.
+    pub backoff: Duration,
+
+    /// How long each attempt can take to connect, and then to send or to be
+    /// replied to. Defaults to 5 seconds.
+    pub timeout: Duration,
+}
+
+/// The payload that alerts are posted to a webhook as.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WebhookFormat {
+    /// A JSON object of the alert's fields, with its time in milliseconds
+    /// since the Unix epoch (e.g. `{"threshold":"high error rate",
+    /// "metric":"errors","condition":"above","limit":5,"value":7,
+    /// "time":1656581405000}`).
+    Json,
+
+    /// A message in the "text" field, which Slack's incoming webhooks and
+    /// the services compatible with them take (e.g. `{"text":"high error
+    /// rate: errors was 7, above 5"}`).
+    Slack,
+}
+
+// Alerter checks a worker's thresholds against the metrics that it receives.
+pub(super) struct Alerter {
+    watches: Vec<Watch>,
+}
+
+struct Watch {
+    threshold: Threshold,
+
+    // The metric's value over the flush interval, if it's been received.
+    value: Option<f64>,
+
+    // Whether the threshold was crossed when it was last checked.
+    firing: bool,
+}
+
+// The parts of a webhook's URL.
+#[derive(Debug, PartialEq)]
+struct Url<'a> {
+    tls: bool,
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl Alerter {
+    pub(super) fn new(thresholds: Vec<Threshold>) -> Alerter {
+        let watches = thresholds
+            .into_iter()
+            .map(|threshold| Watch {
+                threshold,
+                value: None,
+                firing: false,
+            })
+            .collect();
+        Alerter { watches }
+    }
+
+    pub(super) fn record(&mut self, metric: &MetricRef) {
+        for watch in &mut self.watches {
+            if watch.threshold.metric != metric.name {
+                continue;
+            }
+            let value = match metric.numeric_value() {
+                Some(MetricValue::Integer(i)) => i as f64,
+                Some(MetricValue::Unsigned(u)) => u as f64,
+                Some(MetricValue::Float(f)) => f,
+                None => continue,
+            };
+            watch.value = Some(match metric.metric_type {
+                MetricType::Counter | MetricType::Meter => {
+                    let rate =
+                        metric.sample_rate.filter(|&rate| rate > 0.0).unwrap_or(1.0);
+                    watch.value.unwrap_or(0.0) + value / rate
+                }
+                MetricType::Gauge if metric.sign.is_some() => continue,
+                MetricType::Gauge => value,
+                _ => watch.value.map_or(value, |max| max.max(value)),
+            });
+        }
+    }
+
+    // Checks each threshold against its metric's value since the last call,
+    // and returns an alert for each that's been crossed.
+    pub(super) fn take(&mut self, now: SystemTime) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for watch in &mut self.watches {
+            let value = match watch.value.take() {
+                Some(value) => value,
+                None => continue,
+            };
+            let threshold = &watch.threshold;
+            let crossed = match threshold.condition {
+                Condition::Above => value > threshold.value,
+                Condition::Below => value < threshold.value,
+            };
+            if crossed && !watch.firing {
+                alerts.push(Alert {
+                    threshold: threshold.name.clone(),
+                    metric: threshold.metric.clone(),
+                    condition: threshold.condition,
+                    limit: threshold.value,
+                    value,
+                    time: now,
+                });
+            }
+            watch.firing = crossed;
+        }
+        alerts
+    }
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Webhook {
+        Webhook {
+            url: String::from(url),
+            format: WebhookFormat::Json,
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    // Returns an error if the webhook's URL can't be posted to.
+    pub(super) fn check(&self) -> Result<(), ServerError> {
+        let url = parse_url(&self.url)?;
+        if url.tls && !cfg!(feature = "tls") {
+            let message = String::from("https:// webhooks need the `tls` feature");
+            return Err(ServerError::Config { message });
+        }
+        Ok(())
+    }
+
+    // Posts an alert once. If it isn't accepted, returns whether it's worth
+    // trying again, as documented on `Webhook`.
+    fn send(&self, alert: &Alert) -> Result<(), bool> {
+        let url = parse_url(&self.url).map_err(|_| false)?;
+        match post(&url, &self.format.payload(alert), self.timeout) {
+            Ok(200..=299) => Ok(()),
+            Ok(status @ 400..=499) if status != 408 && status != 429 => Err(false),
+            Ok(_) | Err(_) => Err(true),
+        }
+    }
+}
+
+impl WebhookFormat {
+    fn payload(self, alert: &Alert) -> String {
+        let condition = match alert.condition {
+            Condition::Above => "above",
+            Condition::Below => "below",
+        };
+        match self {
+            WebhookFormat::Json => {
+                let time = alert
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let fields = [
+                    ("threshold", json_string(&alert.threshold)),
+                    ("metric", json_string(&alert.metric)),
+                    ("condition", json_string(condition)),
+                    ("limit", json_number(alert.limit)),
+                    ("value", json_number(alert.value)),
+                    ("time", time.to_string()),
+                ];
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(name, value)| format!("\"{}\":{}", name, value))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+            WebhookFormat::Slack => {
+                let text = format!(
+                    "{}: {} was {}, {} {}",
+                    alert.threshold, alert.metric, alert.value, condition, alert.limit
+                );
+                format!(r#"{{"text":{}}}"#, json_string(&text))
+            }
+        }
+    }
+}
+
+// Sends each alert to every webhook until the worker that sends them stops
+// and the retries are done, and counts the alerts that any webhook didn't
+// accept in `undelivered`. Retries wait in a queue rather than in a sleep, so
+// that new alerts are sent while earlier ones back off.
+pub(super) fn deliver(
+    webhooks: &[Webhook],
+    alerts: mpsc::Receiver<Alert>,
+    undelivered: &AtomicU64,
+) {
+    let mut delivery = Delivery {
+        webhooks,
+        undelivered,
+        pending: HashMap::new(),
+        retries: Vec::new(),
+        next: 0,
+    };
+    let mut open = true;
+    while open || !delivery.retries.is_empty() {
+        let next_retry = delivery.retries.iter().map(|retry| retry.at).min();
+        let received = match next_retry {
+            _ if !open => Err(RecvTimeoutError::Timeout),
+            Some(at) => alerts.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => alerts.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(alert) => delivery.start(alert),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => open = false,
+        }
+        if !open {
+            if let Some(at) = next_retry {
+                thread::sleep(at.saturating_duration_since(Instant::now()));
+            }
+        }
+        delivery.retry_due(Instant::now());
+    }
+}
+
+// Delivery is the state of the alerts being sent to webhooks.
+struct Delivery<'a> {
+    webhooks: &'a [Webhook],
+    undelivered: &'a AtomicU64,
+
+    // The alerts that some webhook is still to accept or give up on, by their
+    // number, with how many are, and whether any have given up.
+    pending: HashMap<u64, (Alert, usize, bool)>,
+    retries: Vec<Retry>,
+
+    // The number of the next alert.
+    next: u64,
+}
+
+// Retry is an alert waiting to be sent to a webhook again.
+struct Retry {
+    alert: u64,
+    webhook: usize,
+    attempt: u32,
+    backoff: Duration,
+    at: Instant,
+}
+
+impl Delivery<'_> {
+    // Sends a new alert to every webhook.
+    fn start(&mut self, alert: Alert) {
+        if self.webhooks.is_empty() {
+            return;
+        }
+        let number = self.next;
+        self.next += 1;
+        self.pending
+            .insert(number, (alert, self.webhooks.len(), false));
+        for (i, webhook) in self.webhooks.iter().enumerate() {
+            let backoff = webhook.backoff;
+            self.send(Retry {
+                alert: number,
+                webhook: i,
+                attempt: 1,
+                backoff,
+                at: Instant::now(),
+            });
+        }
+    }
+
+    // Sends the retries that are due at `now`.
+    fn retry_due(&mut self, now: Instant) {
+        let (due, waiting) = self.retries.drain(..).partition(|retry| retry.at <= now);
+        self.retries = waiting;
+        due.into_iter().for_each(|retry: Retry| self.send(retry));
+    }
+
+    // Makes an attempt at sending an alert to a webhook, queueing it to be
+    // retried after its backoff if it can be.
+    fn send(&mut self, retry: Retry) {
+        let webhook = &self.webhooks[retry.webhook];
+        let (alert, left, failed) = self.pending.get_mut(&retry.alert).unwrap();
+        let accepted = match webhook.send(alert) {
+            Ok(()) => true,
+            Err(true) if retry.attempt < webhook.attempts => {
+                self.retries.push(Retry {
+                    attempt: retry.attempt + 1,
+                    backoff: retry.backoff * 2,
+                    at: Instant::now() + retry.backoff,
+                    ..retry
+                });
+                return;
+            }
+            Err(_) => false,
+        };
+        *failed |= !accepted;
+        *left -= 1;
+        if *left == 0 {
+            let (_, _, failed) = self.pending.remove(&retry.alert).unwrap();
+            if failed {
+                self.undelivered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Parses an "http://" or "https://" URL, whose path defaults to "/" and port
+// to the scheme's.
+fn parse_url(url: &str) -> Result<Url<'_>, ServerError> {
+    let invalid = || ServerError::Config {
+        message: format!("invalid webhook URL {}", url),
+    };
+    let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => return Err(invalid()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // The colons of an IPv6 address are inside its brackets.
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().map_err(|_| invalid())?)
+        }
+        _ => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Url {
+        tls,
+        authority,
+        host,
+        port,
+        path,
+    })
+}
+
+// Posts `body` to `url`, and returns the status that it's replied to with.
+fn post(url: &Url, body: &str, timeout: Duration) -> io::Result<u16> {
+    let addr = match (url.host, url.port).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "webhook has no address",
+            ))
+        }
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.authority,
+        body.len(),
+        body
+    );
+    #[cfg(feature = "tls")]
+    if url.tls {
+        let tls = crate::backend::TlsConfig::default();
+        let mut stream = crate::backend::connect_tls(stream, url.host, &tls)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        return exchange(&mut stream, &request);
+    }
+    exchange(&mut stream, &request)
+}
+
+// Writes a request, and reads the status from its response's status line
+// (e.g. "HTTP/1.1 200 OK").
+fn exchange(stream: &mut (impl Read + Write), request: &str) -> io::Result<u16> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+    {
+        Some(status) => Ok(status),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid HTTP response",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_ref, ParserConfig};
+
+    #[test]
+    fn it_alerts_once_per_crossing() {
+        let threshold = |metric: &str, condition, value| Threshold {
+            name: format!("{} alert", metric),
+            metric: String::from(metric),
+            condition,
+            value,
+        };
+        let mut alerter = Alerter::new(vec![
+            threshold("errors", Condition::Above, 5.0),
+            threshold("free", Condition::Below, 10.0),
+            threshold("latency", Condition::Above, 250.0),
+        ]);
+        let mut interval = |payload: &[u8]| {
+            for metric in parse_ref(payload, &ParserConfig::default())
+                .unwrap()
+                .metrics
+            {
+                alerter.record(&metric);
+            }
+            let alerts = alerter.take(UNIX_EPOCH);
+            alerts
+                .into_iter()
+                .map(|alert| (alert.threshold, alert.value))
+                .collect::<Vec<_>>()
+        };
+
+        let metrics =
+            b"errors:4|c\nerrors:1|c|@0.5|#env:production\nfree:20|g\nfree:-15|g\n\
+                        latency:100|ms\nlatency:300|ms";
+        assert_eq!(
+            interval(metrics),
+            vec![
+                (String::from("errors alert"), 6.0),
+                (String::from("latency alert"), 300.0),
+            ]
+        );
+        // Still over, so they don't alert again, while an interval without
+        // latencies doesn't check them.
+        assert_eq!(
+            interval(b"errors:9|c\nfree:5|g"),
+            vec![(String::from("free alert"), 5.0)]
+        );
+        assert_eq!(interval(b"errors:1|c\nlatency:1|ms"), vec![]);
+        assert_eq!(
+            interval(b"errors:6|c"),
+            vec![(String::from("errors alert"), 6.0)]
+        );
+    }
+
+    #[test]
+    fn it_counts_undelivered_alerts_once() {
+        // Nothing listens on the port once the listener's dropped, so every
+        // attempt is refused.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let webhook = Webhook {
+            attempts: 2,
+            backoff: Duration::from_millis(1),
+            ..Webhook::new(&format!("http://{}/alerts", addr))
+        };
+        let (alerts, receiver) = mpsc::channel();
+        for value in [7.0, 9.0] {
+            alerts
+                .send(Alert {
+                    threshold: String::from("high error rate"),
+                    metric: String::from("errors"),
+                    condition: Condition::Above,
+                    limit: 5.0,
+                    value,
+                    time: UNIX_EPOCH,
+                })
+                .unwrap();
+        }
+        drop(alerts);
+        let undelivered = AtomicU64::new(0);
+        deliver(&[webhook.clone(), webhook], receiver, &undelivered);
+        assert_eq!(undelivered.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn it_formats_payloads() {
+        let alert = Alert {
+            threshold: String::from("high \"error\" rate"),
+            metric: String::from("errors"),
+            condition: Condition::Above,
+            limit: 5.0,
+            value: 7.5,
+            time: UNIX_EPOCH + Duration::from_millis(1_656_581_405_000),
+        };
+        assert_eq!(
+            WebhookFormat::Json.payload(&alert),
+            concat!(
+                r#"{"threshold":"high \"error\" rate","metric":"errors","#,
+                r#""condition":"above","#,
+                r#""limit":5,"value":7.5,"time":1656581405000}"#,
+            )
+        );
+        assert_eq!(
+            WebhookFormat::Slack.payload(&alert),
+            r#"{"text":"high \"error\" rate: errors was 7.5, above 5"}"#
+        );
+    }
+
+    #[test]
+    fn it_parses_webhook_urls() {
+        let url = |tls, authority, host, port, path| {
+            Ok(Url {
+                tls,
+                authority,
+                host,
+                port,
+                path,
+            })
+        };
+        assert_eq!(
+            parse_url("https://hooks.slack.com/services/T0/B0/x"),
+            url(
+                true,
+                "hooks.slack.com",
+                "hooks.slack.com",
+                443,
+                "/services/T0/B0/x"
+            )
+        );
+        let local = url(false, "10.0.0.1:8080", "10.0.0.1", 8080, "/");
+        assert_eq!(parse_url("http://10.0.0.1:8080"), local);
+        assert_eq!(
+            parse_url("http://[::1]/alerts"),
+            url(false, "[::1]", "::1", 80, "/alerts")
+        );
+        assert_eq!(
+            parse_url("http://[::1]:81/"),
+            url(false, "[::1]:81", "::1", 81, "/")
+        );
+        let message = String::from("invalid webhook URL ftp://example.com");
+        assert_eq!(
+            parse_url("ftp://example.com"),
+            Err(ServerError::Config { message })
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Limits how fast each source address can send packets to a `Pipeline`, so
+//! that a single runaway client can't starve everyone else.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use super::ServerError;
+
+/// How many packets each source address can send. Every source has a bucket
+/// of `burst` packets which refills at `packets_per_second`, and packets from
+/// a source whose bucket is empty are dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    /// How many packets each source can send per second, on average, which
+    /// has to be above 0.
+    pub packets_per_second: f64,
+
+    /// How many packets each source can send at once, which has to be at
+    /// least 1 for any packet to get through.
+    pub burst: f64,
+}
+
+impl RateLimit {
+    // Returns an error if the limit would drop every packet, or can't be
+    // applied.
+    pub(super) fn check(&self) -> Result<(), ServerError> {
+        let message =
+            if self.packets_per_second.is_nan() || self.packets_per_second <= 0.0 {
+                format!(
+                    "rate limit of {} packets per second isn't above 0",
+                    self.packets_per_second
+                )
+            } else if self.burst.is_nan() || self.burst < 1.0 {
+                format!("rate limit burst of {} is below 1", self.burst)
+            } else {
+                return Ok(());
+            };
+        Err(ServerError::Config { message })
+    }
+}
+
+// Limiter applies a `RateLimit`, and counts the packets that it drops.
+pub(super) struct Limiter {
+    limit: RateLimit,
+    buckets: HashMap<IpAddr, Bucket>,
+
+    // The packets dropped from each source since the last call to `take`.
+    dropped: BTreeMap<IpAddr, u64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    pub(super) fn new(limit: RateLimit) -> Limiter {
+        Limiter {
+            limit,
+            buckets: HashMap::new(),
+            dropped: BTreeMap::new(),
+        }
+    }
+
+    // Returns whether a packet from `source` at `now` is within its limit.
+    pub(super) fn admit(&mut self, source: IpAddr, now: Instant) -> bool {
+        let limit = &self.limit;
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * limit.packets_per_second).min(limit.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        *self.dropped.entry(source).or_insert(0) += 1;
+        false
+    }
+
+    // Returns the number of packets dropped from each source since the last
+    // call, and forgets the sources whose buckets have refilled by `now`,
+    // which are the same as new ones.
+    pub(super) fn take(&mut self, now: Instant) -> BTreeMap<IpAddr, u64> {
+        let limit = &self.limit;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * limit.packets_per_second < limit.burst
+        });
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_limits_each_source() {
+        let mut limiter = Limiter::new(RateLimit {
+            packets_per_second: 2.0,
+            burst: 3.0,
+        });
+        let (noisy, quiet) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let start = Instant::now();
+        let admitted = |limiter: &mut Limiter, at| {
+            (0..5).filter(|_| limiter.admit(noisy, at)).count()
+        };
+        assert_eq!(admitted(&mut limiter, start), 3);
+        assert!(limiter.admit(quiet, start));
+        // Half a second refills one packet.
+        assert_eq!(
+            admitted(&mut limiter, start + Duration::from_millis(500)),
+            1
+        );
+        assert_eq!(
+            limiter.take(start + Duration::from_millis(500)),
+            BTreeMap::from([(noisy, 6)])
+        );
+
+        // Both buckets have refilled after two seconds.
+        assert!(limiter.take(start + Duration::from_secs(2)).is_empty());
+        assert!(limiter.buckets.is_empty());
+    }
+}
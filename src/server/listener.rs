@@ -0,0 +1,363 @@
+//! Listeners receive the packets that a `Pipeline` parses. `UdpListener` and
+//! `TcpListener` receive them from sockets, and `MemoryListener` from
+//! channels, so that tests can run a whole pipeline without binding one.
+
+use std::io::{self, Read};
+use std::net::{self, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The largest payload that a UDP datagram can carry, which is also the most
+// that a TCP connection's packet can be.
+const MAX_DATAGRAM: usize = 65_535;
+
+// How long a `TcpListener` waits between checks of its sockets.
+const TCP_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// How long a `TcpListener` stops accepting connections for when the process
+// is out of file descriptors, while its connections are still read (and
+// closing them frees some).
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Listener is a source of StatsD packets. Each of a pipeline's listeners is
+/// run on its own thread.
+pub trait Listener: Send {
+    /// Waits up to `timeout` for the next packet, and returns it. Returns
+    /// `Ok(None)` if none arrived in time, so that the listener's thread can
+    /// check whether the pipeline's been shut down.
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Packet>>;
+
+    /// Whether the listener won't receive any more packets. Its thread stops
+    /// once it's closed, and the pipeline once every listener has stopped.
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Returns the number of errors that the listener has carried on through
+    /// since the last call, like connections that it failed to accept, which
+    /// the pipeline counts in "server.listener_errors". Errors that end the
+    /// listener are returned by `recv` instead.
+    fn take_errors(&mut self) -> u64 {
+        0
+    }
+}
+
+/// A packet received by a `Listener`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub payload: Vec<u8>,
+
+    /// The address that the packet was sent from.
+    pub source: SocketAddr,
+}
+
+/// UdpListener receives a packet per UDP datagram, like StatsD servers
+/// usually do.
+pub struct UdpListener {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+}
+
+impl UdpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<UdpListener> {
+        Ok(UdpListener::from_socket(UdpSocket::bind(addr)?))
+    }
+
+    /// Receives from a socket that's already bound.
+    pub fn from_socket(socket: UdpSocket) -> UdpListener {
+        UdpListener {
+            socket,
+            buffer: vec![0; MAX_DATAGRAM],
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Listener for UdpListener {
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Packet>> {
+        // A zero timeout isn't allowed, and means to block forever anyway.
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match self.socket.recv_from(&mut self.buffer) {
+            Ok((len, source)) => Ok(Some(Packet {
+                payload: self.buffer[..len].to_vec(),
+                source,
+            })),
+            Err(error) if is_timeout(&error) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// TcpListener receives metrics from TCP connections, which separate them with
+/// newlines like they would in a datagram. Whatever lines a connection has
+/// sent in full since its last packet make up its next packet.
+///
+/// Its sockets are non-blocking, and are checked in turn by `recv`, so that it
+/// doesn't need a thread for each connection. Connections that fail to be
+/// accepted (e.g. because they were reset while waiting) are counted and
+/// skipped. When the process runs out of file descriptors, it stops
+/// accepting for a moment while it keeps reading the connections it has.
+pub struct TcpListener {
+    listener: net::TcpListener,
+    connections: Vec<TcpConnection>,
+
+    // The connection to check first, so that one that always has something
+    // to read can't keep the others from being read.
+    next: usize,
+
+    // The connections that failed to be accepted since `take_errors`, and
+    // when to accept again after running out of file descriptors.
+    errors: u64,
+    accept_at: Option<Instant>,
+}
+
+struct TcpConnection {
+    stream: TcpStream,
+    source: SocketAddr,
+
+    // What's been read from the connection that isn't in a packet yet.
+    pending: Vec<u8>,
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpListener {
+            listener,
+            connections: Vec::new(),
+            next: 0,
+            errors: 0,
+            accept_at: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Accepts any connections that are waiting. Only errors of the listening
+    // socket itself are returned.
+    fn accept(&mut self) -> io::Result<()> {
+        if self.accept_at.is_some_and(|at| Instant::now() < at) {
+            return Ok(());
+        }
+        self.accept_at = None;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, source)) => match stream.set_nonblocking(true) {
+                    Ok(()) => {
+                        let connection = TcpConnection {
+                            stream,
+                            source,
+                            pending: Vec::new(),
+                        };
+                        self.connections.push(connection);
+                    }
+                    Err(_) => self.errors += 1,
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) if is_out_of_files(&error) => {
+                    self.errors += 1;
+                    self.accept_at = Some(Instant::now() + ACCEPT_BACKOFF);
+                    return Ok(());
+                }
+                Err(error) if is_listener_error(&error) => return Err(error),
+                // The connection failed before it could be accepted (e.g. it
+                // was aborted), which doesn't affect the next.
+                Err(_) => self.errors += 1,
+            }
+        }
+    }
+
+    // Returns the next packet that's been read in full from a connection.
+    fn read(&mut self) -> Option<Packet> {
+        let mut buffer = [0; 4096];
+        for _ in 0..self.connections.len() {
+            let i = self.next % self.connections.len();
+            self.next = i + 1;
+            let connection = &mut self.connections[i];
+            let closed = loop {
+                match connection.stream.read(&mut buffer) {
+                    Ok(0) => break true,
+                    Ok(len) => {
+                        connection.pending.extend_from_slice(&buffer[..len]);
+                        if connection.pending.len() >= MAX_DATAGRAM {
+                            break false;
+                        }
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                    Err(error) => break error.kind() != io::ErrorKind::WouldBlock,
+                }
+            };
+            let packet = connection.packet(closed);
+            if closed {
+                self.connections.swap_remove(i);
+            }
+            if packet.is_some() {
+                return packet;
+            }
+        }
+        None
+    }
+}
+
+impl TcpConnection {
+    // Takes the lines read in full, or everything that's been read once the
+    // connection's closed or too much has been read without a newline.
+    fn packet(&mut self, closed: bool) -> Option<Packet> {
+        let len = if closed || self.pending.len() >= MAX_DATAGRAM {
+            self.pending.len()
+        } else {
+            self.pending.iter().rposition(|&b| b == b'\n')? + 1
+        };
+        if len == 0 {
+            return None;
+        }
+        let rest = self.pending.split_off(len);
+        let payload = std::mem::replace(&mut self.pending, rest);
+        Some(Packet {
+            payload,
+            source: self.source,
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Packet>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.accept()?;
+            if let Some(packet) = self.read() {
+                return Ok(Some(packet));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(TCP_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    fn take_errors(&mut self) -> u64 {
+        std::mem::take(&mut self.errors)
+    }
+}
+
+/// MemoryListener receives the packets sent by its `MemorySender`s. It's
+/// closed once they've all been dropped and it's received everything that they
+/// sent, so a test can send packets, drop its senders, and `run` a pipeline
+/// that returns once it's recorded them.
+pub struct MemoryListener {
+    receiver: mpsc::Receiver<Packet>,
+    closed: bool,
+}
+
+/// MemorySender sends packets to a `MemoryListener`. Clones send to the same
+/// listener.
+#[derive(Clone, Debug)]
+pub struct MemorySender {
+    sender: mpsc::Sender<Packet>,
+    source: SocketAddr,
+}
+
+impl MemoryListener {
+    /// Returns a listener and a sender whose packets are from 127.0.0.1:8125.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> (MemoryListener, MemorySender) {
+        let (sender, receiver) = mpsc::channel();
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 8125));
+        (
+            MemoryListener {
+                receiver,
+                closed: false,
+            },
+            MemorySender { sender, source },
+        )
+    }
+}
+
+impl Listener for MemoryListener {
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Packet>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                self.closed = true;
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl MemorySender {
+    pub fn send(&self, payload: &[u8]) {
+        let packet = Packet {
+            payload: payload.to_vec(),
+            source: self.source,
+        };
+        // The listener may have been dropped along with its pipeline, which is
+        // like a packet sent to a server that's gone.
+        let _ = self.sender.send(packet);
+    }
+
+    /// Returns a sender to the same listener whose packets are from `source`.
+    pub fn with_source(&self, source: SocketAddr) -> MemorySender {
+        MemorySender {
+            sender: self.sender.clone(),
+            source,
+        }
+    }
+}
+
+// Whether accepting failed because the process or the system is out of file
+// descriptors, or of the memory for another socket.
+fn is_out_of_files(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+    // WSAEMFILE and WSAENOBUFS.
+    #[cfg(windows)]
+    let codes = [10024, 10055];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+    error.kind() == io::ErrorKind::OutOfMemory
+        || error
+            .raw_os_error()
+            .is_some_and(|code| codes.contains(&code))
+}
+
+// Whether accepting failed because the listening socket can't be accepted on
+// anymore, rather than because of the connection being accepted, so that it
+// would fail again every time.
+fn is_listener_error(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EBADF, libc::ENOTSOCK, libc::EINVAL, libc::EFAULT];
+    // WSAEBADF, WSAENOTSOCK, WSAEFAULT, and WSAEINVAL.
+    #[cfg(windows)]
+    let codes = [10009, 10038, 10014, 10022];
+    #[cfg(not(any(unix, windows)))]
+    return true;
+    #[cfg(any(unix, windows))]
+    error
+        .raw_os_error()
+        .is_some_and(|code| codes.contains(&code))
+}
+
+// Whether a read failed because its timeout elapsed, which is reported as
+// `WouldBlock` on Unix and `TimedOut` on Windows.
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
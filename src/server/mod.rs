@@ -0,0 +1,1281 @@
+//! An embeddable server, which runs the whole pipeline from receiving StatsD
+//! packets to storing them in a backend in-process, so that applications can
+//! aggregate their own metrics without running a separate daemon.
+//!
+//! A `Pipeline` is built with `Pipeline::builder`, e.g. with
+//! `.udp("0.0.0.0:8125").redis("redis://127.0.0.1:6379").build()?`, and then
+//! `run`, which blocks until it's shut down through a `ShutdownHandle`. Each
+//! listener receives packets on its own thread, while the thread that called
+//! `run` parses them, records them to the backend, and flushes the backend
+//! once per flush interval. Packets of nothing but untagged counters, which
+//! are most of a typical server's traffic, are parsed on their listener's
+//! thread instead, and added to the backend's counters there without going
+//! through the thread that called `run` (see `Backend::direct_counters`),
+//! unless the pipeline has a rate limit or thresholds, which see every
+//! packet. With `PipelineBuilder::workers`, the pipeline is split into
+//! workers that each do all of that on their own, on cores of their own (see
+//! `Workers`). With `PipelineBuilder::align_flushes`, flushes are on
+//! multiples of the interval of the wall clock (e.g. at :00, :10, :20 and so
+//! on each minute for 10 seconds) rather than from when the pipeline started,
+//! so that the windows of servers flushing into the same Redis line up.
+//!
+//! With `PipelineBuilder::shutdown_on_interrupt`, the pipeline is shut down
+//! like it is through its `ShutdownHandle` when the process is interrupted:
+//! by SIGINT or SIGTERM on Unix, and by Ctrl+C, Ctrl+Break, or the console
+//! closing or the system shutting down on Windows. Windows services are
+//! stopped through the service control manager instead, whose handler can
+//! call `ShutdownHandle::shutdown`.
+//!
+//! On Windows, `PipelineBuilder::pipe` listens on a named pipe, which is how
+//! other processes on the same host send to it in place of a Unix domain
+//! socket. See `PipeListener`.
+//!
+//! With `PipelineBuilder::threshold`, the pipeline alerts when a metric that
+//! it receives crosses a threshold, and posts each alert to the webhooks
+//! added with `PipelineBuilder::webhook` from a thread of its own, so that
+//! retries don't hold up flushes. See `Threshold` and `Webhook`.
+//!
+//! Tests can run a pipeline without a socket or a Redis server by giving it a
+//! `MemoryListener`, whose `MemorySender` injects packets, and storing metrics
+//! through a `MemoryClient` with `PipelineBuilder::redis_client`, whose
+//! commands they can assert on once `run` returns.
+//!
+//! Failures that a server should carry on through are counted rather than
+//! stopping the pipeline, and recorded as internal counters on each flush:
+//!
+//! * "server.invalid": packets that failed to parse.
+//! * "server.errors": batches that the backend failed to record or write
+//!   (like a `RedisBackend`'s key/values, which are buffered while Redis is
+//!   unreachable, while the rest of the batch is recorded), and flushes that
+//!   it failed to write, or to finish sending between packets (see
+//!   `Backend::send_buffered`).
+//! * "server.throttled": packets dropped by `PipelineBuilder::rate_limit`,
+//!   tagged with the address of the source that sent them (e.g.
+//!   "server.throttled;source=10.0.0.1"), so that runaway clients can be
+//!   found.
+//! * "server.undelivered": alerts that a webhook didn't accept after every
+//!   attempt.
+//! * "server.listener_errors": errors that listeners carried on through,
+//!   like TCP connections that failed to be accepted (see
+//!   `Listener::take_errors`).
+
+mod alert;
+mod limit;
+mod listener;
+#[cfg(windows)]
+mod pipe;
+mod signal;
+mod workers;
+
+pub use self::alert::{Alert, Condition, Threshold, Webhook, WebhookFormat};
+pub use self::limit::RateLimit;
+pub use self::listener::{
+    Listener, MemoryListener, MemorySender, Packet, TcpListener, UdpListener,
+};
+#[cfg(windows)]
+pub use self::pipe::PipeListener;
+pub use self::workers::Workers;
+
+use self::alert::Alerter;
+use self::limit::Limiter;
+
+use crate::backend::{
+    AtomicCounters, Backend, BackendError, Client, RedisBackend, RedisConfig, TlsConfig,
+};
+use crate::parser::{self, Metric, MetricRef, MetricType, MetricValue, ParserConfig};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// How often listeners check whether the pipeline's been shut down.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pipeline receives metrics from its listeners and stores them in a backend.
+pub struct Pipeline {
+    workers: Vec<Worker>,
+    local_addrs: Vec<SocketAddr>,
+    shutdown: ShutdownHandle,
+}
+
+// Worker receives from its own listeners into its own backend.
+struct Worker {
+    listeners: Vec<Box<dyn Listener>>,
+    backend: Box<dyn Backend + Send>,
+    parser: ParserConfig,
+    flush_interval: Duration,
+    align_flushes: bool,
+    limiter: Option<Limiter>,
+    alerter: Option<Alerter>,
+    webhooks: Vec<Webhook>,
+
+    // Where the worker's alerts are sent to be posted to its webhooks, while
+    // it's running.
+    alerts: Option<mpsc::Sender<Alert>>,
+
+    // The core that the worker's threads are pinned to, if they are.
+    core: Option<usize>,
+
+    // Failures since the last flush. See the module's documentation.
+    invalid: u64,
+    errors: u64,
+    undelivered: Arc<AtomicU64>,
+    listener_errors: Arc<AtomicU64>,
+}
+
+/// PipelineBuilder configures a `Pipeline`. It needs at least one listener and
+/// a backend.
+pub struct PipelineBuilder {
+    udp: Vec<String>,
+    tcp: Vec<String>,
+    #[cfg(windows)]
+    pipes: Vec<String>,
+    listeners: Vec<Box<dyn Listener>>,
+    redis: Option<(Redis, RedisConfig)>,
+    backend: Option<Box<dyn Backend + Send>>,
+    parser: ParserConfig,
+    flush_interval: Duration,
+    align_flushes: bool,
+    rate_limit: Option<RateLimit>,
+    thresholds: Vec<Threshold>,
+    webhooks: Vec<Webhook>,
+    workers: Workers,
+    shutdown_on_interrupt: bool,
+}
+
+// Where a `RedisBackend` is connected to.
+enum Redis {
+    Url(String),
+    Client(Box<dyn Client>),
+}
+
+/// ShutdownHandle stops the `Pipeline` that it was returned by. Clones stop
+/// the same pipeline, so it can be handed to a signal handler or another
+/// thread.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    shutdown: Arc<AtomicBool>,
+
+    // Whether the pipeline's also shut down when the process is interrupted.
+    interrupt: bool,
+}
+
+/// ServerError represents an error running a `Pipeline`.
+#[derive(Clone, Debug, Error, PartialEq)]
+#[non_exhaustive]
+pub enum ServerError {
+    /// A listener's socket failed. The `io::Error` is reduced to its kind and
+    /// message so that errors can be compared.
+    #[error("I/O error: {message}")]
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+
+    /// The backend couldn't be set up.
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+
+    /// The pipeline is missing a listener or a backend.
+    #[error("invalid configuration: {message}")]
+    Config { message: String },
+}
+
+impl From<io::Error> for ServerError {
+    fn from(error: io::Error) -> ServerError {
+        ServerError::Io {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder {
+            udp: Vec::new(),
+            tcp: Vec::new(),
+            #[cfg(windows)]
+            pipes: Vec::new(),
+            listeners: Vec::new(),
+            redis: None,
+            backend: None,
+            parser: ParserConfig::default(),
+            flush_interval: Duration::from_secs(10),
+            align_flushes: false,
+            rate_limit: None,
+            thresholds: Vec::new(),
+            webhooks: Vec::new(),
+            workers: Workers::default(),
+            shutdown_on_interrupt: false,
+        }
+    }
+
+    /// Returns the addresses that the pipeline's UDP listeners are bound to,
+    /// followed by its TCP listeners', which is useful after binding to port
+    /// 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Runs the pipeline until it's shut down, then flushes its backends one
+    /// last time. Returns an error if a listener fails, which shuts down the
+    /// rest of the pipeline too.
+    pub fn run(self) -> Result<(), ServerError> {
+        let shutdown = &self.shutdown;
+        let _running = shutdown
+            .interrupt
+            .then(signal::Running::start)
+            .transpose()?;
+        thread::scope(|scope| {
+            let threads: Vec<_> = self
+                .workers
+                .into_iter()
+                .map(|worker| {
+                    scope.spawn(move || stop_on_error(worker.run(shutdown), shutdown))
+                })
+                .collect();
+            threads
+                .into_iter()
+                .try_for_each(|thread| thread.join().unwrap())
+        })
+    }
+}
+
+impl Worker {
+    fn run(mut self, shutdown: &ShutdownHandle) -> Result<(), ServerError> {
+        if let Some(core) = self.core {
+            workers::pin(core)?;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let listeners = std::mem::take(&mut self.listeners);
+        let core = self.core;
+        // The worker has to see every packet to rate limit it or check it
+        // against thresholds.
+        let direct = self.limiter.is_none() && self.alerter.is_none();
+        let counters = direct.then(|| self.backend.direct_counters()).flatten();
+        let parser = self.parser.clone();
+        let (counters, parser) = (counters.as_ref(), &parser);
+        let errors = Arc::clone(&self.listener_errors);
+        let errors = &*errors;
+        thread::scope(|scope| {
+            let threads: Vec<_> = listeners
+                .into_iter()
+                .map(|mut listener| {
+                    let sender = sender.clone();
+                    scope.spawn(move || {
+                        let pinned =
+                            core.map_or(Ok(()), workers::pin).map_err(ServerError::from);
+                        let counters = counters.map(|counters| (counters, parser));
+                        let listened = |()| {
+                            listen(listener.as_mut(), &sender, counters, errors, shutdown)
+                        };
+                        stop_on_error(pinned.and_then(listened), shutdown)
+                    })
+                })
+                .collect();
+            drop(sender);
+            if self.alerter.is_some() {
+                let (alerts, receiver) = mpsc::channel();
+                self.alerts = Some(alerts);
+                let webhooks = std::mem::take(&mut self.webhooks);
+                let undelivered = Arc::clone(&self.undelivered);
+                scope.spawn(move || alert::deliver(&webhooks, receiver, &undelivered));
+            }
+
+            let mut next_flush = self.next_flush(Instant::now());
+            // Whether the backend may have flushes left to send before the
+            // next flush, which are sent between packets.
+            let mut buffered = false;
+            loop {
+                let timeout = match buffered {
+                    true => Duration::ZERO,
+                    false => next_flush.saturating_duration_since(Instant::now()),
+                };
+                match receiver.recv_timeout(timeout) {
+                    Ok(packet) => self.receive(&packet),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Every listener has stopped.
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if Instant::now() >= next_flush {
+                    self.flush();
+                    next_flush = self.next_flush(next_flush);
+                    buffered = true;
+                } else if buffered {
+                    buffered = self.send_buffered();
+                }
+            }
+            self.flush();
+            // Lets the webhooks' thread stop once it's posted the last alerts.
+            self.alerts = None;
+            threads
+                .into_iter()
+                .try_for_each(|thread| thread.join().unwrap())
+        })
+    }
+
+    // Returns when to flush after the flush at `last`.
+    fn next_flush(&self, last: Instant) -> Instant {
+        if !self.align_flushes {
+            return last + self.flush_interval;
+        }
+        // The wall clock is read again each time so that flushes stay aligned
+        // if it's adjusted.
+        Instant::now() + until_aligned(SystemTime::now(), self.flush_interval)
+    }
+
+    fn receive(&mut self, packet: &Packet) {
+        if let Some(limiter) = &mut self.limiter {
+            if !limiter.admit(packet.source.ip(), Instant::now()) {
+                return;
+            }
+        }
+        let metrics = match parser::parse_ref(&packet.payload, &self.parser) {
+            Ok(batch) => batch.metrics,
+            Err(_) => {
+                self.invalid += 1;
+                return;
+            }
+        };
+        if let Some(alerter) = &mut self.alerter {
+            metrics.iter().for_each(|metric| alerter.record(metric));
+        }
+        if self.backend.record_refs(&metrics).is_err() {
+            self.errors += 1;
+        }
+    }
+
+    // Sends some of what the backend has left to send, and returns whether
+    // there's more.
+    fn send_buffered(&mut self) -> bool {
+        self.backend.send_buffered().unwrap_or_else(|_| {
+            self.errors += 1;
+            false
+        })
+    }
+
+    fn flush(&mut self) {
+        if let (Some(alerter), Some(alerts)) = (&mut self.alerter, &self.alerts) {
+            for alert in alerter.take(SystemTime::now()) {
+                let _ = alerts.send(alert);
+            }
+        }
+        let internal = [
+            ("server.invalid", self.invalid),
+            ("server.errors", self.errors),
+            (
+                "server.undelivered",
+                self.undelivered.swap(0, Ordering::Relaxed),
+            ),
+            (
+                "server.listener_errors",
+                self.listener_errors.swap(0, Ordering::Relaxed),
+            ),
+        ];
+        let mut metrics: Vec<_> = internal
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .filter_map(|(name, count)| counter(name, count, None))
+            .collect();
+        if let Some(limiter) = &mut self.limiter {
+            let throttled =
+                limiter
+                    .take(Instant::now())
+                    .into_iter()
+                    .filter_map(|(source, count)| {
+                        counter("server.throttled", count, Some(&source.to_string()))
+                    });
+            metrics.extend(throttled);
+        }
+        (self.invalid, self.errors) = (0, 0);
+        if !metrics.is_empty() && self.backend.record(&metrics).is_err() {
+            self.errors += 1;
+        }
+        if self.backend.flush().is_err() {
+            self.errors += 1;
+        }
+    }
+}
+
+impl PipelineBuilder {
+    /// Adds a listener on a UDP socket bound to `addr` (e.g. "0.0.0.0:8125").
+    pub fn udp(mut self, addr: &str) -> PipelineBuilder {
+        self.udp.push(String::from(addr));
+        self
+    }
+
+    /// Adds a listener on a TCP socket bound to `addr`. See `TcpListener`.
+    pub fn tcp(mut self, addr: &str) -> PipelineBuilder {
+        self.tcp.push(String::from(addr));
+        self
+    }
+
+    /// Adds a listener on the named pipe `name` (e.g. r"\\.\pipe\statsd"). See
+    /// `PipeListener`.
+    #[cfg(windows)]
+    pub fn pipe(mut self, name: &str) -> PipelineBuilder {
+        self.pipes.push(String::from(name));
+        self
+    }
+
+    /// Adds a listener, like a `MemoryListener`.
+    pub fn listener(mut self, listener: impl Listener + 'static) -> PipelineBuilder {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+
+    /// Stores metrics in Redis at `url` (e.g. "redis://127.0.0.1:6379") with a
+    /// `RedisBackend`.
+    pub fn redis(self, url: &str) -> PipelineBuilder {
+        self.redis_with_config(url, RedisConfig::default())
+    }
+
+    /// Like `redis`, but with the backend's configuration.
+    pub fn redis_with_config(
+        mut self,
+        url: &str,
+        config: RedisConfig,
+    ) -> PipelineBuilder {
+        self.redis = Some((Redis::Url(String::from(url)), config));
+        self
+    }
+
+    /// Like `redis_with_config`, but sends commands through `client`, like a
+    /// `MemoryClient`.
+    pub fn redis_client(
+        mut self,
+        client: impl Client + 'static,
+        config: RedisConfig,
+    ) -> PipelineBuilder {
+        self.redis = Some((Redis::Client(Box::new(client)), config));
+        self
+    }
+
+    /// Stores metrics in `backend`, in place of `redis`.
+    pub fn backend(mut self, backend: impl Backend + Send + 'static) -> PipelineBuilder {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    /// Sets how packets are parsed.
+    pub fn parser(mut self, parser: ParserConfig) -> PipelineBuilder {
+        self.parser = parser;
+        self
+    }
+
+    /// Sets how often the backend is flushed. Defaults to every 10 seconds.
+    pub fn flush_interval(mut self, flush_interval: Duration) -> PipelineBuilder {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    /// Whether flushes are aligned to multiples of the flush interval of the
+    /// wall clock. Defaults to false.
+    pub fn align_flushes(mut self, align_flushes: bool) -> PipelineBuilder {
+        self.align_flushes = align_flushes;
+        self
+    }
+
+    /// Limits how fast each source address can send packets. Packets over the
+    /// limit are dropped. A limit that would drop every packet is a
+    /// `ServerError::Config` from `build`. Unlimited by default.
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> PipelineBuilder {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Alerts when a metric crosses `threshold`. With more than one worker,
+    /// each checks it against the metrics that it receives on its own.
+    pub fn threshold(mut self, threshold: Threshold) -> PipelineBuilder {
+        self.thresholds.push(threshold);
+        self
+    }
+
+    /// Posts alerts to `webhook`.
+    pub fn webhook(mut self, webhook: Webhook) -> PipelineBuilder {
+        self.webhooks.push(webhook);
+        self
+    }
+
+    /// Splits the pipeline into workers. Defaults to a single worker that
+    /// isn't pinned to a core.
+    pub fn workers(mut self, workers: Workers) -> PipelineBuilder {
+        self.workers = workers;
+        self
+    }
+
+    /// Whether the pipeline's shut down when the process is interrupted (see
+    /// the module's documentation). This replaces the process's handlers for
+    /// those signals or events while the pipeline runs, and restores the
+    /// defaults once no pipeline that does is running. Defaults to false.
+    pub fn shutdown_on_interrupt(
+        mut self,
+        shutdown_on_interrupt: bool,
+    ) -> PipelineBuilder {
+        self.shutdown_on_interrupt = shutdown_on_interrupt;
+        self
+    }
+
+    /// Binds the listeners and connects to the backend.
+    pub fn build(self) -> Result<Pipeline, ServerError> {
+        let count = self.workers.count.max(1);
+        let mut listeners: Vec<Vec<Box<dyn Listener>>> =
+            (0..count).map(|_| Vec::new()).collect();
+        let mut local_addrs = Vec::new();
+        for addr in &self.udp {
+            if count == 1 {
+                let listener = UdpListener::bind(addr.as_str())?;
+                local_addrs.push(listener.local_addr()?);
+                listeners[0].push(Box::new(listener));
+                continue;
+            }
+            let mut addr = resolve(addr)?;
+            for listeners in &mut listeners {
+                let socket = workers::bind_reuseport(addr)?;
+                // After binding to port 0, the rest are bound to the same port.
+                addr = socket.local_addr()?;
+                listeners.push(Box::new(UdpListener::from_socket(socket)));
+            }
+            local_addrs.push(addr);
+        }
+        let mut first = self.listeners;
+        for addr in &self.tcp {
+            let listener = TcpListener::bind(addr.as_str())?;
+            local_addrs.push(listener.local_addr()?);
+            first.push(Box::new(listener));
+        }
+        #[cfg(windows)]
+        for name in &self.pipes {
+            first.push(Box::new(PipeListener::bind(name)?));
+        }
+        listeners[0].append(&mut first);
+        if listeners[0].is_empty() {
+            return Err(ServerError::Config {
+                message: String::from("no listeners"),
+            });
+        }
+        let mut backends: Vec<Box<dyn Backend + Send>> = Vec::new();
+        match (self.backend, self.redis) {
+            (Some(backend), _) => backends.push(backend),
+            (None, Some((Redis::Url(url), config))) => {
+                for _ in 0..count {
+                    let tls = TlsConfig::default();
+                    backends.push(Box::new(RedisBackend::connect_url(
+                        &url,
+                        &tls,
+                        config.clone(),
+                    )?));
+                }
+            }
+            (None, Some((Redis::Client(client), config))) => {
+                backends.push(Box::new(RedisBackend::with_client(client, config)?));
+            }
+            (None, None) => {
+                return Err(ServerError::Config {
+                    message: String::from("no backend"),
+                });
+            }
+        }
+        if backends.len() < count {
+            let message =
+                String::from("each worker needs a backend of its own, from `redis`");
+            return Err(ServerError::Config { message });
+        }
+        for webhook in &self.webhooks {
+            webhook.check()?;
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.check()?;
+        }
+        let cores = if self.workers.pin {
+            workers::cores()?
+        } else {
+            Vec::new()
+        };
+        let workers = listeners
+            .into_iter()
+            .zip(backends)
+            .enumerate()
+            .map(|(i, (listeners, backend))| Worker {
+                listeners,
+                backend,
+                parser: self.parser.clone(),
+                flush_interval: self.flush_interval,
+                align_flushes: self.align_flushes,
+                limiter: self.rate_limit.clone().map(Limiter::new),
+                alerter: (!self.thresholds.is_empty())
+                    .then(|| Alerter::new(self.thresholds.clone())),
+                webhooks: self.webhooks.clone(),
+                alerts: None,
+                core: (!cores.is_empty()).then(|| cores[i % cores.len()]),
+                invalid: 0,
+                errors: 0,
+                undelivered: Arc::default(),
+                listener_errors: Arc::default(),
+            })
+            .collect();
+        let shutdown = ShutdownHandle {
+            interrupt: self.shutdown_on_interrupt,
+            ..Default::default()
+        };
+        Ok(Pipeline {
+            workers,
+            local_addrs,
+            shutdown,
+        })
+    }
+}
+
+impl ShutdownHandle {
+    /// Stops the pipeline's listeners. `Pipeline::run` returns once it's
+    /// recorded the packets that they've already received and flushed.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed) || (self.interrupt && signal::interrupted())
+    }
+}
+
+// Shuts down the pipeline if a worker or listener failed, so that the rest of
+// it stops too.
+fn stop_on_error(
+    result: Result<(), ServerError>,
+    shutdown: &ShutdownHandle,
+) -> Result<(), ServerError> {
+    if result.is_err() {
+        shutdown.shutdown();
+    }
+    result
+}
+
+// Resolves an address to bind to.
+fn resolve(addr: &str) -> Result<SocketAddr, ServerError> {
+    match addr.to_socket_addrs()?.next() {
+        Some(addr) => Ok(addr),
+        None => Err(ServerError::Config {
+            message: format!("{} has no address", addr),
+        }),
+    }
+}
+
+// Receives packets from a listener until the pipeline's shut down, counting
+// the errors that it carries on through in `errors`. With `counters`, packets
+// of nothing but untagged counters are added to them rather than sent to the
+// worker.
+fn listen(
+    listener: &mut dyn Listener,
+    sender: &mpsc::Sender<Packet>,
+    counters: Option<(&AtomicCounters, &ParserConfig)>,
+    errors: &AtomicU64,
+    shutdown: &ShutdownHandle,
+) -> Result<(), ServerError> {
+    while !shutdown.is_shutdown() && !listener.is_closed() {
+        let packet = listener.recv(POLL_INTERVAL)?;
+        errors.fetch_add(listener.take_errors(), Ordering::Relaxed);
+        let Some(packet) = packet else {
+            continue;
+        };
+        if let Some((counters, parser)) = counters {
+            if add_counters(counters, &packet.payload, parser) {
+                continue;
+            }
+        }
+        if sender.send(packet).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Adds the counters in `payload` to `counters` if it's made up of nothing but
+// untagged counters with integer values and no sample rates, and returns
+// whether it was. Anything else, including packets that don't parse, is
+// left to the worker whole. Packets with a line that doesn't end in "|c" (so
+// it's of another type, or tagged, or sampled) aren't parsed at all.
+fn add_counters(
+    counters: &AtomicCounters,
+    payload: &[u8],
+    parser: &ParserConfig,
+) -> bool {
+    let mut lines = payload
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty());
+    if !lines.all(|line| line.ends_with(b"|c")) {
+        return false;
+    }
+    let Ok(batch) = parser::parse_ref(payload, parser) else {
+        return false;
+    };
+    if batch.metrics.is_empty()
+        || !batch.metrics.iter().all(|m| untagged_count(m).is_some())
+    {
+        return false;
+    }
+    for metric in &batch.metrics {
+        counters.add(&metric.name, untagged_count(metric).unwrap_or_default());
+    }
+    true
+}
+
+// Returns the value of an untagged counter that can be added to
+// `AtomicCounters` as it is.
+fn untagged_count(metric: &MetricRef) -> Option<i64> {
+    let sampled = metric.sample_rate.is_some_and(|rate| rate != 1.0);
+    if metric.metric_type != MetricType::Counter || !metric.tags.is_empty() || sampled {
+        return None;
+    }
+    match metric.numeric_value()? {
+        MetricValue::Integer(i) => Some(i),
+        MetricValue::Unsigned(u) => i64::try_from(u).ok(),
+        MetricValue::Float(_) => None,
+    }
+}
+
+// Returns how long it is from `now` until the next multiple of `interval`
+// since the Unix epoch.
+fn until_aligned(now: SystemTime, interval: Duration) -> Duration {
+    let interval = interval.as_nanos().max(1);
+    let since = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    Duration::from_nanos((interval - since % interval) as u64)
+}
+
+// Returns an internal counter, tagged with the source that it's about.
+fn counter(name: &str, count: u64, source: Option<&str>) -> Option<Metric> {
+    let value = count.to_string();
+    let mut builder = Metric::builder()
+        .name(name)
+        .value(&value)
+        .metric_type(MetricType::Counter);
+    if let Some(source) = source {
+        builder = builder.tag("source", Some(source));
+    }
+    builder.build().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::FakeRedis;
+    use crate::backend::{Command, MemoryClient, Value};
+    use std::io::Write;
+    use std::net::{TcpStream, UdpSocket};
+    use std::sync::Mutex;
+
+    #[test]
+    fn it_runs_a_pipeline() {
+        let memory = MemoryClient::new();
+        let backend =
+            RedisBackend::with_client(Box::new(memory.clone()), RedisConfig::default())
+                .unwrap();
+        let pipeline = Pipeline::builder()
+            .udp("127.0.0.1:0")
+            .backend(backend)
+            .flush_interval(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let addr = pipeline.local_addrs()[0];
+        let shutdown = pipeline.shutdown_handle();
+        let server = thread::spawn(move || pipeline.run());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for payload in [&b"gorets:1|c\nglork:320|ms"[..], b"gorets:2|c", b"gorets"] {
+            socket.send_to(payload, addr).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while memory.get("stats.counters.server.invalid").is_none()
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown.shutdown();
+        assert_eq!(server.join().unwrap(), Ok(()));
+
+        let bulk = |value: &str| Some(Value::Bulk(value.as_bytes().to_vec()));
+        assert_eq!(memory.get("stats.counters.gorets"), bulk("3"));
+        assert_eq!(memory.get("stats.counters.server.invalid"), bulk("1"));
+        assert!(memory
+            .keys()
+            .iter()
+            .any(|key| key.starts_with("{stats.timers.glork.")));
+    }
+
+    #[test]
+    fn it_runs_a_pipeline_in_memory() {
+        let memory = MemoryClient::new();
+        let (listener, sender) = MemoryListener::new();
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        sender.send(b"gorets:1|c\nglork:1.2.3|kv");
+        sender.send(b"gorets:2|c|#env:production");
+        drop(sender);
+        assert_eq!(pipeline.run(), Ok(()));
+
+        let render = |command: &Command| {
+            let args = command
+                .args()
+                .iter()
+                .map(|arg| String::from_utf8_lossy(arg));
+            args.collect::<Vec<_>>().join(" ")
+        };
+        let sent: Vec<Vec<_>> = memory
+            .sent()
+            .iter()
+            .map(|commands| commands.iter().map(render).collect())
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                vec!["SET stats.values.glork 1.2.3"],
+                vec![
+                    "INCRBY stats.counters.gorets 1",
+                    "INCRBY stats.counters.gorets;env=production 2"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn it_adds_untagged_counters_on_listener_threads() {
+        let counters = AtomicCounters::new();
+        let parser = ParserConfig::default();
+        assert!(add_counters(
+            &counters,
+            b"gorets:1|c\ngorets:-4|c\nglork:2|c\n",
+            &parser
+        ));
+        for payload in [
+            &b"gorets:1|c|@0.5"[..],
+            b"gorets:1|c|#env:production",
+            b"gorets:1.5|c",
+            b"gorets:1|c\nglork:320|ms",
+            b"gorets|c",
+            b"",
+        ] {
+            assert!(!add_counters(&counters, payload, &parser));
+        }
+
+        let memory = MemoryClient::new();
+        let (listener, sender) = MemoryListener::new();
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        sender.send(b"gorets:1|c\ngorets:2|c");
+        sender.send(b"gorets:3|c|@0.5\nglork:1|c");
+        sender.send(b"gorets:-1|c");
+        drop(sender);
+        assert_eq!(pipeline.run(), Ok(()));
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"8".to_vec()))
+        );
+        assert_eq!(
+            memory.get("stats.counters.glork"),
+            Some(Value::Bulk(b"1".to_vec()))
+        );
+    }
+
+    #[test]
+    fn it_counts_errors_that_listeners_carry_on_through() {
+        // Flaky fails to accept a connection on each call to `recv`.
+        struct Flaky(u64);
+
+        impl Listener for Flaky {
+            fn recv(&mut self, _timeout: Duration) -> io::Result<Option<Packet>> {
+                self.0 += 1;
+                Ok(None)
+            }
+
+            fn is_closed(&self) -> bool {
+                self.0 >= 3
+            }
+
+            fn take_errors(&mut self) -> u64 {
+                1
+            }
+        }
+
+        let memory = MemoryClient::new();
+        let pipeline = Pipeline::builder()
+            .listener(Flaky(0))
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        assert_eq!(pipeline.run(), Ok(()));
+        let errors = memory.get("stats.counters.server.listener_errors");
+        assert_eq!(errors, Some(Value::Bulk(b"3".to_vec())));
+    }
+
+    #[test]
+    fn it_sends_what_flushes_leave_buffered_between_packets() {
+        // Deferring leaves each flush to be sent in three parts.
+        struct Deferring(Arc<Mutex<Vec<&'static str>>>, usize);
+
+        impl Backend for Deferring {
+            fn record(&mut self, _metrics: &[Metric]) -> Result<(), BackendError> {
+                Ok(())
+            }
+
+            fn flush(&mut self) -> Result<(), BackendError> {
+                self.0.lock().unwrap().push("flush");
+                self.1 = 2;
+                Ok(())
+            }
+
+            fn send_buffered(&mut self) -> Result<bool, BackendError> {
+                self.0.lock().unwrap().push("send");
+                self.1 = self.1.saturating_sub(1);
+                Ok(self.1 > 0)
+            }
+        }
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (listener, sender) = MemoryListener::new();
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .backend(Deferring(Arc::clone(&calls), 0))
+            .flush_interval(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let server = thread::spawn(move || pipeline.run());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while calls.lock().unwrap().len() < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(sender);
+        assert_eq!(server.join().unwrap(), Ok(()));
+        assert_eq!(calls.lock().unwrap()[..3], ["flush", "send", "send"]);
+    }
+
+    #[test]
+    fn it_receives_over_tcp() {
+        let memory = MemoryClient::new();
+        let pipeline = Pipeline::builder()
+            .tcp("127.0.0.1:0")
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let addr = pipeline.local_addrs()[0];
+        let shutdown = pipeline.shutdown_handle();
+        let server = thread::spawn(move || pipeline.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"gorets:1|c\ngor").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(20));
+        stream.write_all(b"ets:2|c\n").unwrap();
+        drop(stream);
+        let bulk = |value: &str| Some(Value::Bulk(value.as_bytes().to_vec()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while memory.get("stats.counters.gorets") != bulk("3")
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown.shutdown();
+        assert_eq!(server.join().unwrap(), Ok(()));
+        assert_eq!(memory.get("stats.counters.gorets"), bulk("3"));
+        assert_eq!(memory.get("stats.counters.server.invalid"), None);
+    }
+
+    #[test]
+    fn it_limits_sources() {
+        let memory = MemoryClient::new();
+        let (listener, sender) = MemoryListener::new();
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .redis_client(memory.clone(), RedisConfig::default())
+            .rate_limit(RateLimit {
+                packets_per_second: 0.001,
+                burst: 2.0,
+            })
+            .flush_interval(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        let noisy = sender.with_source(SocketAddr::from(([10, 0, 0, 1], 8125)));
+        for _ in 0..5 {
+            noisy.send(b"noisy:1|c");
+        }
+        sender.send(b"quiet:1|c");
+        drop((sender, noisy));
+        assert_eq!(pipeline.run(), Ok(()));
+
+        let bulk = |value: &str| Some(Value::Bulk(value.as_bytes().to_vec()));
+        assert_eq!(memory.get("stats.counters.noisy"), bulk("2"));
+        assert_eq!(memory.get("stats.counters.quiet"), bulk("1"));
+        assert_eq!(
+            memory.get("stats.counters.server.throttled;source=10.0.0.1"),
+            bulk("3")
+        );
+        assert_eq!(
+            memory.get("stats.counters.server.throttled;source=127.0.0.1"),
+            None
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn it_runs_pinned_workers() {
+        let redis = FakeRedis::start();
+        let pipeline = Pipeline::builder()
+            .udp("127.0.0.1:0")
+            .redis(&format!("redis://{}", redis.addr()))
+            .workers(Workers {
+                count: 2,
+                pin: true,
+            })
+            .flush_interval(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        assert_eq!(pipeline.workers.len(), 2);
+        let addr = pipeline.local_addrs()[0];
+        let shutdown = pipeline.shutdown_handle();
+        let server = thread::spawn(move || pipeline.run());
+
+        // Sources are spread across the workers by their ports.
+        for _ in 0..8 {
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .send_to(b"gorets:1|c", addr)
+                .unwrap();
+        }
+        let total = || -> i64 {
+            let commands = redis.commands().into_iter();
+            let gorets = ["INCRBY", "stats.counters.gorets"];
+            let increments = commands.filter(|command| command[..2] == gorets);
+            increments
+                .map(|command| command[2].parse::<i64>().unwrap())
+                .sum()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while total() < 8 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown.shutdown();
+        assert_eq!(server.join().unwrap(), Ok(()));
+        assert_eq!(total(), 8);
+    }
+
+    #[test]
+    fn it_needs_a_backend_for_each_worker() {
+        let pipeline = Pipeline::builder()
+            .listener(MemoryListener::new().0)
+            .redis_client(MemoryClient::new(), RedisConfig::default())
+            .workers(Workers {
+                count: 2,
+                pin: false,
+            })
+            .build();
+        let message =
+            String::from("each worker needs a backend of its own, from `redis`");
+        assert_eq!(pipeline.map(|_| ()), Err(ServerError::Config { message }));
+    }
+
+    #[test]
+    fn it_aligns_flushes_to_the_wall_clock() {
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        let interval = Duration::from_secs(10);
+        assert_eq!(until_aligned(at(1_000), interval), Duration::from_secs(9));
+        assert_eq!(
+            until_aligned(at(19_999), interval),
+            Duration::from_millis(1)
+        );
+        // A flush exactly on a boundary is followed by the next one.
+        assert_eq!(until_aligned(at(20_000), interval), interval);
+        let minute = Duration::from_secs(60);
+        assert_eq!(
+            until_aligned(at(61_500), minute),
+            Duration::from_millis(58_500)
+        );
+    }
+
+    #[test]
+    fn it_posts_alerts_to_webhooks() {
+        // Fails the first request, then accepts the next webhook's and the
+        // retry, and returns the bodies of all three.
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", server.local_addr().unwrap());
+        let webhooks = thread::spawn(move || {
+            let statuses = ["503 Service Unavailable", "200 OK", "200 OK"];
+            let bodies: Vec<_> = statuses
+                .iter()
+                .map(|status| {
+                    let (stream, _) = server.accept().unwrap();
+                    let mut reader = io::BufReader::new(stream);
+                    let mut len = 0;
+                    loop {
+                        let mut line = String::new();
+                        io::BufRead::read_line(&mut reader, &mut line).unwrap();
+                        if let Some(value) = line.strip_prefix("Content-Length: ") {
+                            len = value.trim().parse().unwrap();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; len];
+                    io::Read::read_exact(&mut reader, &mut body).unwrap();
+                    let response =
+                        format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                    reader.get_mut().write_all(response.as_bytes()).unwrap();
+                    String::from_utf8(body).unwrap()
+                })
+                .collect();
+            bodies
+        });
+
+        let memory = MemoryClient::new();
+        let (listener, sender) = MemoryListener::new();
+        let webhook = Webhook {
+            backoff: Duration::from_millis(1),
+            ..Webhook::new(&url)
+        };
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_secs(3600))
+            .threshold(Threshold {
+                name: String::from("high error rate"),
+                metric: String::from("errors"),
+                condition: Condition::Above,
+                value: 5.0,
+            })
+            .webhook(webhook.clone())
+            .webhook(Webhook {
+                format: WebhookFormat::Slack,
+                ..webhook
+            })
+            .build()
+            .unwrap();
+        sender.send(b"errors:4|c\nerrors:3|c|#env:production");
+        drop(sender);
+        assert_eq!(pipeline.run(), Ok(()));
+
+        // The next webhook is posted to while the first backs off.
+        let bodies = webhooks.join().unwrap();
+        let json =
+            r#"{"threshold":"high error rate","metric":"errors","condition":"above","#;
+        assert!(bodies[0].starts_with(json));
+        assert_eq!(
+            bodies[1],
+            r#"{"text":"high error rate: errors was 7, above 5"}"#
+        );
+        assert_eq!(bodies[2], bodies[0]);
+    }
+
+    #[test]
+    fn it_requires_valid_rate_limits() {
+        let build = |packets_per_second, burst| {
+            let pipeline = Pipeline::builder()
+                .listener(MemoryListener::new().0)
+                .redis_client(MemoryClient::new(), RedisConfig::default())
+                .rate_limit(RateLimit {
+                    packets_per_second,
+                    burst,
+                })
+                .build();
+            pipeline.map(|_| ())
+        };
+        assert_eq!(build(10.0, 1.0), Ok(()));
+        for (packets_per_second, burst) in [
+            (0.0, 5.0),
+            (-1.0, 5.0),
+            (f64::NAN, 5.0),
+            (10.0, 0.5),
+            (10.0, f64::NAN),
+        ] {
+            let pipeline = build(packets_per_second, burst);
+            assert!(
+                matches!(pipeline, Err(ServerError::Config { .. })),
+                "{:?}",
+                pipeline
+            );
+        }
+    }
+
+    #[test]
+    fn it_requires_valid_webhooks() {
+        let pipeline = Pipeline::builder()
+            .listener(MemoryListener::new().0)
+            .redis_client(MemoryClient::new(), RedisConfig::default())
+            .webhook(Webhook::new("hooks.slack.com/services/T0/B0/x"))
+            .build();
+        let message =
+            String::from("invalid webhook URL hooks.slack.com/services/T0/B0/x");
+        assert_eq!(pipeline.map(|_| ()), Err(ServerError::Config { message }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_shuts_down_on_interrupt() {
+        // SAFETY: a null action only reads the handler.
+        let handler = || unsafe {
+            let mut action = std::mem::zeroed::<libc::sigaction>();
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        };
+        let memory = MemoryClient::new();
+        let (listener, sender) = MemoryListener::new();
+        let pipeline = Pipeline::builder()
+            .listener(listener)
+            .redis_client(memory.clone(), RedisConfig::default())
+            .flush_interval(Duration::from_secs(3600))
+            .shutdown_on_interrupt(true)
+            .build()
+            .unwrap();
+        sender.send(b"gorets:1|c");
+        // The sender isn't dropped, so only the interrupt stops the pipeline.
+        let server = thread::spawn(move || pipeline.run());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handler() == libc::SIG_DFL && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_ne!(handler(), libc::SIG_DFL);
+        // SAFETY: the pipeline's handler for SIGINT is installed.
+        unsafe { libc::raise(libc::SIGINT) };
+        assert_eq!(server.join().unwrap(), Ok(()));
+        assert_eq!(
+            memory.get("stats.counters.gorets"),
+            Some(Value::Bulk(b"1".to_vec()))
+        );
+        drop(sender);
+
+        // The default handler is back once the pipeline's done.
+        assert_eq!(handler(), libc::SIG_DFL);
+    }
+
+    #[test]
+    fn it_requires_listeners_and_a_backend() {
+        let error = |message: &str| {
+            Err(ServerError::Config {
+                message: String::from(message),
+            })
+        };
+        let pipeline = Pipeline::builder().redis("redis://127.0.0.1:6379").build();
+        assert_eq!(pipeline.map(|_| ()), error("no listeners"));
+        let pipeline = Pipeline::builder().udp("127.0.0.1:0").build();
+        assert_eq!(pipeline.map(|_| ()), error("no backend"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Receives metrics over Windows named pipes, which Windows services use in
+//! place of Unix domain sockets for talking to other processes on the same
+//! host.
+
+use super::listener::{Listener, Packet};
+use std::ffi::c_void;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Handle = *mut c_void;
+
+const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+const PIPE_ACCESS_INBOUND: u32 = 0x1;
+const PIPE_TYPE_BYTE: u32 = 0x0;
+const PIPE_READMODE_BYTE: u32 = 0x0;
+const PIPE_NOWAIT: u32 = 0x1;
+const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+const ERROR_NO_DATA: i32 = 232;
+const ERROR_PIPE_CONNECTED: i32 = 535;
+const ERROR_PIPE_LISTENING: i32 = 536;
+
+// The size of each pipe instance's buffer, and the most that's read at once.
+const BUFFER_SIZE: u32 = 65_536;
+
+// How long a `PipeListener` waits between checks of its pipes.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+extern "system" {
+    fn CreateNamedPipeW(
+        name: *const u16,
+        open_mode: u32,
+        pipe_mode: u32,
+        max_instances: u32,
+        out_buffer_size: u32,
+        in_buffer_size: u32,
+        default_timeout: u32,
+        security_attributes: *mut c_void,
+    ) -> Handle;
+    fn ConnectNamedPipe(pipe: Handle, overlapped: *mut c_void) -> i32;
+    fn ReadFile(
+        file: Handle,
+        buffer: *mut c_void,
+        len: u32,
+        read: *mut u32,
+        overlapped: *mut c_void,
+    ) -> i32;
+    fn CloseHandle(handle: Handle) -> i32;
+}
+
+/// PipeListener receives metrics from clients of a named pipe (like
+/// r"\\.\pipe\statsd"), which send them separated by newlines like a
+/// `TcpListener`'s connections do. Each client has an instance of the pipe of
+/// its own, and another is always waiting for the next client.
+///
+/// Pipes don't have addresses, so packets from them are from 127.0.0.1:0, and
+/// `RateLimit` limits them together.
+pub struct PipeListener {
+    name: Vec<u16>,
+
+    // The instance that's waiting for a client.
+    listening: Pipe,
+
+    // The instances that clients are connected to, and what's been read from
+    // each that isn't in a packet yet.
+    connected: Vec<(Pipe, Vec<u8>)>,
+    next: usize,
+
+    // What each read is read into, which is kept between reads rather than
+    // allocated for each poll.
+    buffer: Vec<u8>,
+}
+
+// An instance of a named pipe, which is closed once it's dropped.
+struct Pipe(Handle);
+
+// SAFETY: a pipe's handle can be used from any thread.
+unsafe impl Send for Pipe {}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // SAFETY: the handle is valid, and is only closed here.
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
+impl PipeListener {
+    pub fn bind(name: &str) -> io::Result<PipeListener> {
+        let name: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        let listening = create(&name)?;
+        let buffer = vec![0; BUFFER_SIZE as usize];
+        Ok(PipeListener {
+            name,
+            listening,
+            connected: Vec::new(),
+            next: 0,
+            buffer,
+        })
+    }
+
+    // Moves the listening instance to the connected ones if a client has
+    // connected to it, and creates another instance to listen.
+    fn accept(&mut self) -> io::Result<()> {
+        // SAFETY: the handle is valid, and a pipe that isn't overlapped takes
+        // no `OVERLAPPED`.
+        if unsafe { ConnectNamedPipe(self.listening.0, ptr::null_mut()) } != 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(ERROR_PIPE_LISTENING) => Ok(()),
+            // A client that's disconnected already may still have written.
+            Some(ERROR_PIPE_CONNECTED) | Some(ERROR_NO_DATA) => {
+                let listening =
+                    std::mem::replace(&mut self.listening, create(&self.name)?);
+                self.connected.push((listening, Vec::new()));
+                Ok(())
+            }
+            _ => Err(error),
+        }
+    }
+
+    // Returns the next packet that's been read in full from a client, like
+    // `TcpListener` does.
+    fn read(&mut self) -> Option<Packet> {
+        let buffer = &mut self.buffer;
+        for _ in 0..self.connected.len() {
+            let i = self.next % self.connected.len();
+            self.next = i + 1;
+            let (pipe, pending) = &mut self.connected[i];
+            let mut len = 0;
+            // SAFETY: the buffer is `BUFFER_SIZE` long, and a pipe that isn't
+            // overlapped takes no `OVERLAPPED`.
+            let read = unsafe {
+                ReadFile(
+                    pipe.0,
+                    buffer.as_mut_ptr().cast(),
+                    BUFFER_SIZE,
+                    &mut len,
+                    ptr::null_mut(),
+                )
+            };
+            // Without data to read, a read fails with `ERROR_NO_DATA`, and once
+            // the client has disconnected with `ERROR_BROKEN_PIPE`.
+            let closed = read == 0
+                && io::Error::last_os_error().raw_os_error() != Some(ERROR_NO_DATA);
+            pending.extend_from_slice(&buffer[..len as usize]);
+            let end = if closed || pending.len() >= BUFFER_SIZE as usize {
+                pending.len()
+            } else {
+                pending
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(0, |i| i + 1)
+            };
+            let rest = pending.split_off(end);
+            let payload = std::mem::replace(pending, rest);
+            if closed {
+                self.connected.swap_remove(i);
+            }
+            if !payload.is_empty() {
+                let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+                return Some(Packet { payload, source });
+            }
+        }
+        None
+    }
+}
+
+impl Listener for PipeListener {
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Packet>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.accept()?;
+            if let Some(packet) = self.read() {
+                return Ok(Some(packet));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+// Creates an instance of the pipe named `name`, which is null-terminated.
+// Instances don't block, so that waiting for clients and reading from them
+// can be checked in turn.
+fn create(name: &[u16]) -> io::Result<Pipe> {
+    // SAFETY: the name is null-terminated, and no security attributes means
+    // the defaults.
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            PIPE_ACCESS_INBOUND,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_NOWAIT,
+            PIPE_UNLIMITED_INSTANCES,
+            0,
+            BUFFER_SIZE,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Pipe(handle))
+}
@@ -0,0 +1,149 @@
+//! Shuts down pipelines when the process is interrupted: by SIGINT or SIGTERM
+//! on Unix, and by Ctrl+C, Ctrl+Break, or the console closing or the system
+//! shutting down on Windows. The handler is installed while any pipeline that
+//! stops on interrupts is running, and the defaults are restored once none
+//! are, so that interrupting the process afterwards ends it like usual.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Whether the process has been interrupted since the handler was installed.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The number of pipelines running that stop when the process is interrupted.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// Held while the handler's installed or restored, so that a pipeline starting
+// while the last one stops doesn't end up without it.
+static INSTALLING: Mutex<()> = Mutex::new(());
+
+// Running counts a pipeline as running until it's dropped.
+pub(super) struct Running;
+
+impl Running {
+    // Counts a pipeline as running, installing the handler if it's the first.
+    pub(super) fn start() -> io::Result<Running> {
+        let _installing = INSTALLING.lock().unwrap();
+        if RUNNING.load(Ordering::SeqCst) == 0 {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+            install()?;
+        }
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        Ok(Running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _installing = INSTALLING.lock().unwrap();
+        if RUNNING.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // The handler was installed, so the defaults can be too.
+            let _ = restore();
+        }
+    }
+}
+
+pub(super) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+#[cfg(unix)]
+fn install() -> io::Result<()> {
+    extern "C" fn handle(_signal: libc::c_int) {
+        // Only storing to an atomic is safe in a signal handler.
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    let handler = handle as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t;
+    set_handler(handler)
+}
+
+#[cfg(unix)]
+fn restore() -> io::Result<()> {
+    set_handler(libc::SIG_DFL)
+}
+
+#[cfg(unix)]
+fn set_handler(handler: libc::sighandler_t) -> io::Result<()> {
+    for signal in SIGNALS {
+        // SAFETY: the handler is either the default or only stores to an
+        // atomic.
+        let previous = unsafe { libc::signal(signal, handler) };
+        if previous == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+mod console {
+    use super::{INTERRUPTED, RUNNING};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    const CTRL_CLOSE_EVENT: u32 = 2;
+    const CTRL_LOGOFF_EVENT: u32 = 5;
+
+    extern "system" {
+        pub(super) fn SetConsoleCtrlHandler(
+            handler: extern "system" fn(u32) -> i32,
+            add: i32,
+        ) -> i32;
+    }
+
+    pub(super) extern "system" fn handle(event: u32) -> i32 {
+        // Logging off only ends the processes of the user logging off, which
+        // services aren't.
+        if event == CTRL_LOGOFF_EVENT {
+            return 0;
+        }
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        // The process is ended as soon as the handler returns for everything
+        // but Ctrl+C and Ctrl+Break, so it waits for the pipelines to flush
+        // (for as long as Windows lets it).
+        if event >= CTRL_CLOSE_EVENT {
+            while RUNNING.load(Ordering::SeqCst) > 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        1
+    }
+}
+
+#[cfg(windows)]
+fn install() -> io::Result<()> {
+    // SAFETY: the handler is a function that lives as long as the process.
+    if unsafe { console::SetConsoleCtrlHandler(console::handle, 1) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Removes the handler, which leaves the default one that ends the process.
+#[cfg(windows)]
+fn restore() -> io::Result<()> {
+    // SAFETY: the handler was added by `install`.
+    if unsafe { console::SetConsoleCtrlHandler(console::handle, 0) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn install() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "interrupts need Unix or Windows",
+    ))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn restore() -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! Splits a `Pipeline` into workers so that it can use more than one core, and
+//! pins each to a core of its own, so that a packet is received, parsed, and
+//! aggregated in that core's caches.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+/// How many workers a `Pipeline` is split into. Each worker has a UDP socket
+/// of its own bound to each of the pipeline's UDP addresses with
+/// `SO_REUSEPORT`, which the kernel spreads datagrams across by where they're
+/// from (so each source's packets go to the same worker), and a backend of its
+/// own that it records to and flushes on its own threads. Only the first
+/// worker receives from TCP and other listeners.
+///
+/// Since each worker's backend is separate, quotas and
+/// `RedisConfig::last_flush_key` apply to each worker on its own, as does
+/// `PipelineBuilder::rate_limit`. More than one worker needs `SO_REUSEPORT`'s
+/// load balancing, which is only on Linux.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workers {
+    /// The number of workers. Defaults to one.
+    pub count: usize,
+
+    /// Whether each worker's threads are pinned to a core, going through the
+    /// cores that the process may run on in order (and starting over if
+    /// there are more workers than cores). Only on Linux; elsewhere it's
+    /// ignored.
+    pub pin: bool,
+}
+
+impl Default for Workers {
+    fn default() -> Workers {
+        Workers {
+            count: 1,
+            pin: false,
+        }
+    }
+}
+
+// Binds a UDP socket to `addr` with `SO_REUSEPORT`, so that each worker's
+// socket can be bound to it.
+#[cfg(target_os = "linux")]
+pub(super) fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    // SAFETY: the descriptor is owned by the socket as soon as it's created,
+    // which closes it if anything after fails.
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option's value is a `c_int` of the size passed.
+    let set = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEPORT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if set != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: each address is a zeroed `sockaddr_in` or `sockaddr_in6` with
+    // its fields filled in, and is passed with its own size.
+    let bound = unsafe {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let mut sockaddr: libc::sockaddr_in = mem::zeroed();
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let mut sockaddr: libc::sockaddr_in6 = mem::zeroed();
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_flowinfo = addr.flowinfo();
+                sockaddr.sin6_scope_id = addr.scope_id();
+                libc::bind(
+                    fd,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if bound != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn bind_reuseport(_addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "more than one worker needs Linux",
+    ))
+}
+
+// Returns the cores that the process may run on, in order.
+#[cfg(target_os = "linux")]
+pub(super) fn cores() -> io::Result<Vec<usize>> {
+    // SAFETY: the set is zeroed, which is empty, and is passed with its size.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let count = libc::CPU_SETSIZE as usize;
+        Ok((0..count)
+            .filter(|&core| libc::CPU_ISSET(core, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn cores() -> io::Result<Vec<usize>> {
+    Ok(vec![0])
+}
+
+// Pins the calling thread to `core`.
+#[cfg(target_os = "linux")]
+pub(super) fn pin(core: usize) -> io::Result<()> {
+    // SAFETY: the set is zeroed, which is empty, before the core's added to
+    // it, and is passed with its size.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn pin(_core: usize) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! proptest strategies that generate metrics representable in the StatsD
+//! line format, along with `Arbitrary` implementations for the parser's types
+//! built on them. Only available with the `proptest` feature.
+
+use crate::parser::{Batch, Metric, MetricSign, MetricType, Tag, Unit};
+use proptest::prelude::*;
+
+/// The largest batch that `batch` will generate.
+pub const MAX_BATCH_LEN: usize = 32;
+
+/// The most tags that `tags` will generate for one metric.
+pub const MAX_TAGS: usize = 4;
+
+/// Generates metric names made up of characters that are safe in StatsD.
+pub fn name() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9_.-]{1,32}"
+}
+
+/// Generates unsigned integer or decimal values.
+pub fn value() -> impl Strategy<Value = String> {
+    "[0-9]{1,10}(\\.[0-9]{1,6})?"
+}
+
+/// Generates sample rates in the range (0, 1].
+pub fn sample_rate() -> impl Strategy<Value = f64> {
+    (1u32..=1000).prop_map(|n| f64::from(n) / 1000.0)
+}
+
+/// Generates any metric type.
+pub fn metric_type() -> impl Strategy<Value = MetricType> {
+    prop_oneof![
+        Just(MetricType::Counter),
+        Just(MetricType::Gauge),
+        Just(MetricType::Sample),
+        Just(MetricType::Set),
+        Just(MetricType::KeyValue),
+        Just(MetricType::Histogram),
+        Just(MetricType::Distribution),
+        Just(MetricType::Meter),
+    ]
+}
+
+/// Generates tags that are either bare keys or key/value pairs. Values may
+/// contain ":" since only the first one separates the key.
+pub fn tag() -> impl Strategy<Value = Tag> {
+    (
+        "[a-z][a-z0-9_.]{0,15}",
+        proptest::option::of("[a-zA-Z0-9_./:-]{1,16}"),
+    )
+        .prop_map(|(key, value)| Tag { key, value })
+}
+
+/// Generates up to `MAX_TAGS` tags.
+pub fn tags() -> impl Strategy<Value = Vec<Tag>> {
+    proptest::collection::vec(tag(), 0..=MAX_TAGS)
+}
+
+/// Generates either metric sign.
+pub fn sign() -> impl Strategy<Value = MetricSign> {
+    prop_oneof![Just(MetricSign::Minus), Just(MetricSign::Plus)]
+}
+
+/// Generates metrics of any type along with the optional parts (sign, unit,
+/// sample rate, container ID, timestamp) that make sense for that type.
+pub fn metric() -> impl Strategy<Value = Metric> {
+    (
+        name(),
+        value(),
+        metric_type(),
+        prop_oneof![Just("ms"), Just("us"), Just("ns")],
+        proptest::option::of(sample_rate()),
+        proptest::option::of(sign()),
+        tags(),
+        proptest::option::of("[0-9a-f]{1,12}"),
+        proptest::option::of(0..i64::MAX),
+    )
+        .prop_map(
+            |(
+                name,
+                value,
+                metric_type,
+                unit,
+                sample_rate,
+                sign,
+                tags,
+                container_id,
+                timestamp,
+            )| {
+                Metric {
+                    name,
+                    value,
+                    metric_type,
+                    unit: if metric_type == MetricType::Sample {
+                        Some(Unit::from(unit))
+                    } else {
+                        None
+                    },
+                    sample_rate: sample_rate
+                        .filter(|_| metric_type.supports_sample_rate()),
+                    sign: match metric_type {
+                        MetricType::Gauge => sign,
+                        MetricType::Counter => sign.filter(|s| *s == MetricSign::Minus),
+                        _ => None,
+                    },
+                    tags,
+                    container_id,
+                    timestamp,
+                }
+            },
+        )
+}
+
+/// Generates batches of between 1 and `MAX_BATCH_LEN` metrics with no events,
+/// service checks, or diagnostics.
+pub fn batch() -> impl Strategy<Value = Batch> {
+    proptest::collection::vec(metric(), 1..=MAX_BATCH_LEN).prop_map(|metrics| Batch {
+        metrics,
+        ..Batch::default()
+    })
+}
+
+impl Arbitrary for Metric {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Metric>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        metric().boxed()
+    }
+}
+
+impl Arbitrary for MetricType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<MetricType>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        metric_type().boxed()
+    }
+}
+
+impl Arbitrary for MetricSign {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<MetricSign>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        sign().boxed()
+    }
+}
+
+impl Arbitrary for Tag {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Tag>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        tag().boxed()
+    }
+}
+
+impl Arbitrary for Batch {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Batch>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        batch().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, ParserConfig};
+
+    proptest! {
+        #[test]
+        fn it_generates_parseable_batches(batch in any::<Batch>()) {
+            let lines: Vec<_> = batch.metrics.iter().map(|m| m.to_string()).collect();
+            let payload = lines.join("\n");
+            let parsed = parser::parse(payload.as_bytes(), &ParserConfig::default())
+                .unwrap();
+            prop_assert_eq!(parsed.metrics, batch.metrics);
+        }
+    }
+}